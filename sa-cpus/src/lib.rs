@@ -0,0 +1,118 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of the number of CPUs available to the process, shared by the archive, its backend and the work queue.
+
+use std::{fs, path::Path};
+
+/// Mount point of the cgroup filesystem.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The number of CPUs this process may use.
+/// In a container this is the CPU quota of its cgroup, rather than the number of cores of the host.
+pub fn available_cpus() -> usize {
+	let cpus = cpus_within(num_cpus::get(), cgroup_cpu_quota(Path::new(CGROUP_ROOT)));
+	log::debug!("Detected {} available CPUs", cpus);
+	cpus
+}
+
+/// Limit `cpus` to a cgroup quota, rounding a partial CPU up.
+fn cpus_within(cpus: usize, quota: Option<f64>) -> usize {
+	match quota {
+		Some(quota) => std::cmp::max(1, std::cmp::min(cpus, quota.ceil() as usize)),
+		None => cpus,
+	}
+}
+
+/// Read the CPU quota, in CPUs, of the cgroup mounted at `root`.
+/// Supports both cgroup v2 (`cpu.max`) and v1 (`cpu.cfs_quota_us` and `cpu.cfs_period_us`).
+/// Returns `None` if there is no quota.
+fn cgroup_cpu_quota(root: &Path) -> Option<f64> {
+	let read = |path: &Path| fs::read_to_string(path).ok();
+	let (quota, period) = match read(&root.join("cpu.max")) {
+		// cgroup v2: `<quota> <period>`, with a quota of `max` if unlimited
+		Some(max) => {
+			let mut fields = max.split_whitespace();
+			(fields.next()?.to_string(), fields.next()?.to_string())
+		}
+		// cgroup v1: a quota of `-1` if unlimited
+		None => {
+			let dir = ["cpu", "cpu,cpuacct", "cpuacct,cpu"].iter().map(|d| root.join(d)).find(|d| d.is_dir())?;
+			(read(&dir.join("cpu.cfs_quota_us"))?, read(&dir.join("cpu.cfs_period_us"))?)
+		}
+	};
+	let quota: f64 = quota.trim().parse().ok()?;
+	let period: f64 = period.trim().parse().ok()?;
+	if quota > 0.0 && period > 0.0 {
+		Some(quota / period)
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::PathBuf;
+
+	/// A directory standing in for the cgroup mount, removed on drop.
+	struct MockCgroup(PathBuf);
+
+	impl MockCgroup {
+		fn new(name: &str, files: &[(&str, &str)]) -> Self {
+			let root = std::env::temp_dir().join(format!("sa-cpus-cgroup-{}-{}", name, std::process::id()));
+			for (path, contents) in files {
+				let path = root.join(path);
+				fs::create_dir_all(path.parent().unwrap()).unwrap();
+				fs::write(path, contents).unwrap();
+			}
+			Self(root)
+		}
+
+		fn quota(&self) -> Option<f64> {
+			cgroup_cpu_quota(&self.0)
+		}
+	}
+
+	impl Drop for MockCgroup {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn default_threads_respect_cgroup_quota() {
+		let v2 = MockCgroup::new("v2", &[("cpu.max", "150000 100000\n")]);
+		assert_eq!(v2.quota(), Some(1.5));
+		assert_eq!(cpus_within(64, v2.quota()), 2);
+
+		let v1 = MockCgroup::new(
+			"v1",
+			&[("cpu,cpuacct/cpu.cfs_quota_us", "400000\n"), ("cpu,cpuacct/cpu.cfs_period_us", "100000\n")],
+		);
+		assert_eq!(cpus_within(64, v1.quota()), 4);
+
+		// a quota above the host's cores does not add threads
+		assert_eq!(cpus_within(2, v1.quota()), 2);
+
+		let unlimited = MockCgroup::new("unlimited", &[("cpu.max", "max 100000\n")]);
+		assert_eq!(unlimited.quota(), None);
+		assert_eq!(cpus_within(64, unlimited.quota()), 64);
+
+		let missing = MockCgroup::new("missing", &[]);
+		assert_eq!(missing.quota(), None);
+	}
+}
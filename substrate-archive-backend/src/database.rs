@@ -17,13 +17,24 @@
 //! Custom Read-Only Database Instance using RocksDB Secondary features.
 //! Will try catching up with primary database on every `get()`.
 
-use std::{collections::HashMap, fmt, io, path::PathBuf};
+use std::{
+	collections::HashMap,
+	fmt, io,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+	time::SystemTime,
+};
 
 use kvdb::KeyValueDB;
 use kvdb_rocksdb::{Database, DatabaseConfig};
 
 use sp_database::{ColumnId, Database as DatabaseTrait, Transaction};
 
+use crate::util::{self, columns, meta_keys};
+
 const NUM_COLUMNS: u32 = 11;
 
 pub type KeyValuePair = (Box<[u8]>, Box<[u8]>);
@@ -38,15 +49,67 @@ pub trait ReadOnlyDb: Send + Sync {
 	fn iter<'a>(&'a self, col: u32) -> Box<dyn Iterator<Item = KeyValuePair> + 'a>;
 	/// Catch up with the latest information added to the database
 	fn catch_up_with_primary(&self) -> io::Result<()>;
+	/// How often, and when last, the database caught up with the primary.
+	/// Nothing is recorded by default.
+	fn catch_up_stats(&self) -> CatchUpStats {
+		CatchUpStats::default()
+	}
 	/// Open database as read-only
 	fn open_database(path: &str, cache_size: usize, db_path: PathBuf) -> io::Result<Self>
 	where
 		Self: Sized;
 }
 
+/// Statistics of a secondary database catching up with the primary database of the running node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CatchUpStats {
+	/// Times the database caught up with the primary.
+	pub count: u64,
+	/// Times catching up with the primary failed.
+	pub failures: u64,
+	/// When the database last caught up with the primary, if it ever did.
+	pub last: Option<SystemTime>,
+	/// Best block of the primary when the database last caught up with it.
+	pub best_block: Option<u32>,
+}
+
+#[derive(Default)]
+struct CatchUpCounter {
+	count: AtomicU64,
+	failures: AtomicU64,
+	last: Mutex<Option<(SystemTime, Option<u32>)>>,
+}
+
+impl CatchUpCounter {
+	/// Record a catch-up, along with the best block it caught up to.
+	fn record(&self, result: &io::Result<Option<u32>>) {
+		match result {
+			Ok(best_block) => {
+				self.count.fetch_add(1, Ordering::Relaxed);
+				*self.last.lock().expect("Lock is never poisoned") = Some((SystemTime::now(), *best_block));
+			}
+			Err(_) => {
+				self.failures.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	fn stats(&self) -> CatchUpStats {
+		let last = *self.last.lock().expect("Lock is never poisoned");
+		CatchUpStats {
+			count: self.count.load(Ordering::Relaxed),
+			failures: self.failures.load(Ordering::Relaxed),
+			last: last.map(|(at, _)| at),
+			best_block: last.and_then(|(_, best_block)| best_block),
+		}
+	}
+}
+
 #[derive(parity_util_mem::MallocSizeOf)]
 pub struct SecondaryRocksDb {
 	inner: Database,
+	#[ignore_malloc_size_of = "only counters"]
+	catch_ups: CatchUpCounter,
 }
 
 impl fmt::Debug for SecondaryRocksDb {
@@ -58,9 +121,21 @@ impl fmt::Debug for SecondaryRocksDb {
 
 impl SecondaryRocksDb {
 	pub fn open(config: DatabaseConfig, path: &str) -> io::Result<Self> {
-		let inner = Database::open(&config, path)?;
-		inner.try_catch_up_with_primary()?;
-		Ok(Self { inner })
+		let db = Self { inner: Database::open(&config, path)?, catch_ups: CatchUpCounter::default() };
+		db.try_catch_up()?;
+		Ok(db)
+	}
+
+	fn try_catch_up(&self) -> io::Result<()> {
+		let result = self.inner.try_catch_up_with_primary().map(|()| self.best_block());
+		self.catch_ups.record(&result);
+		result.map(drop)
+	}
+
+	/// Number of the best block, as last written by the primary.
+	fn best_block(&self) -> Option<u32> {
+		let key = self.inner.get(columns::META, meta_keys::BEST_BLOCK).ok()??;
+		util::lookup_key_to_number(&key)
 	}
 
 	fn get(&self, col: ColumnId, key: &[u8]) -> Option<Vec<u8>> {
@@ -91,7 +166,11 @@ impl ReadOnlyDb for SecondaryRocksDb {
 	}
 
 	fn catch_up_with_primary(&self) -> io::Result<()> {
-		self.inner.try_catch_up_with_primary()
+		self.try_catch_up()
+	}
+
+	fn catch_up_stats(&self) -> CatchUpStats {
+		self.catch_ups.stats()
 	}
 
 	fn open_database(path: &str, cache_size: usize, db_path: PathBuf) -> io::Result<SecondaryRocksDb> {
@@ -134,3 +213,30 @@ impl<H: Clone + AsRef<[u8]>> DatabaseTrait<H> for SecondaryRocksDb {
 		self.get(col, key)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_count_catch_ups() {
+		let counter = CatchUpCounter::default();
+		assert_eq!(counter.stats(), CatchUpStats::default());
+		counter.record(&Ok(Some(10)));
+		counter.record(&Ok(Some(12)));
+		counter.record(&Err(io::Error::new(io::ErrorKind::Other, "primary is gone")));
+		let stats = counter.stats();
+		assert_eq!((stats.count, stats.failures), (2, 1));
+		assert!(stats.last.is_some());
+		// the best block of the last successful catch-up
+		assert_eq!(stats.best_block, Some(12));
+	}
+
+	#[test]
+	fn should_read_number_of_lookup_key() {
+		let mut key = util::number_index_key(1_234_567_u32).unwrap().to_vec();
+		key.extend_from_slice(&[0xAB; 32]);
+		assert_eq!(util::lookup_key_to_number(&key), Some(1_234_567));
+		assert_eq!(util::lookup_key_to_number(&[0x01, 0x02]), None);
+	}
+}
@@ -35,6 +35,12 @@ pub enum BackendError {
 	VersionNotFound,
 	#[error("Storage does not exist")]
 	StorageNotExist,
+	/// The block is not in the database, I.E because the node has not imported it yet.
+	#[error("Block {0} not found")]
+	BlockNotFound(String),
+	/// The block is known, but its state was pruned by the node.
+	#[error("State of block {block} is not available, it may have been pruned: {reason}")]
+	StateUnavailable { block: String, reason: String },
 	#[error("Unexpected Error: {0}")]
 	Msg(String),
 }
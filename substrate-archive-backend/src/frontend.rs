@@ -15,6 +15,7 @@
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
 mod client;
+mod overrides;
 
 use serde::Deserialize;
 use std::{
@@ -35,7 +36,10 @@ use sp_api::ConstructRuntimeApi;
 use sp_core::traits::SpawnNamed;
 use sp_runtime::traits::{BlakeTwo256, Block as BlockT, NumberFor};
 
-pub use self::client::{Client, GetMetadata};
+pub use self::{
+	client::{Client, ExecutorPin, GetMetadata, PinExecutor},
+	overrides::OverridesWatcher,
+};
 use crate::{database::ReadOnlyDb, error::BackendError, read_only_backend::ReadOnlyBackend, RuntimeApiCollection};
 
 /// Archive Client Condensed Type
@@ -80,6 +84,10 @@ pub struct RuntimeConfig {
 	pub wasm_pages: Option<u64>,
 	/// Path to WASM blobs to override the on-chain WASM with (required for state change tracing).
 	pub wasm_runtime_overrides: Option<PathBuf>,
+	/// File to persist the runtime versions found at blocks to,
+	/// so that they are not computed from the WASM again on every startup.
+	/// The archive resolves relative paths against its data directory.
+	pub runtime_cache_path: Option<PathBuf>,
 	/// code substitutes that should be used for the on chain wasm.
 	///
 	/// NOTE: Not to be confused with 'wasm_runtime_overrides'. code_substitutes
@@ -97,6 +105,17 @@ impl RuntimeConfig {
 	pub fn set_code_substitutes(&mut self, spec: &dyn ChainSpec) {
 		self.code_substitutes = spec.code_substitutes();
 	}
+
+	/// Add code substitutes, keyed by the block number they apply from.
+	/// Replaces any substitute already set for the same block.
+	pub fn add_code_substitutes(&mut self, substitutes: impl IntoIterator<Item = (String, Vec<u8>)>) {
+		self.code_substitutes.extend(substitutes);
+	}
+
+	/// Code substitutes, keyed by the block number they apply from.
+	pub fn code_substitutes(&self) -> &BTreeMap<String, Vec<u8>> {
+		&self.code_substitutes
+	}
 }
 
 impl Default for RuntimeConfig {
@@ -106,15 +125,16 @@ impl Default for RuntimeConfig {
 			block_workers: default_block_workers(),
 			wasm_pages: None,
 			wasm_runtime_overrides: None,
+			runtime_cache_path: None,
 			code_substitutes: Default::default(),
 			storage_mode: TransactionStorageMode::BlockBody,
 		}
 	}
 }
 
-// the number of logical cpus in the system
+// the number of cpus available to the process, which may be limited by its cgroup
 fn default_block_workers() -> usize {
-	num_cpus::get()
+	sa_cpus::available_cpus()
 }
 
 const fn default_storage_mode() -> TransactionStorageMode {
@@ -170,6 +190,21 @@ where
 		+ Sync
 		+ 'static,
 	<Runtime::RuntimeApi as sp_api::ApiExt<Block>>::StateBackend: sp_api::StateBackend<BlakeTwo256>,
+{
+	let executor = call_executor(config, backend.clone(), task_executor)?;
+	let client = Client::new(backend, executor, ExecutionExtensions::new(execution_strategies(), None, None))?;
+	Ok(client)
+}
+
+/// Build the executor calling into the runtime, with the WASM runtime overrides read from the folder in `config`.
+fn call_executor<Block, D>(
+	config: RuntimeConfig,
+	backend: Arc<ReadOnlyBackend<Block, D>>,
+	task_executor: impl SpawnNamed + 'static,
+) -> Result<TFullCallExecutor<Block, D>, BackendError>
+where
+	Block: BlockT,
+	D: ReadOnlyDb + 'static,
 {
 	let executor = WasmExecutor::<sp_io::SubstrateHostFunctions>::new(
 		config.exec_method.into(),
@@ -178,9 +213,7 @@ where
 		None,
 		128,
 	);
-	let executor = LocalCallExecutor::new(backend.clone(), executor, Box::new(task_executor), config.try_into()?)?;
-	let client = Client::new(backend, executor, ExecutionExtensions::new(execution_strategies(), None, None))?;
-	Ok(client)
+	Ok(LocalCallExecutor::new(backend, executor, Box::new(task_executor), config.try_into()?)?)
 }
 
 fn execution_strategies() -> ExecutionStrategies {
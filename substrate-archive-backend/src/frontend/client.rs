@@ -23,9 +23,11 @@
 //! It's recommended to use the backend (ReadOnlyBackend) for anything that requires getting blocks, querying
 //! storage, or similar operations. Client usage should be reserved for calling into the Runtime.
 
-use std::{marker::PhantomData, panic::UnwindSafe, sync::Arc};
+use std::{marker::PhantomData, panic::UnwindSafe, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use codec::{Decode, Encode};
+use parking_lot::{RwLock, RwLockReadGuard};
 
 use sc_client_api::{backend::Backend as _, execution_extensions::ExecutionExtensions, CallExecutor};
 use sc_executor::RuntimeVersion;
@@ -46,10 +48,21 @@ pub trait GetMetadata<Block: BlockT>: Send + Sync {
 	fn metadata(&self, id: &BlockId<Block>) -> Result<sp_core::OpaqueMetadata, BackendError>;
 }
 
+/// Guard keeping the executor of a client from being replaced, returned by [`PinExecutor::pin_executor`].
+pub type ExecutorPin<'a> = RwLockReadGuard<'a, ()>;
+
+/// Trait to keep the executor of a client from being replaced, I.E by reloading WASM runtime overrides.
+pub trait PinExecutor {
+	/// All calls into the runtime made while the returned guard is alive use the same executor.
+	fn pin_executor(&self) -> ExecutorPin<'_>;
+}
+
 /// Archive Client
 pub struct Client<Exec, Block: BlockT, RA, D: ReadOnlyDb> {
 	backend: Arc<ReadOnlyBackend<Block, D>>,
-	executor: Exec,
+	executor: ArcSwap<Exec>,
+	/// Held for reading by callers which need the executor to stay the same across calls.
+	pins: RwLock<()>,
 	execution_extensions: ExecutionExtensions<Block>,
 	_marker: PhantomData<RA>,
 }
@@ -66,7 +79,13 @@ where
 		executor: Exec,
 		execution_extensions: ExecutionExtensions<Block>,
 	) -> Result<Self, BackendError> {
-		Ok(Client { backend, executor, execution_extensions, _marker: PhantomData })
+		Ok(Client {
+			backend,
+			executor: ArcSwap::from_pointee(executor),
+			pins: RwLock::new(()),
+			execution_extensions,
+			_marker: PhantomData,
+		})
 	}
 
 	pub fn state_at(&self, id: &BlockId<Block>) -> Option<TrieState<Block, D>> {
@@ -83,6 +102,32 @@ where
 	}
 }
 
+impl<Exec, Block: BlockT, RA, D: ReadOnlyDb> Client<Exec, Block, RA, D> {
+	/// Replace the executor, I.E to reload WASM runtime overrides.
+	/// Waits up to `timeout` for the executor to be unpinned, returning an error if it is still pinned.
+	/// Calls which are already running finish with the previous executor.
+	pub fn replace_executor(&self, executor: Exec, timeout: Duration) -> Result<(), BackendError> {
+		let _pins = self
+			.pins
+			.try_write_for(timeout)
+			.ok_or_else(|| BackendError::Msg(format!("executor is still pinned after {:?}", timeout)))?;
+		self.executor.store(Arc::new(executor));
+		Ok(())
+	}
+
+	/// The executor calls into the runtime are currently made with.
+	#[cfg(test)]
+	pub(crate) fn executor(&self) -> Arc<Exec> {
+		self.executor.load_full()
+	}
+}
+
+impl<Exec, Block: BlockT, RA, D: ReadOnlyDb> PinExecutor for Client<Exec, Block, RA, D> {
+	fn pin_executor(&self) -> ExecutorPin<'_> {
+		self.pins.read()
+	}
+}
+
 impl<Exec, Block, RA, D> GetRuntimeVersionAt<Block> for Client<Exec, Block, RA, D>
 where
 	D: ReadOnlyDb + 'static,
@@ -91,7 +136,7 @@ where
 	RA: Send + Sync,
 {
 	fn runtime_version(&self, at: &BlockId<Block>) -> Result<sp_version::RuntimeVersion, String> {
-		GetRuntimeVersionAt::runtime_version(&self.executor, at)
+		GetRuntimeVersionAt::runtime_version(&**self.executor.load(), at)
 	}
 }
 
@@ -142,7 +187,9 @@ where
 
 		let (manager, extensions) = self.execution_extensions.manager_and_extensions(at, params.context);
 
+		// a snapshot of the executor, so that it stays alive if it is replaced during the call.
 		self.executor
+			.load_full()
 			.contextual_call::<fn(_, _) -> _, _, _>(
 				at,
 				params.function,
@@ -0,0 +1,209 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reloads the WASM runtime overrides of a client when the folder they are kept in changes,
+//! so that new tracing-enabled runtimes are picked up without a restart.
+
+use std::{
+	sync::{
+		mpsc::{self, Receiver, RecvTimeoutError},
+		Arc,
+	},
+	thread,
+	time::Duration,
+};
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use sp_core::traits::SpawnNamed;
+use sp_runtime::traits::Block as BlockT;
+
+use super::{call_executor, RuntimeConfig, TArchiveClient};
+use crate::{database::ReadOnlyDb, error::BackendError};
+
+// Time for writes to the folder to settle before the overrides are reloaded.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+// Time to wait for blocks executing with the previous overrides to finish, before the reload is retried.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Watches the folder of WASM runtime overrides, reloading them into the client when it changes.
+/// Stops watching once dropped.
+pub struct OverridesWatcher {
+	_watcher: RecommendedWatcher,
+}
+
+impl OverridesWatcher {
+	/// Watch the folder of `config.wasm_runtime_overrides`, reloading the overrides of `client` when it changes.
+	pub fn spawn<Block, Runtime, D>(
+		client: Arc<TArchiveClient<Block, Runtime, D>>,
+		config: RuntimeConfig,
+		task_executor: impl SpawnNamed + Clone + Send + 'static,
+	) -> Result<Self, BackendError>
+	where
+		Block: BlockT,
+		Runtime: Send + Sync + 'static,
+		D: ReadOnlyDb + 'static,
+	{
+		let folder = config.wasm_runtime_overrides.clone().ok_or("no folder of WASM runtime overrides is set")?;
+		let (tx, rx) = mpsc::channel();
+		let mut watcher = notify::watcher(tx, DEBOUNCE).map_err(|e| BackendError::Msg(e.to_string()))?;
+		watcher.watch(&folder, RecursiveMode::NonRecursive).map_err(|e| BackendError::Msg(e.to_string()))?;
+		log::info!("Watching {} for changes to the WASM runtime overrides", folder.display());
+		thread::Builder::new()
+			.name("wasm-overrides-watcher".into())
+			.spawn(move || reload_on_change(rx, client, config, task_executor))?;
+		Ok(Self { _watcher: watcher })
+	}
+}
+
+fn reload_on_change<Block, Runtime, D>(
+	events: Receiver<DebouncedEvent>,
+	client: Arc<TArchiveClient<Block, Runtime, D>>,
+	config: RuntimeConfig,
+	task_executor: impl SpawnNamed + Clone + 'static,
+) where
+	Block: BlockT,
+	D: ReadOnlyDb + 'static,
+{
+	let mut pending = false;
+	loop {
+		match events.recv_timeout(RELOAD_TIMEOUT) {
+			Ok(DebouncedEvent::Create(_))
+			| Ok(DebouncedEvent::Write(_))
+			| Ok(DebouncedEvent::Remove(_))
+			| Ok(DebouncedEvent::Rename(_, _))
+			| Ok(DebouncedEvent::Rescan) => pending = true,
+			Ok(DebouncedEvent::Error(e, path)) => log::warn!("Error watching WASM runtime overrides {:?}: {}", path, e),
+			Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+			// the watcher was dropped
+			Err(RecvTimeoutError::Disconnected) => return,
+		}
+		if !pending {
+			continue;
+		}
+		// the overrides are read from the folder when the executor is built
+		let executor = match call_executor(config.clone(), client.backend(), task_executor.clone()) {
+			Ok(executor) => executor,
+			Err(e) => {
+				log::error!("Failed to load WASM runtime overrides, keeping the previous ones: {}", e);
+				pending = false;
+				continue;
+			}
+		};
+		// blocks pin the executor while they execute, so that none executes with a mix of old and new overrides.
+		match client.replace_executor(executor, RELOAD_TIMEOUT) {
+			Ok(()) => {
+				log::info!("Reloaded WASM runtime overrides");
+				pending = false;
+			}
+			Err(e) => log::warn!("Failed to reload WASM runtime overrides, retrying: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		database::KeyValuePair,
+		frontend::{execution_strategies, Client, TFullCallExecutor},
+		read_only_backend::ReadOnlyBackend,
+	};
+	use sc_client_api::execution_extensions::ExecutionExtensions;
+	use sp_core::testing::TaskExecutor;
+	use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper};
+	use std::{
+		fs, io,
+		path::{Path, PathBuf},
+		time::Instant,
+	};
+
+	type Block = TestBlock<ExtrinsicWrapper<u64>>;
+	type TestClient = TArchiveClient<Block, (), EmptyDb>;
+
+	/// A database of the node without any blocks. Loading overrides doesn't read from it.
+	struct EmptyDb;
+
+	impl ReadOnlyDb for EmptyDb {
+		fn get(&self, _col: u32, _key: &[u8]) -> Option<Vec<u8>> {
+			None
+		}
+
+		fn iter<'a>(&'a self, _col: u32) -> Box<dyn Iterator<Item = KeyValuePair> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn catch_up_with_primary(&self) -> io::Result<()> {
+			Ok(())
+		}
+
+		fn open_database(_path: &str, _cache_size: usize, _db_path: PathBuf) -> io::Result<Self> {
+			Ok(EmptyDb)
+		}
+	}
+
+	/// A client using the WASM runtime overrides of `folder`, whose overrides are reloaded when it changes.
+	fn watched_client(folder: &Path) -> Result<(Arc<TestClient>, OverridesWatcher), BackendError> {
+		let config = RuntimeConfig { wasm_runtime_overrides: Some(folder.to_path_buf()), ..Default::default() };
+		let backend = Arc::new(ReadOnlyBackend::new(Arc::new(EmptyDb), true, config.storage_mode));
+		let executor = call_executor(config.clone(), backend.clone(), TaskExecutor::new())?;
+		let client =
+			Arc::new(Client::new(backend, executor, ExecutionExtensions::new(execution_strategies(), None, None))?);
+		let watcher = OverridesWatcher::spawn(client.clone(), config, TaskExecutor::new())?;
+		Ok((client, watcher))
+	}
+
+	/// Wait for the executor of `client` to be replaced, for a while longer than changes are debounced for.
+	fn is_replaced(client: &TestClient, executor: &Arc<TFullCallExecutor<Block, EmptyDb>>) -> bool {
+		let now = Instant::now();
+		while now.elapsed() < DEBOUNCE * 3 {
+			if !Arc::ptr_eq(&client.executor(), executor) {
+				return true;
+			}
+			thread::sleep(Duration::from_millis(50));
+		}
+		false
+	}
+
+	#[test]
+	fn should_reload_rewritten_overrides() -> Result<(), BackendError> {
+		let folder = tempfile::tempdir()?;
+		let file = folder.path().join("runtime.wasm");
+		fs::write(&file, substrate_test_runtime::wasm_binary_unwrap())?;
+		let (client, _watcher) = watched_client(folder.path())?;
+
+		let executor = client.executor();
+		fs::write(&file, substrate_test_runtime::wasm_binary_unwrap())?;
+		assert!(is_replaced(&client, &executor));
+		Ok(())
+	}
+
+	#[test]
+	fn should_keep_executor_if_overrides_are_broken() -> Result<(), BackendError> {
+		let folder = tempfile::tempdir()?;
+		let file = folder.path().join("runtime.wasm");
+		fs::write(&file, substrate_test_runtime::wasm_binary_unwrap())?;
+		let (client, _watcher) = watched_client(folder.path())?;
+
+		let executor = client.executor();
+		fs::write(&file, b"not a runtime")?;
+		assert!(!is_replaced(&client, &executor));
+
+		// the overrides are reloaded once the file is fixed
+		fs::write(&file, substrate_test_runtime::wasm_binary_unwrap())?;
+		assert!(is_replaced(&client, &executor));
+		Ok(())
+	}
+}
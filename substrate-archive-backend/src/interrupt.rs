@@ -0,0 +1,90 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Interruption of runtime calls which run for too long.
+//! WASM can't be stopped from the outside while it runs, but every read of the state of a block goes through
+//! the backend. Once the [`Interrupt`] in scope on the thread making a runtime call is triggered,
+//! the next read of state fails, which fails the runtime call and frees the thread.
+//! Runtime code which loops without reading state is not interrupted.
+
+use std::{
+	cell::RefCell,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
+
+thread_local!(static CURRENT: RefCell<Option<Interrupt>> = RefCell::new(None));
+
+/// Handle to interrupt the runtime calls made in [`Interrupt::scope`].
+#[derive(Clone, Default, Debug)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Fail the next read of state of the runtime calls made in the scope of this interrupt.
+	pub fn trigger(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_triggered(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+
+	/// Run `f`, interrupting the runtime calls it makes on this thread once this interrupt is triggered.
+	pub fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+		struct Restore(Option<Interrupt>);
+		impl Drop for Restore {
+			fn drop(&mut self) {
+				let previous = self.0.take();
+				CURRENT.with(|current| *current.borrow_mut() = previous);
+			}
+		}
+		let _restore = Restore(CURRENT.with(|current| current.borrow_mut().replace(self.clone())));
+		f()
+	}
+
+	/// Whether the interrupt in scope on this thread was triggered.
+	pub fn is_interrupted() -> bool {
+		CURRENT.with(|current| current.borrow().as_ref().map_or(false, Interrupt::is_triggered))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_interrupt_only_in_scope() {
+		let interrupt = Interrupt::new();
+		interrupt.trigger();
+		assert!(!Interrupt::is_interrupted());
+		interrupt.scope(|| {
+			assert!(Interrupt::is_interrupted());
+			// a nested scope shadows the outer one
+			Interrupt::new().scope(|| assert!(!Interrupt::is_interrupted()));
+			assert!(Interrupt::is_interrupted());
+		});
+		assert!(!Interrupt::is_interrupted());
+
+		// threads spawned in the scope are not interrupted
+		assert!(!interrupt.scope(|| std::thread::spawn(Interrupt::is_interrupted).join().unwrap()));
+	}
+}
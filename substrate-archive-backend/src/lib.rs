@@ -37,6 +37,7 @@
 mod database;
 mod error;
 mod frontend;
+mod interrupt;
 mod read_only_backend;
 mod runtime_version_cache;
 mod util;
@@ -51,11 +52,14 @@ use sp_version::GetRuntimeVersionAt;
 use self::frontend::GetMetadata;
 // re-exports
 pub use self::{
-	database::{KeyValuePair, ReadOnlyDb, SecondaryRocksDb},
+	database::{CatchUpStats, KeyValuePair, ReadOnlyDb, SecondaryRocksDb},
 	error::BackendError,
-	frontend::{runtime_api, ExecutionMethod, RuntimeConfig, TArchiveClient},
+	frontend::{
+		runtime_api, ExecutionMethod, ExecutorPin, OverridesWatcher, PinExecutor, RuntimeConfig, TArchiveClient,
+	},
+	interrupt::Interrupt,
 	read_only_backend::ReadOnlyBackend,
-	runtime_version_cache::RuntimeVersionCache,
+	runtime_version_cache::{FileVersions, PersistVersion, RuntimeVersionCache},
 };
 
 pub type Meta<B> = Arc<dyn GetMetadata<B>>;
@@ -69,6 +73,7 @@ pub trait ApiAccess<Block, Backend, Runtime>:
 	+ CallApiAt<Block, StateBackend = Backend::State>
 	+ GetMetadata<Block>
 	+ GetRuntimeVersionAt<Block>
+	+ PinExecutor
 where
 	Block: BlockT,
 	Backend: BackendT<Block>,
@@ -85,6 +90,7 @@ where
 		+ CallApiAt<Block, StateBackend = Backend::State>
 		+ GetMetadata<Block>
 		+ GetRuntimeVersionAt<Block>
+		+ PinExecutor
 		+ Sized
 		+ Send
 		+ Sync,
@@ -45,7 +45,11 @@ use sp_runtime::{
 
 pub use self::state_backend::TrieState;
 use self::state_backend::{DbState, StateVault};
-use crate::{database::ReadOnlyDb, error::Result, util::columns};
+use crate::{
+	database::ReadOnlyDb,
+	error::{BackendError, Result},
+	util::columns,
+};
 
 pub struct ReadOnlyBackend<Block, D> {
 	db: Arc<D>,
@@ -94,6 +98,27 @@ where
 		header.map(|h| *h.state_root())
 	}
 
+	/// Get the value of `key` in the state of the block `id`, or `None` if the key is not set.
+	/// Errors if the block is unknown, or if its state was pruned by the node.
+	pub fn storage_at(&self, id: BlockId<Block>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+		let header = super::util::read_header::<Block, D>(&*self.db, columns::KEY_LOOKUP, columns::HEADER, id)?
+			.ok_or_else(|| BackendError::BlockNotFound(id.to_string()))?;
+		let hash = header.hash();
+		let state = DbState::<Block>::new(self.storage.clone(), *header.state_root());
+		let state = TrieState::<Block, D>::new(state, self.storage.clone(), Some(hash));
+		// the trie nodes of pruned states are missing, so reads of them fail rather than return `None`.
+		state.storage(key).map_err(|reason| BackendError::StateUnavailable {
+			block: format!("{:?}", hash),
+			reason: reason.to_string(),
+		})
+	}
+
+	/// Check the state of the block `id` is available, by reading the runtime code from it.
+	/// Errors like [`ReadOnlyBackend::storage_at`] if the block is unknown, or if its state was pruned.
+	pub fn check_state(&self, id: BlockId<Block>) -> Result<()> {
+		self.storage_at(id, sp_storage::well_known_keys::CODE).map(|_| ())
+	}
+
 	/// gets storage for some block hash
 	pub fn storage(&self, hash: Block::Hash, key: &[u8]) -> Option<Vec<u8>> {
 		match self.state_at(hash) {
@@ -175,3 +200,59 @@ fn construct_block<Block: BlockT>(
 		_ => None,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{database::KeyValuePair, util::number_index_key};
+	use codec::Encode;
+	use sp_core::H256;
+	use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper, Header};
+	use std::{collections::HashMap, io, path::PathBuf};
+
+	type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+	/// An in-memory database, keyed by column and key.
+	#[derive(Default)]
+	struct MockDb(HashMap<(u32, Vec<u8>), Vec<u8>>);
+
+	impl ReadOnlyDb for MockDb {
+		fn get(&self, col: u32, key: &[u8]) -> Option<Vec<u8>> {
+			self.0.get(&(col, key.to_vec())).cloned()
+		}
+
+		fn iter<'a>(&'a self, _col: u32) -> Box<dyn Iterator<Item = KeyValuePair> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn catch_up_with_primary(&self) -> io::Result<()> {
+			Ok(())
+		}
+
+		fn open_database(_path: &str, _cache_size: usize, _db_path: PathBuf) -> io::Result<Self> {
+			Ok(Self::default())
+		}
+	}
+
+	#[test]
+	fn storage_at_unknown_block_should_fail() {
+		let backend =
+			ReadOnlyBackend::<Block, _>::new(Arc::new(MockDb::default()), true, TransactionStorageMode::BlockBody);
+		let res = backend.storage_at(BlockId::Number(5), b":code");
+		assert!(matches!(res, Err(BackendError::BlockNotFound(_))));
+	}
+
+	#[test]
+	fn storage_at_pruned_state_should_fail() {
+		// the header of block 1 is known, but none of the trie nodes of its state are
+		let header = Header::new(1, H256::zero(), H256::repeat_byte(1), H256::zero(), Default::default());
+		let lookup_key = number_index_key(1u32).unwrap().to_vec();
+		let mut db = MockDb::default();
+		db.0.insert((columns::KEY_LOOKUP, lookup_key.clone()), lookup_key.clone());
+		db.0.insert((columns::HEADER, lookup_key), header.encode());
+
+		let backend = ReadOnlyBackend::<Block, _>::new(Arc::new(db), true, TransactionStorageMode::BlockBody);
+		let res = backend.storage_at(BlockId::Number(1), b":code");
+		assert!(matches!(res, Err(BackendError::StateUnavailable { .. })));
+	}
+}
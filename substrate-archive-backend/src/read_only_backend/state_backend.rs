@@ -28,7 +28,7 @@ use sp_runtime::traits::{Block as BlockT, HashFor};
 use sp_state_machine::{StateMachineStats, TrieBackend, UsageInfo as StateUsageInfo};
 use sp_storage::StateVersion;
 
-use crate::database::ReadOnlyDb;
+use crate::{database::ReadOnlyDb, interrupt::Interrupt};
 
 /// DB-backed patricia trie state, transaction type is an overlay of changes to commit.
 pub type DbState<B> = TrieBackend<Arc<dyn sp_state_machine::Storage<HashFor<B>>>, HashFor<B>>;
@@ -58,6 +58,9 @@ where
 	D: ReadOnlyDb,
 {
 	fn get(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		if Interrupt::is_interrupted() {
+			return Err("runtime call interrupted".into());
+		}
 		if self.prefix_keys {
 			let key = sp_trie::prefixed_key::<HashFor<Block>>(key, prefix);
 			Ok(self.db.get(super::columns::STATE, &key))
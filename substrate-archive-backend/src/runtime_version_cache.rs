@@ -17,14 +17,15 @@
 //! Will only call the `runtime_version` function once per wasm blob
 
 use std::{
-	collections::hash_map::DefaultHasher,
-	hash::{Hash as StdHash, Hasher as _},
+	fs, io,
+	path::{Path, PathBuf},
 	sync::Arc,
 };
 
 use arc_swap::ArcSwap;
-use codec::Decode;
+use codec::{Decode, Encode};
 use hashbrown::HashMap;
+use parking_lot::Mutex;
 
 use sc_executor::WasmExecutor;
 use sp_core::traits::ReadRuntimeVersion;
@@ -43,11 +44,79 @@ use crate::{
 	read_only_backend::ReadOnlyBackend,
 };
 
+/// Persists runtime versions discovered by the [`RuntimeVersionCache`],
+/// so that they do not need to be computed again after a restart.
+pub trait PersistVersion: Send + Sync {
+	/// Persist a new runtime version.
+	/// `code_hash` is the hash of the WASM blob, `block` the hash of the block the version was found at.
+	fn persist(&self, code_hash: u64, block: &[u8], version: &RuntimeVersion) -> Result<(), BackendError>;
+}
+
+/// Persists runtime versions into a file, I.E the one at [`RuntimeConfig::runtime_cache_path`].
+/// The whole file is rewritten whenever a version is added, which only happens on runtime upgrades.
+pub struct FileVersions {
+	path: PathBuf,
+	/// (Hash of the WASM blob, Hash of the block it was found at, RuntimeVersion)
+	entries: Mutex<Vec<(u64, Vec<u8>, RuntimeVersion)>>,
+}
+
+impl FileVersions {
+	/// Read the versions persisted at `path`. The file is created once a version is persisted.
+	pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+		let path = path.into();
+		let entries = match fs::read(&path) {
+			Ok(bytes) => Decode::decode(&mut bytes.as_slice())?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+			Err(e) => return Err(e.into()),
+		};
+		Ok(Self { path, entries: Mutex::new(entries) })
+	}
+
+	/// Path of the file versions are persisted to.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Versions in the file, keyed by the hash of their WASM blob.
+	pub fn versions(&self) -> Vec<(u64, RuntimeVersion)> {
+		self.entries.lock().iter().map(|(code_hash, _, version)| (*code_hash, version.clone())).collect()
+	}
+
+	/// Only keep versions for which `f` returns true, given the hash of their WASM blob
+	/// and of the block they were found at. Returns the number of versions removed.
+	pub fn retain(&self, mut f: impl FnMut(u64, &[u8]) -> bool) -> usize {
+		let mut entries = self.entries.lock();
+		let len = entries.len();
+		entries.retain(|(code_hash, block, _)| f(*code_hash, block));
+		len - entries.len()
+	}
+
+	fn write(&self, entries: &[(u64, Vec<u8>, RuntimeVersion)]) -> Result<()> {
+		// written to a temporary file first, so that a crash never leaves a truncated cache behind.
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, entries.encode())?;
+		fs::rename(&tmp, &self.path)?;
+		Ok(())
+	}
+}
+
+impl PersistVersion for FileVersions {
+	fn persist(&self, code_hash: u64, block: &[u8], version: &RuntimeVersion) -> Result<(), BackendError> {
+		let mut entries = self.entries.lock();
+		if entries.iter().any(|(hash, _, _)| *hash == code_hash) {
+			return Ok(());
+		}
+		entries.push((code_hash, block.to_vec(), version.clone()));
+		self.write(&entries)
+	}
+}
+
 pub struct RuntimeVersionCache<Block, Db> {
 	/// Hash of the WASM Blob -> RuntimeVersion
 	versions: ArcSwap<HashMap<u64, RuntimeVersion>>,
 	backend: Arc<ReadOnlyBackend<Block, Db>>,
 	exec: WasmExecutor<sp_io::SubstrateHostFunctions>,
+	persist: Vec<Arc<dyn PersistVersion>>,
 }
 
 impl<Block: BlockT, Db: ReadOnlyDb + 'static> RuntimeVersionCache<Block, Db> {
@@ -60,7 +129,59 @@ impl<Block: BlockT, Db: ReadOnlyDb + 'static> RuntimeVersionCache<Block, Db> {
 			None,
 			128,
 		);
-		Self { versions: ArcSwap::from_pointee(HashMap::new()), backend, exec }
+		let mut cache = Self { versions: ArcSwap::from_pointee(HashMap::new()), backend, exec, persist: Vec::new() };
+		if let Some(path) = config.runtime_cache_path {
+			match FileVersions::open(&path) {
+				Ok(file) => cache = cache.with_file(file),
+				Err(e) => log::warn!("Failed to read the runtime version cache at {}: {}", path.display(), e),
+			}
+		}
+		cache
+	}
+
+	/// Persist every version newly added to the cache with `persist`,
+	/// in addition to any other persistence already set.
+	#[must_use]
+	pub fn with_persistence(mut self, persist: Arc<dyn PersistVersion>) -> Self {
+		self.persist.push(persist);
+		self
+	}
+
+	/// Load the versions of `file` and persist new versions into it.
+	/// Versions found at a block which is no longer in the backend, or whose code hashes differently, are dropped.
+	fn with_file(self, file: FileVersions) -> Self {
+		let invalid = file.retain(|code_hash, block| {
+			Block::Hash::decode(&mut &*block)
+				.ok()
+				.and_then(|hash| self.backend.storage(hash, well_known_keys::CODE))
+				.map_or(false, |code| make_hash(&code) == code_hash)
+		});
+		if invalid > 0 {
+			log::info!("Dropped {} outdated runtime versions from {}", invalid, file.path().display());
+		}
+		self.load(file.versions());
+		self.with_persistence(Arc::new(file))
+	}
+
+	/// Load previously persisted versions into the cache, keyed by the hash of their WASM blob.
+	pub fn load(&self, versions: impl IntoIterator<Item = (u64, RuntimeVersion)>) {
+		let versions = versions.into_iter().collect::<Vec<_>>();
+		log::debug!("Loading {} persisted runtime versions", versions.len());
+		self.versions.rcu(|cache| {
+			let mut cache = HashMap::clone(cache);
+			cache.extend(versions.iter().cloned());
+			cache
+		});
+	}
+
+	/// Amount of versions currently cached.
+	pub fn len(&self) -> usize {
+		self.versions.load().len()
+	}
+
+	/// Returns true if there are no versions cached.
+	pub fn is_empty(&self) -> bool {
+		self.versions.load().is_empty()
 	}
 
 	/// Get a version of the runtime for some Block Hash
@@ -84,6 +205,11 @@ impl<Block: BlockT, Db: ReadOnlyDb + 'static> RuntimeVersionCache<Block, Db> {
 				cache.insert(code_hash, version.clone());
 				cache
 			});
+			for persist in self.persist.iter() {
+				if let Err(e) = persist.persist(code_hash, hash.as_ref(), &version) {
+					log::warn!("Failed to persist runtime version {}: {}", version.spec_version, e);
+				}
+			}
 			Ok(Some(version))
 		}
 	}
@@ -144,9 +270,36 @@ fn decode_version(version: &[u8]) -> Result<sp_version::RuntimeVersion> {
 	Decode::decode(&mut &*version).map_err(Into::into)
 }
 
-// Make a hash out of a byte string using the default hasher.
-fn make_hash<K: StdHash + ?Sized>(val: &K) -> u64 {
-	let mut state = DefaultHasher::new();
-	val.hash(&mut state);
-	state.finish()
+// Make a hash out of the WASM blob.
+// This must be stable across runs, since the hash is persisted.
+fn make_hash(code: &[u8]) -> u64 {
+	u64::from_le_bytes(sp_core::hashing::twox_64(code))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn version(spec_version: u32) -> RuntimeVersion {
+		RuntimeVersion { spec_version, ..Default::default() }
+	}
+
+	#[test]
+	fn should_persist_versions_to_file() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		let path = dir.path().join("runtime_versions");
+
+		let file = FileVersions::open(&path)?;
+		assert!(file.versions().is_empty());
+		file.persist(1, &[0xAA], &version(1))?;
+		file.persist(2, &[0xBB], &version(2))?;
+		// a code hash is only persisted once
+		file.persist(2, &[0xCC], &version(3))?;
+
+		let file = FileVersions::open(&path)?;
+		assert_eq!(file.versions(), vec![(1, version(1)), (2, version(2))]);
+		assert_eq!(file.retain(|_, block| block == [0xBB]), 1);
+		assert_eq!(file.versions(), vec![(2, version(2))]);
+		Ok(())
+	}
 }
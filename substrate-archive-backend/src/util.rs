@@ -128,6 +128,11 @@ pub fn number_index_key<N: TryInto<u32>>(n: N) -> Result<NumberIndexKey> {
 	Ok([(n >> 24) as u8, ((n >> 16) & 0xff) as u8, ((n >> 8) & 0xff) as u8, (n & 0xff) as u8])
 }
 
+/// Number of the block a lookup key (big-endian number followed by hash) points to.
+pub fn lookup_key_to_number(key: &[u8]) -> Option<u32> {
+	key.get(..4)?.try_into().ok().map(u32::from_be_bytes)
+}
+
 /// Database metadata.
 #[derive(Debug)]
 pub struct Meta<N, H> {
@@ -16,13 +16,18 @@
 //! Main entrypoint for substrate-archive. `init` will start the actor loop and begin indexing the
 //! chain defined with the passed-in Client and URL.
 
+mod dedup;
 mod workers;
 
 use std::{
 	convert::TryInto,
 	marker::PhantomData,
 	panic::AssertUnwindSafe,
-	sync::Arc,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
 	time::{Duration, Instant},
 };
 
@@ -30,58 +35,101 @@ use async_std::{
 	future::timeout,
 	task::{self, JoinHandle},
 };
-use futures::{future, FutureExt, StreamExt};
+use codec::Decode;
+use futures::{future, Future, StreamExt, TryStreamExt};
 use futures_timer::Delay;
-use sa_work_queue::{Job as _, QueueHandle, Runner};
+use sa_work_queue::{JobExt, QueueHandle, Runner};
 use serde::{de::DeserializeOwned, Deserialize};
+use sqlx::PgConnection;
 use xtra::{prelude::*, spawn::AsyncStd};
 
 use sc_client_api::backend;
 use sp_api::{ApiExt, ConstructRuntimeApi};
 use sp_block_builder::BlockBuilder as BlockBuilderApi;
-use sp_runtime::traits::{Block as BlockT, NumberFor};
+use sp_blockchain::HeaderBackend;
+use sp_runtime::{
+	generic::BlockId,
+	traits::{Block as BlockT, NumberFor},
+};
+use sp_storage::{StorageData, StorageKey};
 
-use substrate_archive_backend::{ApiAccess, Meta, ReadOnlyBackend, ReadOnlyDb, RuntimeConfig};
+use substrate_archive_backend::{
+	ApiAccess, BackendError, Meta, OverridesWatcher, ReadOnlyBackend, ReadOnlyDb, RuntimeConfig, RuntimeVersionCache,
+};
 
+use self::dedup::EnqueueFilter;
+#[cfg(feature = "metrics")]
+use self::workers::metrics::UpdateMetrics;
+#[cfg(feature = "health")]
+pub use self::workers::HealthActor;
+#[cfg(feature = "metrics")]
+pub use self::workers::MetricsActor;
 use self::workers::{
 	blocks::{Crawl, ReIndex},
-	database::GetState,
+	database::{ExecutionJob, GetState},
+	events_decoder::EVENTS_KEY,
 	extrinsics_decoder::Index,
 	storage_aggregator::{SendStorage, SendTraces},
 };
-pub use self::workers::{BlocksIndexer, DatabaseActor, ExtrinsicsDecoder, StorageAggregator};
+pub use self::{
+	dedup::DuplicateEnqueues,
+	workers::{BlocksIndexer, DatabaseActor, EventsDecoder, ExtrinsicsDecoder, StorageAggregator},
+};
 use crate::{
-	archive::Archive,
+	archive::{Archive, BackendStats},
 	database::{
-		models::{BlockModelDecoder, PersistentConfig},
-		queries, Channel, Listener,
+		models::{BlockModel, BlockModelDecoder, PersistentConfig},
+		outbox::{self, OutboxJob, PgOutbox},
+		queries::{self, IndexingGap},
+		schema, Database, DatabaseConfig, DbConn, Insert, SchemaDescription, Sink, WriteAheadLog,
 	},
-	error::Result,
+	error::{ArchiveError, ConfigError, Result},
+	substrate_archive_default_dir,
 	tasks::Environment,
+	types::Metadata,
+	wasm_tracing::{TraceOutput, TraceSampling, DEFAULT_MAX_FIELD_LENGTH},
 };
 
 /// Provides parameters that are passed in from the user.
 /// Provides context that every actor may use
 pub struct SystemConfig<Block, Db> {
 	pub backend: Arc<ReadOnlyBackend<Block, Db>>,
-	pub pg_url: String,
+	pub database: DatabaseConfig,
 	pub meta: Meta<Block>,
 	pub control: ControlConfig,
 	pub runtime: RuntimeConfig,
 	pub tracing_targets: Option<String>,
+	trace_output: TraceOutput,
+	trace_sampling: TraceSampling,
+	max_trace_field_length: usize,
+	ss58_prefix: u16,
 	persistent_config: PersistentConfig,
+	sink: Option<Arc<dyn Sink>>,
+	#[cfg(feature = "metrics")]
+	metrics_addr: Option<std::net::SocketAddr>,
+	#[cfg(feature = "health")]
+	health: Option<crate::archive::HealthConfig>,
 }
 
 impl<Block, Db> Clone for SystemConfig<Block, Db> {
 	fn clone(&self) -> SystemConfig<Block, Db> {
 		SystemConfig {
 			backend: Arc::clone(&self.backend),
-			pg_url: self.pg_url.clone(),
+			database: self.database.clone(),
 			meta: self.meta.clone(),
 			control: self.control.clone(),
 			runtime: self.runtime.clone(),
 			tracing_targets: self.tracing_targets.clone(),
+			trace_output: self.trace_output.clone(),
+			trace_sampling: self.trace_sampling.clone(),
+			max_trace_field_length: self.max_trace_field_length,
+			ss58_prefix: self.ss58_prefix,
 			persistent_config: self.persistent_config.clone(),
+			sink: self.sink.clone(),
+			#[cfg(feature = "metrics")]
+			metrics_addr: self.metrics_addr,
+			#[cfg(feature = "health")]
+			health: self.health.clone(),
 		}
 	}
 }
@@ -98,9 +146,75 @@ pub struct ControlConfig {
 	/// RabbitMq URL. default: `amqp://localhost:5672`
 	#[serde(default = "default_task_url")]
 	pub(crate) task_url: String,
-	/// Whether to index storage or not
+	/// Whether to persist the runtime versions of blocks in Postgres.
+	/// Persisted versions are loaded on startup, rather than being computed again.
+	#[serde(default = "default_persist_runtime_versions")]
+	pub(crate) persist_runtime_versions: bool,
+	/// Whether to index storage or not.
+	/// Storage is indexed by re-executing blocks on the task queue.
+	/// Blocks, metadata and extrinsics only need the block body,
+	/// so they are indexed regardless of this setting.
 	#[serde(default = "default_storage_indexing")]
 	pub(crate) storage_indexing: bool,
+	/// Whether to decode the items of block digests into the `digest_items` table.
+	/// The SCALE-encoded digest is stored in `blocks` regardless.
+	#[serde(default)]
+	pub(crate) index_digest_items: bool,
+	/// Maximum amount of seconds a block may take to execute.
+	/// Specs whose blocks repeatedly exceed it are quarantined, so blocks of other specs keep executing.
+	/// `None` lets blocks execute indefinitely.
+	/// A timed out execution is interrupted at its next read of state. Runtime code looping without reading state
+	/// keeps its thread running, and once 16 of those are left running no more blocks are executed.
+	#[serde(default = "default_block_execution_timeout")]
+	pub(crate) block_execution_timeout: Option<u64>,
+	/// Stack size in bytes of the threads executing blocks, for runtimes which overflow the default of 2 MiB.
+	#[serde(default)]
+	pub(crate) block_execution_stack_size: Option<usize>,
+	/// Whether to record executed storage in a local write-ahead log until it is committed to Postgres.
+	/// Storage left in the log after a crash is inserted on the next start, rather than executing the blocks again.
+	/// The log is kept in the `wal` directory of [`substrate_archive_default_dir`].
+	#[serde(default)]
+	pub(crate) enable_wal: bool,
+	/// How blocks which are enqueued for execution more than once are handled.
+	/// Blocks are enqueued both when they are inserted, and when their storage is found to be missing.
+	#[serde(default)]
+	pub(crate) duplicate_enqueues: DuplicateEnqueues,
+	/// Seconds a block is remembered for after being enqueued, when skipping recently enqueued blocks.
+	#[serde(default = "default_dedup_window")]
+	pub(crate) dedup_window: u64,
+	/// Milliseconds after which a database query or block execution is logged as slow,
+	/// and counted in [`slow_count`](crate::slow_count).
+	/// `None` disables the slow log.
+	#[serde(default = "default_slow_threshold_ms")]
+	pub(crate) slow_threshold_ms: Option<u64>,
+	/// Whether to decode the calls wrapped by `Proxy::proxy`, `Multisig::as_multi`, `Sudo::sudo` and `Utility::batch`,
+	/// nesting them in the JSON of the wrapper call rather than leaving them encoded.
+	#[serde(default = "default_decode_wrapped_calls")]
+	pub(crate) decode_wrapped_calls: bool,
+	/// Whether to render the accounts among the arguments of decoded calls as SS58 addresses, and balances as
+	/// decimal strings, rather than as bytes and numbers. Addresses use the `ss58Format` of the chain spec.
+	#[serde(default)]
+	pub(crate) format_call_params: bool,
+	/// Whether to only index finalized blocks, so no block is indexed which is later reorged out.
+	/// While finalization lags behind the best block, crawling waits for it to catch up.
+	#[serde(default)]
+	pub(crate) finalized_only: bool,
+	/// Inclusive range of block numbers to index, rather than following the tip of the chain.
+	/// The archive stops once every block in the range is indexed.
+	#[serde(default)]
+	pub(crate) block_range: Option<(u32, u32)>,
+	/// Whether to stop once every block up to the tip of the chain is indexed and no blocks remain to be executed,
+	/// rather than following the chain. Ignored if `block_range` is set.
+	#[serde(default)]
+	pub(crate) stop_at_tip: bool,
+	/// File recording the highest block up to which every block is indexed.
+	/// Read on startup, so missing blocks are only searched for after it.
+	#[serde(default)]
+	pub(crate) checkpoint_file: Option<PathBuf>,
+	/// Prefixes of the storage keys to index, as hex in configuration files (I.E `0x<twox128(pallet)>`).
+	/// Changes of other keys are dropped before they are inserted. Empty to index every key.
+	#[serde(default, deserialize_with = "deserialize_hex_prefixes")]
+	pub(crate) storage_key_prefixes: Vec<Vec<u8>>,
 }
 
 impl Default for ControlConfig {
@@ -110,10 +224,39 @@ impl Default for ControlConfig {
 			max_block_load: default_max_block_load(),
 			task_url: default_task_url(),
 			storage_indexing: default_storage_indexing(),
+			persist_runtime_versions: default_persist_runtime_versions(),
+			index_digest_items: false,
+			block_execution_timeout: default_block_execution_timeout(),
+			block_execution_stack_size: None,
+			enable_wal: false,
+			duplicate_enqueues: DuplicateEnqueues::default(),
+			dedup_window: default_dedup_window(),
+			slow_threshold_ms: default_slow_threshold_ms(),
+			decode_wrapped_calls: default_decode_wrapped_calls(),
+			format_call_params: false,
+			finalized_only: false,
+			block_range: None,
+			stop_at_tip: false,
+			checkpoint_file: None,
+			storage_key_prefixes: Vec::new(),
 		}
 	}
 }
 
+fn deserialize_hex_prefixes<'de, D>(deserializer: D) -> std::result::Result<Vec<Vec<u8>>, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|prefix| hex::decode(prefix.trim_start_matches("0x")).map_err(serde::de::Error::custom))
+		.collect()
+}
+
+const fn default_persist_runtime_versions() -> bool {
+	true
+}
+
 const fn default_storage_indexing() -> bool {
 	true
 }
@@ -122,6 +265,18 @@ fn default_task_url() -> String {
 	"amqp://localhost:5672".into()
 }
 
+// Maximum number of jobs published from the outbox at once.
+const OUTBOX_RELAY_BATCH: u32 = 1000;
+// How often the highest indexed block is sampled into `indexing_progress`.
+const PROGRESS_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+// How long samples of the indexing progress are kept.
+const PROGRESS_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// How often a bounded block range, or catching up with the tip, is checked for completion.
+const RANGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// How often the metrics of the indexing progress are updated.
+#[cfg(feature = "metrics")]
+const METRICS_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
 const fn default_task_timeout() -> u64 {
 	20
 }
@@ -130,20 +285,109 @@ const fn default_max_block_load() -> u32 {
 	100_000
 }
 
+const fn default_block_execution_timeout() -> Option<u64> {
+	Some(120)
+}
+
+const fn default_dedup_window() -> u64 {
+	300
+}
+
+const fn default_slow_threshold_ms() -> Option<u64> {
+	Some(1000)
+}
+
+const fn default_decode_wrapped_calls() -> bool {
+	true
+}
+
+/// SS58 prefix of generic Substrate chains, used if the chain spec has no `ss58Format` property.
+pub(crate) const DEFAULT_SS58_PREFIX: u16 = 42;
+
+/// Idempotency key of the job executing `block`.
+fn execution_key<B: BlockT>(block: &B) -> String {
+	format!("execute_block/{:?}", block.hash())
+}
+
 impl<Block: BlockT + Unpin, Db: ReadOnlyDb> SystemConfig<Block, Db>
 where
 	Block::Hash: Unpin,
 {
 	pub fn new(
 		backend: Arc<ReadOnlyBackend<Block, Db>>,
-		pg_url: String,
+		database: DatabaseConfig,
 		meta: Meta<Block>,
 		control: ControlConfig,
 		runtime: RuntimeConfig,
 		tracing_targets: Option<String>,
 		persistent_config: PersistentConfig,
 	) -> Self {
-		Self { backend, pg_url, meta, control, runtime, tracing_targets, persistent_config }
+		Self {
+			backend,
+			database,
+			meta,
+			control,
+			runtime,
+			tracing_targets,
+			trace_output: TraceOutput::default(),
+			trace_sampling: TraceSampling::default(),
+			max_trace_field_length: DEFAULT_MAX_FIELD_LENGTH,
+			ss58_prefix: DEFAULT_SS58_PREFIX,
+			persistent_config,
+			sink: None,
+			#[cfg(feature = "metrics")]
+			metrics_addr: None,
+			#[cfg(feature = "health")]
+			health: None,
+		}
+	}
+
+	/// Write the traces of executed blocks to `output`, rather than only to the database.
+	pub fn with_trace_output(mut self, output: TraceOutput) -> Self {
+		self.trace_output = output;
+		self
+	}
+
+	/// Only trace the blocks sampled by `sampling`.
+	pub fn with_trace_sampling(mut self, sampling: TraceSampling) -> Self {
+		self.trace_sampling = sampling;
+		self
+	}
+
+	/// Truncate string values of traces longer than `max_len` bytes.
+	pub fn with_max_trace_field_length(mut self, max_len: usize) -> Self {
+		self.max_trace_field_length = max_len;
+		self
+	}
+
+	/// Render accounts in decoded calls as SS58 addresses with `prefix`, if `format_call_params` is enabled.
+	pub fn with_ss58_prefix(mut self, prefix: u16) -> Self {
+		self.ss58_prefix = prefix;
+		self
+	}
+
+	pub(crate) fn ss58_prefix(&self) -> u16 {
+		self.ss58_prefix
+	}
+
+	/// Serve liveness and readiness probes, as configured by `health`.
+	#[cfg(feature = "health")]
+	pub fn with_health(mut self, health: crate::archive::HealthConfig) -> Self {
+		self.health = Some(health);
+		self
+	}
+
+	/// Serve Prometheus metrics of the indexing progress on `addr`.
+	#[cfg(feature = "metrics")]
+	pub fn with_metrics_addr(mut self, addr: std::net::SocketAddr) -> Self {
+		self.metrics_addr = Some(addr);
+		self
+	}
+
+	/// Write indexed data to `sink` rather than to PostgreSQL.
+	pub fn with_sink(mut self, sink: Arc<dyn Sink>) -> Self {
+		self.sink = Some(sink);
+		self
 	}
 
 	pub fn backend(&self) -> &Arc<ReadOnlyBackend<Block, Db>> {
@@ -151,7 +395,11 @@ where
 	}
 
 	pub fn pg_url(&self) -> &str {
-		self.pg_url.as_str()
+		self.database.url.as_str()
+	}
+
+	pub fn database(&self) -> &DatabaseConfig {
+		&self.database
 	}
 
 	pub fn meta(&self) -> &Meta<Block> {
@@ -165,6 +413,15 @@ struct Actors<Block: Send + Sync + 'static, Hash: Send + Sync + 'static, Db: Sen
 	metadata: Address<workers::MetadataActor<Block>>,
 	db: Address<DatabaseActor>,
 	extrinsics: Address<ExtrinsicsDecoder>,
+	events: Address<EventsDecoder>,
+	#[cfg(feature = "metrics")]
+	metrics: Option<Address<workers::MetricsActor<Block, Db>>>,
+	/// keeps the health actor running as long as the other actors are
+	#[cfg(feature = "health")]
+	_health: Option<Address<workers::HealthActor<Block, Db>>>,
+	/// closes the HTTP server of the metrics and health probes once the actors are dropped
+	#[cfg(any(feature = "metrics", feature = "health"))]
+	_http_shutdown: flume::Sender<()>,
 }
 
 impl<Block: Send + Sync + 'static, Hash: Send + Sync + 'static, Db: Send + Sync + 'static> Clone
@@ -177,6 +434,13 @@ impl<Block: Send + Sync + 'static, Hash: Send + Sync + 'static, Db: Send + Sync
 			metadata: self.metadata.clone(),
 			db: self.db.clone(),
 			extrinsics: self.extrinsics.clone(),
+			events: self.events.clone(),
+			#[cfg(feature = "metrics")]
+			metrics: self.metrics.clone(),
+			#[cfg(feature = "health")]
+			_health: self._health.clone(),
+			#[cfg(any(feature = "metrics", feature = "health"))]
+			_http_shutdown: self._http_shutdown.clone(),
 		}
 	}
 }
@@ -188,15 +452,88 @@ where
 	Block::Hash: Unpin,
 	NumberFor<Block>: Into<u32>,
 {
-	async fn spawn(conf: &SystemConfig<Block, Db>) -> Result<Self> {
-		let db = workers::DatabaseActor::new(conf.pg_url()).await?.create(None).spawn(&mut AsyncStd);
-		let storage = workers::StorageAggregator::new(db.clone()).create(None).spawn(&mut AsyncStd);
+	/// Spawn the actors. If `execution` is set, the jobs executing the blocks are stored in the outbox
+	/// along with the blocks, unless they are kept out by the filter.
+	async fn spawn(
+		conf: &SystemConfig<Block, Db>,
+		execution: Option<(ExecutionJob<Block>, EnqueueFilter)>,
+	) -> Result<Self> {
+		let db = workers::DatabaseActor::new(conf.database())
+			.await?
+			.with_digest_items(conf.control.index_digest_items)
+			.with_slow_threshold(conf.control.slow_threshold_ms.map(Duration::from_millis));
+		let db = match execution {
+			Some((job, filter)) => db.with_execution(job, filter),
+			None => db,
+		};
+		let db = match conf.sink.clone() {
+			Some(sink) => db.with_sink(sink),
+			None => db,
+		};
+		let db = db.create(None).spawn(&mut AsyncStd);
+		let storage =
+			workers::StorageAggregator::new(db.clone()).with_key_prefixes(conf.control.storage_key_prefixes.clone());
+		let storage = if conf.control.enable_wal {
+			let mut path = substrate_archive_default_dir();
+			path.extend(&["wal", &hex::encode(conf.backend().info().genesis_hash)]);
+			storage.with_wal(WriteAheadLog::open(path).await?).await?
+		} else {
+			storage
+		};
+		let storage = storage.with_trace_output(&conf.trace_output).await?;
+		let storage = storage.create(None).spawn(&mut AsyncStd);
 		let metadata =
 			workers::MetadataActor::new(db.clone(), conf.meta().clone()).await?.create(None).spawn(&mut AsyncStd);
-		let blocks = workers::BlocksIndexer::new(conf, db.clone(), metadata.clone()).create(None).spawn(&mut AsyncStd);
+		let blocks = workers::BlocksIndexer::new(conf, db.clone(), metadata.clone()).await?;
+		#[cfg(feature = "health")]
+		let heartbeat = workers::health::Heartbeat::new();
+		#[cfg(feature = "health")]
+		let blocks = blocks.with_heartbeat(heartbeat.clone());
+		let blocks = blocks.create(None).spawn(&mut AsyncStd);
 		let extrinsics = workers::ExtrinsicsDecoder::new(conf, db.clone()).await?.create(None).spawn(&mut AsyncStd);
+		let events = workers::EventsDecoder::new(conf, db.clone()).await?.create(None).spawn(&mut AsyncStd);
+		#[cfg(any(feature = "metrics", feature = "health"))]
+		let mut http = workers::http::HttpRoutes::default();
+		#[cfg(feature = "metrics")]
+		let metrics = match conf.metrics_addr {
+			Some(addr) => {
+				let metrics = workers::MetricsActor::new(conf.backend().clone(), db.clone()).await?;
+				workers::metrics::route(http.at(addr), metrics.registry());
+				Some(metrics.create(None).spawn(&mut AsyncStd))
+			}
+			None => None,
+		};
+		#[cfg(feature = "health")]
+		let health = match conf.health.as_ref() {
+			Some(config) => {
+				let health = workers::HealthActor::new(conf.backend().clone(), db.clone(), heartbeat, config)
+					.await?
+					.create(None)
+					.spawn(&mut AsyncStd);
+				workers::health::route(http.at(config.addr), health.downgrade());
+				Some(health)
+			}
+			None => None,
+		};
+		#[cfg(any(feature = "metrics", feature = "health"))]
+		let (http_shutdown, closed) = flume::bounded(1);
+		#[cfg(any(feature = "metrics", feature = "health"))]
+		http.serve(closed).await?;
 
-		Ok(Actors { storage, blocks, metadata, db, extrinsics })
+		Ok(Actors {
+			storage,
+			blocks,
+			metadata,
+			db,
+			extrinsics,
+			events,
+			#[cfg(feature = "metrics")]
+			metrics,
+			#[cfg(feature = "health")]
+			_health: health,
+			#[cfg(any(feature = "metrics", feature = "health"))]
+			_http_shutdown: http_shutdown,
+		})
 	}
 
 	/// Run a future that sends actors a signal to progress once the previous
@@ -204,6 +541,14 @@ where
 	async fn tick_interval(&self) -> Result<()> {
 		// messages that only need to be sent once
 		self.blocks.send(ReIndex).await?;
+		#[cfg(feature = "metrics")]
+		if let Some(metrics) = self.metrics.clone() {
+			task::spawn(async move {
+				while metrics.send(UpdateMetrics).await.is_ok() {
+					Delay::new(METRICS_UPDATE_INTERVAL).await;
+				}
+			});
+		}
 		let actors = self.clone();
 		task::spawn(async move {
 			loop {
@@ -212,8 +557,9 @@ where
 					Box::pin(actors.storage.send(SendStorage)),
 					Box::pin(actors.storage.send(SendTraces)),
 					Box::pin(actors.extrinsics.send(Index)),
+					Box::pin(actors.events.send(Index)),
 				);
-				if future::try_join4(fut.0, fut.1, fut.2, fut.3).await.is_err() {
+				if future::try_join5(fut.0, fut.1, fut.2, fut.3, fut.4).await.is_err() {
 					break;
 				}
 			}
@@ -236,6 +582,15 @@ where
 	/// handle to the futures runtime indexing the running chain
 	handle: Option<JoinHandle<Result<()>>>,
 	client: Arc<C>,
+	/// receives a message when the system should shut down
+	shutdown_signal: Option<flume::Receiver<()>>,
+	/// notified once the system has stopped
+	stopped: Option<flume::Receiver<()>>,
+	/// RPC server, closed on shutdown
+	#[cfg(feature = "rpc")]
+	rpc: Option<crate::rpc::RpcServer>,
+	/// reloads the WASM runtime overrides until the system is dropped
+	overrides_watcher: Option<OverridesWatcher>,
 	_marker: PhantomData<(B, R, D)>,
 }
 
@@ -265,17 +620,85 @@ where
 		client: Arc<Client>,
 		config: SystemConfig<Block, Db>,
 	) -> Result<Self> {
-		Ok(Self { handle: None, config, client, _marker: PhantomData })
+		Ok(Self {
+			handle: None,
+			config,
+			client,
+			shutdown_signal: None,
+			stopped: None,
+			#[cfg(feature = "rpc")]
+			rpc: None,
+			overrides_watcher: None,
+			_marker: PhantomData,
+		})
+	}
+
+	/// Close `server` when the system shuts down.
+	#[cfg(feature = "rpc")]
+	pub fn with_rpc_server(mut self, server: crate::rpc::RpcServer) -> Self {
+		self.rpc = Some(server);
+		self
+	}
+
+	/// Keep `watcher` reloading the WASM runtime overrides for as long as the system runs.
+	pub fn with_overrides_watcher(mut self, watcher: OverridesWatcher) -> Self {
+		self.overrides_watcher = Some(watcher);
+		self
+	}
+
+	/// Shut down once a message is received on `signal`.
+	/// `Archive::block_until_stopped` returns after the system has shut down.
+	pub fn with_shutdown_signal(mut self, signal: flume::Receiver<()>) -> Self {
+		self.shutdown_signal = Some(signal);
+		self
 	}
 
 	fn drive(&mut self) -> Result<()> {
 		let instance = SystemInstance::new(self.config.clone(), self.client.clone())?;
-		let handle = task::spawn(instance.work());
+		let handle = match self.shutdown_signal.take() {
+			Some(signal) => {
+				let (stopped_tx, stopped_rx) = flume::bounded(1);
+				self.stopped.replace(stopped_rx);
+				task::spawn(until_signal(instance.work(), signal, stopped_tx))
+			}
+			None => {
+				let (stopped_tx, stopped_rx) = flume::bounded(1);
+				self.stopped.replace(stopped_rx);
+				task::spawn(until_done(instance.work(), stopped_tx))
+			}
+		};
 		self.handle.replace(handle);
 		Ok(())
 	}
 }
 
+/// Drive `work` until it finishes, I.E because its block range is indexed. `stopped` is notified afterwards.
+async fn until_done<F>(work: F, stopped: flume::Sender<()>) -> Result<()>
+where
+	F: Future<Output = Result<()>>,
+{
+	let res = work.await;
+	let _ = stopped.try_send(());
+	res
+}
+
+/// Drive `work` until it finishes or a message is received on `signal`, whichever is first.
+/// `stopped` is notified afterwards.
+async fn until_signal<F>(work: F, signal: flume::Receiver<()>, stopped: flume::Sender<()>) -> Result<()>
+where
+	F: Future<Output = Result<()>>,
+{
+	let res = match future::select(Box::pin(work), Box::pin(signal.recv_async())).await {
+		future::Either::Left((res, _)) => res,
+		future::Either::Right(_) => {
+			log::info!("Received shutdown signal, shutting down");
+			Ok(())
+		}
+	};
+	let _ = stopped.try_send(());
+	res
+}
+
 type TaskRunner<Block, Hash, Runtime, Client, Db> =
 	Runner<AssertUnwindSafe<Environment<Block, Hash, Runtime, Client, Db>>>;
 
@@ -306,20 +729,48 @@ where
 	}
 
 	async fn work(self) -> Result<()> {
-		let actors = Actors::spawn(&self.config).await?;
+		// whether the task queue has no jobs left, and is not restoring missing storage.
+		let queue_idle = Arc::new(AtomicBool::new(false));
+		match self.config.control.block_range {
+			Some((from, to)) => {
+				let pool = Database::connect(self.config.pg_url(), &self.config.database).await?;
+				let storage_indexing = self.config.control.storage_indexing;
+				let range_indexed = Box::pin(Self::wait_for_range(pool, from, to, storage_indexing));
+				match future::select(Box::pin(self.index(queue_idle)), range_indexed).await {
+					future::Either::Left((res, _)) | future::Either::Right((res, _)) => res,
+				}
+			}
+			None if self.config.control.stop_at_tip => {
+				let pool = Database::connect(self.config.pg_url(), &self.config.database).await?;
+				let caught_up = Box::pin(Self::wait_for_tip(pool, self.config.clone(), queue_idle.clone()));
+				match future::select(Box::pin(self.index(queue_idle)), caught_up).await {
+					future::Either::Left((res, _)) | future::Either::Right((res, _)) => res,
+				}
+			}
+			None => self.index(queue_idle).await,
+		}
+	}
+
+	async fn index(self, queue_idle: Arc<AtomicBool>) -> Result<()> {
+		let filter = EnqueueFilter::new(
+			self.config.control.duplicate_enqueues,
+			Duration::from_secs(self.config.control.dedup_window),
+		);
+		let execution = self.config.control.storage_indexing.then(|| (Self::execution_job(), filter.clone()));
+		let actors = Actors::spawn(&self.config, execution).await?;
 		let pool = actors.db.send(GetState::Pool).await??.pool();
 		let persistent_config = &self.config.persistent_config;
+		// indexes blocks, metadata and extrinsics. None of these require block execution.
 		let actors_future = actors.tick_interval();
+		let progress = Self::record_progress(pool.clone());
 
 		if self.config.control.storage_indexing {
 			let runner = self.start_queue(&actors, &persistent_config.task_queue)?;
-			let handle = runner.unique_handle()?;
-			let mut listener = self.init_listeners(handle.clone()).await?;
-			let task_loop = self.storage_index(runner, pool);
-			futures::try_join!(task_loop, actors_future)?;
-			listener.kill().await?;
+			let relay = Self::relay_outbox(pool.clone(), runner.unique_handle()?);
+			let task_loop = self.storage_index(runner, pool, filter, queue_idle);
+			futures::try_join!(task_loop, actors_future, relay, progress)?;
 		} else {
-			actors_future.await?
+			futures::try_join!(actors_future, progress)?;
 		};
 
 		Ok(())
@@ -329,6 +780,8 @@ where
 		&self,
 		runner: TaskRunner<Block, Block::Hash, Runtime, Client, Db>,
 		pool: sqlx::PgPool,
+		filter: EnqueueFilter,
+		queue_idle: Arc<AtomicBool>,
 	) -> Result<()> {
 		let control_config = self.config.control.clone();
 		let mut last = Instant::now();
@@ -336,19 +789,31 @@ where
 		task::spawn_blocking(move || loop {
 			match runner.run_pending_tasks() {
 				Ok(_) => {
+					// the queue is declared again, since the count of its handle is only that of when it was declared
+					let mut idle = match runner.current_job_count() {
+						Ok(count) => count == 0,
+						Err(e) => {
+							log::warn!("Failed to count the jobs of the task queue: {:?}", e);
+							false
+						}
+					};
 					// we don't have any tasks to process. Add more.
-					if runner.job_count() == 0 && last.elapsed() > Duration::from_secs(60) {
+					if idle && last.elapsed() > Duration::from_secs(60) {
 						// we don't want to restore too often to avoid dups.
 						last = Instant::now();
 						let handle = task::spawn(Self::restore_missing_storage(
 							control_config.clone(),
 							pool.clone(),
 							handle.clone(),
+							filter.clone(),
 						));
 						if let Err(e) = task::block_on(handle) {
 							log::error!("{}", e);
 						}
+						// restored blocks may have been enqueued
+						idle = false;
 					}
+					queue_idle.store(idle, Ordering::SeqCst);
 				}
 				Err(sa_work_queue::FetchError::Timeout) => log::warn!("Tasks timed out"),
 				Err(e) => log::error!("{:?}", e),
@@ -367,52 +832,155 @@ where
 			self.client.clone(),
 			actors.storage.clone(),
 			self.config.tracing_targets.clone(),
-		);
+			self.config.control.block_execution_timeout.map(Duration::from_secs),
+			self.config.control.slow_threshold_ms.map(Duration::from_millis),
+		)
+		.with_trace_sampling(self.config.trace_sampling.clone())
+		.with_max_trace_field_length(self.config.max_trace_field_length)
+		.with_stack_size(self.config.control.block_execution_stack_size);
 		let env = AssertUnwindSafe(env);
 
-		let runner = sa_work_queue::Runner::builder(env, &self.config.control.task_url)
-			.register_job::<crate::tasks::execute_block::Job<Block, Runtime, Client, Db>>()
+		let mut builder = sa_work_queue::Runner::builder(env, &self.config.control.task_url)
+			.try_register_job::<crate::tasks::execute_block::Job<Block, Runtime, Client, Db>>()?;
+		// without a timeout, blocks execute on the threads of the workers
+		if let Some(size) = self.config.control.block_execution_stack_size {
+			builder = builder.thread_stack_size(size);
+		}
+		let runner = builder
 			.num_threads(self.config.runtime.block_workers)
 			.queue_name(queue)
 			.prefetch(100)
 			// times out if tasks don't start execution on the threadpool within timeout.
 			.timeout(Duration::from_secs(self.config.control.task_timeout))
 			.build()?;
+		runner.validate_registry()?;
 
 		Ok(runner)
 	}
 
-	async fn init_listeners(&self, handle: QueueHandle) -> Result<Listener> {
-		Listener::builder(self.config.pg_url(), handle, move |notif, conn, handle| {
-			async move {
-				let sql_block = queries::get_full_block_by_number(conn, notif.block_num).await?;
-				let b = sql_block.into_block_and_spec()?;
-				crate::tasks::execute_block::<Block, Runtime, Client, Db>(b.0, PhantomData).enqueue(handle).await?;
-				Ok(())
-			}
-			.boxed()
+	/// The job executing a block, keyed on its hash so that workers drop blocks which are enqueued more than once,
+	/// I.E because the outbox relay published it again.
+	fn execution_job() -> ExecutionJob<Block> {
+		Arc::new(|block: &Block| {
+			let key = execution_key(block);
+			let job = crate::tasks::execute_block::<Block, Runtime, Client, Db>(block.clone(), PhantomData);
+			Ok(OutboxJob::new(&job, Some(key))?)
 		})
-		.listen_on(Channel::Blocks)
-		.spawn()
-		.await
+	}
+
+	/// Publish the jobs stored in the outbox to the task queue.
+	async fn relay_outbox(pool: sqlx::PgPool, handle: QueueHandle) -> Result<()> {
+		handle.channel().confirm_select(Default::default()).await.map_err(sa_work_queue::Error::from)?;
+		loop {
+			let mut conn = pool.acquire().await?;
+			match outbox::relay(&mut conn, &handle, OUTBOX_RELAY_BATCH).await {
+				Ok(0) => Delay::new(Duration::from_millis(500)).await,
+				Ok(n) => log::debug!("Relayed {} jobs from the outbox", n),
+				Err(e) => {
+					log::error!("Failed to relay jobs from the outbox: {}", e);
+					Delay::new(Duration::from_secs(1)).await;
+				}
+			}
+		}
+	}
+
+	/// Wait until every block in `from..=to` is indexed, along with its storage if `storage_indexing` is enabled.
+	async fn wait_for_range(pool: sqlx::PgPool, from: u32, to: u32, storage_indexing: bool) -> Result<()> {
+		let len = to.checked_sub(from).map(|len| u64::from(len) + 1).ok_or_else(|| ConfigError::InvalidValue {
+			field: "block_range",
+			reason: format!("start {} is after end {}", from, to),
+		})?;
+		loop {
+			let mut conn = pool.acquire().await?;
+			let indexed = queries::block_count_in_range(&mut conn, from, to).await? == len
+				&& (!storage_indexing || queries::missing_storage_count_in_range(&mut conn, from, to).await? == 0);
+			if indexed {
+				log::info!("Indexed all blocks in {}..={}, stopping", from, to);
+				return Ok(());
+			}
+			drop(conn);
+			Delay::new(RANGE_CHECK_INTERVAL).await;
+		}
+	}
+
+	/// Wait until every block up to the tip of the chain is indexed, along with its storage if `storage_indexing`
+	/// is enabled, and `queue_idle` is set. The tip is the finalized block if only finalized blocks are indexed.
+	/// The genesis block is never executed, so it is not waited for.
+	async fn wait_for_tip(
+		pool: sqlx::PgPool,
+		config: SystemConfig<Block, Db>,
+		queue_idle: Arc<AtomicBool>,
+	) -> Result<()> {
+		loop {
+			let info = config.backend.info();
+			let tip: u32 = if config.control.finalized_only { info.finalized_number } else { info.best_number }.into();
+			let mut conn = pool.acquire().await?;
+			let idle = queue_idle.load(Ordering::SeqCst);
+			if caught_up(&mut conn, tip, config.control.storage_indexing, idle).await? {
+				log::info!("Indexed all blocks up to the tip {}, stopping", tip);
+				return Ok(());
+			}
+			drop(conn);
+			Delay::new(RANGE_CHECK_INTERVAL).await;
+		}
+	}
+
+	/// Periodically sample the highest indexed block, to compute the indexing rate from.
+	async fn record_progress(pool: sqlx::PgPool) -> Result<()> {
+		loop {
+			let mut conn = pool.acquire().await?;
+			if let Err(e) = queries::record_indexing_progress(&mut conn, PROGRESS_RETENTION).await {
+				log::error!("Failed to record indexing progress: {}", e);
+			}
+			drop(conn);
+			Delay::new(PROGRESS_SAMPLE_INTERVAL).await;
+		}
 	}
 
 	/// Checks if any blocks that should be executed are missing
 	/// from the task queue.
 	/// If any are found, they are re-enqueued.
-	async fn restore_missing_storage(config: ControlConfig, pool: sqlx::PgPool, handle: QueueHandle) -> Result<()> {
+	async fn restore_missing_storage(
+		config: ControlConfig,
+		pool: sqlx::PgPool,
+		handle: QueueHandle,
+		filter: EnqueueFilter,
+	) -> Result<()> {
 		let mut conn = pool.acquire().await?;
 		let nums = queries::missing_storage_blocks(&mut *conn).await?;
+		let nums = filter.filter(&mut *conn, nums).await?;
 		log::info!("Restoring {} missing storage entries.", nums.len());
 		let load: usize = config.max_block_load.try_into()?;
 		let mut block_stream = queries::blocks_paginated(&mut *conn, nums.as_slice(), load);
 		while let Some(page) = block_stream.next().await {
-			let jobs: Vec<crate::tasks::execute_block::Job<Block, Runtime, Client, Db>> =
+			// keyed on the block hash, so workers drop blocks which were enqueued again before they were executed
+			let jobs: Vec<(String, crate::tasks::execute_block::Job<Block, Runtime, Client, Db>)> =
 				BlockModelDecoder::with_vec(page?)?
 					.into_iter()
-					.map(|b| crate::tasks::execute_block::<Block, Runtime, Client, Db>(b.inner.block, PhantomData))
+					.map(|b| {
+						let key = execution_key(&b.inner.block);
+						(key, crate::tasks::execute_block::<Block, Runtime, Client, Db>(b.inner.block, PhantomData))
+					})
 					.collect();
-			sa_work_queue::JobExt::enqueue_batch(&handle, jobs).await?;
+			futures::stream::iter(jobs)
+				.map(Ok)
+				.try_for_each_concurrent(16, |(key, job)| job.enqueue_with_key(&handle, key))
+				.await?;
+		}
+		Ok(())
+	}
+
+	/// Store the execution of the blocks numbered `nums` in the outbox,
+	/// to be published to the task queue by the relay of a running archive.
+	async fn enqueue_executions(conn: &mut PgConnection, nums: &[u32]) -> Result<()> {
+		let pages: Vec<Vec<BlockModel>> =
+			queries::blocks_paginated(&mut *conn, nums, nums.len().max(1)).try_collect().await?;
+		for page in pages {
+			for block in BlockModelDecoder::with_vec(page)? {
+				let key = execution_key(&block.inner.block);
+				let job = crate::tasks::execute_block::<Block, Runtime, Client, Db>(block.inner.block, PhantomData);
+				job.enqueue_to_with_key(&mut PgOutbox::new(&mut *conn), key).await?;
+			}
 		}
 		Ok(())
 	}
@@ -442,6 +1010,10 @@ where
 	}
 
 	async fn block_until_stopped(&self) {
+		if let Some(stopped) = self.stopped.as_ref() {
+			let _ = stopped.recv_async().await;
+			return;
+		}
 		loop {
 			Delay::new(std::time::Duration::from_secs(1)).await;
 		}
@@ -449,6 +1021,8 @@ where
 
 	fn shutdown(self) -> Result<()> {
 		let now = std::time::Instant::now();
+		// the fields of the system are moved out while shutting down
+		let stats = self.backend_stats();
 		if let Some(h) = self.handle {
 			task::block_on(async {
 				if timeout(Duration::from_secs(1), h.cancel()).await.is_err() {
@@ -456,6 +1030,17 @@ where
 				}
 			})
 		}
+		#[cfg(feature = "rpc")]
+		if let Some(rpc) = self.rpc {
+			rpc.close();
+		}
+		drop(self.overrides_watcher);
+		log::info!(
+			"Backend caught up with the primary database {} times ({} failed), up to block {:?}",
+			stats.catch_up.count,
+			stats.catch_up.failures,
+			stats.catch_up.best_block
+		);
 		log::debug!("Shutdown took {:?}", now.elapsed());
 		Ok(())
 	}
@@ -467,4 +1052,305 @@ where
 	fn context(&self) -> &SystemConfig<Block, Db> {
 		&self.config
 	}
+
+	async fn tip_lag(&self) -> Result<u32> {
+		let best: u32 = self.config.backend.info().best_number.into();
+		let mut conn = self.config.database.connection().await?;
+		queries::tip_lag(&mut conn, best).await
+	}
+
+	async fn dry_execute(&self, block_num: u32) -> Result<Vec<(StorageKey, Option<StorageData>)>> {
+		let block = self
+			.config
+			.backend
+			.block(&BlockId::Number(block_num.into()))
+			.ok_or_else(|| ArchiveError::from(format!("Block {} not found in the backend", block_num)))?
+			.block;
+		let (client, backend) = (self.client.clone(), self.config.backend.clone());
+		let storage = task::spawn_blocking(move || {
+			crate::tasks::dry_execute::<Block, Runtime, Client, Db>(&client, &backend, block)
+		})
+		.await?;
+		Ok(storage.changes)
+	}
+
+	fn backend_stats(&self) -> BackendStats {
+		BackendStats { catch_up: self.config.backend.backing_db().catch_up_stats() }
+	}
+
+	async fn indexing_eta(&self, window: Duration) -> Result<Option<Duration>> {
+		let best: u32 = self.config.backend.info().best_number.into();
+		let mut conn = self.config.database.connection().await?;
+		queries::indexing_eta(&mut conn, best, window).await
+	}
+
+	async fn backfill_spec_versions(&self) -> Result<u64> {
+		let cache = RuntimeVersionCache::new(self.config.backend.clone(), self.config.runtime.clone());
+		let meta = self.config.meta().clone();
+		let mut conn = Database::with_config(&self.config.database).await?.conn().await?;
+		let spec_at = |hash: &[u8]| -> Result<u32> {
+			let hash = Block::Hash::decode(&mut &*hash)?;
+			Ok(cache.get(hash)?.ok_or(BackendError::VersionNotFound)?.spec_version)
+		};
+		let metadata_at = |hash: &[u8]| -> Result<Vec<u8>> {
+			let hash = Block::Hash::decode(&mut &*hash)?;
+			Ok(meta.metadata(&BlockId::hash(hash))?.to_vec())
+		};
+		backfill_spec_versions(&mut conn, self.config.control.max_block_load, spec_at, metadata_at).await
+	}
+
+	async fn export_schema(&self) -> Result<SchemaDescription> {
+		let mut conn = self.config.database.connection().await?;
+		schema::describe(&mut conn).await
+	}
+
+	async fn repair(&self) -> Result<Vec<IndexingGap>> {
+		let mut conn = Database::with_config(&self.config.database).await?.conn().await?;
+		let cache = RuntimeVersionCache::new(self.config.backend.clone(), self.config.runtime.clone());
+		let mut gaps: Vec<IndexingGap> = Vec::new();
+		loop {
+			let from = gaps.last().map_or(0, |gap| gap.block_num + 1);
+			let page = queries::indexing_gaps(&mut conn, from, self.config.control.max_block_load, &EVENTS_KEY).await?;
+			if page.is_empty() {
+				break;
+			}
+			for gap in page.iter().filter(|gap| gap.block) {
+				insert_block(&mut conn, &self.config.backend, &cache, &self.config.meta, gap.block_num).await?;
+			}
+			if self.config.control.storage_indexing {
+				let nums = page.iter().filter(|gap| gap.storage).map(|gap| gap.block_num).collect::<Vec<_>>();
+				SystemInstance::<Block, Runtime, Db, Client>::enqueue_executions(&mut conn, &nums).await?;
+			}
+			gaps.extend(page);
+		}
+		let count = |missing: fn(&IndexingGap) -> bool| gaps.iter().filter(|gap| missing(gap)).count();
+		log::info!(
+			"Repaired {} missing blocks and enqueued {} missing executions. {} blocks miss extrinsics and {} events",
+			count(|gap| gap.block),
+			count(|gap| gap.storage),
+			count(|gap| gap.extrinsics),
+			count(|gap| gap.events),
+		);
+		Ok(gaps)
+	}
+
+	async fn reindex_block(&self, block_num: u32) -> Result<()> {
+		let database = Database::with_config(&self.config.database).await?;
+		let mut tx = database.pool().begin().await?;
+		let block = queries::get_full_block_by_number(&mut tx, i32::try_from(block_num)?).await?;
+		let deleted = queries::delete_block_storage(&mut tx, block_num).await?;
+		for block in BlockModelDecoder::<Block>::with_vec(vec![block])? {
+			let key = execution_key(&block.inner.block);
+			let job = crate::tasks::execute_block::<Block, Runtime, Client, Db>(block.inner.block, PhantomData);
+			job.enqueue_to_with_key(&mut PgOutbox::new(&mut *tx), key).await?;
+		}
+		tx.commit().await?;
+		log::info!("Deleted {} storage rows of block {} and enqueued it for execution", deleted, block_num);
+		Ok(())
+	}
+}
+
+/// Whether every block up to `tip` is indexed, and if `storage_indexing` is enabled, its storage as well
+/// with no jobs left in the task queue. The genesis block is never executed, so it is left out.
+async fn caught_up(conn: &mut DbConn, tip: u32, storage_indexing: bool, queue_idle: bool) -> Result<bool> {
+	Ok(queries::block_count_in_range(conn, 1, tip).await? == u64::from(tip)
+		&& (!storage_indexing || (queue_idle && queries::missing_storage_count_in_range(conn, 1, tip).await? == 0)))
+}
+
+/// Insert the block numbered `block_num` from `backend`, along with the metadata of its spec if it is not present.
+/// Blocks which are not in the backend are skipped.
+async fn insert_block<Block, Db>(
+	conn: &mut DbConn,
+	backend: &ReadOnlyBackend<Block, Db>,
+	cache: &RuntimeVersionCache<Block, Db>,
+	meta: &Meta<Block>,
+	block_num: u32,
+) -> Result<()>
+where
+	Block: BlockT + Unpin,
+	Db: ReadOnlyDb + 'static,
+	Block::Hash: Unpin,
+	NumberFor<Block>: Into<u32> + From<u32>,
+{
+	let block = match backend.block(&BlockId::Number(block_num.into())) {
+		Some(block) => block,
+		None => {
+			log::warn!("Block {} is not in the backend, it can't be repaired", block_num);
+			return Ok(());
+		}
+	};
+	let hash = block.block.hash();
+	let spec = cache.get(hash)?.ok_or(BackendError::VersionNotFound)?.spec_version;
+	if !queries::check_if_meta_exists(spec, conn).await? {
+		Metadata::new(spec, meta.metadata(&BlockId::hash(hash))?.to_vec()).insert(conn).await?;
+	}
+	crate::types::Block::new(block, spec).insert(conn).await?;
+	Ok(())
+}
+
+/// Set the spec version of blocks without one to the version `spec_at` finds at their hash,
+/// inserting the metadata found by `metadata_at` for versions which are not yet present.
+async fn backfill_spec_versions<S, M>(
+	conn: &mut DbConn,
+	max_block_load: u32,
+	mut spec_at: S,
+	mut metadata_at: M,
+) -> Result<u64>
+where
+	S: FnMut(&[u8]) -> Result<u32>,
+	M: FnMut(&[u8]) -> Result<Vec<u8>>,
+{
+	let mut updated = 0;
+	loop {
+		let blocks = queries::blocks_without_spec(conn, max_block_load).await?;
+		if blocks.is_empty() {
+			break;
+		}
+		for (block_num, hash) in blocks {
+			let spec = spec_at(&hash)?;
+			if !queries::check_if_meta_exists(spec, conn).await? {
+				Metadata::new(spec, metadata_at(&hash)?).insert(conn).await?;
+			}
+			queries::set_block_spec(conn, &hash, spec).await?;
+			log::debug!("Backfilled spec {} of block {}", spec, block_num);
+			updated += 1;
+		}
+	}
+	log::info!("Backfilled the spec versions of {} blocks", updated);
+	Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{database::BlockModel, types::BatchBlock};
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use test_common::TestGuard;
+
+	struct DropGuard(Arc<AtomicBool>);
+
+	impl Drop for DropGuard {
+		fn drop(&mut self) {
+			self.0.store(true, Ordering::SeqCst);
+		}
+	}
+
+	#[test]
+	fn should_shutdown_on_signal() {
+		let dropped = Arc::new(AtomicBool::new(false));
+		let guard = DropGuard(dropped.clone());
+		let work = async move {
+			let _guard = guard;
+			future::pending::<()>().await;
+			Ok::<_, crate::error::ArchiveError>(())
+		};
+		let (signal_tx, signal_rx) = flume::bounded(1);
+		let (stopped_tx, stopped_rx) = flume::bounded(1);
+
+		let handle = task::spawn(until_signal(work, signal_rx, stopped_tx));
+		assert!(stopped_rx.try_recv().is_err());
+		signal_tx.send(()).unwrap();
+
+		task::block_on(async {
+			timeout(Duration::from_secs(5), handle).await.expect("shutdown after signal").unwrap();
+			stopped_rx.recv_async().await.unwrap();
+		});
+		assert!(dropped.load(Ordering::SeqCst), "work was not stopped");
+	}
+
+	#[test]
+	fn should_parse_hex_storage_key_prefixes() {
+		let config: ControlConfig = serde_json::from_value(serde_json::json!({
+			"storage_key_prefixes": ["0xc2261276cc9d1f8598ea4b6a74b15c2f", "5f3e4907f716ac89b6347d15ececedca"]
+		}))
+		.unwrap();
+		assert_eq!(config.storage_key_prefixes.len(), 2);
+		assert_eq!(config.storage_key_prefixes[0][..2], [0xc2, 0x26]);
+		assert!(
+			serde_json::from_value::<ControlConfig>(serde_json::json!({ "storage_key_prefixes": ["0xzz"] })).is_err()
+		);
+	}
+
+	#[test]
+	fn should_catch_up_once_storage_is_indexed_and_queue_is_idle() -> Result<(), anyhow::Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let mut conn = database.conn().await?;
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES (0, $1)")
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(&mut conn)
+				.await?;
+			let block = |n: u8| {
+				sqlx::query(
+					"INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec)
+					VALUES ($1, $2, $3, $4, $4, $5, $5, 0)",
+				)
+				.bind(vec![n - 1; 32])
+				.bind(vec![n; 32])
+				.bind(i32::from(n))
+				.bind(vec![0u8; 32])
+				.bind(Vec::<u8>::new())
+			};
+			for n in 1..=2 {
+				block(n).execute(&mut conn).await?;
+			}
+			// block 3 is not indexed yet
+			assert!(!caught_up(&mut conn, 3, false, true).await?);
+
+			block(3).execute(&mut conn).await?;
+			assert!(caught_up(&mut conn, 3, false, true).await?);
+			// none of the blocks was executed
+			assert!(!caught_up(&mut conn, 3, true, true).await?);
+
+			for n in 1..=3_u8 {
+				sqlx::query("INSERT INTO storage (block_num, hash, is_full, key) VALUES ($1, $2, false, $3)")
+					.bind(i32::from(n))
+					.bind(vec![n; 32])
+					.bind(vec![0xAA])
+					.execute(&mut conn)
+					.await?;
+			}
+			// jobs are left in the task queue
+			assert!(!caught_up(&mut conn, 3, true, false).await?);
+			assert!(caught_up(&mut conn, 3, true, true).await?);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_backfill_spec_versions() -> Result<(), anyhow::Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let mut conn = database.conn().await?;
+			for version in [0_i32, 26] {
+				sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2)")
+					.bind(version)
+					.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+					.execute(&mut conn)
+					.await?;
+			}
+			// imported without a spec version
+			let blocks = test_common::get_kusama_blocks()?
+				.drain(0..2)
+				.map(|b| BlockModel { spec: 0, ..BlockModel::from(b) })
+				.collect::<Vec<_>>();
+			let blocks = BlockModelDecoder::<polkadot_service::Block>::with_vec(blocks)?;
+			database.insert(BatchBlock::new(blocks.clone())).await?;
+			let upgrade = blocks[1].inner.block.hash();
+
+			let spec_at = |hash: &[u8]| Ok(if hash == upgrade.as_ref() { 27 } else { 26 });
+			let updated = backfill_spec_versions(&mut conn, 1, spec_at, |_| Ok(vec![0x27])).await?;
+			assert_eq!(updated, 2);
+
+			let specs: Vec<(i32,)> =
+				sqlx::query_as("SELECT spec FROM blocks ORDER BY block_num").fetch_all(&mut conn).await?;
+			assert_eq!(specs, vec![(26,), (27,)]);
+			assert_eq!(queries::metadata(&mut conn, 27).await?, vec![0x27]);
+			Ok(())
+		})
+	}
 }
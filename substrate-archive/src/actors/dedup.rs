@@ -0,0 +1,113 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! De-duplication of blocks enqueued for execution.
+//! Both the insert of new blocks and the restore of missing storage enqueue blocks,
+//! so without it the same block may be executed more than once.
+
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use sqlx::PgConnection;
+
+use crate::{database::queries, error::Result};
+
+/// How blocks which are enqueued for execution more than once are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateEnqueues {
+	/// Enqueue blocks every time.
+	Allow,
+	/// Skip blocks of which storage is already indexed.
+	SkipExecuted,
+	/// Skip blocks of which storage is already indexed,
+	/// as well as blocks that were enqueued within the dedup window.
+	SkipRecent,
+}
+
+impl Default for DuplicateEnqueues {
+	fn default() -> Self {
+		Self::SkipRecent
+	}
+}
+
+/// Filters blocks about to be enqueued according to a [`DuplicateEnqueues`] strategy.
+/// Clones share the blocks that were seen.
+#[derive(Clone)]
+pub(crate) struct EnqueueFilter {
+	strategy: DuplicateEnqueues,
+	window: Duration,
+	/// Block numbers enqueued within `window`, and when they were enqueued.
+	seen: Arc<Mutex<HashMap<u32, Instant>>>,
+}
+
+impl EnqueueFilter {
+	pub fn new(strategy: DuplicateEnqueues, window: Duration) -> Self {
+		Self { strategy, window, seen: Arc::new(Mutex::new(HashMap::new())) }
+	}
+
+	/// Keep only the blocks out of `nums` which should be enqueued.
+	/// With [`DuplicateEnqueues::SkipRecent`], the blocks that are kept are marked as seen.
+	pub async fn filter(&self, conn: &mut PgConnection, mut nums: Vec<u32>) -> Result<Vec<u32>> {
+		if self.strategy == DuplicateEnqueues::Allow {
+			return Ok(nums);
+		}
+		let executed: HashSet<u32> = queries::has_storage(nums.as_slice(), conn).await?.into_iter().collect();
+		nums.retain(|n| !executed.contains(n));
+		if self.strategy == DuplicateEnqueues::SkipRecent {
+			let now = Instant::now();
+			let mut seen = self.seen.lock();
+			seen.retain(|_, at| now.duration_since(*at) < self.window);
+			nums.retain(|n| seen.insert(*n, now).is_none());
+		}
+		Ok(nums)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::error::ArchiveError;
+	use async_std::task;
+	use sqlx::Connection;
+
+	#[test]
+	fn should_enqueue_block_once() -> Result<()> {
+		crate::initialize();
+		let _guard = test_common::TestGuard::lock();
+
+		task::block_on(async move {
+			let filter = EnqueueFilter::new(DuplicateEnqueues::SkipRecent, Duration::from_secs(60));
+			let mut insert_conn = PgConnection::connect(&test_common::DATABASE_URL).await?;
+			let mut restore_conn = PgConnection::connect(&test_common::DATABASE_URL).await?;
+			// the insert of a new block and the restore of missing storage find the same block
+			let (inserted, restored) = futures::join!(
+				filter.filter(&mut insert_conn, vec![1337]),
+				filter.filter(&mut restore_conn, vec![1337])
+			);
+
+			let mut enqueued = inserted?;
+			enqueued.extend(restored?);
+			assert_eq!(enqueued, vec![1337]);
+			Ok::<(), ArchiveError>(())
+		})
+	}
+}
@@ -16,12 +16,24 @@
 
 pub mod blocks;
 pub mod database;
+pub mod events_decoder;
 pub mod extrinsics_decoder;
+#[cfg(feature = "health")]
+pub mod health;
+#[cfg(any(feature = "metrics", feature = "health"))]
+pub mod http;
 mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod storage_aggregator;
 
 pub use self::database::DatabaseActor;
 pub use self::metadata::MetadataActor;
+#[cfg(feature = "metrics")]
+pub use self::metrics::MetricsActor;
 pub use blocks::BlocksIndexer;
+pub use events_decoder::EventsDecoder;
 pub use extrinsics_decoder::ExtrinsicsDecoder;
+#[cfg(feature = "health")]
+pub use health::HealthActor;
 pub use storage_aggregator::StorageAggregator;
@@ -13,17 +13,24 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 use async_std::task;
+use sqlx::PgConnection;
 use xtra::prelude::*;
 
+use sp_blockchain::HeaderBackend;
 use sp_runtime::{
 	generic::SignedBlock,
 	traits::{Block as BlockT, Header as _, NumberFor},
 };
 use substrate_archive_backend::{ReadOnlyBackend, ReadOnlyDb, RuntimeVersionCache};
 
+#[cfg(feature = "health")]
+use crate::actors::workers::health::Heartbeat;
 use crate::{
 	actors::{
 		workers::{
@@ -32,13 +39,17 @@ use crate::{
 		},
 		SystemConfig,
 	},
-	database::queries,
+	checkpoint::Checkpoint,
+	database::{queries, PersistentVersions},
 	error::{ArchiveError, Result},
 	types::{BatchBlock, Block},
 };
 type DatabaseAct = Address<DatabaseActor>;
 type MetadataAct<B> = Address<MetadataActor<B>>;
 
+/// How often the checkpoint file is updated.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct BlocksIndexer<B: Send + 'static, D: Send + 'static> {
 	/// background task to crawl blocks
 	backend: Arc<ReadOnlyBackend<B, D>>,
@@ -49,6 +60,19 @@ pub struct BlocksIndexer<B: Send + 'static, D: Send + 'static> {
 	last_max: u32,
 	/// the maximum amount of blocks to index at once
 	max_block_load: u32,
+	/// whether to only crawl blocks up to the last finalized block
+	finalized_only: bool,
+	/// the inclusive range of blocks to crawl, if not every block is indexed
+	block_range: Option<(u32, u32)>,
+	/// file recording the block up to which every block is indexed
+	checkpoint: Option<Checkpoint>,
+	/// the block number last written to the checkpoint
+	checkpointed: Option<u32>,
+	/// when the checkpoint was last updated
+	checkpointed_at: Instant,
+	/// beats on every crawl, to tell the blocks are still being indexed
+	#[cfg(feature = "health")]
+	heartbeat: Option<Heartbeat>,
 }
 
 impl<B, D> BlocksIndexer<B, D>
@@ -58,15 +82,37 @@ where
 	B::Hash: Unpin,
 	NumberFor<B>: Into<u32>,
 {
-	pub fn new(conf: &SystemConfig<B, D>, db: DatabaseAct, meta: MetadataAct<B>) -> Self {
-		Self {
-			rt_cache: Arc::new(RuntimeVersionCache::new(conf.backend.clone(), conf.runtime.clone())),
+	pub async fn new(conf: &SystemConfig<B, D>, db: DatabaseAct, meta: MetadataAct<B>) -> Result<Self> {
+		let mut rt_cache = RuntimeVersionCache::new(conf.backend.clone(), conf.runtime.clone());
+		if conf.control.persist_runtime_versions {
+			let pool = db.send(GetState::Pool).await??.pool();
+			let mut conn = pool.acquire().await?;
+			rt_cache.load(queries::runtime_versions_cache(&mut conn).await?);
+			log::info!("Loaded {} persisted runtime versions", rt_cache.len());
+			rt_cache = rt_cache.with_persistence(Arc::new(PersistentVersions::new(pool)));
+		}
+		Ok(Self {
+			rt_cache: Arc::new(rt_cache),
 			last_max: 0,
 			backend: conf.backend().clone(),
 			db,
 			meta,
 			max_block_load: conf.control.max_block_load,
-		}
+			finalized_only: conf.control.finalized_only,
+			block_range: conf.control.block_range,
+			checkpoint: conf.control.checkpoint_file.clone().map(Checkpoint::new),
+			checkpointed: None,
+			checkpointed_at: Instant::now(),
+			#[cfg(feature = "health")]
+			heartbeat: None,
+		})
+	}
+
+	/// Beat `heartbeat` on every crawl.
+	#[cfg(feature = "health")]
+	pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+		self.heartbeat = Some(heartbeat);
+		self
 	}
 
 	/// A async wrapper around the backend fn `iter_blocks` which
@@ -121,9 +167,11 @@ where
 		};
 
 		let mut missing_blocks = 0;
-		let mut min = self.last_max;
+		let (start, end) = self.block_range.unwrap_or((0, u32::MAX));
+		self.checkpointed = self.read_checkpoint(&mut conn, start, cur_max).await?;
+		let mut min = std::cmp::max(self.last_max, self.checkpointed.map_or(start, |n| n + 1));
 		loop {
-			let batch = queries::missing_blocks_min_max(&mut conn, min, self.max_block_load).await?;
+			let batch = queries::missing_blocks_min_max(&mut conn, min, end, self.max_block_load).await?;
 			if !batch.is_empty() {
 				missing_blocks += batch.len();
 				min += self.max_block_load;
@@ -141,24 +189,91 @@ where
 
 	/// Crawl up to `max_block_load` blocks that are greater than the last max
 	async fn crawl(&mut self) -> Result<Vec<Block<B>>> {
-		let copied_last_max = self.last_max;
-		let max_to_collect = copied_last_max + self.max_block_load;
-		let blocks = self
-			.collect_blocks(move |n| {
-				if copied_last_max == 0 {
-					// includes the genesis block
-					n >= copied_last_max && n <= max_to_collect
-				} else {
-					n > copied_last_max && n <= max_to_collect
-				}
-			})
-			.await?;
+		let start = self.block_range.map_or(0, |(start, _)| start);
+		// includes the genesis block, or the start of the range
+		let from = if self.last_max == 0 || self.last_max < start { start } else { self.last_max + 1 };
+		let max_to_collect = self.crawl_limit(from.saturating_add(self.max_block_load.saturating_sub(1)));
+		if from > max_to_collect {
+			return Ok(Vec::new());
+		}
+		let blocks = self.collect_blocks(move |n| n >= from && n <= max_to_collect).await?;
 		self.last_max = blocks
 			.iter()
 			.map(|b| (*b.inner.block.header().number()).into())
 			.fold(self.last_max, |ac, e| if e > ac { e } else { ac });
 		Ok(blocks)
 	}
+
+	/// Read the checkpoint, discarding it if the database disagrees with it,
+	/// I.E because the database was restored from a backup taken before the checkpoint was written.
+	async fn read_checkpoint(&self, conn: &mut PgConnection, start: u32, cur_max: u32) -> Result<Option<u32>> {
+		let checkpoint = match self.checkpoint.as_ref() {
+			Some(checkpoint) => checkpoint,
+			None => return Ok(None),
+		};
+		let block_num = match checkpoint.read().await {
+			Ok(Some(block_num)) => block_num,
+			Ok(None) => return Ok(None),
+			Err(e) => {
+				log::warn!("{}; searching for missing blocks from #{}", e, start);
+				return Ok(None);
+			}
+		};
+		let indexed = block_num >= start
+			&& block_num <= cur_max
+			&& queries::block_count_in_range(conn, start, block_num).await? == u64::from(block_num - start) + 1;
+		if indexed {
+			log::info!("Searching for missing blocks after checkpoint #{}", block_num);
+			Ok(Some(block_num))
+		} else {
+			log::warn!("Checkpoint #{} is stale; searching for missing blocks from #{}", block_num, start);
+			Ok(None)
+		}
+	}
+
+	/// Record the highest block up to which every block is indexed in the checkpoint,
+	/// at most once every `CHECKPOINT_INTERVAL`.
+	async fn update_checkpoint(&mut self) -> Result<()> {
+		let checkpoint = match self.checkpoint.as_ref() {
+			Some(checkpoint) if self.checkpointed_at.elapsed() >= CHECKPOINT_INTERVAL => checkpoint,
+			_ => return Ok(()),
+		};
+		self.checkpointed_at = Instant::now();
+		let (start, end) = self.block_range.unwrap_or((0, u32::MAX));
+		let from = self.checkpointed.map_or(start, |n| n + 1);
+		let mut conn = self.db.send(GetState::Conn).await??.conn();
+		let contiguous = match queries::missing_blocks_min_max(&mut conn, from, end, 1).await?.into_iter().next() {
+			Some(missing) => missing.checked_sub(1),
+			None => queries::max_block(&mut conn).await?.map(|max| std::cmp::min(max, end)),
+		};
+		if let Some(block_num) = contiguous.filter(|n| *n >= start && Some(*n) != self.checkpointed) {
+			checkpoint.write(block_num).await?;
+			self.checkpointed = Some(block_num);
+		}
+		Ok(())
+	}
+
+	/// Limit the highest block to crawl to the end of the block range,
+	/// and to the last finalized block if only finalized blocks are indexed.
+	/// Blocks past the last finalized block are left for a later crawl, once they are finalized.
+	fn crawl_limit(&self, max_to_collect: u32) -> u32 {
+		let finalized = if self.finalized_only { Some(self.backend.info().finalized_number.into()) } else { None };
+		limit_crawl(max_to_collect, self.block_range, finalized)
+	}
+}
+
+/// Limit `max_to_collect` to the end of `block_range`, and to the `finalized` block if there is one.
+fn limit_crawl(max_to_collect: u32, block_range: Option<(u32, u32)>, finalized: Option<u32>) -> u32 {
+	let max_to_collect = block_range.map_or(max_to_collect, |(_, end)| std::cmp::min(max_to_collect, end));
+	match finalized {
+		Some(finalized) => {
+			if finalized < max_to_collect {
+				log::debug!("Waiting for blocks after #{} to be finalized", finalized);
+			}
+			std::cmp::min(max_to_collect, finalized)
+		}
+		None => max_to_collect,
+	}
 }
 
 #[async_trait::async_trait]
@@ -184,6 +299,13 @@ where
 				}
 			}
 		}
+		if let Err(e) = self.update_checkpoint().await {
+			log::warn!("Failed to update the checkpoint: {}", e);
+		}
+		#[cfg(feature = "health")]
+		if let Some(heartbeat) = &self.heartbeat {
+			heartbeat.beat();
+		}
 	}
 }
 
@@ -207,3 +329,20 @@ where
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_only_crawl_finalized_blocks() {
+		// every block up to the load is crawled if finality is ignored
+		assert_eq!(limit_crawl(1_000, None, None), 1_000);
+		// blocks past the last finalized block wait for it to catch up
+		assert_eq!(limit_crawl(1_000, None, Some(800)), 800);
+		assert_eq!(limit_crawl(1_000, None, Some(1_200)), 1_000);
+		// the end of the block range still applies
+		assert_eq!(limit_crawl(1_000, Some((0, 500)), Some(800)), 500);
+		assert_eq!(limit_crawl(1_000, Some((0, 900)), Some(800)), 800);
+	}
+}
@@ -14,102 +14,136 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use futures_timer::Delay;
-use std::time::Duration;
+use std::{any::Any, sync::Arc, time::Duration};
 
-use sp_runtime::traits::{Block as BlockT, NumberFor};
+use hashbrown::HashSet;
+
+use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
 
 use xtra::prelude::*;
 
 use crate::{
-	database::{models::StorageModel, queries, Database, DbConn},
-	error::Result,
-	types::{BatchBlock, BatchExtrinsics, BatchStorage, Block, Metadata, Storage},
+	actors::dedup::EnqueueFilter,
+	database::{
+		models::{BlockModel, DigestItemModel, StorageModel},
+		outbox::OutboxJob,
+		Database, DatabaseConfig, DbConn, Sink,
+	},
+	error::{ArchiveError, Result},
+	slow_log::{SlowLog, SlowOperation},
+	types::{BatchBlock, BatchEvents, BatchExtrinsics, BatchStorage, Block, Metadata, Storage, UnexecutableBlock},
 	wasm_tracing::Traces,
 };
 
+/// Builds the job executing a block of type `B`.
+pub type ExecutionJob<B> = Arc<dyn Fn(&B) -> Result<OutboxJob> + Send + Sync>;
+
 #[derive(Clone)]
 pub struct DatabaseActor {
 	db: Database,
+	/// Where indexed data is written to. Defaults to `db`.
+	sink: Arc<dyn Sink>,
+	/// Whether to insert the items of block digests into `digest_items`.
+	index_digest_items: bool,
+	/// The [`ExecutionJob`] of the blocks, type-erased since the actor handles blocks of any type,
+	/// and the filter of blocks which are not executed again.
+	/// If `None`, blocks are not executed.
+	execution: Option<(Arc<dyn Any + Send + Sync>, EnqueueFilter)>,
+	/// Logs writes which exceed the slow threshold.
+	slow_log: SlowLog,
 }
 
 impl DatabaseActor {
-	pub async fn new(url: &str) -> Result<Self> {
-		Ok(Self { db: Database::new(url).await? })
+	pub async fn new(config: &DatabaseConfig) -> Result<Self> {
+		let db = Database::with_config(config).await?;
+		let slow_log = SlowLog::new(SlowOperation::Query, None);
+		Ok(Self { sink: Arc::new(db.clone()), db, index_digest_items: false, execution: None, slow_log })
+	}
+
+	/// Insert the items of block digests into the `digest_items` table along with the blocks.
+	pub fn with_digest_items(mut self, index_digest_items: bool) -> Self {
+		self.index_digest_items = index_digest_items;
+		self
+	}
+
+	/// Store the jobs executing the blocks in the outbox, in the transaction inserting them.
+	/// Blocks kept out by `filter` are not executed.
+	pub(crate) fn with_execution<B: BlockT>(mut self, job: ExecutionJob<B>, filter: EnqueueFilter) -> Self {
+		self.execution = Some((Arc::new(job), filter));
+		self
+	}
+
+	/// Log writes which take longer than `threshold` as slow.
+	pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+		self.slow_log = SlowLog::new(SlowOperation::Query, threshold);
+		self
+	}
+
+	/// Write indexed data to `sink` rather than to PostgreSQL.
+	/// PostgreSQL is still used to serve `GetState`.
+	pub fn with_sink(mut self, sink: Arc<dyn Sink>) -> Self {
+		self.sink = sink;
+		self
 	}
 
-	async fn block_handler<B>(&self, blk: Block<B>) -> Result<()>
+	fn digest_items<B>(&self, blocks: &[Block<B>]) -> Result<Vec<DigestItemModel>>
 	where
 		B: BlockT,
 		NumberFor<B>: Into<u32>,
 	{
-		let mut conn = self.db.conn().await?;
-		while !queries::check_if_meta_exists(blk.spec, &mut conn).await? {
-			Delay::new(Duration::from_millis(20)).await;
+		if !self.index_digest_items {
+			return Ok(Vec::new());
 		}
-		std::mem::drop(conn);
-		self.db.insert(blk).await?;
-		Ok(())
-	}
-
-	// Returns true if all versions are in database
-	// false if versions are missing
-	async fn db_contains_metadata<B>(blocks: &[Block<B>], conn: &mut DbConn) -> Result<bool> {
-		let specs: hashbrown::HashSet<u32> = blocks.iter().map(|b| b.spec).collect();
-		let versions: hashbrown::HashSet<u32> = queries::get_versions(conn).await?.into_iter().collect();
-		Ok(specs.is_subset(&versions))
+		let mut items = Vec::new();
+		for block in blocks {
+			items.extend(DigestItemModel::from_block(&block.inner.block)?);
+		}
+		Ok(items)
 	}
 
-	async fn batch_block_handler<B>(&self, blks: BatchBlock<B>) -> Result<()>
+	async fn execution_jobs<B>(&self, blocks: &[Block<B>]) -> Result<Vec<OutboxJob>>
 	where
 		B: BlockT,
 		NumberFor<B>: Into<u32>,
 	{
+		let (job, filter) = match self.execution.as_ref() {
+			Some(execution) => execution,
+			None => return Ok(Vec::new()),
+		};
+		let job = job
+			.downcast_ref::<ExecutionJob<B>>()
+			.ok_or_else(|| ArchiveError::from("Blocks are executed as blocks of another type"))?;
+		let number = |block: &Block<B>| -> u32 { (*block.inner.block.header().number()).into() };
 		let mut conn = self.db.conn().await?;
-		while !Self::db_contains_metadata(blks.inner(), &mut conn).await? {
-			log::info!("Doesn't contain metadata");
-			Delay::new(Duration::from_millis(50)).await;
-		}
-		std::mem::drop(conn);
-		self.db.insert(blks).await?;
-		Ok(())
+		let nums: HashSet<u32> =
+			filter.filter(&mut conn, blocks.iter().map(number).collect()).await?.into_iter().collect();
+		blocks.iter().filter(|block| nums.contains(&number(block))).map(|block| job(&block.inner.block)).collect()
 	}
 
-	async fn storage_handler<H>(&self, storage: Storage<H>) -> Result<()>
+	async fn block_handler<B>(&self, blks: Vec<Block<B>>) -> Result<()>
 	where
-		H: Send + Sync + Copy + AsRef<[u8]> + 'static,
+		B: BlockT,
+		NumberFor<B>: Into<u32>,
 	{
-		let mut conn = self.db.conn().await?;
-		while !queries::has_block::<H>(*storage.hash(), &mut conn).await? {
-			Delay::new(Duration::from_millis(10)).await;
-		}
-		let storage = Vec::<StorageModel<H>>::from(storage);
-		std::mem::drop(conn);
-		self.db.insert(storage).await?;
+		let digest_items = self.digest_items(&blks)?;
+		let jobs = self.execution_jobs(&blks).await?;
+		let blocks = blks.into_iter().map(BlockModel::from).collect();
+		self.slow_log.time("insert_blocks", self.sink.insert_blocks(blocks, digest_items, jobs)).await?;
 		Ok(())
 	}
 
-	async fn batch_storage_handler<H>(&self, storages: BatchStorage<H>) -> Result<()>
+	async fn storage_handler<H>(&self, storage: Vec<StorageModel<H>>) -> Result<()>
 	where
-		H: Send + Sync + Copy + AsRef<[u8]> + 'static,
+		H: AsRef<[u8]>,
 	{
-		let mut conn = self.db.conn().await?;
-		let mut block_nums = storages.inner().iter().map(|s| s.block_num()).collect::<Vec<_>>();
-		block_nums.sort_unstable();
-		if !block_nums.is_empty() {
+		if !storage.is_empty() {
+			let mut block_nums = storage.iter().map(|s| s.block_num()).collect::<Vec<_>>();
+			block_nums.sort_unstable();
 			log::info!("Inserting: {:#?}, {} .. {}", block_nums.len(), block_nums[0], block_nums.last().unwrap());
 		}
-		let len = block_nums.len();
+		let storage = storage.into_iter().map(StorageModel::into_bytes_hash).collect();
 		let now = std::time::Instant::now();
-		while queries::has_blocks(block_nums.as_slice(), &mut conn).await?.len() != len {
-			Delay::new(std::time::Duration::from_millis(50)).await;
-		}
-		log::debug!("Insert Integrity Query Check took {:?}", now.elapsed());
-		// we drop the connection early so that the insert() has the use of all db connections
-		std::mem::drop(conn);
-		let storage = Vec::<StorageModel<H>>::from(storages);
-		let now = std::time::Instant::now();
-		self.db.concurrent_insert(storage).await?;
+		self.slow_log.time("insert_storage", self.sink.insert_storage(storage)).await?;
 		log::debug!("[Batch Storage Insert] took {:?}", now.elapsed());
 		Ok(())
 	}
@@ -124,7 +158,7 @@ where
 	NumberFor<B>: Into<u32>,
 {
 	async fn handle(&mut self, blk: Block<B>, _: &mut Context<Self>) {
-		if let Err(e) = self.block_handler(blk).await {
+		if let Err(e) = self.block_handler(vec![blk]).await {
 			log::error!("{}", e.to_string())
 		}
 	}
@@ -139,7 +173,7 @@ where
 	async fn handle(&mut self, blks: BatchBlock<B>, _: &mut Context<Self>) {
 		let len = blks.inner.len();
 		let now = std::time::Instant::now();
-		if let Err(e) = self.batch_block_handler(blks).await {
+		if let Err(e) = self.block_handler(blks.inner).await {
 			log::error!("{}", e.to_string());
 		}
 		if len > 1000 {
@@ -153,7 +187,7 @@ where
 #[async_trait::async_trait]
 impl Handler<Metadata> for DatabaseActor {
 	async fn handle(&mut self, meta: Metadata, _ctx: &mut Context<Self>) {
-		if let Err(e) = self.db.insert(meta).await {
+		if let Err(e) = self.slow_log.time("insert_metadata", self.sink.insert_metadata(meta)).await {
 			log::error!("{}", e.to_string());
 		}
 	}
@@ -165,7 +199,7 @@ where
 	H: Copy + Send + Sync + AsRef<[u8]> + 'static,
 {
 	async fn handle(&mut self, storage: Storage<H>, _ctx: &mut Context<Self>) {
-		if let Err(e) = self.storage_handler(storage).await {
+		if let Err(e) = self.storage_handler(Vec::<StorageModel<H>>::from(storage)).await {
 			log::error!("{}", e.to_string())
 		}
 	}
@@ -176,16 +210,33 @@ impl<H> Handler<BatchStorage<H>> for DatabaseActor
 where
 	H: Copy + Send + Sync + AsRef<[u8]> + 'static,
 {
-	async fn handle(&mut self, storages: BatchStorage<H>, _ctx: &mut Context<Self>) {
+	async fn handle(&mut self, storages: BatchStorage<H>, _ctx: &mut Context<Self>) -> Result<()> {
 		let len = storages.inner.iter().map(|storage| storage.changes.len()).sum::<usize>();
 		let now = std::time::Instant::now();
-		if let Err(e) = self.batch_storage_handler(storages).await {
+		let res = self.storage_handler(Vec::<StorageModel<H>>::from(storages)).await;
+		if let Err(e) = &res {
 			log::error!("{}", e.to_string());
 		}
 
 		if now.elapsed() > std::time::Duration::from_millis(5000) {
 			log::warn!("Took {:?} to insert {} storage entries", now.elapsed(), len);
 		}
+		res
+	}
+}
+
+#[async_trait::async_trait]
+impl<H> Handler<UnexecutableBlock<H>> for DatabaseActor
+where
+	H: AsRef<[u8]> + Send + 'static,
+{
+	async fn handle(&mut self, block: UnexecutableBlock<H>, _: &mut Context<Self>) {
+		let block =
+			UnexecutableBlock { hash: block.hash.as_ref().to_vec(), block_num: block.block_num, reason: block.reason };
+		let res = self.slow_log.time("insert_unexecutable_block", self.sink.insert_unexecutable_block(block)).await;
+		if let Err(e) = res {
+			log::error!("{}", e.to_string());
+		}
 	}
 }
 
@@ -197,7 +248,7 @@ impl Message for Traces {
 impl Handler<Traces> for DatabaseActor {
 	async fn handle(&mut self, traces: Traces, _: &mut Context<Self>) {
 		let now = std::time::Instant::now();
-		if let Err(e) = self.db.insert(traces).await {
+		if let Err(e) = self.slow_log.time("insert_traces", self.sink.insert_traces(traces)).await {
 			log::error!("{}", e.to_string());
 		}
 		log::debug!("took {:?} to insert traces", now.elapsed());
@@ -209,13 +260,45 @@ impl Handler<BatchExtrinsics> for DatabaseActor {
 	async fn handle(&mut self, extrinsics: BatchExtrinsics, _: &mut Context<Self>) {
 		let len = extrinsics.len();
 		let now = std::time::Instant::now();
-		if let Err(e) = self.db.insert(extrinsics.inner()).await {
+		let upgrades = match extrinsics.runtime_upgrades() {
+			Ok(upgrades) => upgrades,
+			Err(e) => {
+				log::error!("{}", e.to_string());
+				Vec::new()
+			}
+		};
+		let BatchExtrinsics { inner, undecodable } = extrinsics;
+		let insert = self.sink.insert_extrinsics(inner, upgrades);
+		if let Err(e) = self.slow_log.time("insert_extrinsics", insert).await {
 			log::error!("{}", e.to_string());
 		}
+		if !undecodable.is_empty() {
+			if let Err(e) = self.sink.insert_undecodable_blocks(undecodable).await {
+				log::error!("{}", e.to_string());
+			}
+		}
 		log::debug!("took {:?} to insert {} extrinsics", now.elapsed(), len);
 	}
 }
 
+#[async_trait::async_trait]
+impl Handler<BatchEvents> for DatabaseActor {
+	async fn handle(&mut self, events: BatchEvents, _: &mut Context<Self>) {
+		let len = events.len();
+		let now = std::time::Instant::now();
+		let BatchEvents { inner, empty } = events;
+		if let Err(e) = self.slow_log.time("insert_events", self.sink.insert_events(inner)).await {
+			log::error!("{}", e.to_string());
+		}
+		if !empty.is_empty() {
+			if let Err(e) = self.sink.insert_blocks_without_events(empty).await {
+				log::error!("{}", e.to_string());
+			}
+		}
+		log::debug!("took {:?} to insert {} events", now.elapsed(), len);
+	}
+}
+
 // this is an enum in case there is some more state
 // that might be needed in the future
 /// Get Some State from the Database Actor
@@ -225,6 +308,8 @@ pub enum GetState {
 	Conn,
 	// Get the Connection Pool
 	Pool,
+	// Get the Connection Pool for read-only queries
+	ReadPool,
 }
 
 /// A response to `GetState`
@@ -277,6 +362,136 @@ impl Handler<GetState> for DatabaseActor {
 				let pool = self.db.pool().clone();
 				Ok(StateResponse::Pool(pool))
 			}
+			GetState::ReadPool => {
+				let pool = self.db.read_pool().clone();
+				Ok(StateResponse::Pool(pool))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::actors::DuplicateEnqueues;
+	use crate::database::{
+		models::{BlockModelDecoder, ExtrinsicsModel, RuntimeUpgradeModel},
+		BlockModel,
+	};
+	use anyhow::Error;
+	use async_std::task;
+	use codec::Encode;
+	use polkadot_service::Block as PolkadotBlock;
+	use sp_runtime::traits::{Block as _, Header as _};
+	use sp_storage::{StorageData, StorageKey};
+	use std::sync::Mutex;
+	use xtra::spawn::AsyncStd;
+
+	#[derive(Default)]
+	struct MockSink {
+		blocks: Mutex<Vec<BlockModel>>,
+		storage: Mutex<Vec<StorageModel<Vec<u8>>>>,
+		extrinsics: Mutex<Vec<ExtrinsicsModel>>,
+		jobs: Mutex<Vec<OutboxJob>>,
+	}
+
+	#[async_trait::async_trait]
+	impl Sink for MockSink {
+		async fn insert_blocks(
+			&self,
+			blocks: Vec<BlockModel>,
+			_: Vec<DigestItemModel>,
+			jobs: Vec<OutboxJob>,
+		) -> Result<u64> {
+			let len = blocks.len() as u64;
+			self.jobs.lock().unwrap().extend(jobs);
+			self.blocks.lock().unwrap().extend(blocks);
+			Ok(len)
+		}
+
+		async fn insert_storage(&self, storage: Vec<StorageModel<Vec<u8>>>) -> Result<u64> {
+			let len = storage.len() as u64;
+			self.storage.lock().unwrap().extend(storage);
+			Ok(len)
+		}
+
+		async fn insert_extrinsics(
+			&self,
+			extrinsics: Vec<ExtrinsicsModel>,
+			_: Vec<RuntimeUpgradeModel>,
+		) -> Result<u64> {
+			let len = extrinsics.len() as u64;
+			self.extrinsics.lock().unwrap().extend(extrinsics);
+			Ok(len)
 		}
+
+		async fn insert_traces(&self, _: Traces) -> Result<u64> {
+			Ok(0)
+		}
+	}
+
+	#[test]
+	fn should_write_to_sink() -> Result<(), Error> {
+		crate::initialize();
+		task::block_on(async {
+			let sink = Arc::new(MockSink::default());
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let db = DatabaseActor::new(&config).await?.with_sink(sink.clone()).create(None).spawn(&mut AsyncStd);
+
+			let blocks: Vec<BlockModel> = test_common::get_kusama_blocks()?.drain(0..2).map(BlockModel::from).collect();
+			let blocks = BlockModelDecoder::<PolkadotBlock>::with_vec(blocks)?;
+			let block = blocks[0].inner.block.clone();
+			db.send(BatchBlock::new(blocks)).await?;
+
+			let changes = vec![(StorageKey(b"key".to_vec()), Some(StorageData(b"value".to_vec())))];
+			let storage = Storage::new(block.hash(), *block.header().number(), false, changes);
+			db.send(BatchStorage::new(vec![storage])).await??;
+
+			let extrinsics =
+				ExtrinsicsModel::new(block.hash().as_ref().to_vec(), *block.header().number(), Vec::new())?;
+			db.send(BatchExtrinsics::new(vec![extrinsics])).await?;
+
+			let inserted = sink.blocks.lock().unwrap();
+			assert_eq!(inserted.len(), 2);
+			assert_eq!(inserted[0].hash, block.hash().as_ref().to_vec());
+			let storage = sink.storage.lock().unwrap();
+			assert_eq!(storage.len(), 1);
+			assert_eq!(storage[0].hash(), &block.hash().as_ref().to_vec());
+			assert_eq!(storage[0].data(), Some(&StorageData(b"value".to_vec())));
+			assert_eq!(sink.extrinsics.lock().unwrap().len(), 1);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_write_execution_jobs_with_blocks() -> Result<(), Error> {
+		crate::initialize();
+		task::block_on(async {
+			let sink = Arc::new(MockSink::default());
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let job: ExecutionJob<PolkadotBlock> = Arc::new(|block: &PolkadotBlock| {
+				let key = format!("execute_block/{:?}", block.hash());
+				Ok(OutboxJob { job_type: "execute_block".into(), payload: block.encode(), key: Some(key) })
+			});
+			let filter = EnqueueFilter::new(DuplicateEnqueues::Allow, Duration::from_secs(60));
+			let db = DatabaseActor::new(&config)
+				.await?
+				.with_sink(sink.clone())
+				.with_execution(job, filter)
+				.create(None)
+				.spawn(&mut AsyncStd);
+
+			let blocks: Vec<BlockModel> = test_common::get_kusama_blocks()?.drain(0..2).map(BlockModel::from).collect();
+			let blocks = BlockModelDecoder::<PolkadotBlock>::with_vec(blocks)?;
+			let hashes = blocks.iter().map(|b| b.inner.block.hash()).collect::<Vec<_>>();
+			db.send(BatchBlock::new(blocks)).await?;
+
+			// every block is handed to the sink along with the job executing it
+			let jobs = sink.jobs.lock().unwrap();
+			let keys = jobs.iter().map(|job| job.key.clone().unwrap()).collect::<Vec<_>>();
+			let expected = hashes.iter().map(|hash| format!("execute_block/{:?}", hash)).collect::<Vec<_>>();
+			assert_eq!(keys, expected);
+			Ok(())
+		})
 	}
 }
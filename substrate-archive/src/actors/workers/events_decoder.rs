@@ -0,0 +1,264 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+use async_std::task;
+use itertools::Itertools;
+use sqlx::PgPool;
+use std::{
+	collections::{BTreeSet, HashMap, HashSet},
+	sync::Arc,
+};
+use xtra::prelude::*;
+
+use desub::Decoder;
+
+use crate::{
+	actors::{
+		workers::{
+			database::{DatabaseActor, GetState},
+			extrinsics_decoder::{ExtrinsicsDecoder, Index},
+		},
+		SystemConfig,
+	},
+	database::{models::EventModel, queries, BlobStore},
+	error::{ArchiveError, Result},
+	types::BatchEvents,
+};
+
+/// Storage key of `System::Events`, `twox128("System") ++ twox128("Events")`.
+pub const EVENTS_KEY: [u8; 32] = [
+	0x26, 0xaa, 0x39, 0x4e, 0xea, 0x56, 0x30, 0xe0, 0x7c, 0x48, 0xae, 0x0c, 0x95, 0x58, 0xce, 0xf7, 0x80, 0xd4, 0x1e,
+	0x5e, 0x16, 0x05, 0x67, 0x65, 0xbc, 0x84, 0x61, 0x85, 0x10, 0x72, 0xc9, 0xd7,
+];
+
+/// A block whose events are yet to be decoded: number, hash, spec and the encoded `System::Events` value.
+type EncodedEvents = (u32, Vec<u8>, u32, Vec<u8>);
+
+/// Maximum number of blocks which failed to decode that are remembered, and skipped.
+/// Once reached, the lowest blocks are forgotten, and crawled again.
+const MAX_SKIPPED_BLOCKS: usize = 10_000;
+
+/// Actor which crawls executed blocks missing from the `events` table,
+/// decodes their `System::Events` storage and sends the events to the database.
+/// Crawls missing events upon receiving an `Index` message.
+pub struct EventsDecoder {
+	/// Pool of Postgres Connections used for reading.
+	pool: PgPool,
+	/// Address of the database actor.
+	addr: Address<DatabaseActor>,
+	/// Max amount of blocks to load at any one time.
+	max_block_load: u32,
+	/// Desub Legacy + current decoder.
+	decoder: Arc<Decoder>,
+	/// Blocks where runtime upgrades occurred.
+	/// number -> spec
+	upgrades: HashMap<u32, u32>,
+	/// Store of storage values too large to be kept in Postgres, if configured.
+	blob_store: Option<BlobStore>,
+	/// Specs with metadata that could not be registered with the decoder.
+	/// Blocks of these specs are skipped.
+	skipped_specs: HashSet<u32>,
+	/// Blocks whose events could not be decoded, up to [`MAX_SKIPPED_BLOCKS`].
+	/// These are skipped until restart, rather than crawled again on every tick.
+	skipped_blocks: BTreeSet<u32>,
+}
+
+impl EventsDecoder {
+	pub async fn new<B: Send + Sync, Db: Send + Sync>(
+		config: &SystemConfig<B, Db>,
+		addr: Address<DatabaseActor>,
+	) -> Result<Self> {
+		let max_block_load = config.control.max_block_load;
+		let chain = config.persistent_config.chain();
+		let pool = addr.send(GetState::ReadPool).await??.pool();
+		let decoder = Arc::new(Decoder::new(chain));
+		let blob_store = match config.database().blob_store.as_ref() {
+			Some(blob_config) => Some(BlobStore::new(blob_config).await?),
+			None => None,
+		};
+		let mut conn = pool.acquire().await?;
+		let upgrades = queries::upgrade_blocks_from_spec(&mut conn, 0).await?;
+		log::info!("Started events decoder");
+		Ok(Self {
+			pool,
+			addr,
+			max_block_load,
+			decoder,
+			upgrades,
+			blob_store,
+			skipped_specs: HashSet::new(),
+			skipped_blocks: BTreeSet::new(),
+		})
+	}
+
+	async fn crawl_missing_events(&mut self) -> Result<()> {
+		let mut conn = self.pool.acquire().await?;
+		let skip_specs = self.skipped_specs.iter().map(|s| *s as i32).collect::<Vec<_>>();
+		let skip_blocks = self.skipped_blocks.iter().map(|b| *b as i32).collect::<Vec<_>>();
+		let rows =
+			queries::blocks_missing_events(&mut conn, self.max_block_load, &EVENTS_KEY, &skip_specs, &skip_blocks)
+				.await?;
+		if rows.is_empty() {
+			return Ok(());
+		}
+
+		if self.upgrades.values().max() < rows.iter().map(|&(_, _, spec, _, _)| spec).max().as_ref() {
+			self.upgrades = queries::upgrade_blocks_from_spec(&mut conn, 0).await?;
+		}
+
+		let mut blocks = Vec::with_capacity(rows.len());
+		// blocks whose `System::Events` storage was removed
+		let mut empty = Vec::new();
+		for (number, hash, spec, value, offloaded) in rows.into_iter() {
+			let spec = self.runtime_spec(number, spec)?;
+			match self.resolve_value(value, offloaded).await? {
+				Some(value) => blocks.push((number, hash, spec, value)),
+				None => empty.push((hash, number)),
+			}
+		}
+
+		let versions: Vec<u32> =
+			blocks.iter().map(|(_, _, spec, _)| *spec).unique().filter(|v| !self.decoder.has_version(v)).collect();
+		for version in versions.into_iter() {
+			let metadata = queries::metadata(&mut conn, version as i32).await?;
+			log::debug!("Registering version {}", version);
+			let decoder = Arc::get_mut(&mut self.decoder)
+				.ok_or_else(|| ArchiveError::Msg("Reference to decoder is not safe to access".into()))?;
+			if let Err(e) = ExtrinsicsDecoder::register_version(decoder, version, &metadata) {
+				log::error!("{}; skipping events of spec {}", e, version);
+				self.skipped_specs.insert(version);
+			}
+		}
+		let skipped_specs = &self.skipped_specs;
+		let blocks: Vec<_> = blocks.into_iter().filter(|(_, _, spec, _)| !skipped_specs.contains(spec)).collect();
+
+		let decoder = self.decoder.clone();
+		let (events, without_events, failed) = task::spawn_blocking(move || Self::decode(&decoder, blocks)).await;
+		empty.extend(without_events);
+		Self::skip(&mut self.skipped_blocks, failed);
+
+		self.addr.send(BatchEvents::new(events).with_empty(empty)).await?;
+		Ok(())
+	}
+
+	/// Skip blocks which failed to decode, forgetting the lowest ones beyond [`MAX_SKIPPED_BLOCKS`].
+	fn skip(skipped: &mut BTreeSet<u32>, blocks: Vec<u32>) {
+		skipped.extend(blocks);
+		while skipped.len() > MAX_SKIPPED_BLOCKS {
+			let lowest = *skipped.iter().next().expect("Checked len; qed");
+			skipped.remove(&lowest);
+		}
+	}
+
+	/// The spec of the runtime which executed block `number`.
+	/// The block in which the runtime was upgraded is executed by the previous runtime.
+	fn runtime_spec(&self, number: u32, spec: u32) -> Result<u32> {
+		match self.upgrades.get(&number) {
+			Some(version) => self
+				.upgrades
+				.values()
+				.filter(|v| *v < version)
+				.max()
+				.copied()
+				.ok_or(ArchiveError::PrevSpecNotFound(*version)),
+			None => Ok(spec),
+		}
+	}
+
+	/// Read a storage value which may have been offloaded to the blob store.
+	async fn resolve_value(&self, value: Option<Vec<u8>>, offloaded: bool) -> Result<Option<Vec<u8>>> {
+		match (value, &self.blob_store) {
+			(Some(reference), Some(blob_store)) if offloaded => Ok(Some(blob_store.get(&reference).await?)),
+			(Some(_), None) if offloaded => {
+				Err(ArchiveError::from("Storage value was offloaded, but no blob store is configured"))
+			}
+			(value, _) => Ok(value),
+		}
+	}
+
+	/// Decode the events of `blocks`, returning the events, the hashes and numbers of blocks which emitted
+	/// no events, and the numbers of blocks which failed to decode.
+	fn decode(decoder: &Decoder, blocks: Vec<EncodedEvents>) -> (Vec<EventModel>, Vec<(Vec<u8>, u32)>, Vec<u32>) {
+		let mut events = Vec::new();
+		let mut empty = Vec::new();
+		let mut failed = Vec::new();
+		for (number, hash, spec, value) in blocks.into_iter() {
+			match Self::decode_events(decoder, spec, &value)
+				.and_then(|records| EventModel::from_json(&hash, number, &records))
+			{
+				Ok(block_events) if block_events.is_empty() => empty.push((hash, number)),
+				Ok(block_events) => events.extend(block_events),
+				Err(err) => {
+					failed.push(number);
+					log::warn!("decode events failed, block: {}, spec: {}, reason: {:?}", number, spec, err);
+				}
+			}
+		}
+		(events, empty, failed)
+	}
+
+	/// Decode the encoded `System::Events` storage against the metadata of `spec`.
+	fn decode_events(decoder: &Decoder, spec: u32, value: &[u8]) -> Result<serde_json::Value> {
+		let records = decoder.decode_storage(spec, (&EVENTS_KEY[..], Some(value)))?;
+		Ok(serde_json::to_value(records)?)
+	}
+}
+
+#[async_trait::async_trait]
+impl Actor for EventsDecoder {}
+
+#[async_trait::async_trait]
+impl Handler<Index> for EventsDecoder {
+	async fn handle(&mut self, _: Index, ctx: &mut Context<Self>) {
+		match self.crawl_missing_events().await {
+			Err(ArchiveError::Disconnected) => ctx.stop(),
+			Ok(_) => {}
+			Err(e) => log::error!("{:?}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Encode;
+	use desub::Chain;
+	use polkadot_service::kusama_runtime;
+
+	#[test]
+	fn should_count_blocks_without_events_as_decoded() {
+		crate::initialize();
+		let spec = kusama_runtime::VERSION.spec_version;
+		let mut decoder = Decoder::new(Chain::Kusama);
+		ExtrinsicsDecoder::register_version(&mut decoder, spec, &kusama_runtime::Runtime::metadata().encode()).unwrap();
+		// no metadata is registered for the spec of the second block
+		let blocks = vec![(1, vec![0xAA; 32], spec, Vec::<()>::new().encode()), (2, vec![0xBB; 32], 1055, vec![0xDE])];
+
+		let (events, empty, failed) = EventsDecoder::decode(&decoder, blocks);
+		assert!(events.is_empty());
+		assert_eq!(empty, [(vec![0xAA; 32], 1)]);
+		assert_eq!(failed, [2]);
+	}
+
+	#[test]
+	fn should_bound_skipped_blocks() {
+		let mut skipped = BTreeSet::new();
+		EventsDecoder::skip(&mut skipped, (0..MAX_SKIPPED_BLOCKS as u32).collect());
+		EventsDecoder::skip(&mut skipped, vec![MAX_SKIPPED_BLOCKS as u32, MAX_SKIPPED_BLOCKS as u32 + 1]);
+		assert_eq!(skipped.len(), MAX_SKIPPED_BLOCKS);
+		// the lowest blocks are forgotten
+		assert_eq!(skipped.iter().next(), Some(&2));
+	}
+}
@@ -15,12 +15,17 @@
 
 use arc_swap::ArcSwap;
 use async_std::task;
+use codec::Encode;
 use itertools::Itertools;
 use sqlx::PgPool;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{HashMap, HashSet},
+	panic::{self, AssertUnwindSafe},
+	sync::Arc,
+};
 use xtra::prelude::*;
 
-use desub::Decoder;
+use desub::{Chain, Decoder};
 
 use crate::{
 	actors::{
@@ -28,15 +33,15 @@ use crate::{
 		SystemConfig,
 	},
 	database::{models::ExtrinsicsModel, queries},
-	error::{ArchiveError, Result},
-	types::BatchExtrinsics,
+	error::{ArchiveError, MetadataError, Result},
+	types::{BatchExtrinsics, UndecodableBlock},
 };
 
 /// Actor which crawls missing encoded extrinsics and
 /// sends decoded JSON to the database.
 /// Crawls missing extrinsics upon receiving an `Index` message.
 pub struct ExtrinsicsDecoder {
-	/// Pool of Postgres Connections.
+	/// Pool of Postgres Connections used for reading.
 	pool: PgPool,
 	/// Address of the database actor.
 	addr: Address<DatabaseActor>,
@@ -47,6 +52,54 @@ pub struct ExtrinsicsDecoder {
 	/// Cache of blocks where runtime upgrades occurred.
 	/// number -> spec
 	upgrades: ArcSwap<HashMap<u32, u32>>,
+	/// Specs with metadata that could not be registered with the decoder.
+	/// Blocks of these specs are skipped.
+	skipped_specs: HashSet<u32>,
+	/// Blocks decoded since startup.
+	decoded_total: u64,
+	/// Blocks which failed to decode since startup.
+	failed_total: u64,
+	/// Whether to decode the calls wrapped by `Proxy`, `Multisig`, `Sudo` and `Utility` calls.
+	decode_wrapped_calls: bool,
+	/// SS58 prefix to render accounts in call arguments with, if they are formatted.
+	format_params: Option<u16>,
+}
+
+/// Fraction of blocks in a batch which may fail to decode before decoding is reported as broken,
+/// rather than a few blocks being bad.
+const MAX_DECODE_FAILURE_RATE: f64 = 0.5;
+/// Minimum number of blocks in a batch for the failure rate to be checked.
+const MIN_DECODE_SAMPLE: usize = 10;
+
+/// Outcome of decoding the extrinsics of a batch of blocks.
+#[derive(Debug, Default, PartialEq)]
+struct DecodeStats {
+	decoded: usize,
+	/// Blocks which failed to decode.
+	failed: Vec<UndecodableBlock>,
+}
+
+impl DecodeStats {
+	fn total(&self) -> usize {
+		self.decoded + self.failed.len()
+	}
+
+	fn failure_rate(&self) -> f64 {
+		if self.total() == 0 {
+			0.0
+		} else {
+			self.failed.len() as f64 / self.total() as f64
+		}
+	}
+
+	/// Whether so many blocks failed to decode that decoding is likely broken.
+	fn is_broken(&self) -> bool {
+		self.total() >= MIN_DECODE_SAMPLE && self.failure_rate() > MAX_DECODE_FAILURE_RATE
+	}
+
+	fn failed(&mut self, hash: Vec<u8>, block_num: u32, spec: u32, reason: String) {
+		self.failed.push(UndecodableBlock { hash, block_num, spec, reason });
+	}
 }
 
 impl ExtrinsicsDecoder {
@@ -55,18 +108,42 @@ impl ExtrinsicsDecoder {
 		addr: Address<DatabaseActor>,
 	) -> Result<Self> {
 		let max_block_load = config.control.max_block_load;
+		let decode_wrapped_calls = config.control.decode_wrapped_calls;
+		let format_params = config.control.format_call_params.then(|| config.ss58_prefix());
 		let chain = config.persistent_config.chain();
-		let pool = addr.send(GetState::Pool).await??.pool();
+		Self::with_options(addr, chain, max_block_load, decode_wrapped_calls, format_params).await
+	}
+
+	async fn with_options(
+		addr: Address<DatabaseActor>,
+		chain: Chain,
+		max_block_load: u32,
+		decode_wrapped_calls: bool,
+		format_params: Option<u16>,
+	) -> Result<Self> {
+		let pool = addr.send(GetState::ReadPool).await??.pool();
 		let decoder = Arc::new(Decoder::new(chain));
 		let mut conn = pool.acquire().await?;
 		let upgrades = ArcSwap::from_pointee(queries::upgrade_blocks_from_spec(&mut conn, 0).await?);
 		log::info!("Started extrinsic decoder");
-		Ok(Self { pool, addr, max_block_load, decoder, upgrades })
+		Ok(Self {
+			pool,
+			addr,
+			max_block_load,
+			decoder,
+			upgrades,
+			skipped_specs: HashSet::new(),
+			decoded_total: 0,
+			failed_total: 0,
+			decode_wrapped_calls,
+			format_params,
+		})
 	}
 
 	async fn crawl_missing_extrinsics(&mut self) -> Result<()> {
 		let mut conn = self.pool.acquire().await?;
-		let blocks = queries::blocks_missing_extrinsics(&mut conn, self.max_block_load).await?;
+		let skip_specs = self.skipped_specs.iter().map(|s| *s as i32).collect::<Vec<_>>();
+		let blocks = queries::blocks_missing_extrinsics(&mut conn, self.max_block_load, &skip_specs).await?;
 
 		let versions: Vec<u32> =
 			blocks.iter().filter(|b| !self.decoder.has_version(&b.3)).map(|(_, _, _, v)| *v).unique().collect();
@@ -74,33 +151,74 @@ impl ExtrinsicsDecoder {
 		for version in versions.iter() {
 			let metadata = queries::metadata(&mut conn, *version as i32).await?;
 			log::debug!("Registering version {}", version);
-			Arc::get_mut(&mut self.decoder)
-				.ok_or_else(|| ArchiveError::Msg("Reference to decoder is not safe to access".into()))?
-				.register_version(*version, &metadata)?;
+			let decoder = Arc::get_mut(&mut self.decoder)
+				.ok_or_else(|| ArchiveError::Msg("Reference to decoder is not safe to access".into()))?;
+			if let Err(e) = Self::register_version(decoder, *version, &metadata) {
+				log::error!("{}; skipping blocks of spec {}", e, version);
+				self.skipped_specs.insert(*version);
+			}
 		}
 
 		if let Some(first) = versions.first() {
 			if let (Some(past), _, Some(past_metadata), _) =
 				queries::past_and_present_version(&mut conn, *first as i32).await?
 			{
-				Arc::get_mut(&mut self.decoder)
-					.ok_or_else(|| ArchiveError::Msg("Reference to decoder is not safe to access".into()))?
-					.register_version(past, &past_metadata)?;
-				log::debug!("Registered previous version {}", past);
+				let decoder = Arc::get_mut(&mut self.decoder)
+					.ok_or_else(|| ArchiveError::Msg("Reference to decoder is not safe to access".into()))?;
+				match Self::register_version(decoder, past, &past_metadata) {
+					Ok(()) => log::debug!("Registered previous version {}", past),
+					Err(e) => {
+						log::error!("{}; skipping blocks of spec {}", e, past);
+						self.skipped_specs.insert(past);
+					}
+				}
 			}
 		}
 
+		let skipped_specs = &self.skipped_specs;
+		let blocks: Vec<_> = blocks.into_iter().filter(|(_, _, _, spec)| !skipped_specs.contains(spec)).collect();
+
 		if self.upgrades.load().iter().max_by(|a, b| a.1.cmp(b.1)).map(|(_, v)| v)
 			< blocks.iter().map(|&(_, _, _, v)| v).max().as_ref()
 		{
 			self.update_upgrade_blocks().await?;
 		}
+		let range = blocks.first().zip(blocks.last()).map(|(first, last)| (first.0, last.0));
 		let decoder = self.decoder.clone();
 		let upgrades = self.upgrades.load().clone();
-		let extrinsics =
-			task::spawn_blocking(move || Ok::<_, ArchiveError>(Self::decode(&decoder, blocks, &upgrades))).await??;
+		let decode_wrapped_calls = self.decode_wrapped_calls;
+		let format_params = self.format_params;
+		let (extrinsics, mut stats) = task::spawn_blocking(move || {
+			Ok::<_, ArchiveError>(Self::decode(&decoder, blocks, &upgrades, decode_wrapped_calls, format_params))
+		})
+		.await??;
 
-		self.addr.send(BatchExtrinsics::new(extrinsics)).await?;
+		self.decoded_total += stats.decoded as u64;
+		self.failed_total += stats.failed.len() as u64;
+		if let Some((from, to)) = range {
+			log::info!(
+				"Decoded extrinsics of {} blocks in {}..{}, {} failed ({:.1}%). Failed since startup: {} of {}",
+				stats.total(),
+				from,
+				to,
+				stats.failed.len(),
+				stats.failure_rate() * 100.0,
+				self.failed_total,
+				self.decoded_total + self.failed_total,
+			);
+			if stats.is_broken() {
+				log::error!(
+					"Extrinsics of {} out of {} blocks in {}..{} failed to decode, check the metadata",
+					stats.failed.len(),
+					stats.total(),
+					from,
+					to
+				);
+			}
+		}
+		// failed blocks are recorded, so they are not crawled again
+		let undecodable = std::mem::take(&mut stats.failed);
+		self.addr.send(BatchExtrinsics::new(extrinsics).with_undecodable(undecodable)).await?;
 		Ok(())
 	}
 
@@ -108,8 +226,11 @@ impl ExtrinsicsDecoder {
 		decoder: &Decoder,
 		blocks: Vec<(u32, Vec<u8>, Vec<u8>, u32)>,
 		upgrades: &HashMap<u32, u32>,
-	) -> Result<Vec<ExtrinsicsModel>> {
+		decode_wrapped_calls: bool,
+		format_params: Option<u16>,
+	) -> Result<(Vec<ExtrinsicsModel>, DecodeStats)> {
 		let mut extrinsics = Vec::new();
+		let mut stats = DecodeStats::default();
 		if blocks.len() > 2 {
 			let first = blocks.first().expect("Checked len; qed");
 			let last = blocks.last().expect("Checked len; qed");
@@ -133,7 +254,14 @@ impl ExtrinsicsDecoder {
 					.ok_or(ArchiveError::PrevSpecNotFound(*version))?;
 				match decoder.decode_extrinsics(*previous, ext.as_slice()) {
 					Ok(exts) => {
-						if let Ok(exts_model) = ExtrinsicsModel::new(hash, number, exts) {
+						stats.decoded += 1;
+						if let Ok(mut exts_model) = ExtrinsicsModel::new(hash, number, exts) {
+							if decode_wrapped_calls {
+								exts_model.nest_wrapped_calls(|call| Self::decode_call(decoder, *previous, call));
+							}
+							if let Some(ss58_prefix) = format_params {
+								exts_model.format_params(ss58_prefix);
+							}
 							extrinsics.push(exts_model);
 						}
 					}
@@ -144,22 +272,60 @@ impl ExtrinsicsDecoder {
 							spec,
 							err
 						);
+						stats.failed(hash, number, *previous, format!("{:?}", err));
 					}
 				}
 			} else {
 				match decoder.decode_extrinsics(spec, ext.as_slice()) {
 					Ok(exts) => {
-						if let Ok(exts_model) = ExtrinsicsModel::new(hash, number, exts) {
+						stats.decoded += 1;
+						if let Ok(mut exts_model) = ExtrinsicsModel::new(hash, number, exts) {
+							if decode_wrapped_calls {
+								exts_model.nest_wrapped_calls(|call| Self::decode_call(decoder, spec, call));
+							}
+							if let Some(ss58_prefix) = format_params {
+								exts_model.format_params(ss58_prefix);
+							}
 							extrinsics.push(exts_model);
 						}
 					}
 					Err(err) => {
 						log::warn!("decode extrinsic failed, block: {}, spec: {}, reason: {:?}", number, spec, err);
+						stats.failed(hash, number, spec, format!("{:?}", err));
 					}
 				}
 			}
 		}
-		Ok(extrinsics)
+		Ok((extrinsics, stats))
+	}
+
+	/// Decode an encoded call against the metadata of `spec`.
+	/// The decoder only decodes whole extrinsics, so the call is wrapped in an unsigned extrinsic.
+	fn decode_call(decoder: &Decoder, spec: u32, call: &[u8]) -> Option<serde_json::Value> {
+		// version 4, unsigned
+		let mut extrinsic = vec![0b0000_0100];
+		extrinsic.extend_from_slice(call);
+		let extrinsic =
+			decoder.decode_extrinsics(spec, vec![extrinsic].encode().as_slice()).ok()?.into_iter().next()?;
+		match serde_json::to_value(extrinsic).ok()? {
+			serde_json::Value::Object(mut map) => {
+				// the inner call is not signed
+				map.remove("signature");
+				Some(serde_json::Value::Object(map))
+			}
+			value => Some(value),
+		}
+	}
+
+	/// Register the metadata of `spec` with the decoder.
+	/// Metadata which is corrupt or of an unsupported version results in a `MetadataError`,
+	/// rather than a panic.
+	pub(super) fn register_version(decoder: &mut Decoder, spec: u32, metadata: &[u8]) -> Result<(), MetadataError> {
+		match panic::catch_unwind(AssertUnwindSafe(|| decoder.register_version(spec, metadata))) {
+			Ok(Ok(_)) => Ok(()),
+			Ok(Err(e)) => Err(MetadataError::Undecodable { spec, reason: e.to_string() }),
+			Err(_) => Err(MetadataError::Undecodable { spec, reason: "decoder panicked".into() }),
+		}
 	}
 
 	async fn update_upgrade_blocks(&self) -> Result<()> {
@@ -193,3 +359,158 @@ impl Handler<Index> for ExtrinsicsDecoder {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_common::TestGuard;
+
+	#[test]
+	fn should_skip_corrupt_metadata() {
+		crate::initialize();
+		let mut decoder = Decoder::new(Chain::Kusama);
+		let corrupt = vec![0xDE, 0xAD, 0xBE, 0xEF];
+
+		let err = ExtrinsicsDecoder::register_version(&mut decoder, 1055, &corrupt).unwrap_err();
+		assert!(matches!(err, MetadataError::Undecodable { spec: 1055, .. }));
+		assert!(!decoder.has_version(&1055));
+	}
+
+	#[test]
+	fn should_record_blocks_which_fail_to_decode() {
+		crate::initialize();
+		let decoder = Decoder::new(Chain::Kusama);
+		// no metadata is registered for the spec, so no block can be decoded
+		let blocks =
+			(0..MIN_DECODE_SAMPLE as u32).map(|n| (n, vec![0; 32], vec![0xDE, 0xAD, 0xBE, 0xEF], 1055)).collect();
+
+		let (extrinsics, stats) = ExtrinsicsDecoder::decode(&decoder, blocks, &HashMap::new(), true, None).unwrap();
+		assert!(extrinsics.is_empty());
+		assert_eq!(stats.failed.len(), MIN_DECODE_SAMPLE);
+		assert!(stats.failed.iter().enumerate().all(|(n, b)| b.block_num == n as u32 && b.spec == 1055));
+		assert!(stats.failure_rate() > MAX_DECODE_FAILURE_RATE);
+		assert!(stats.is_broken());
+	}
+
+	#[test]
+	fn should_index_calls_of_utility_batch() {
+		use polkadot_service::kusama_runtime;
+
+		crate::initialize();
+		let spec = kusama_runtime::VERSION.spec_version;
+		let mut decoder = Decoder::new(Chain::Kusama);
+		ExtrinsicsDecoder::register_version(&mut decoder, spec, &kusama_runtime::Runtime::metadata().encode()).unwrap();
+
+		// `System::remark` and `Utility::batch`, by their indices in the Kusama runtime
+		let remark = |data: u8| vec![0x00, 0x01, 0x04, data];
+		let batch = |calls: Vec<Vec<u8>>| {
+			let mut batch = vec![0x18, 0x00];
+			batch.extend(codec::Compact(calls.len() as u32).encode());
+			calls.into_iter().for_each(|call| batch.extend(call));
+			batch
+		};
+		// version 4, unsigned
+		let mut extrinsic = vec![0b0000_0100];
+		extrinsic.extend(batch(vec![remark(0xAA), batch(vec![remark(0xBB)])]));
+		let blocks = vec![(1, vec![0; 32], vec![extrinsic].encode(), spec)];
+
+		let (extrinsics, stats) = ExtrinsicsDecoder::decode(&decoder, blocks, &HashMap::new(), true, None).unwrap();
+		assert_eq!(stats.decoded, 1);
+		let calls = extrinsics[0].calls();
+		let calls = calls.iter().map(|c| (c.call_index, c.parent_index, c.depth, c.module.as_str(), c.call.as_str()));
+		assert_eq!(
+			calls.collect::<Vec<_>>(),
+			[
+				(0, None, 0, "utility", "batch"),
+				(1, Some(0), 1, "system", "remark"),
+				(2, Some(0), 1, "utility", "batch"),
+				(3, Some(2), 2, "system", "remark"),
+			]
+		);
+	}
+
+	/// Insert the metadata of the Kusama runtime and a block of that spec with the encoded extrinsics `ext`.
+	async fn insert_kusama_block(pool: &PgPool, ext: Vec<u8>) -> Result<(), anyhow::Error> {
+		use polkadot_service::kusama_runtime;
+
+		let spec = kusama_runtime::VERSION.spec_version as i32;
+		sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+			.bind(spec)
+			.bind(kusama_runtime::Runtime::metadata().encode())
+			.execute(pool)
+			.await?;
+		sqlx::query(
+			"INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+		)
+		.bind(vec![0u8; 32])
+		.bind(vec![1u8; 32])
+		.bind(1_i32)
+		.bind(vec![0u8; 32])
+		.bind(vec![0u8; 32])
+		.bind(Vec::<u8>::new().encode())
+		.bind(ext)
+		.bind(spec)
+		.execute(pool)
+		.await?;
+		Ok(())
+	}
+
+	#[test]
+	fn should_decode_extrinsics_without_storage_indexing() -> Result<(), anyhow::Error> {
+		use crate::database::DatabaseConfig;
+		use xtra::spawn::AsyncStd;
+
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			// no execution is attached to the database, as when storage indexing is disabled
+			let db = DatabaseActor::new(&config).await?.create(None).spawn(&mut AsyncStd);
+			let pool = db.send(GetState::Pool).await??.pool();
+			// an unsigned `System::remark`, by its indices in the Kusama runtime
+			let extrinsic = vec![0b0000_0100, 0x00, 0x01, 0x04, 0xAA];
+			insert_kusama_block(&pool, vec![extrinsic].encode()).await?;
+
+			let mut decoder = ExtrinsicsDecoder::with_options(db, Chain::Kusama, 100, true, None).await?;
+			decoder.crawl_missing_extrinsics().await?;
+
+			let count = |table: &str| format!("SELECT COUNT(*) FROM {}", table);
+			let (extrinsics,): (i64,) = sqlx::query_as(&count("extrinsics")).fetch_one(&pool).await?;
+			let (storage,): (i64,) = sqlx::query_as(&count("storage")).fetch_one(&pool).await?;
+			let (jobs,): (i64,) = sqlx::query_as(&count("_job_outbox")).fetch_one(&pool).await?;
+			assert_eq!(extrinsics, 1);
+			// nothing was executed or queued for execution
+			assert_eq!((storage, jobs), (0, 0));
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_not_crawl_undecodable_blocks_again() -> Result<(), anyhow::Error> {
+		use crate::database::DatabaseConfig;
+		use xtra::spawn::AsyncStd;
+
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let db = DatabaseActor::new(&config).await?.create(None).spawn(&mut AsyncStd);
+			let pool = db.send(GetState::Pool).await??.pool();
+			// an extrinsic of a module which does not exist
+			let extrinsic = vec![0b0000_0100, 0xFF, 0x00];
+			insert_kusama_block(&pool, vec![extrinsic].encode()).await?;
+
+			let mut decoder = ExtrinsicsDecoder::with_options(db, Chain::Kusama, 100, true, None).await?;
+			decoder.crawl_missing_extrinsics().await?;
+
+			let mut conn = pool.acquire().await?;
+			assert_eq!(queries::undecodable_block_count(&mut conn).await?, 1);
+			assert!(queries::blocks_missing_extrinsics(&mut conn, 100, &[]).await?.is_empty());
+			// crawling again does not fail, and finds nothing to decode
+			decoder.crawl_missing_extrinsics().await?;
+			assert_eq!((decoder.decoded_total, decoder.failed_total), (0, 1));
+			Ok(())
+		})
+	}
+}
@@ -0,0 +1,260 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Liveness and readiness probes of the archive, served over HTTP on `/healthz` and `/readyz`.
+
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use sqlx::PgPool;
+use tide::{Request, Response, StatusCode};
+use xtra::{prelude::*, WeakAddress};
+
+use sp_blockchain::HeaderBackend;
+use sp_runtime::traits::{Block as BlockT, NumberFor};
+use substrate_archive_backend::{ReadOnlyBackend, ReadOnlyDb};
+
+use crate::{
+	actors::workers::database::{DatabaseActor, GetState},
+	archive::HealthConfig,
+	database::queries,
+	error::{ArchiveError, Result},
+};
+
+/// Time of the last crawl of the blocks worker, shared with the [`HealthActor`].
+#[derive(Clone)]
+pub struct Heartbeat(Arc<Mutex<Instant>>);
+
+impl Heartbeat {
+	pub fn new() -> Self {
+		Self(Arc::new(Mutex::new(Instant::now())))
+	}
+
+	/// Record that the blocks worker is alive.
+	pub fn beat(&self) {
+		*self.0.lock() = Instant::now();
+	}
+
+	fn elapsed(&self) -> Duration {
+		self.0.lock().elapsed()
+	}
+}
+
+impl Default for Heartbeat {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Checks the archive is alive and caught up with the chain, answering the probes of an orchestrator.
+///
+/// | Path | `200 OK` if |
+/// | -- | -- |
+/// | `/healthz` | the database is reachable and the blocks worker crawled within `heartbeat_timeout` |
+/// | `/readyz` | the archive is healthy and at most `max_lag` blocks behind the best block |
+///
+/// Otherwise, the probes respond with `503 Service Unavailable` and the reason.
+pub struct HealthActor<B: Send + 'static, D: Send + 'static> {
+	pool: PgPool,
+	db: Address<DatabaseActor>,
+	backend: Arc<ReadOnlyBackend<B, D>>,
+	heartbeat: Heartbeat,
+	heartbeat_timeout: Duration,
+	max_lag: u32,
+}
+
+impl<B, D> HealthActor<B, D>
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	pub async fn new(
+		backend: Arc<ReadOnlyBackend<B, D>>,
+		db: Address<DatabaseActor>,
+		heartbeat: Heartbeat,
+		config: &HealthConfig,
+	) -> Result<Self> {
+		let pool = db.send(GetState::Pool).await??.pool();
+		Ok(Self {
+			pool,
+			db,
+			backend,
+			heartbeat,
+			heartbeat_timeout: Duration::from_secs(config.heartbeat_timeout),
+			max_lag: config.max_lag,
+		})
+	}
+
+	async fn check_health(&self) -> Result<()> {
+		if !self.db.is_connected() {
+			return Err(ArchiveError::Msg("database actor stopped".into()));
+		}
+		let elapsed = self.heartbeat.elapsed();
+		if elapsed > self.heartbeat_timeout {
+			return Err(ArchiveError::Msg(format!("blocks were last crawled {:?} ago", elapsed)));
+		}
+		sqlx::query("SELECT 1").execute(&self.pool).await?;
+		Ok(())
+	}
+
+	async fn check_ready(&self) -> Result<()> {
+		self.check_health().await?;
+		let best: u32 = self.backend.info().best_number.into();
+		let mut conn = self.pool.acquire().await?;
+		let lag = queries::tip_lag(&mut conn, best).await?;
+		if lag > self.max_lag {
+			return Err(ArchiveError::Msg(format!("{} blocks behind the best block {}", lag, best)));
+		}
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl<B: Send + Sync, D: Send + Sync> Actor for HealthActor<B, D> {}
+
+/// Probe the archive, responding with the reason it failed.
+pub enum Probe {
+	Health,
+	Ready,
+}
+
+impl Message for Probe {
+	type Result = Result<(), String>;
+}
+
+#[async_trait::async_trait]
+impl<B, D> Handler<Probe> for HealthActor<B, D>
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	async fn handle(&mut self, probe: Probe, _: &mut Context<Self>) -> Result<(), String> {
+		let result = match probe {
+			Probe::Health => self.check_health().await,
+			Probe::Ready => self.check_ready().await,
+		};
+		result.map_err(|e| e.to_string())
+	}
+}
+
+/// Serve the probes of `actor` on `/healthz` and `/readyz` of `app`.
+pub(crate) fn route<B, D>(app: &mut tide::Server<()>, actor: WeakAddress<HealthActor<B, D>>)
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	let health = actor.clone();
+	app.at("/healthz").get(move |_: Request<()>| probe(health.clone(), Probe::Health));
+	app.at("/readyz").get(move |_: Request<()>| probe(actor.clone(), Probe::Ready));
+}
+
+async fn probe<B, D>(actor: WeakAddress<HealthActor<B, D>>, probe: Probe) -> tide::Result
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	let response = match actor.send(probe).await {
+		Ok(Ok(())) => Response::builder(StatusCode::Ok).body("ok").build(),
+		Ok(Err(reason)) => Response::builder(StatusCode::ServiceUnavailable).body(reason).build(),
+		Err(_) => Response::builder(StatusCode::ServiceUnavailable).body("health actor stopped").build(),
+	};
+	Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::DatabaseConfig;
+	use anyhow::Error;
+	use async_std::task;
+	use polkadot_service::Block;
+	use std::{io, path::PathBuf};
+	use substrate_archive_backend::{KeyValuePair, RuntimeConfig};
+	use test_common::TestGuard;
+	use tide::http::{Method, Url};
+	use xtra::spawn::AsyncStd;
+
+	/// A chain database without blocks, so the best block is the genesis block.
+	struct EmptyDb;
+
+	impl ReadOnlyDb for EmptyDb {
+		fn get(&self, _col: u32, _key: &[u8]) -> Option<Vec<u8>> {
+			None
+		}
+
+		fn iter<'a>(&'a self, _col: u32) -> Box<dyn Iterator<Item = KeyValuePair> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn catch_up_with_primary(&self) -> io::Result<()> {
+			Ok(())
+		}
+
+		fn open_database(_path: &str, _cache_size: usize, _db_path: PathBuf) -> io::Result<Self> {
+			Ok(Self)
+		}
+	}
+
+	async fn get(app: &tide::Server<()>, path: &str) -> Result<(StatusCode, String), Error> {
+		let request = tide::http::Request::new(Method::Get, Url::parse(&format!("http://localhost{}", path))?);
+		let mut response: tide::http::Response = app.respond(request).await.map_err(|e| e.into_inner())?;
+		let body = response.body_string().await.map_err(|e| e.into_inner())?;
+		Ok((response.status(), body))
+	}
+
+	#[test]
+	fn should_fail_probes_once_blocks_stop_being_crawled() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let db = DatabaseActor::new(&config).await?.create(None).spawn(&mut AsyncStd);
+			let storage_mode = RuntimeConfig::default().storage_mode;
+			let backend = Arc::new(ReadOnlyBackend::<Block, _>::new(Arc::new(EmptyDb), true, storage_mode));
+			let health = HealthConfig { addr: ([127, 0, 0, 1], 0).into(), max_lag: 10, heartbeat_timeout: 1 };
+			let heartbeat = Heartbeat::new();
+			let actor =
+				HealthActor::new(backend, db, heartbeat.clone(), &health).await?.create(None).spawn(&mut AsyncStd);
+			let mut app = tide::new();
+			route(&mut app, actor.downgrade());
+
+			assert_eq!(get(&app, "/healthz").await?, (StatusCode::Ok, "ok".to_string()));
+			// nothing is indexed, but the chain has no blocks past genesis either
+			assert_eq!(get(&app, "/readyz").await?.0, StatusCode::Ok);
+
+			task::sleep(Duration::from_millis(1_100)).await;
+			let (status, reason) = get(&app, "/healthz").await?;
+			assert_eq!(status, StatusCode::ServiceUnavailable);
+			assert!(reason.starts_with("blocks were last crawled"), "{}", reason);
+			assert_eq!(get(&app, "/readyz").await?.0, StatusCode::ServiceUnavailable);
+
+			// the blocks worker crawled again
+			heartbeat.beat();
+			assert_eq!(get(&app, "/healthz").await?.0, StatusCode::Ok);
+			Ok(())
+		})
+	}
+}
@@ -0,0 +1,55 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! HTTP server of the metrics and the health probes.
+//! Routes served on the same address share a server.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use async_std::task;
+use futures::future;
+use tide::listener::Listener;
+
+use crate::error::Result;
+
+/// Routes of the metrics and the health probes, grouped by the address they are served on.
+#[derive(Default)]
+pub struct HttpRoutes {
+	servers: HashMap<SocketAddr, tide::Server<()>>,
+}
+
+impl HttpRoutes {
+	/// The server of `addr`, to add routes to.
+	pub fn at(&mut self, addr: SocketAddr) -> &mut tide::Server<()> {
+		self.servers.entry(addr).or_insert_with(tide::new)
+	}
+
+	/// Serve the routes until `closed` is disconnected.
+	pub async fn serve(self, closed: flume::Receiver<()>) -> Result<()> {
+		for (addr, app) in self.servers {
+			let mut listener = app.bind(addr).await?;
+			log::info!("Serving HTTP on {}", addr);
+			let closed = closed.clone();
+			task::spawn(async move {
+				if let future::Either::Left((Err(e), _)) =
+					future::select(Box::pin(listener.accept()), Box::pin(closed.recv_async())).await
+				{
+					log::error!("HTTP server on {} failed: {}", addr, e);
+				}
+			});
+		}
+		Ok(())
+	}
+}
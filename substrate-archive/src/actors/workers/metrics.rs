@@ -0,0 +1,195 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prometheus metrics of the indexing progress, served over HTTP on `/metrics`.
+
+use std::sync::Arc;
+
+use prometheus::{Encoder, IntGauge, Registry, TextEncoder};
+use sqlx::PgPool;
+use tide::{Request, Response, StatusCode};
+use xtra::prelude::*;
+
+use sp_blockchain::HeaderBackend;
+use sp_runtime::traits::{Block as BlockT, NumberFor};
+use substrate_archive_backend::{ReadOnlyBackend, ReadOnlyDb};
+
+use crate::{
+	actors::workers::database::{DatabaseActor, GetState},
+	database::queries,
+	error::Result,
+};
+
+/// Periodically reads the indexing progress from the database into Prometheus metrics,
+/// which are served on `/metrics`.
+///
+/// | Metric | Value |
+/// | -- | -- |
+/// | `substrate_archive_indexed_blocks` | number of blocks in the `blocks` table |
+/// | `substrate_archive_max_indexed_block` | highest block in the `blocks` table |
+/// | `substrate_archive_max_known_block` | best block of the backend |
+/// | `substrate_archive_indexing_lag` | blocks the archive is behind the best block |
+/// | `substrate_archive_storage_rows` | estimated rows of the `storage` table |
+/// | `substrate_archive_extrinsics_rows` | estimated rows of the `extrinsics` table, one per block |
+/// | `substrate_archive_undecodable_blocks` | rows of the `undecodable_extrinsics` table |
+pub struct MetricsActor<B: Send + 'static, D: Send + 'static> {
+	pool: PgPool,
+	registry: Registry,
+	backend: Arc<ReadOnlyBackend<B, D>>,
+	indexed_blocks: IntGauge,
+	max_indexed_block: IntGauge,
+	max_known_block: IntGauge,
+	indexing_lag: IntGauge,
+	storage_rows: IntGauge,
+	extrinsics_rows: IntGauge,
+	undecodable_blocks: IntGauge,
+}
+
+impl<B, D> MetricsActor<B, D>
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	/// Register the metrics.
+	pub async fn new(backend: Arc<ReadOnlyBackend<B, D>>, db: Address<DatabaseActor>) -> Result<Self> {
+		let pool = db.send(GetState::Pool).await??.pool();
+		let registry = Registry::new();
+		let gauge = |name: &str, help: &str| -> Result<IntGauge> {
+			let gauge = IntGauge::new(name, help)?;
+			registry.register(Box::new(gauge.clone()))?;
+			Ok(gauge)
+		};
+		let indexed_blocks = gauge("substrate_archive_indexed_blocks", "Number of indexed blocks")?;
+		let max_indexed_block = gauge("substrate_archive_max_indexed_block", "Highest indexed block")?;
+		let max_known_block = gauge("substrate_archive_max_known_block", "Best block known to the backend")?;
+		let indexing_lag =
+			gauge("substrate_archive_indexing_lag", "Number of blocks the archive is behind the best block")?;
+		let storage_rows = gauge("substrate_archive_storage_rows", "Estimated number of indexed storage changes")?;
+		let extrinsics_rows =
+			gauge("substrate_archive_extrinsics_rows", "Estimated number of blocks with decoded extrinsics")?;
+		let undecodable_blocks =
+			gauge("substrate_archive_undecodable_blocks", "Number of blocks whose extrinsics failed to decode")?;
+
+		Ok(Self {
+			pool,
+			registry,
+			backend,
+			indexed_blocks,
+			max_indexed_block,
+			max_known_block,
+			indexing_lag,
+			storage_rows,
+			extrinsics_rows,
+			undecodable_blocks,
+		})
+	}
+
+	/// Registry of the metrics, to serve them over HTTP.
+	pub fn registry(&self) -> Registry {
+		self.registry.clone()
+	}
+
+	async fn update(&mut self) -> Result<()> {
+		let best: u32 = self.backend.info().best_number.into();
+		let mut conn = self.pool.acquire().await?;
+		let max = queries::max_block(&mut conn).await?;
+		let indexed = match max {
+			Some(max) => queries::block_count_in_range(&mut conn, 0, max).await?,
+			None => 0,
+		};
+		self.indexed_blocks.set(i64::try_from(indexed)?);
+		self.max_indexed_block.set(max.map(i64::from).unwrap_or(0));
+		self.max_known_block.set(i64::from(best));
+		self.indexing_lag.set(i64::from(best.saturating_sub(max.unwrap_or(0))));
+		self.storage_rows.set(i64::try_from(queries::estimated_row_count(&mut conn, "storage").await?)?);
+		self.extrinsics_rows.set(i64::try_from(queries::estimated_row_count(&mut conn, "extrinsics").await?)?);
+		self.undecodable_blocks.set(i64::try_from(queries::undecodable_block_count(&mut conn).await?)?);
+		Ok(())
+	}
+}
+
+/// Serve the metrics of `registry` on `/metrics` of `app`.
+pub(crate) fn route(app: &mut tide::Server<()>, registry: Registry) {
+	app.at("/metrics").get(move |_: Request<()>| {
+		let registry = registry.clone();
+		async move { render(&registry) }
+	});
+}
+
+fn render(registry: &Registry) -> tide::Result {
+	let encoder = TextEncoder::new();
+	let mut body = Vec::new();
+	encoder.encode(&registry.gather(), &mut body)?;
+	Ok(Response::builder(StatusCode::Ok).content_type(encoder.format_type()).body(body).build())
+}
+
+#[async_trait::async_trait]
+impl<B: Send + Sync, D: Send + Sync> Actor for MetricsActor<B, D> {}
+
+pub struct UpdateMetrics;
+impl Message for UpdateMetrics {
+	type Result = ();
+}
+
+#[async_trait::async_trait]
+impl<B, D> Handler<UpdateMetrics> for MetricsActor<B, D>
+where
+	B: BlockT + Unpin,
+	D: ReadOnlyDb + 'static,
+	B::Hash: Unpin,
+	NumberFor<B>: Into<u32>,
+{
+	async fn handle(&mut self, _: UpdateMetrics, _: &mut Context<Self>) {
+		if let Err(e) = self.update().await {
+			log::error!("Failed to update metrics: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Error;
+	use async_std::task;
+	use tide::http::{Method, Url};
+
+	fn get(app: &tide::Server<()>, path: &str) -> Result<(StatusCode, String), Error> {
+		let request = tide::http::Request::new(Method::Get, Url::parse(&format!("http://localhost{}", path))?);
+		task::block_on(async {
+			let mut response: tide::http::Response = app.respond(request).await.map_err(|e| e.into_inner())?;
+			let body = response.body_string().await.map_err(|e| e.into_inner())?;
+			Ok((response.status(), body))
+		})
+	}
+
+	#[test]
+	fn should_serve_metrics() -> Result<(), Error> {
+		let registry = Registry::new();
+		let gauge = IntGauge::new("substrate_archive_indexing_lag", "lag")?;
+		registry.register(Box::new(gauge.clone()))?;
+		gauge.set(42);
+
+		let mut app = tide::new();
+		route(&mut app, registry);
+
+		let (status, body) = get(&app, "/metrics")?;
+		assert_eq!(status, StatusCode::Ok);
+		assert!(body.contains("substrate_archive_indexing_lag 42"));
+		assert_eq!(get(&app, "/")?.0, StatusCode::NotFound);
+		Ok(())
+	}
+}
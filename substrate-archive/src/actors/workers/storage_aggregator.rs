@@ -21,20 +21,64 @@ use xtra::prelude::*;
 
 use crate::{
 	actors::workers::database::DatabaseActor,
+	database::WriteAheadLog,
 	error::Result,
-	types::{BatchStorage, Hash, Storage},
-	wasm_tracing::Traces,
+	types::{BatchStorage, Hash, Storage, UnexecutableBlock},
+	wasm_tracing::{TraceFiles, TraceOutput, Traces},
 };
 
 pub struct StorageAggregator<H: Send + Sync + 'static> {
 	db: Address<DatabaseActor>,
 	storage: Vec<Storage<H>>,
 	traces: Vec<Traces>,
+	/// Log of storage which is not yet committed to Postgres.
+	wal: Option<WriteAheadLog>,
+	/// Prefixes of the storage keys to index. Empty to index every key.
+	key_prefixes: Vec<Vec<u8>>,
+	/// Files traces are written to, in addition to or instead of the database.
+	trace_files: Option<TraceFiles>,
+	/// Whether traces are inserted into the database.
+	traces_to_database: bool,
 }
 
 impl<H: Hash> StorageAggregator<H> {
 	pub fn new(db: Address<DatabaseActor>) -> Self {
-		Self { db, storage: Vec::with_capacity(500), traces: Vec::with_capacity(250) }
+		Self {
+			db,
+			storage: Vec::with_capacity(500),
+			traces: Vec::with_capacity(250),
+			wal: None,
+			key_prefixes: Vec::new(),
+			trace_files: None,
+			traces_to_database: true,
+		}
+	}
+
+	/// Write traces to `output`.
+	pub async fn with_trace_output(mut self, output: &TraceOutput) -> Result<Self> {
+		if let Some(dir) = output.dir() {
+			self.trace_files = Some(TraceFiles::open(dir).await?);
+		}
+		self.traces_to_database = output.database();
+		Ok(self)
+	}
+
+	/// Only index the storage keys starting with one of `prefixes`.
+	pub fn with_key_prefixes(mut self, prefixes: Vec<Vec<u8>>) -> Self {
+		self.key_prefixes = prefixes;
+		self
+	}
+
+	/// Record storage in a write-ahead log until it is committed.
+	/// Storage left in the log by a previous run is queued to be inserted again.
+	pub async fn with_wal(mut self, wal: WriteAheadLog) -> Result<Self> {
+		let replayed = wal.replay::<H>().await?;
+		if !replayed.is_empty() {
+			log::info!("Replaying storage of {} blocks from the write-ahead log", replayed.len());
+		}
+		self.storage.extend(replayed);
+		self.wal = Some(wal);
+		Ok(self)
 	}
 
 	async fn handle_storage(&mut self, ctx: &mut Context<Self>) -> Result<()> {
@@ -42,7 +86,11 @@ impl<H: Hash> StorageAggregator<H> {
 		if !storage.is_empty() {
 			let changes = storage.iter().flat_map(|c| c.changes.iter()).count();
 			log::info!("Indexing {} blocks of storage entries, with {} total changes", storage.len(), changes);
-			ctx.handle_while(self, self.db.send(BatchStorage::new(storage))).await?;
+			let blocks = storage.iter().map(|s| (s.block_num(), *s.hash())).collect::<Vec<_>>();
+			ctx.handle_while(self, self.db.send(BatchStorage::new(storage))).await??;
+			if let Some(wal) = &self.wal {
+				wal.commit(&blocks).await?;
+			}
 		}
 		Ok(())
 	}
@@ -52,7 +100,14 @@ impl<H: Hash> StorageAggregator<H> {
 		if !traces.is_empty() {
 			log::info!("Inserting {} traces", traces.len());
 			for trace in traces.drain(..) {
-				ctx.handle_while(self, self.db.send(trace)).await?;
+				if let Some(files) = &self.trace_files {
+					if let Err(e) = files.write(&trace).await {
+						log::error!("Failed to write traces of block {} to a file: {:?}", trace.block_num(), e);
+					}
+				}
+				if self.traces_to_database {
+					ctx.handle_while(self, self.db.send(trace)).await?;
+				}
 			}
 		}
 		std::mem::swap(&mut self.traces, &mut traces);
@@ -93,14 +148,84 @@ impl<H: Hash> Handler<SendTraces> for StorageAggregator<H> {
 
 #[async_trait::async_trait]
 impl<H: Hash> Handler<Storage<H>> for StorageAggregator<H> {
-	async fn handle(&mut self, s: Storage<H>, _: &mut Context<Self>) {
+	async fn handle(&mut self, mut s: Storage<H>, _: &mut Context<Self>) {
+		s.retain_prefixes(&self.key_prefixes);
+		if let Some(wal) = &self.wal {
+			if let Err(e) = wal.append(&s).await {
+				log::error!("Failed to write block {} to the write-ahead log: {:?}", s.block_num(), e);
+			}
+		}
 		self.storage.push(s)
 	}
 }
 
+#[async_trait::async_trait]
+impl<H: Hash> Handler<UnexecutableBlock<H>> for StorageAggregator<H> {
+	async fn handle(&mut self, block: UnexecutableBlock<H>, _: &mut Context<Self>) {
+		if let Err(e) = self.db.send(block).await {
+			log::error!("{:?}", e);
+		}
+	}
+}
+
 #[async_trait::async_trait]
 impl<H: Hash> Handler<Traces> for StorageAggregator<H> {
 	async fn handle(&mut self, t: Traces, _: &mut Context<Self>) {
 		self.traces.push(t)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		database::{models::BlockModelDecoder, queries, BlockModel, Database, DatabaseConfig},
+		types::BatchBlock,
+	};
+	use anyhow::Error;
+	use async_std::task;
+	use polkadot_service::{Block, Hash};
+	use sp_runtime::traits::{Block as _, Header as _};
+	use sp_storage::{StorageData, StorageKey};
+	use test_common::TestGuard;
+	use xtra::spawn::AsyncStd;
+
+	#[test]
+	fn should_replay_uncommitted_storage() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2)")
+				.bind(26_i32)
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(&mut database.conn().await?)
+				.await?;
+			let blocks: Vec<BlockModel> = test_common::get_kusama_blocks()?.drain(0..1).map(BlockModel::from).collect();
+			let blocks = BlockModelDecoder::<Block>::with_vec(blocks)?;
+			database.insert(BatchBlock::new(blocks.clone())).await?;
+			let block = &blocks[0].inner.block;
+			let changes = vec![(StorageKey(b"key".to_vec()), Some(StorageData(b"value".to_vec())))];
+			let storage = Storage::new(block.hash(), *block.header().number(), false, changes);
+
+			// the block was executed and logged, but the process crashed before the storage was committed
+			let dir = tempfile::tempdir()?;
+			WriteAheadLog::open(dir.path()).await?.append(&storage).await?;
+
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let db = DatabaseActor::new(&config).await?.create(None).spawn(&mut AsyncStd);
+			let aggregator = StorageAggregator::<Hash>::new(db)
+				.with_wal(WriteAheadLog::open(dir.path()).await?)
+				.await?
+				.create(None)
+				.spawn(&mut AsyncStd);
+			aggregator.send(SendStorage).await?;
+
+			let mut conn = database.conn().await?;
+			let changes = queries::storage_keys_changed_at(&mut conn, storage.block_num(), 10, 0).await?;
+			assert_eq!(changes, vec![(b"key".to_vec(), Some(b"value".to_vec()))]);
+			assert!(WriteAheadLog::open(dir.path()).await?.replay::<Hash>().await?.is_empty());
+			Ok(())
+		})
+	}
+}
@@ -0,0 +1,84 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checkpoint of the indexing progress on the local filesystem.
+//! It records the highest block up to which every block is indexed,
+//! so missing blocks are searched for from there on startup, rather than from the first block.
+
+use std::{io, path::PathBuf};
+
+use async_std::fs;
+
+use crate::error::{ArchiveError, Result};
+
+/// A file holding the number of the highest block up to which every block is indexed.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+	path: PathBuf,
+}
+
+impl Checkpoint {
+	pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+		Self { path: path.into() }
+	}
+
+	/// Read the checkpointed block number, if a checkpoint was written yet.
+	pub async fn read(&self) -> Result<Option<u32>> {
+		match fs::read_to_string(&self.path).await {
+			Ok(contents) => contents
+				.trim()
+				.parse()
+				.map(Some)
+				.map_err(|e| ArchiveError::from(format!("Invalid checkpoint file {}: {}", self.path.display(), e))),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Durably record that every block up to `block_num` is indexed.
+	pub async fn write(&self, block_num: u32) -> Result<()> {
+		if let Some(parent) = self.path.parent() {
+			fs::create_dir_all(parent).await?;
+		}
+		// write to a temporary file first, so a partially written checkpoint is never read.
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, block_num.to_string()).await?;
+		fs::rename(&tmp, &self.path).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_std::task;
+
+	#[test]
+	fn should_read_back_written_checkpoint() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		let checkpoint = Checkpoint::new(dir.path().join("nested").join("checkpoint"));
+		task::block_on(async {
+			assert_eq!(checkpoint.read().await?, None);
+			checkpoint.write(42).await?;
+			checkpoint.write(1337).await?;
+			assert_eq!(checkpoint.read().await?, Some(1337));
+
+			fs::write(dir.path().join("nested").join("checkpoint"), "not a block").await?;
+			assert!(checkpoint.read().await.is_err());
+			Ok(())
+		})
+	}
+}
@@ -18,54 +18,175 @@
 //! Handles inserting of data into the database
 
 mod batch;
-pub mod listener;
+pub mod blob_store;
+mod copy;
 pub mod models;
+pub mod outbox;
 pub mod queries;
+pub mod schema;
+pub mod sink;
+pub mod wal;
 
 use std::{
 	cmp::max,
 	convert::{TryFrom, TryInto},
 	fmt,
+	sync::Arc,
 	time::Duration,
 };
 
 use codec::Encode;
+use futures::future::BoxFuture;
+use lru::LruCache;
+use parking_lot::Mutex;
 use serde::Deserialize;
 use sqlx::{
 	pool::PoolConnection,
 	postgres::{PgConnection, PgPool, PgPoolOptions, Postgres},
-	Connection,
+	Connection, Executor,
 };
 
 use sc_executor::RuntimeVersion;
 use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
+use substrate_archive_backend::{BackendError, PersistVersion};
 
 use self::batch::Batch;
-pub use self::{listener::*, models::*};
+pub use self::{
+	blob_store::{BlobStore, BlobStoreConfig},
+	models::*,
+	schema::SchemaDescription,
+	sink::Sink,
+	wal::WriteAheadLog,
+};
 use crate::{
 	error::{ArchiveError, Result},
 	types::*,
-	wasm_tracing::Traces,
+	wasm_tracing::{TraceRecord, Traces},
 };
 
-/// Run all the migrations.
-pub async fn setup<T, H>(url: T, version: RuntimeVersion, genesis: H) -> Result<PersistentConfig>
+/// Run all the migrations, in the schema of `config` if one is set.
+pub async fn setup<H>(config: &DatabaseConfig, version: RuntimeVersion, genesis: H) -> Result<PersistentConfig>
 where
-	T: AsRef<str>,
 	H: AsRef<[u8]>,
 {
-	let mut conn = PgConnection::connect(url.as_ref()).await?;
+	let mut conn = PgConnection::connect(&config.url).await?;
+	if let Some(schema) = config.schema.as_deref() {
+		conn.execute(format!("CREATE SCHEMA IF NOT EXISTS {}", quote_ident(schema)).as_str()).await?;
+		use_schema(&mut conn, schema).await?;
+	}
 
-	sqlx::migrate!("./src/migrations/").run(&mut conn).await?;
+	schema::MIGRATOR.run(&mut conn).await?;
 	let persistent_config = PersistentConfig::fetch_and_update(&mut conn, version, genesis).await?;
 
 	Ok(persistent_config)
 }
 
+/// Set the `search_path` of `conn` to `schema`, so that queries refer to the tables in it.
+pub(crate) async fn use_schema(conn: &mut PgConnection, schema: &str) -> Result<(), sqlx::Error> {
+	conn.execute(format!("SET search_path TO {}", quote_ident(schema)).as_str()).await?;
+	Ok(())
+}
+
+/// Quote an identifier, so that it may contain any character.
+fn quote_ident(ident: &str) -> String {
+	format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+// Kill connections after 3.6 seconds of idle.
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 3600;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 30_000;
+
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct DatabaseConfig {
 	/// PostgreSQL url.
 	pub url: String,
+	/// PostgreSQL url of a read-only replica.
+	/// If set, read-oriented queries are run against this database rather than the primary.
+	#[serde(default)]
+	pub read_replica_url: Option<String>,
+	/// Store for storage values that are too large to keep in PostgreSQL.
+	/// If set, values above the threshold of the store are offloaded to it,
+	/// and only a reference to the value is kept in the `storage` table.
+	#[serde(default)]
+	pub blob_store: Option<BlobStoreConfig>,
+	/// Number of storage values read by [`Database::storage_value_at`] to keep in memory.
+	/// Archived storage does not change, so repeated reads of a key at a block are served from the cache.
+	/// If `None`, every read queries PostgreSQL.
+	#[serde(default)]
+	pub storage_cache_size: Option<usize>,
+	/// Minimum number of connections kept open in each pool.
+	/// If `None`, half the number of available CPUs.
+	#[serde(default)]
+	pub min_connections: Option<u32>,
+	/// Maximum number of connections opened by each pool.
+	/// If `None`, the number of available CPUs.
+	#[serde(default)]
+	pub max_connections: Option<u32>,
+	/// Milliseconds after which idle connections above `min_connections` are closed.
+	/// If `None`, 3600 milliseconds.
+	#[serde(default)]
+	pub idle_timeout_ms: Option<u64>,
+	/// Milliseconds to wait for a connection to be established, or to become available in the pool.
+	/// If `None`, 30 seconds.
+	#[serde(default)]
+	pub connect_timeout_ms: Option<u64>,
+	/// Insert storage with `COPY ... FROM STDIN BINARY` rather than multi-row `INSERT`s.
+	/// `COPY` is a lot faster, but can't upsert. Storage that was already inserted, I.E of a block
+	/// that is executed again, fails the copy and is inserted with `INSERT ... ON CONFLICT` instead.
+	#[serde(default)]
+	pub bulk_copy: bool,
+	/// Schema the tables are created and queried in, so several archives may share a database.
+	/// Created if it does not exist. If `None`, the tables are in the `public` schema.
+	#[serde(default)]
+	pub schema: Option<String>,
+}
+
+impl DatabaseConfig {
+	pub fn new<S: Into<String>>(url: S) -> Self {
+		Self { url: url.into(), ..Default::default() }
+	}
+
+	/// Insert storage with `COPY`, see the `bulk_copy` field.
+	#[must_use]
+	pub fn bulk_copy(mut self, bulk_copy: bool) -> Self {
+		self.bulk_copy = bulk_copy;
+		self
+	}
+
+	/// Create and use the tables in the schema `name`, rather than in `public`. See the `schema` field.
+	#[must_use]
+	pub fn schema<S: Into<String>>(mut self, name: S) -> Self {
+		self.schema = Some(name.into());
+		self
+	}
+
+	/// Open a single connection to the primary database, outside of any pool, using the configured schema.
+	pub async fn connection(&self) -> Result<PgConnection> {
+		let mut conn = PgConnection::connect(&self.url).await?;
+		if let Some(schema) = self.schema.as_deref() {
+			use_schema(&mut conn, schema).await?;
+		}
+		Ok(conn)
+	}
+
+	fn pool_options(&self) -> Result<PgPoolOptions> {
+		let cpus: u32 = sa_cpus::available_cpus().try_into()?;
+		let max_connections = self.max_connections.unwrap_or(cpus);
+		let min_connections = self.min_connections.unwrap_or_else(|| max(1, cpus / 2)).min(max_connections);
+		let options = PgPoolOptions::new()
+			.min_connections(min_connections)
+			.max_connections(max_connections)
+			.idle_timeout(Duration::from_millis(self.idle_timeout_ms.unwrap_or(DEFAULT_IDLE_TIMEOUT_MS)))
+			.connect_timeout(Duration::from_millis(self.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS)));
+		// every connection of the pool uses the schema, so queries need not qualify the tables they refer to.
+		Ok(match self.schema.clone() {
+			Some(schema) => options.after_connect(move |conn| {
+				let schema = schema.clone();
+				Box::pin(async move { use_schema(conn, &schema).await })
+			}),
+			None => options,
+		})
+	}
 }
 
 impl fmt::Display for DatabaseConfig {
@@ -78,25 +199,48 @@ impl fmt::Display for DatabaseConfig {
 pub struct Database {
 	/// pool of database connections
 	pool: PgPool,
+	/// pool of connections to a read-only replica
+	read_pool: Option<PgPool>,
+	/// store for large storage values
+	blob_store: Option<BlobStore>,
+	/// cache of storage values, keyed on block number and storage key
+	storage_cache: Option<Arc<Mutex<LruCache<(u32, Vec<u8>), Option<Vec<u8>>>>>>,
+	/// whether storage is inserted with `COPY`
+	bulk_copy: bool,
 }
 
 impl Database {
 	/// Connect to the database
 	pub async fn new(url: &str) -> Result<Self> {
-		let cpus = num_cpus::get().try_into()?;
-		let pool = PgPoolOptions::new()
-			.min_connections(max(1, cpus / 2))
-			.max_connections(cpus)
-			.idle_timeout(Duration::from_millis(3600)) // kill connections after 3.6 seconds of idle
-			.connect(url)
-			.await?;
-		Ok(Self { pool })
+		let pool = Self::connect(url, &DatabaseConfig::new(url)).await?;
+		Ok(Self { pool, read_pool: None, blob_store: None, storage_cache: None, bulk_copy: false })
+	}
+
+	/// Connect to the database, and the read replica and blob store if they are configured.
+	pub async fn with_config(config: &DatabaseConfig) -> Result<Self> {
+		let pool = Self::connect(&config.url, config).await?;
+		let read_pool = match config.read_replica_url.as_ref() {
+			Some(url) => Some(Self::connect(url, config).await?),
+			None => None,
+		};
+		let blob_store = match config.blob_store.as_ref() {
+			Some(blob_config) => Some(BlobStore::new(blob_config).await?),
+			None => None,
+		};
+		let storage_cache =
+			config.storage_cache_size.filter(|size| *size > 0).map(|size| Arc::new(Mutex::new(LruCache::new(size))));
+		Ok(Self { pool, read_pool, blob_store, storage_cache, bulk_copy: config.bulk_copy })
+	}
+
+	/// Connect a pool to `url`, sized as configured by `config` and using its schema.
+	pub(crate) async fn connect(url: &str, config: &DatabaseConfig) -> Result<PgPool> {
+		Ok(config.pool_options()?.connect(url).await?)
 	}
 
 	/// Start the database with a pre-defined pool
 	#[allow(unused)]
 	pub fn with_pool(pool: PgPool) -> Self {
-		Self { pool }
+		Self { pool, read_pool: None, blob_store: None, storage_cache: None, bulk_copy: false }
 	}
 
 	pub async fn insert(&self, data: impl Insert) -> Result<u64> {
@@ -109,6 +253,46 @@ impl Database {
 		data.concurrent_insert(self.pool.clone()).await
 	}
 
+	/// Insert `storage` with `COPY`, falling back to upserting it if any of it was already inserted.
+	/// Changes of child tries are always upserted.
+	pub async fn copy_storage<H>(&self, storage: Vec<StorageModel<H>>) -> Result<u64>
+	where
+		H: Send + Sync + AsRef<[u8]> + 'static,
+	{
+		let (child, main): (Vec<_>, Vec<_>) = storage.into_iter().partition(|s| s.child().is_some());
+		let mut conn = self.pool.acquire().await?;
+		let rows = match copy::copy_storage(&mut conn, &main).await {
+			Ok(rows) => rows,
+			Err(e) if copy::is_unique_violation(&e) => {
+				log::debug!("Storage was already inserted, upserting {} changes instead", main.len());
+				drop(conn);
+				main.concurrent_insert(self.pool.clone()).await?
+			}
+			Err(e) => return Err(e),
+		};
+		Ok(rows + child.concurrent_insert(self.pool.clone()).await?)
+	}
+
+	/// Run `f` inside a single transaction.
+	/// Everything `f` writes is committed if it returns `Ok`, and rolled back if it returns an error.
+	pub async fn transaction<F, T>(&self, f: F) -> Result<T>
+	where
+		F: for<'c> FnOnce(&'c mut PgConnection) -> BoxFuture<'c, Result<T>> + Send,
+		T: Send,
+	{
+		let mut tx = self.pool.begin().await?;
+		match f(&mut *tx).await {
+			Ok(v) => {
+				tx.commit().await?;
+				Ok(v)
+			}
+			Err(e) => {
+				tx.rollback().await?;
+				Err(e)
+			}
+		}
+	}
+
 	pub async fn conn(&self) -> Result<DbConn> {
 		self.pool.acquire().await.map_err(Into::into)
 	}
@@ -116,6 +300,117 @@ impl Database {
 	pub fn pool(&self) -> &sqlx::PgPool {
 		&self.pool
 	}
+
+	/// Pool to use for read-only queries.
+	/// This is the read replica if one is configured, otherwise the primary pool.
+	pub fn read_pool(&self) -> &sqlx::PgPool {
+		self.read_pool.as_ref().unwrap_or(&self.pool)
+	}
+
+	/// Move values above the threshold of the blob store into the store,
+	/// replacing them with their reference. Does nothing if no blob store is configured.
+	pub async fn offload_storage<H>(&self, storage: &mut [StorageModel<H>]) -> Result<()> {
+		let blob_store = match self.blob_store.as_ref() {
+			Some(blob_store) => blob_store,
+			None => return Ok(()),
+		};
+		for s in storage.iter_mut().filter(|s| !s.is_offloaded()) {
+			let reference = match s.data() {
+				Some(data) if blob_store.should_offload(&data.0) => blob_store.put(&data.0).await?,
+				_ => continue,
+			};
+			s.offload(reference);
+		}
+		Ok(())
+	}
+
+	/// Get the value of a storage key at a block.
+	/// Values which were offloaded are read from the blob store.
+	/// Returns `None` if the key is not indexed at the block or was deleted.
+	pub async fn storage_value(&self, hash: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+		let row = sqlx::query_as::<_, (Option<Vec<u8>>, bool)>(
+			"SELECT storage, offloaded FROM storage WHERE hash = $1 AND key = $2 LIMIT 1",
+		)
+		.bind(hash)
+		.bind(key)
+		.fetch_optional(self.read_pool())
+		.await?;
+		self.resolve_storage(row).await
+	}
+
+	/// Get the value of a storage key as of block `block_num`,
+	/// which is the value of the last change to the key at or before that block.
+	/// Returns `None` if the key was never set or was deleted.
+	/// Values are cached if a [`storage_cache_size`](DatabaseConfig::storage_cache_size) is configured.
+	pub async fn storage_value_at(&self, block_num: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+		let cache_key = (block_num, key.to_vec());
+		if let Some(cache) = self.storage_cache.as_ref() {
+			if let Some(value) = cache.lock().get(&cache_key) {
+				return Ok(value.clone());
+			}
+		}
+		let row = sqlx::query_as::<_, (Option<Vec<u8>>, bool)>(
+			"SELECT storage, offloaded FROM storage WHERE key = $1 AND block_num <= $2 ORDER BY block_num DESC LIMIT 1",
+		)
+		.bind(key)
+		.bind(i32::try_from(block_num)?)
+		.fetch_optional(self.read_pool())
+		.await?;
+		let value = self.resolve_storage(row).await?;
+		if let Some(cache) = self.storage_cache.as_ref() {
+			cache.lock().put(cache_key, value.clone());
+		}
+		Ok(value)
+	}
+
+	async fn resolve_storage(&self, row: Option<(Option<Vec<u8>>, bool)>) -> Result<Option<Vec<u8>>> {
+		match row {
+			Some((Some(reference), true)) => {
+				let blob_store = self.blob_store.as_ref().ok_or_else(|| {
+					ArchiveError::from("Storage value was offloaded, but no blob store is configured")
+				})?;
+				Ok(Some(blob_store.get(&reference).await?))
+			}
+			Some((value, _)) => Ok(value),
+			None => Ok(None),
+		}
+	}
+}
+
+/// Persists runtime versions found by the `RuntimeVersionCache` into the `runtime_versions_cache` table.
+#[derive(Clone)]
+pub struct PersistentVersions {
+	pool: PgPool,
+}
+
+impl PersistentVersions {
+	pub fn new(pool: PgPool) -> Self {
+		Self { pool }
+	}
+}
+
+impl PersistVersion for PersistentVersions {
+	// called from the blocking threads the cache is used in.
+	fn persist(&self, code_hash: u64, block: &[u8], version: &RuntimeVersion) -> Result<(), BackendError> {
+		let spec_version = i32::try_from(version.spec_version).map_err(|e| BackendError::Msg(e.to_string()))?;
+		async_std::task::block_on(
+			sqlx::query(
+				r#"
+				INSERT INTO runtime_versions_cache (code_hash, block_hash, spec_version, version)
+				VALUES ($1, $2, $3, $4)
+				ON CONFLICT DO NOTHING
+				"#,
+			)
+			// stored as the signed bit-equivalent of the hash.
+			.bind(code_hash as i64)
+			.bind(block)
+			.bind(spec_version)
+			.bind(version.encode())
+			.execute(&self.pool),
+		)
+		.map_err(|e| BackendError::Msg(e.to_string()))?;
+		Ok(())
+	}
 }
 
 pub type DbReturn = Result<u64>;
@@ -123,7 +418,7 @@ pub type DbConn = PoolConnection<Postgres>;
 
 #[async_trait::async_trait]
 pub trait Insert: Send + Sized {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn;
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn;
 	async fn concurrent_insert(mut self, conn: PgPool) -> DbReturn {
 		self.insert(&mut conn.acquire().await?).await
 	}
@@ -135,40 +430,14 @@ where
 	B: BlockT,
 	NumberFor<B>: Into<u32>,
 {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		log::info!("Inserting single block");
 		log::trace!(
 			"block_num = {:?}, hash = {:X?}",
 			self.inner.block.header().number(),
 			hex::encode(self.inner.block.header().hash().as_ref())
 		);
-		let query = sqlx::query(
-			r#"
-            INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec) VALUES($1, $2, $3, $4, $5, $6, $7, $8)
-            ON CONFLICT DO NOTHING
-        "#,
-		);
-		let parent_hash = self.inner.block.header().parent_hash().as_ref();
-		let hash = self.inner.block.header().hash();
-		let block_num: u32 = (*self.inner.block.header().number()).into();
-		let state_root = self.inner.block.header().state_root().as_ref();
-		let extrinsics_root = self.inner.block.header().extrinsics_root().as_ref();
-		let digest = self.inner.block.header().digest().encode();
-		let extrinsics = self.inner.block.extrinsics().encode();
-
-		query
-			.bind(parent_hash)
-			.bind(hash.as_ref())
-			.bind(block_num)
-			.bind(state_root)
-			.bind(extrinsics_root)
-			.bind(digest.as_slice())
-			.bind(extrinsics.as_slice())
-			.bind(self.spec)
-			.execute(conn)
-			.await
-			.map(|d| d.rows_affected())
-			.map_err(Into::into)
+		vec![BlockModel::from(self)].insert(conn).await
 	}
 }
 
@@ -178,7 +447,14 @@ where
 	B: BlockT,
 	NumberFor<B>: Into<u32>,
 {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		self.inner.into_iter().map(BlockModel::from).collect::<Vec<_>>().insert(conn).await
+	}
+}
+
+#[async_trait::async_trait]
+impl Insert for Vec<BlockModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		let mut batch = Batch::new(
 			"blocks",
 			r#"
@@ -190,32 +466,25 @@ where
             ON CONFLICT DO NOTHING
             "#,
 		);
-		for b in self.inner {
+		for b in self {
 			batch.reserve(8)?;
 			if batch.current_num_arguments() > 0 {
 				batch.append(",");
 			}
-			let parent_hash = b.inner.block.header().parent_hash().as_ref();
-			let hash = b.inner.block.header().hash();
-			let block_num: u32 = (*b.inner.block.header().number()).into();
-			let state_root = b.inner.block.header().state_root().as_ref();
-			let extrinsics_root = b.inner.block.header().extrinsics_root().as_ref();
-			let digest = b.inner.block.header().digest().encode();
-			let extrinsics = b.inner.block.extrinsics().encode();
 			batch.append("(");
-			batch.bind(parent_hash)?;
+			batch.bind(b.parent_hash)?;
 			batch.append(",");
-			batch.bind(hash.as_ref())?;
+			batch.bind(b.hash)?;
 			batch.append(",");
-			batch.bind(block_num)?;
+			batch.bind(b.block_num)?;
 			batch.append(",");
-			batch.bind(state_root)?;
+			batch.bind(b.state_root)?;
 			batch.append(",");
-			batch.bind(extrinsics_root)?;
+			batch.bind(b.extrinsics_root)?;
 			batch.append(",");
-			batch.bind(digest.as_slice())?;
+			batch.bind(b.digest)?;
 			batch.append(",");
-			batch.bind(extrinsics.as_slice())?;
+			batch.bind(b.ext)?;
 			batch.append(",");
 			batch.bind(b.spec)?;
 			batch.append(")");
@@ -229,18 +498,19 @@ impl<Hash> Insert for StorageModel<Hash>
 where
 	Hash: Send + Sync + AsRef<[u8]> + 'static,
 {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		log::info!("Inserting Single Storage");
 		sqlx::query(
 			r#"
                 INSERT INTO storage (
-                    block_num, hash, is_full, key, storage
-                ) VALUES ($1, $2, $3, $4, $5)
+                    block_num, hash, is_full, key, storage, offloaded
+                ) VALUES ($1, $2, $3, $4, $5, $6)
                 ON CONFLICT (hash, key, md5(storage)) DO UPDATE SET
                     hash = EXCLUDED.hash,
                     key = EXCLUDED.key,
                     storage = EXCLUDED.storage,
-                    is_full = EXCLUDED.is_full
+                    is_full = EXCLUDED.is_full,
+                    offloaded = EXCLUDED.offloaded
             "#,
 		)
 		.bind(self.block_num())
@@ -248,6 +518,7 @@ where
 		.bind(self.is_full())
 		.bind(self.key().0.as_slice())
 		.bind(self.data().map(|d| d.0.as_slice()))
+		.bind(self.is_offloaded())
 		.execute(conn)
 		.await
 		.map(|d| d.rows_affected())
@@ -255,12 +526,56 @@ where
 	}
 }
 
+fn build_child_storage_batch<H: AsRef<[u8]>>(storage: Vec<StorageModel<H>>) -> Result<Batch> {
+	let mut batch = Batch::new(
+		"child_storage",
+		r#"
+		INSERT INTO "child_storage" (
+			block_num, hash, is_full, child_key, key, storage, offloaded
+		) VALUES
+		"#,
+		r#"
+		ON CONFLICT (hash, child_key, key, md5(storage)) DO UPDATE SET
+			storage = EXCLUDED.storage,
+			is_full = EXCLUDED.is_full,
+			offloaded = EXCLUDED.offloaded
+		"#,
+	);
+
+	for s in storage {
+		let child = match s.child() {
+			Some(child) => child.0.as_slice(),
+			None => continue,
+		};
+		batch.reserve(7)?;
+		if batch.current_num_arguments() > 0 {
+			batch.append(",");
+		}
+		batch.append("(");
+		batch.bind(s.block_num())?;
+		batch.append(",");
+		batch.bind(s.hash().as_ref())?;
+		batch.append(",");
+		batch.bind(s.is_full())?;
+		batch.append(",");
+		batch.bind(child)?;
+		batch.append(",");
+		batch.bind(s.key().0.as_slice())?;
+		batch.append(",");
+		batch.bind(s.data().map(|d| d.0.as_slice()))?;
+		batch.append(",");
+		batch.bind(s.is_offloaded())?;
+		batch.append(")");
+	}
+	Ok(batch)
+}
+
 fn build_storage_batch<H: AsRef<[u8]>>(storage: Vec<StorageModel<H>>) -> Result<Batch> {
 	let mut batch = Batch::new(
 		"storage",
 		r#"
         INSERT INTO "storage" (
-            block_num, hash, is_full, key, storage
+            block_num, hash, is_full, key, storage, offloaded
         ) VALUES
         "#,
 		r#"
@@ -268,12 +583,13 @@ fn build_storage_batch<H: AsRef<[u8]>>(storage: Vec<StorageModel<H>>) -> Result<
             hash = EXCLUDED.hash,
             key = EXCLUDED.key,
             storage = EXCLUDED.storage,
-            is_full = EXCLUDED.is_full
+            is_full = EXCLUDED.is_full,
+            offloaded = EXCLUDED.offloaded
         "#,
 	);
 
 	for s in storage {
-		batch.reserve(5)?;
+		batch.reserve(6)?;
 		if batch.current_num_arguments() > 0 {
 			batch.append(",");
 		}
@@ -287,6 +603,8 @@ fn build_storage_batch<H: AsRef<[u8]>>(storage: Vec<StorageModel<H>>) -> Result<
 		batch.bind(s.key().0.as_slice())?;
 		batch.append(",");
 		batch.bind(s.data().map(|d| d.0.as_slice()))?;
+		batch.append(",");
+		batch.bind(s.is_offloaded())?;
 		batch.append(")");
 	}
 	Ok(batch)
@@ -297,20 +615,22 @@ impl<Hash> Insert for Vec<StorageModel<Hash>>
 where
 	Hash: Send + Sync + AsRef<[u8]> + 'static,
 {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
-		let batch = build_storage_batch(self)?;
-		Ok(batch.execute(conn).await?)
+	async fn insert(self, conn: &mut PgConnection) -> DbReturn {
+		let (child, main): (Vec<_>, Vec<_>) = self.into_iter().partition(|s| s.child().is_some());
+		let rows = build_storage_batch(main)?.execute(conn).await?;
+		Ok(rows + build_child_storage_batch(child)?.execute(conn).await?)
 	}
 
-	async fn concurrent_insert(mut self, conn: PgPool) -> DbReturn {
-		let batch = build_storage_batch(self)?;
-		batch.execute_concurrent(conn, None).await
+	async fn concurrent_insert(self, conn: PgPool) -> DbReturn {
+		let (child, main): (Vec<_>, Vec<_>) = self.into_iter().partition(|s| s.child().is_some());
+		let rows = build_storage_batch(main)?.execute_concurrent(conn.clone(), None).await?;
+		Ok(rows + build_child_storage_batch(child)?.execute_concurrent(conn, None).await?)
 	}
 }
 
 #[async_trait::async_trait]
 impl Insert for Metadata {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		log::debug!("Inserting Metadata, version = {}", self.version());
 		sqlx::query(
 			r#"
@@ -328,15 +648,155 @@ impl Insert for Metadata {
 	}
 }
 
+#[async_trait::async_trait]
+impl<H: AsRef<[u8]> + Send> Insert for UnexecutableBlock<H> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		sqlx::query(
+			r#"
+			INSERT INTO unexecutable_blocks (hash, block_num, reason)
+			VALUES($1, $2, $3)
+			ON CONFLICT DO NOTHING
+		"#,
+		)
+		.bind(self.hash.as_ref())
+		.bind(i32::try_from(self.block_num)?)
+		.bind(self.reason)
+		.execute(conn)
+		.await
+		.map(|d| d.rows_affected())
+		.map_err(Into::into)
+	}
+}
+
+#[async_trait::async_trait]
+impl Insert for Vec<UndecodableBlock> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		let mut batch = Batch::new(
+			"undecodable_extrinsics",
+			r#"
+			INSERT INTO "undecodable_extrinsics" (hash, block_num, spec, reason) VALUES
+			"#,
+			r#"
+			ON CONFLICT DO NOTHING
+			"#,
+		);
+
+		for block in self.into_iter() {
+			batch.reserve(4)?;
+			if batch.current_num_arguments() > 0 {
+				batch.append(",");
+			}
+			batch.append("(");
+			batch.bind(block.hash)?;
+			batch.append(",");
+			batch.bind(i32::try_from(block.block_num)?)?;
+			batch.append(",");
+			batch.bind(i32::try_from(block.spec)?)?;
+			batch.append(",");
+			batch.bind(block.reason)?;
+			batch.append(")");
+		}
+		Ok(batch.execute(conn).await?)
+	}
+}
+
 #[async_trait::async_trait]
 impl Insert for Traces {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		log::debug!("Inserting Trace Data");
+		let records = self.records()?;
+		let mut batch = trace_batch();
+		for record in &records {
+			bind_trace(&mut batch, record)?;
+		}
+		// the batch is inserted in a transaction, so that no trace is inserted twice if it is retried.
+		let mut tx = conn.begin().await?;
+		match batch.execute(&mut *tx).await {
+			Ok(rows) => {
+				tx.commit().await?;
+				return Ok(rows);
+			}
+			Err(e) => {
+				tx.rollback().await?;
+				log::warn!(
+					"Failed to insert the traces of block {}, inserting them one by one: {}",
+					self.block_num(),
+					e
+				);
+			}
+		}
+		// a trace which can't be inserted on its own is dropped, rather than every trace of the block.
+		let mut rows = 0;
+		for record in &records {
+			let mut batch = trace_batch();
+			bind_trace(&mut batch, record)?;
+			match batch.execute(conn).await {
+				Ok(inserted) => rows += inserted,
+				Err(e) => log::warn!("Dropping trace `{}` of block {}: {}", record.name, record.block_num, e),
+			}
+		}
+		Ok(rows)
+	}
+}
+
+fn trace_batch() -> Batch {
+	Batch::new(
+		"state_tracing",
+		r#"
+		INSERT INTO "state_traces" (
+			block_num, hash, is_event, timestamp, duration, file, line, trace_id, trace_parent_id, follows_from,
+			target, name, traces
+		) VALUES
+		"#,
+		r#"
+		ON CONFLICT DO NOTHING
+		"#,
+	)
+}
+
+fn bind_trace(batch: &mut Batch, record: &TraceRecord<'_>) -> Result<()> {
+	batch.reserve(13)?;
+	if batch.current_num_arguments() > 0 {
+		batch.append(",");
+	}
+	batch.append("(");
+	batch.bind(record.block_num)?; // block number
+	batch.append(",");
+	batch.bind(record.hash)?; // hash
+	batch.append(",");
+	batch.bind(record.is_event)?; // is_event
+	batch.append(",");
+	batch.bind(record.timestamp)?; // timestamp
+	batch.append(",");
+	batch.bind(record.duration)?; // duration
+	batch.append(",");
+	batch.bind(record.file)?; // file
+	batch.append(",");
+	batch.bind(record.line)?; // line
+	batch.append(",");
+	batch.bind(record.trace_id)?; // trace_id
+	batch.append(",");
+	batch.bind(record.trace_parent_id)?; // trace_parent_id
+	batch.append(",");
+	batch.bind(record.follows_from.clone())?; // follows_from
+	batch.append(",");
+	batch.bind(record.target)?; // target
+	batch.append(",");
+	batch.bind(record.name)?; // name
+	batch.append(",");
+	batch.bind(sqlx::types::Json(record.traces))?; // traces
+	batch.append(")");
+	Ok(())
+}
+
+#[async_trait::async_trait]
+impl Insert for Vec<ExtrinsicsModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		let mut batch = Batch::new(
-			"state_tracing",
+			"extrinsic",
 			r#"
-			INSERT INTO "state_traces" (
-				block_num, hash, is_event, timestamp, duration, file, line, trace_id, trace_parent_id, target, name, traces
+			INSERT INTO "extrinsics" (
+				hash, number, extrinsics
 			) VALUES
 			"#,
 			r#"
@@ -344,87 +804,153 @@ impl Insert for Traces {
 			"#,
 		);
 
-		for span in self.spans.iter() {
-			let id = i32::try_from(span.id.into_u64())?;
-			let parent_id: Option<i32> =
-				if let Some(id) = &span.parent_id { Some(i32::try_from(id.into_u64())?) } else { None };
-			let overall_time: i64 = time_to_std(span.overall_time)?.as_nanos().try_into()?;
-			batch.reserve(12)?;
+		for extrinsic in self.into_iter() {
+			batch.reserve(3)?;
 			if batch.current_num_arguments() > 0 {
 				batch.append(",");
 			}
 			batch.append("(");
-			batch.bind(self.block_num())?; // block_numk
-			batch.append(",");
-			batch.bind(self.hash())?; // hash
+			batch.bind(extrinsic.hash)?;
 			batch.append(",");
-			batch.bind(false)?; // is_event
+			batch.bind(extrinsic.number)?;
 			batch.append(",");
-			batch.bind(span.start_time)?; // timestamp
+			batch.bind(extrinsic.extrinsics)?;
+			batch.append(")");
+		}
+		Ok(batch.execute(conn).await?)
+	}
+}
+
+#[async_trait::async_trait]
+impl Insert for Vec<ExtrinsicCallModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		let mut batch = Batch::new(
+			"extrinsic_calls",
+			r#"
+			INSERT INTO "extrinsic_calls" (
+				hash, block_num, extrinsic_index, call_index, parent_index, depth, module, call, signed
+			) VALUES
+			"#,
+			r#"
+			ON CONFLICT DO NOTHING
+			"#,
+		);
+
+		for call in self.into_iter() {
+			batch.reserve(9)?;
+			if batch.current_num_arguments() > 0 {
+				batch.append(",");
+			}
+			batch.append("(");
+			batch.bind(call.hash)?;
 			batch.append(",");
-			batch.bind(overall_time)?; // duration
+			batch.bind(call.block_num)?;
 			batch.append(",");
-			batch.bind(span.file.as_ref())?; // file
+			batch.bind(call.extrinsic_index)?;
 			batch.append(",");
-			batch.bind(span.line)?; // line
+			batch.bind(call.call_index)?;
 			batch.append(",");
-			batch.bind(id)?; // trace_id
+			batch.bind(call.parent_index)?;
 			batch.append(",");
-			batch.bind(parent_id)?; // trace_parent_id
+			batch.bind(call.depth)?;
 			batch.append(",");
-			batch.bind(&span.target)?; // target
+			batch.bind(call.module)?;
 			batch.append(",");
-			batch.bind(&span.name)?; // name
+			batch.bind(call.call)?;
 			batch.append(",");
-			batch.bind(sqlx::types::Json(&span.values))?; // traces
+			batch.bind(call.signed)?;
 			batch.append(")");
 		}
+		Ok(batch.execute(conn).await?)
+	}
+}
 
-		for event in self.events.iter() {
-			let parent_id = event.parent_id.as_ref().map(|id| i32::try_from(id.into_u64())).transpose()?;
-			batch.reserve(12)?;
+#[async_trait::async_trait]
+impl Insert for Vec<RuntimeUpgradeModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		let mut batch = Batch::new(
+			"runtime_upgrade_events",
+			r#"
+			INSERT INTO "runtime_upgrade_events" (
+				hash, block_num, call, code_hash, code_size
+			) VALUES
+			"#,
+			r#"
+			ON CONFLICT DO NOTHING
+			"#,
+		);
+
+		for upgrade in self.into_iter() {
+			batch.reserve(5)?;
 			if batch.current_num_arguments() > 0 {
 				batch.append(",");
 			}
 			batch.append("(");
-			batch.bind(self.block_num())?; // block number
+			batch.bind(upgrade.hash)?;
 			batch.append(",");
-			batch.bind(self.hash())?; // hash
+			batch.bind(upgrade.block_num)?;
 			batch.append(",");
-			batch.bind(true)?; // is_event
+			batch.bind(upgrade.call)?;
 			batch.append(",");
-			batch.bind(event.time)?; //time
+			batch.bind(upgrade.code_hash)?;
 			batch.append(",");
-			batch.bind(Option::<i64>::None)?; // an event won't have a duration
+			batch.bind(upgrade.code_size)?;
+			batch.append(")");
+		}
+		Ok(batch.execute(conn).await?)
+	}
+}
+
+#[async_trait::async_trait]
+impl Insert for Vec<EventModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+		let mut batch = Batch::new(
+			"events",
+			r#"
+			INSERT INTO "events" (
+				hash, block_num, idx, phase, extrinsic_idx, module, event, params
+			) VALUES
+			"#,
+			r#"
+			ON CONFLICT DO NOTHING
+			"#,
+		);
+
+		for event in self.into_iter() {
+			batch.reserve(8)?;
+			if batch.current_num_arguments() > 0 {
+				batch.append(",");
+			}
+			batch.append("(");
+			batch.bind(event.hash)?;
 			batch.append(",");
-			batch.bind(&event.file)?; // file
+			batch.bind(event.block_num)?;
 			batch.append(",");
-			batch.bind(event.line)?; // line
+			batch.bind(event.idx)?;
 			batch.append(",");
-			batch.bind(Option::<i32>::None)?; // Event has no ID
+			batch.bind(event.phase)?;
 			batch.append(",");
-			batch.bind(parent_id)?; // parent ikd
+			batch.bind(event.extrinsic_idx)?;
 			batch.append(",");
-			batch.bind(&event.target)?; // target
+			batch.bind(event.module)?;
 			batch.append(",");
-			batch.bind(&event.name)?; // name
+			batch.bind(event.event)?;
 			batch.append(",");
-			batch.bind(sqlx::types::Json(&event.values))?; // values
+			batch.bind(event.params)?;
 			batch.append(")");
 		}
-
 		Ok(batch.execute(conn).await?)
 	}
 }
 
 #[async_trait::async_trait]
-impl Insert for Vec<ExtrinsicsModel> {
-	async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+impl Insert for Vec<DigestItemModel> {
+	async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
 		let mut batch = Batch::new(
-			"extrinsic",
+			"digest_items",
 			r#"
-			INSERT INTO "extrinsics" (
-				hash, number, extrinsics
+			INSERT INTO "digest_items" (
+				hash, block_num, idx, kind, engine, payload, data
 			) VALUES
 			"#,
 			r#"
@@ -432,26 +958,27 @@ impl Insert for Vec<ExtrinsicsModel> {
 			"#,
 		);
 
-		for extrinsic in self.into_iter() {
-			batch.reserve(3)?;
+		for item in self.into_iter() {
+			batch.reserve(7)?;
 			if batch.current_num_arguments() > 0 {
 				batch.append(",");
 			}
 			batch.append("(");
-			batch.bind(extrinsic.hash)?;
+			batch.bind(item.hash)?;
 			batch.append(",");
-			batch.bind(extrinsic.number)?;
+			batch.bind(item.block_num)?;
 			batch.append(",");
-			batch.bind(extrinsic.extrinsics)?;
+			batch.bind(item.idx)?;
+			batch.append(",");
+			batch.bind(item.kind)?;
+			batch.append(",");
+			batch.bind(item.engine)?;
+			batch.append(",");
+			batch.bind(item.payload)?;
+			batch.append(",");
+			batch.bind(item.data)?;
 			batch.append(")");
 		}
 		Ok(batch.execute(conn).await?)
 	}
 }
-
-// Chrono depends on an error type in `time` that is a full version behind the one that SQLX uses
-// This function avoids depending on two time lib.
-// Old time is disabled in chrono by not providing the feature flag in Cargo.toml.
-fn time_to_std(time: chrono::Duration) -> Result<Duration> {
-	time.to_std().map_err(|_| ArchiveError::TimestampOutOfRange)
-}
@@ -0,0 +1,84 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage of very large values (I.E `:code`) outside of PostgreSQL.
+//! Values are content-addressed by their Blake2-256 hash, which is the reference kept in the `storage` table.
+
+use std::{io, path::PathBuf};
+
+use async_std::fs;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlobStoreConfig {
+	/// Directory blobs are written to.
+	pub path: PathBuf,
+	/// Values larger than this amount of bytes are offloaded to the blob store.
+	/// default: 1 MiB
+	#[serde(default = "default_threshold")]
+	pub threshold: usize,
+}
+
+impl BlobStoreConfig {
+	pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+		Self { path: path.into(), threshold: default_threshold() }
+	}
+}
+
+const fn default_threshold() -> usize {
+	1024 * 1024
+}
+
+/// Blob store on the local filesystem.
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+	path: PathBuf,
+	threshold: usize,
+}
+
+impl BlobStore {
+	pub async fn new(config: &BlobStoreConfig) -> io::Result<Self> {
+		fs::create_dir_all(&config.path).await?;
+		Ok(Self { path: config.path.clone(), threshold: config.threshold })
+	}
+
+	/// Whether `value` is large enough to be offloaded.
+	pub fn should_offload(&self, value: &[u8]) -> bool {
+		value.len() > self.threshold
+	}
+
+	/// Write `value` to the store, returning the reference to read it back with.
+	pub async fn put(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+		let reference = sp_core::hashing::blake2_256(value).to_vec();
+		let path = self.blob_path(&reference);
+		if !path.exists().await {
+			// write to a temporary file first, so a partially written blob is never read.
+			let tmp = path.with_extension("tmp");
+			fs::write(&tmp, value).await?;
+			fs::rename(&tmp, &path).await?;
+		}
+		Ok(reference)
+	}
+
+	/// Read the value identified by `reference`.
+	pub async fn get(&self, reference: &[u8]) -> io::Result<Vec<u8>> {
+		fs::read(self.blob_path(reference)).await
+	}
+
+	fn blob_path(&self, reference: &[u8]) -> async_std::path::PathBuf {
+		self.path.join(hex::encode(reference)).into()
+	}
+}
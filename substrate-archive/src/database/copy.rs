@@ -0,0 +1,117 @@
+// Copyright 2018-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bulk insertion of storage with `COPY ... FROM STDIN BINARY`.
+//!
+//! `COPY` streams rows without building a statement with parameters for each of them,
+//! which makes it a lot faster than the multi-row `INSERT`s of [`Batch`](super::batch::Batch).
+//! However, `COPY` can't upsert: a single row conflicting with an existing one fails the whole copy.
+
+use std::convert::TryFrom;
+
+use sqlx::postgres::PgConnection;
+
+use super::models::StorageModel;
+use crate::error::{ArchiveError, Result};
+
+const STORAGE_COPY: &str =
+	"COPY storage (block_num, hash, is_full, key, storage, offloaded) FROM STDIN WITH (FORMAT binary)";
+// Signature, flags and header extension length of the binary format.
+const HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+const TRAILER: &[u8] = &(-1_i16).to_be_bytes();
+// Number of columns of every row in `STORAGE_COPY`.
+const COLUMNS: i16 = 6;
+// Flush the buffer to Postgres once it holds this many bytes.
+const FLUSH_SIZE: usize = 4 * 1024 * 1024;
+// SQLSTATE of a violated unique constraint.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Insert `storage` into the `storage` table with `COPY`, returning the number of rows copied.
+/// Nothing is inserted if any of the rows already exists.
+pub(crate) async fn copy_storage<H: AsRef<[u8]>>(conn: &mut PgConnection, storage: &[StorageModel<H>]) -> Result<u64> {
+	if storage.is_empty() {
+		return Ok(0);
+	}
+	let mut copy = conn.copy_in_raw(STORAGE_COPY).await?;
+	let mut buf = HEADER.to_vec();
+	for s in storage {
+		encode_row(s, &mut buf)?;
+		if buf.len() >= FLUSH_SIZE {
+			copy.send(std::mem::take(&mut buf)).await?;
+		}
+	}
+	buf.extend_from_slice(TRAILER);
+	copy.send(buf).await?;
+	Ok(copy.finish().await?)
+}
+
+/// Whether `err` is a copy which failed because a row already exists.
+pub(crate) fn is_unique_violation(err: &ArchiveError) -> bool {
+	matches!(err, ArchiveError::Sql(sqlx::Error::Database(e)) if e.code().as_deref() == Some(UNIQUE_VIOLATION))
+}
+
+fn encode_row<H: AsRef<[u8]>>(s: &StorageModel<H>, buf: &mut Vec<u8>) -> Result<()> {
+	buf.extend_from_slice(&COLUMNS.to_be_bytes());
+	encode_field(Some(&i32::try_from(s.block_num())?.to_be_bytes()), buf)?;
+	encode_field(Some(s.hash().as_ref()), buf)?;
+	encode_field(Some(&[u8::from(s.is_full())]), buf)?;
+	encode_field(Some(s.key().0.as_slice()), buf)?;
+	encode_field(s.data().map(|d| d.0.as_slice()), buf)?;
+	encode_field(Some(&[u8::from(s.is_offloaded())]), buf)
+}
+
+/// Encode a field as its length followed by its bytes. `NULL` has a length of -1 and no bytes.
+fn encode_field(field: Option<&[u8]>, buf: &mut Vec<u8>) -> Result<()> {
+	match field {
+		Some(bytes) => {
+			buf.extend_from_slice(&i32::try_from(bytes.len())?.to_be_bytes());
+			buf.extend_from_slice(bytes);
+		}
+		None => buf.extend_from_slice(&(-1_i32).to_be_bytes()),
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_storage::{StorageData, StorageKey};
+
+	#[test]
+	fn should_encode_binary_rows() {
+		let storage =
+			StorageModel::new([0xAA; 2], 7, false, StorageKey(vec![0x01]), Some(StorageData(vec![0x02, 0x03])));
+		let mut buf = Vec::new();
+		encode_row(&storage, &mut buf).unwrap();
+		#[rustfmt::skip]
+		let expected = vec![
+			0, 6,
+			0, 0, 0, 4, 0, 0, 0, 7,
+			0, 0, 0, 2, 0xAA, 0xAA,
+			0, 0, 0, 1, 0,
+			0, 0, 0, 1, 0x01,
+			0, 0, 0, 2, 0x02, 0x03,
+			0, 0, 0, 1, 0,
+		];
+		assert_eq!(buf, expected);
+
+		let deleted = StorageModel::new([0xAA; 2], 7, true, StorageKey(vec![0x01]), None);
+		buf.clear();
+		encode_row(&deleted, &mut buf).unwrap();
+		assert_eq!(&buf[16..21], &[0, 0, 0, 1, 1]);
+		assert_eq!(&buf[26..30], &[0xFF; 4]);
+	}
+}
@@ -18,9 +18,12 @@
 //! Only some types implemented, for convenience most types are already in their database model
 //! equivalents
 
-use std::{convert::TryInto, marker::PhantomData};
+use std::{
+	convert::{TryFrom, TryInto},
+	marker::PhantomData,
+};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use codec::{Decode, Encode, Error as DecodeError};
 use serde::{Deserialize, Serialize};
 use sqlx::{types::Json, FromRow, PgConnection, Postgres};
@@ -29,7 +32,8 @@ use desub::{types::LegacyOrCurrentExtrinsic, Chain};
 use sc_executor::RuntimeVersion;
 use sp_runtime::{
 	generic::SignedBlock,
-	traits::{Block as BlockT, Header as HeaderT},
+	traits::{Block as BlockT, Header as HeaderT, NumberFor},
+	ConsensusEngineId,
 };
 use sp_storage::{StorageData, StorageKey};
 
@@ -68,6 +72,29 @@ impl BlockModel {
 	}
 }
 
+impl<B> From<Block<B>> for BlockModel
+where
+	B: BlockT,
+	NumberFor<B>: Into<u32>,
+{
+	/// The `id` is assigned by the database on insert, and is left as `0`.
+	fn from(block: Block<B>) -> Self {
+		let header = block.inner.block.header();
+		let block_num: u32 = (*header.number()).into();
+		Self {
+			id: 0,
+			parent_hash: header.parent_hash().as_ref().to_vec(),
+			hash: header.hash().as_ref().to_vec(),
+			block_num: block_num as i32,
+			state_root: header.state_root().as_ref().to_vec(),
+			extrinsics_root: header.extrinsics_root().as_ref().to_vec(),
+			digest: header.digest().encode(),
+			ext: block.inner.block.extrinsics().encode(),
+			spec: block.spec as i32,
+		}
+	}
+}
+
 /// Helper struct for decoding block modeling data into block type.
 pub struct BlockModelDecoder<B: BlockT> {
 	_marker: PhantomData<B>,
@@ -94,11 +121,37 @@ pub struct StorageModel<Hash> {
 	full_storage: bool,
 	key: StorageKey,
 	data: Option<StorageData>,
+	/// Whether `data` is a reference to the value in the blob store, rather than the value itself.
+	#[serde(default)]
+	offloaded: bool,
+	/// Storage key of the child trie the key belongs to, if it is not in the main trie.
+	#[serde(default)]
+	child: Option<StorageKey>,
 }
 
 impl<Hash> StorageModel<Hash> {
 	pub fn new(hash: Hash, block_num: u32, full_storage: bool, key: StorageKey, data: Option<StorageData>) -> Self {
-		Self { hash, block_num, full_storage, key, data }
+		Self { hash, block_num, full_storage, key, data, offloaded: false, child: None }
+	}
+
+	/// Move the key into the child trie with the storage key `child`.
+	pub fn in_child(mut self, child: StorageKey) -> Self {
+		self.child = Some(child);
+		self
+	}
+
+	pub fn child(&self) -> Option<&StorageKey> {
+		self.child.as_ref()
+	}
+
+	/// Replace the value with its reference in the blob store.
+	pub fn offload(&mut self, reference: Vec<u8>) {
+		self.data = Some(StorageData(reference));
+		self.offloaded = true;
+	}
+
+	pub fn is_offloaded(&self) -> bool {
+		self.offloaded
 	}
 
 	pub fn is_full(&self) -> bool {
@@ -120,6 +173,22 @@ impl<Hash> StorageModel<Hash> {
 	pub fn data(&self) -> Option<&StorageData> {
 		self.data.as_ref()
 	}
+
+	/// Replace the hash of the block with its bytes.
+	pub fn into_bytes_hash(self) -> StorageModel<Vec<u8>>
+	where
+		Hash: AsRef<[u8]>,
+	{
+		StorageModel {
+			hash: self.hash.as_ref().to_vec(),
+			block_num: self.block_num,
+			full_storage: self.full_storage,
+			key: self.key,
+			data: self.data,
+			offloaded: self.offloaded,
+			child: self.child,
+		}
+	}
 }
 
 impl<Hash: Copy> From<Storage<Hash>> for Vec<StorageModel<Hash>> {
@@ -127,10 +196,16 @@ impl<Hash: Copy> From<Storage<Hash>> for Vec<StorageModel<Hash>> {
 		let hash = *original.hash();
 		let block_num = original.block_num();
 		let full_storage = original.is_full();
+		let child_changes = original.child_changes.into_iter().flat_map(|(child, changes)| {
+			changes.into_iter().map(move |(key, data)| {
+				StorageModel::new(hash, block_num, full_storage, key, data).in_child(child.clone())
+			})
+		});
 		original
 			.changes
 			.into_iter()
 			.map(|changes| StorageModel::new(hash, block_num, full_storage, changes.0, changes.1))
+			.chain(child_changes)
 			.collect::<Vec<StorageModel<Hash>>>()
 	}
 }
@@ -146,14 +221,607 @@ pub struct ExtrinsicsModel {
 	pub id: Option<i32>,
 	pub hash: Vec<u8>,
 	pub number: i32,
-	pub extrinsics: Json<Vec<LegacyOrCurrentExtrinsic>>,
+	/// JSON of the decoded extrinsics.
+	pub extrinsics: Json<Vec<serde_json::Value>>,
 }
 
 impl ExtrinsicsModel {
 	pub fn new(hash: Vec<u8>, number: u32, extrinsics: Vec<LegacyOrCurrentExtrinsic>) -> Result<Self> {
 		let number = number.try_into()?;
+		let extrinsics = extrinsics.iter().map(serde_json::to_value).collect::<Result<_, _>>()?;
 		Ok(Self { id: None, hash, number, extrinsics: Json(extrinsics) })
 	}
+
+	/// Decode the calls wrapped by `Proxy`, `Multisig`, `Sudo` and `Utility` calls which were left encoded,
+	/// replacing the encoded `call` (or each of the encoded `calls`) with the JSON returned by `decode`.
+	/// The other arguments of the wrapper (I.E the `real` account of a proxy) are kept.
+	pub fn nest_wrapped_calls(&mut self, decode: impl Fn(&[u8]) -> Option<serde_json::Value>) {
+		for extrinsic in self.extrinsics.0.iter_mut() {
+			nest_wrapped_calls(extrinsic, &decode);
+		}
+	}
+
+	/// Render the accounts and balances among the call arguments and signatures of these extrinsics
+	/// so they can be queried directly, I.E with `extrinsics->0->'call'->'args'`:
+	/// accounts as SS58 addresses of the chain with `ss58_prefix`, and balances as decimal strings.
+	/// Arguments are recognized by their name (see [`ACCOUNT_ARGS`] and [`BALANCE_ARGS`]).
+	/// Accounts which are not 32 bytes long are left as they are.
+	pub fn format_params(&mut self, ss58_prefix: u16) {
+		for extrinsic in self.extrinsics.0.iter_mut() {
+			format_params(extrinsic, ss58_prefix);
+		}
+	}
+
+	/// Extract any `set_code`/`set_code_without_checks` calls contained in these extrinsics.
+	pub fn runtime_upgrades(&self) -> Result<Vec<RuntimeUpgradeModel>> {
+		let extrinsics = serde_json::to_value(&self.extrinsics.0)?;
+		RuntimeUpgradeModel::from_json(&self.hash, self.number, &extrinsics)
+	}
+
+	/// The pallet and call of each call of these extrinsics, as indexed into the `extrinsic_calls` table.
+	/// Along with the outermost call, the calls dispatched by wrapper calls (I.E by `Utility::batch`)
+	/// which were decoded with [`Self::nest_wrapped_calls`] are indexed, each linked to its wrapper.
+	/// Extrinsics whose call can not be found are left out.
+	pub fn calls(&self) -> Vec<ExtrinsicCallModel> {
+		let mut models = Vec::new();
+		for (index, extrinsic) in self.extrinsics.0.iter().enumerate() {
+			let extrinsic_index = match i32::try_from(index) {
+				Ok(index) => index,
+				Err(_) => continue,
+			};
+			let signed = extrinsic.get("signature").map_or(false, |s| !s.is_null());
+			let mut calls = Vec::new();
+			find_calls(extrinsic, None, 0, &mut calls);
+			models.extend(calls.into_iter().enumerate().filter_map(|(call_index, (module, call, parent, depth))| {
+				Some(ExtrinsicCallModel {
+					hash: self.hash.clone(),
+					block_num: self.number,
+					extrinsic_index,
+					call_index: i32::try_from(call_index).ok()?,
+					parent_index: parent.map(i32::try_from).transpose().ok()?,
+					depth: i32::try_from(depth).ok()?,
+					module,
+					call,
+					signed,
+				})
+			}));
+		}
+		models
+	}
+
+	/// The time set by the `Timestamp::set` inherent of these extrinsics, if any.
+	pub fn timestamp(&self) -> Result<Option<DateTime<Utc>>> {
+		let extrinsics = serde_json::to_value(&self.extrinsics.0)?;
+		Ok(find_timestamp(&extrinsics)
+			.and_then(|millis| i64::try_from(millis).ok())
+			.and_then(|millis| Utc.timestamp_millis_opt(millis).single()))
+	}
+}
+
+/// The pallet and call of a single call of an extrinsic. Names are lowercase, I.E `balances` and `transfer`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct ExtrinsicCallModel {
+	/// Hash of the block the extrinsic is contained in.
+	pub hash: Vec<u8>,
+	pub block_num: i32,
+	/// Index of the extrinsic within its block.
+	pub extrinsic_index: i32,
+	/// Index of the call within its extrinsic, in the order calls appear. The outermost call is 0.
+	pub call_index: i32,
+	/// `call_index` of the wrapper call dispatching this call, if any.
+	pub parent_index: Option<i32>,
+	/// Number of wrapper calls around this call.
+	pub depth: i32,
+	pub module: String,
+	pub call: String,
+	/// Whether the extrinsic is signed, rather than an inherent or unsigned transaction.
+	pub signed: bool,
+}
+
+/// Names of the `System` calls which replace the runtime code.
+const SET_CODE_CALLS: [&str; 2] = ["set_code", "set_code_without_checks"];
+
+/// A runtime upgrade which was dispatched through a `set_code` extrinsic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct RuntimeUpgradeModel {
+	pub id: Option<i32>,
+	/// Hash of the block the upgrade extrinsic is contained in.
+	pub hash: Vec<u8>,
+	pub block_num: i32,
+	/// Name of the call which set the code.
+	pub call: String,
+	/// Blake2-256 hash of the new runtime code.
+	pub code_hash: Vec<u8>,
+	/// Size of the new runtime code in bytes.
+	pub code_size: i32,
+}
+
+impl RuntimeUpgradeModel {
+	pub fn new(hash: Vec<u8>, block_num: i32, call: String, code: &[u8]) -> Result<Self> {
+		let code_size = code.len().try_into()?;
+		let code_hash = sp_core::hashing::blake2_256(code).to_vec();
+		Ok(Self { id: None, hash, block_num, call, code_hash, code_size })
+	}
+
+	/// Search the JSON representation of decoded extrinsics for calls which set the runtime code.
+	/// Calls nested inside of other calls (I.E `Sudo::sudo`) are found as well.
+	pub fn from_json(hash: &[u8], block_num: i32, extrinsics: &serde_json::Value) -> Result<Vec<Self>> {
+		let mut upgrades = Vec::new();
+		for (call, code) in find_set_code(extrinsics) {
+			upgrades.push(Self::new(hash.to_vec(), block_num, call, code.as_slice())?);
+		}
+		Ok(upgrades)
+	}
+}
+
+/// Recursively walk a JSON value, returning the call name and code argument of every `set_code` call.
+fn find_set_code(value: &serde_json::Value) -> Vec<(String, Vec<u8>)> {
+	use serde_json::Value;
+	let mut found = Vec::new();
+	match value {
+		Value::Object(map) => {
+			let call = map
+				.iter()
+				.filter(|(k, _)| ["name", "call", "call_name", "method", "ty"].contains(&k.as_str()))
+				.filter_map(|(_, v)| v.as_str().or_else(|| v.get("name").and_then(Value::as_str)))
+				.find(|name| SET_CODE_CALLS.contains(&name.to_ascii_lowercase().as_str()));
+			match call.and_then(|call| Some((call, find_arg(value, "code", json_to_bytes)?))) {
+				Some((call, code)) => found.push((call.to_ascii_lowercase(), code)),
+				None => found.extend(map.values().flat_map(find_set_code)),
+			}
+		}
+		Value::Array(values) => found.extend(values.iter().flat_map(find_set_code)),
+		_ => (),
+	}
+	found
+}
+
+/// Recursively walk a JSON value, returning the `now` argument (in milliseconds) of the first `Timestamp::set` call.
+fn find_timestamp(value: &serde_json::Value) -> Option<u64> {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => {
+			let has = |keys: &[&str], name: &str| {
+				keys.iter().filter_map(|k| map.get(*k)?.as_str()).any(|v| v.eq_ignore_ascii_case(name))
+			};
+			if has(&["module", "pallet", "section"], "timestamp") && has(&["name", "call_name", "method"], "set") {
+				if let Some(now) = find_arg(value, "now", json_to_u64) {
+					return Some(now);
+				}
+			}
+			map.values().find_map(find_timestamp)
+		}
+		Value::Array(values) => values.iter().find_map(find_timestamp),
+		_ => None,
+	}
+}
+
+/// The lowercase pallet and call name of a JSON object, if it is a call.
+fn call_name(map: &serde_json::Map<String, serde_json::Value>) -> Option<(String, String)> {
+	let name = |keys: &[&str]| {
+		keys.iter()
+			.filter_map(|k| map.get(*k))
+			.find_map(|v| v.as_str().or_else(|| v.get("name").and_then(serde_json::Value::as_str)))
+			.map(str::to_ascii_lowercase)
+	};
+	Some((name(&["module", "pallet", "pallet_name", "section"])?, name(&["name", "call_name", "method", "ty"])?))
+}
+
+/// Recursively walk a JSON value, collecting the pallet and call name of every call along with the index
+/// (within `calls`) of the call it is nested in and its depth. Calls are collected in pre-order,
+/// so a wrapper call comes before the calls it dispatches.
+fn find_calls(
+	value: &serde_json::Value,
+	parent: Option<usize>,
+	depth: usize,
+	calls: &mut Vec<(String, String, Option<usize>, usize)>,
+) {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => match call_name(map) {
+			Some((module, call)) => {
+				let index = calls.len();
+				calls.push((module, call, parent, depth));
+				map.values().for_each(|v| find_calls(v, Some(index), depth + 1, calls));
+			}
+			None => map.values().for_each(|v| find_calls(v, parent, depth, calls)),
+		},
+		Value::Array(values) => values.iter().for_each(|v| find_calls(v, parent, depth, calls)),
+		_ => (),
+	}
+}
+
+/// `(pallet, call, argument)` names of the calls which dispatch calls passed to them as an argument.
+/// The `calls` argument of `Utility` batches is a list of calls.
+const WRAPPER_CALLS: [(&str, &str, &str); 10] = [
+	("proxy", "proxy", "call"),
+	("proxy", "proxy_announced", "call"),
+	("multisig", "as_multi", "call"),
+	("multisig", "as_multi_threshold_1", "call"),
+	("sudo", "sudo", "call"),
+	("sudo", "sudo_as", "call"),
+	("sudo", "sudo_unchecked_weight", "call"),
+	("utility", "batch", "calls"),
+	("utility", "batch_all", "calls"),
+	("utility", "force_batch", "calls"),
+];
+
+/// Recursively walk a JSON value, decoding the encoded call arguments of every wrapper call with `decode`.
+/// Wrapper calls within a decoded call are decoded as well.
+fn nest_wrapped_calls(value: &mut serde_json::Value, decode: &dyn Fn(&[u8]) -> Option<serde_json::Value>) {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => {
+			let has = |keys: &[&str], name: &str| {
+				keys.iter().filter_map(|k| map.get(*k)?.as_str()).any(|v| v.eq_ignore_ascii_case(name))
+			};
+			let arg = WRAPPER_CALLS
+				.iter()
+				.find(|(pallet, call, _)| {
+					has(&["module", "pallet", "section"], pallet) && has(&["name", "call_name", "method"], call)
+				})
+				.map(|(_, _, arg)| *arg);
+			match arg.and_then(|arg| find_arg_mut(value, arg, is_encoded_call)) {
+				Some(call) => decode_wrapped_call(call, decode),
+				None => {
+					if let Value::Object(map) = value {
+						map.values_mut().for_each(|v| nest_wrapped_calls(v, decode));
+					}
+				}
+			}
+		}
+		Value::Array(values) => values.iter_mut().for_each(|v| nest_wrapped_calls(v, decode)),
+		_ => (),
+	}
+}
+
+/// Names of the call arguments which are accounts (or lists of accounts), along with the `address` of a signature.
+pub const ACCOUNT_ARGS: [&str; 17] = [
+	"address",
+	"dest",
+	"who",
+	"target",
+	"targets",
+	"real",
+	"delegate",
+	"source",
+	"controller",
+	"stash",
+	"beneficiary",
+	"owner",
+	"admin",
+	"issuer",
+	"freezer",
+	"new",
+	"other_signatories",
+];
+
+/// Names of the call arguments which are balances.
+pub const BALANCE_ARGS: [&str; 5] = ["value", "amount", "new_free", "new_reserved", "max_additional"];
+
+/// Recursively walk a JSON value, formatting the accounts and balances of every call with [`format_arg`].
+fn format_params(value: &mut serde_json::Value, ss58_prefix: u16) {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => {
+			// `{"name": "dest", "value": ..}`
+			let name = map.get("name").and_then(Value::as_str).map(str::to_string);
+			for (key, arg) in map.iter_mut() {
+				let name = if key == "value" { name.as_deref().unwrap_or(key) } else { key.as_str() };
+				if !format_arg(name, arg, ss58_prefix) {
+					format_params(arg, ss58_prefix);
+				}
+			}
+		}
+		Value::Array(values) => {
+			let formatted = match values.as_mut_slice() {
+				// `["dest", ..]`
+				[Value::String(name), arg] => format_arg(name, arg, ss58_prefix),
+				_ => false,
+			};
+			if !formatted {
+				values.iter_mut().for_each(|v| format_params(v, ss58_prefix));
+			}
+		}
+		_ => (),
+	}
+}
+
+/// Format the argument `arg` named `name`, if it is an account or balance.
+/// Returns whether it was formatted.
+fn format_arg(name: &str, arg: &mut serde_json::Value, ss58_prefix: u16) -> bool {
+	if ACCOUNT_ARGS.contains(&name) {
+		format_account(arg, ss58_prefix)
+	} else if BALANCE_ARGS.contains(&name) {
+		format_balance(arg)
+	} else {
+		false
+	}
+}
+
+/// Replace a 32-byte account (I.E a `MultiAddress::Id`), or each account of a list of them, with its SS58 address.
+fn format_account(value: &mut serde_json::Value, ss58_prefix: u16) -> bool {
+	use sp_core::crypto::{AccountId32, Ss58AddressFormat, Ss58Codec};
+	match json_to_bytes(value).map(<[u8; 32]>::try_from) {
+		Some(Ok(account)) => {
+			let address = AccountId32::new(account).to_ss58check_with_version(Ss58AddressFormat::custom(ss58_prefix));
+			*value = serde_json::Value::String(address);
+			true
+		}
+		Some(Err(_)) => false,
+		None => match value {
+			serde_json::Value::Array(accounts) if !accounts.is_empty() => {
+				accounts.iter_mut().fold(false, |formatted, account| format_account(account, ss58_prefix) || formatted)
+			}
+			_ => false,
+		},
+	}
+}
+
+/// Replace a balance, which may be wrapped in a single-entry object (I.E `{"Compact": 100}`), with a decimal string.
+fn format_balance(value: &mut serde_json::Value) -> bool {
+	use serde_json::Value;
+	let formatted = match value {
+		Value::Number(n) => Value::String(n.to_string()),
+		// already a decimal string
+		Value::String(s) => return !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()),
+		Value::Object(map) if map.len() == 1 => {
+			let mut inner = map.values().next().cloned().unwrap_or_default();
+			if !format_balance(&mut inner) {
+				return false;
+			}
+			inner
+		}
+		_ => return false,
+	};
+	*value = formatted;
+	true
+}
+
+/// Whether a JSON value is an encoded call, or a non-empty list of encoded calls.
+fn is_encoded_call(value: &serde_json::Value) -> bool {
+	match value {
+		serde_json::Value::Array(calls) if json_to_bytes(value).is_none() => {
+			!calls.is_empty() && calls.iter().all(|call| json_to_bytes(call).is_some())
+		}
+		_ => json_to_bytes(value).is_some(),
+	}
+}
+
+/// Replace an encoded call, or each call of a list of encoded calls, with its decoded JSON.
+/// Calls which fail to decode are left encoded.
+fn decode_wrapped_call(call: &mut serde_json::Value, decode: &dyn Fn(&[u8]) -> Option<serde_json::Value>) {
+	match json_to_bytes(call) {
+		Some(bytes) => {
+			if let Some(mut decoded) = decode(&bytes) {
+				nest_wrapped_calls(&mut decoded, decode);
+				*call = decoded;
+			}
+		}
+		None => {
+			if let serde_json::Value::Array(calls) = call {
+				calls.iter_mut().for_each(|call| decode_wrapped_call(call, decode));
+			}
+		}
+	}
+}
+
+/// Like [`find_arg`], but returns the first argument named `name` which is accepted by `accept`, to be modified.
+fn find_arg_mut<'a>(
+	value: &'a mut serde_json::Value,
+	name: &str,
+	accept: fn(&serde_json::Value) -> bool,
+) -> Option<&'a mut serde_json::Value> {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => {
+			if map.get(name).map_or(false, accept) {
+				return map.get_mut(name);
+			}
+			if map.get("name").and_then(Value::as_str) == Some(name) && map.get("value").map_or(false, accept) {
+				return map.get_mut("value");
+			}
+			map.values_mut().find_map(|v| find_arg_mut(v, name, accept))
+		}
+		Value::Array(values) => {
+			if matches!(values.as_slice(), [Value::String(arg_name), arg] if arg_name == name && accept(arg)) {
+				return values.get_mut(1);
+			}
+			values.iter_mut().find_map(|v| find_arg_mut(v, name, accept))
+		}
+		_ => None,
+	}
+}
+
+/// Find an argument named `name` within a call, either as a `"name": value` entry,
+/// a `["name", value]` pair or a `{"name": "name", "value": value}` object.
+/// The first value `parse` accepts is returned.
+fn find_arg<T>(value: &serde_json::Value, name: &str, parse: fn(&serde_json::Value) -> Option<T>) -> Option<T> {
+	use serde_json::Value;
+	match value {
+		Value::Object(map) => {
+			if let Some(arg) = map.get(name).and_then(parse) {
+				return Some(arg);
+			}
+			if map.get("name").and_then(Value::as_str) == Some(name) {
+				if let Some(arg) = map.get("value").and_then(parse) {
+					return Some(arg);
+				}
+			}
+			map.values().find_map(|v| find_arg(v, name, parse))
+		}
+		Value::Array(values) => match values.as_slice() {
+			[Value::String(arg_name), arg] if arg_name == name => parse(arg),
+			_ => values.iter().find_map(|v| find_arg(v, name, parse)),
+		},
+		_ => None,
+	}
+}
+
+/// Interpret a JSON value as bytes. Accepts hex strings, arrays of bytes
+/// and single-entry objects wrapping either of those.
+fn json_to_bytes(value: &serde_json::Value) -> Option<Vec<u8>> {
+	use serde_json::Value;
+	match value {
+		Value::String(s) => hex::decode(s.trim_start_matches("0x")).ok(),
+		Value::Array(values) => {
+			values.iter().map(|v| v.as_u64().and_then(|n| u8::try_from(n).ok())).collect::<Option<Vec<u8>>>()
+		}
+		Value::Object(map) if map.len() == 1 => map.values().next().and_then(json_to_bytes),
+		_ => None,
+	}
+}
+
+/// Interpret a JSON value as an unsigned integer. Accepts numbers, numeric strings
+/// and single-entry objects wrapping either of those (I.E `{"Compact": 1}`).
+fn json_to_u64(value: &serde_json::Value) -> Option<u64> {
+	use serde_json::Value;
+	match value {
+		Value::Number(n) => n.as_u64(),
+		Value::String(s) => s.parse().ok(),
+		Value::Object(map) if map.len() == 1 => map.values().next().and_then(json_to_u64),
+		_ => None,
+	}
+}
+
+/// A single item of a block header digest.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct DigestItemModel {
+	/// Hash of the block the digest belongs to.
+	pub hash: Vec<u8>,
+	pub block_num: i32,
+	/// Position of the item within the digest.
+	pub idx: i32,
+	/// One of `PreRuntime`, `Consensus`, `Seal`, `Other` or `Unknown`.
+	pub kind: String,
+	/// Consensus engine ID (I.E `BABE`), if the item has one.
+	pub engine: Option<String>,
+	/// Decoded payload of the item, if the engine and kind are known.
+	pub payload: Option<Json<serde_json::Value>>,
+	/// The SCALE-encoded item.
+	pub data: Vec<u8>,
+}
+
+impl DigestItemModel {
+	/// Split the digest of a block into its items, decoding the payloads of known consensus engines.
+	pub fn from_block<B>(block: &B) -> Result<Vec<Self>>
+	where
+		B: BlockT,
+		NumberFor<B>: Into<u32>,
+	{
+		let header = block.header();
+		let hash = header.hash().as_ref().to_vec();
+		let block_num = i32::try_from((*header.number()).into())?;
+		let mut items = Vec::new();
+		for (idx, item) in header.digest().logs().iter().enumerate() {
+			let (kind, engine_data) = match (item.as_pre_runtime(), item.as_consensus(), item.as_seal()) {
+				(Some(pre_runtime), _, _) => ("PreRuntime", Some(pre_runtime)),
+				(_, Some(consensus), _) => ("Consensus", Some(consensus)),
+				(_, _, Some(seal)) => ("Seal", Some(seal)),
+				_ if item.as_other().is_some() => ("Other", None),
+				_ => ("Unknown", None),
+			};
+			items.push(Self {
+				hash: hash.clone(),
+				block_num,
+				idx: i32::try_from(idx)?,
+				kind: kind.to_string(),
+				engine: engine_data.map(|(id, _)| String::from_utf8_lossy(&id).into_owned()),
+				payload: engine_data.and_then(|(id, data)| decode_digest_payload(kind, id, data)).map(Json),
+				data: item.encode(),
+			});
+		}
+		Ok(items)
+	}
+}
+
+/// Decode the payload of digest items which carry consensus data of known engines.
+fn decode_digest_payload(kind: &str, engine: ConsensusEngineId, mut data: &[u8]) -> Option<serde_json::Value> {
+	match (kind, &engine) {
+		// `PreDigest` variants all start with the authority index and slot.
+		("PreRuntime", b"BABE") => {
+			let (variant, authority_index, slot) = <(u8, u32, u64)>::decode(&mut data).ok()?;
+			let variant = match variant {
+				1 => "Primary",
+				2 => "SecondaryPlain",
+				3 => "SecondaryVRF",
+				_ => return None,
+			};
+			Some(serde_json::json!({ "type": variant, "authority_index": authority_index, "slot": slot }))
+		}
+		("PreRuntime", b"aura") => {
+			let slot = u64::decode(&mut data).ok()?;
+			Some(serde_json::json!({ "slot": slot }))
+		}
+		_ => None,
+	}
+}
+
+/// A single event emitted by the runtime, read from the `System::Events` storage of a block.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct EventModel {
+	/// Hash of the block the event was emitted in.
+	pub hash: Vec<u8>,
+	pub block_num: i32,
+	/// Position of the event within the events of the block.
+	pub idx: i32,
+	/// One of `ApplyExtrinsic`, `Finalization` or `Initialization`.
+	pub phase: String,
+	/// Index of the extrinsic which emitted the event, if the phase is `ApplyExtrinsic`.
+	pub extrinsic_idx: Option<i32>,
+	/// Name of the pallet which emitted the event.
+	pub module: String,
+	pub event: String,
+	/// JSON of the decoded event parameters.
+	pub params: Json<serde_json::Value>,
+}
+
+impl EventModel {
+	/// Split the JSON of the decoded `System::Events` storage of a block into events.
+	/// Event records are expected to be objects of a `phase` and an externally tagged `event`,
+	/// I.E `{ "phase": { "ApplyExtrinsic": 1 }, "event": { "Balances": { "Transfer": [...] } } }`.
+	pub fn from_json(hash: &[u8], block_num: u32, records: &serde_json::Value) -> Result<Vec<Self>> {
+		let block_num = i32::try_from(block_num)?;
+		let records = records.as_array().ok_or_else(|| ArchiveError::from("Events are not a list of records"))?;
+		let mut events = Vec::with_capacity(records.len());
+		for (idx, record) in records.iter().enumerate() {
+			let (phase, extrinsic_idx) = match record.get("phase") {
+				Some(serde_json::Value::String(phase)) => (phase.clone(), None),
+				Some(serde_json::Value::Object(map)) if map.len() == 1 => {
+					let (phase, index) = map.iter().next().expect("Checked len; qed");
+					(phase.clone(), index.as_u64().map(i32::try_from).transpose()?)
+				}
+				_ => return Err(ArchiveError::from(format!("Event {} of block {} has no phase", idx, block_num))),
+			};
+			let (module, event, params) = record
+				.get("event")
+				.and_then(single_entry)
+				.and_then(|(module, event)| Some((module, single_entry(event)?)))
+				.map(|(module, (event, params))| (module.clone(), event.clone(), params.clone()))
+				.ok_or_else(|| ArchiveError::from(format!("Event {} of block {} is malformed", idx, block_num)))?;
+			events.push(Self {
+				hash: hash.to_vec(),
+				block_num,
+				idx: i32::try_from(idx)?,
+				phase,
+				extrinsic_idx,
+				module,
+				event,
+				params: Json(params),
+			});
+		}
+		Ok(events)
+	}
+}
+
+/// The key and value of a JSON object with exactly one entry.
+/// Events without parameters are serialized as plain strings, and have `null` parameters.
+fn single_entry(value: &serde_json::Value) -> Option<(&String, &serde_json::Value)> {
+	match value {
+		serde_json::Value::Object(map) if map.len() == 1 => map.iter().next(),
+		serde_json::Value::String(name) => Some((name, &serde_json::Value::Null)),
+		_ => None,
+	}
 }
 
 /// Config that is stored/restored in Postgres on every run.
@@ -313,4 +981,237 @@ mod test {
 		})?;
 		Ok(())
 	}
+
+	#[test]
+	fn should_index_set_code_upgrades() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		let code = vec![0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+		let extrinsics = serde_json::json!([
+			{ "signature": null, "call": { "module": "Timestamp", "name": "set", "args": [["now", 1_600_000_000]] } },
+			{
+				"signature": { "address": "0x00" },
+				"call": {
+					"module": "Sudo",
+					"name": "sudo",
+					"args": [["call", { "module": "System", "name": "set_code", "args": [["code", hex::encode(&code)]] }]]
+				}
+			}
+		]);
+		let upgrades = RuntimeUpgradeModel::from_json(&[0xAB; 32], 100, &extrinsics)?;
+		assert_eq!(upgrades.len(), 1);
+
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			crate::database::Insert::insert(upgrades, &mut conn).await?;
+			let stored = sqlx::query_as::<Postgres, RuntimeUpgradeModel>(
+				"SELECT id, hash, block_num, call, code_hash, code_size FROM runtime_upgrade_events",
+			)
+			.fetch_one(&mut *conn)
+			.await?;
+			assert_eq!(stored.block_num, 100);
+			assert_eq!(stored.call, "set_code");
+			assert_eq!(stored.code_size, code.len() as i32);
+			assert_eq!(stored.code_hash, sp_core::hashing::blake2_256(&code).to_vec());
+			Ok::<(), Error>(())
+		})?;
+		Ok(())
+	}
+
+	#[test]
+	fn should_find_extrinsic_calls() {
+		let extrinsics = serde_json::json!([
+			{ "signature": null, "call": { "module": "Timestamp", "name": "set", "args": [["now", 1]] } },
+			{ "signature": { "address": "0x00" }, "call": { "module": "Balances", "name": "transfer", "args": [] } },
+			{ "signature": { "address": "0x00" }, "no_call": {} },
+			{
+				"signature": { "address": "0x00" },
+				"call": {
+					"module": "Utility",
+					"name": "batch",
+					"args": [["calls", [{ "module": "System", "name": "remark", "args": [] }]]]
+				}
+			}
+		]);
+		let model = ExtrinsicsModel {
+			id: None,
+			hash: vec![0xAB],
+			number: 7,
+			extrinsics: Json(extrinsics.as_array().unwrap().clone()),
+		};
+		let calls = model.calls();
+		let calls = calls.iter().map(|c| (c.extrinsic_index, c.module.as_str(), c.call.as_str(), c.signed));
+		assert_eq!(
+			calls.collect::<Vec<_>>(),
+			[
+				(0, "timestamp", "set", false),
+				(1, "balances", "transfer", true),
+				(3, "utility", "batch", true),
+				(3, "system", "remark", true)
+			]
+		);
+		// the batched call is linked to the batch
+		let tree = model.calls().iter().map(|c| (c.call_index, c.parent_index, c.depth)).collect::<Vec<_>>();
+		assert_eq!(tree, [(0, None, 0), (0, None, 0), (0, None, 0), (1, Some(0), 1)]);
+	}
+
+	#[test]
+	fn should_format_accounts_and_balances() {
+		let alice = hex::decode("d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d").unwrap();
+		let alice_ss58 = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+		let extrinsics = serde_json::json!([
+			{
+				"signature": { "address": { "Id": hex::encode(&alice) }, "signature": "0x00" },
+				"call": {
+					"module": "Balances",
+					"name": "transfer",
+					"args": [["dest", { "Id": alice.clone() }], ["value", { "Compact": 1_000_000_000_000_u64 }]]
+				}
+			},
+			{
+				"signature": null,
+				"call": {
+					"module": "Staking",
+					"name": "nominate",
+					"args": [{ "name": "targets", "value": [hex::encode(&alice), "0x01"] }, ["amount", "12"]]
+				}
+			}
+		]);
+		let mut model = ExtrinsicsModel {
+			id: None,
+			hash: vec![0xAB],
+			number: 7,
+			extrinsics: Json(extrinsics.as_array().unwrap().clone()),
+		};
+		model.format_params(42);
+		let transfer = &model.extrinsics.0[0];
+		assert_eq!(transfer["signature"]["address"], alice_ss58);
+		assert_eq!(transfer["call"]["args"][0], serde_json::json!(["dest", alice_ss58]));
+		assert_eq!(transfer["call"]["args"][1], serde_json::json!(["value", "1000000000000"]));
+		let nominate = &model.extrinsics.0[1];
+		// accounts which are not 32 bytes long are left as they are
+		assert_eq!(nominate["call"]["args"][0]["value"], serde_json::json!([alice_ss58, "0x01"]));
+		assert_eq!(nominate["call"]["args"][1], serde_json::json!(["amount", "12"]));
+	}
+
+	#[test]
+	fn should_extract_timestamp() {
+		let extrinsics = serde_json::json!([
+			{
+				"signature": null,
+				"call": { "module": "Timestamp", "name": "set", "args": [["now", { "Compact": 1_600_000_000_123_u64 }]] }
+			},
+			{ "signature": { "address": "0x00" }, "call": { "module": "Balances", "name": "set", "args": [["now", 1]] } }
+		]);
+		assert_eq!(find_timestamp(&extrinsics), Some(1_600_000_000_123));
+		assert_eq!(find_timestamp(&extrinsics[1]), None);
+	}
+
+	#[test]
+	fn should_decode_proxied_call() {
+		let transfer = vec![0x04, 0x00, 0xAA, 0xBB];
+		let mut extrinsics = serde_json::json!([{
+			"signature": { "address": "0x01" },
+			"call": {
+				"module": "Proxy",
+				"name": "proxy",
+				"args": [["real", "0x02"], ["force_proxy_type", null], ["call", format!("0x{}", hex::encode(&transfer))]]
+			}
+		}]);
+		nest_wrapped_calls(&mut extrinsics, &|call| {
+			assert_eq!(call, transfer.as_slice());
+			Some(serde_json::json!({ "module": "Balances", "name": "transfer", "args": [["value", 100]] }))
+		});
+		let proxy = &extrinsics[0]["call"];
+		assert_eq!(proxy["args"][0], serde_json::json!(["real", "0x02"]));
+		let inner = &proxy["args"][2][1];
+		assert_eq!(inner["module"], "Balances");
+		assert_eq!(inner["name"], "transfer");
+		assert_eq!(find_arg(inner, "value", json_to_u64), Some(100));
+	}
+
+	#[test]
+	fn should_decode_batched_calls() {
+		let remark = vec![0x00, 0x01, 0x04, 0xAA];
+		let mut extrinsics = serde_json::json!([{
+			"signature": { "address": "0x01" },
+			"call": {
+				"module": "Utility",
+				"name": "batch_all",
+				"args": [["calls", [format!("0x{}", hex::encode(&remark)), "0x0502"]]]
+			}
+		}]);
+		nest_wrapped_calls(&mut extrinsics, &|call| match call {
+			[0x00, 0x01, ..] => Some(serde_json::json!({
+				"module": "Sudo",
+				"name": "sudo",
+				"args": [["call", format!("0x{}", hex::encode(&call[2..]))]]
+			})),
+			[0x04, 0xAA] => Some(serde_json::json!({ "module": "System", "name": "remark", "args": [] })),
+			_ => None,
+		});
+		let calls = &extrinsics[0]["call"]["args"][0][1];
+		assert_eq!(calls[0]["module"], "Sudo");
+		// wrapper calls within batched calls are decoded as well
+		assert_eq!(calls[0]["args"][0][1]["name"], "remark");
+		// calls which fail to decode are left encoded
+		assert_eq!(calls[1], "0x0502");
+	}
+
+	#[test]
+	fn should_extract_babe_slot() -> Result<(), Error> {
+		use polkadot_service::{Block, Header};
+		use sp_runtime::generic::DigestItem;
+
+		let mut header =
+			<Header as HeaderT>::new(5, Default::default(), Default::default(), Default::default(), Default::default());
+		// `SecondaryPlain` pre-digest of authority 7 at slot 42.
+		header.digest_mut().push(DigestItem::PreRuntime(*b"BABE", (2u8, 7u32, 42u64).encode()));
+		header.digest_mut().push(DigestItem::Seal(*b"BABE", vec![0xAA; 64]));
+		let block = <Block as BlockT>::new(header, Vec::new());
+
+		let items = DigestItemModel::from_block(&block)?;
+		assert_eq!(items.len(), 2);
+		assert_eq!(items[0].kind, "PreRuntime");
+		assert_eq!(items[0].engine.as_deref(), Some("BABE"));
+		let payload = &items[0].payload.as_ref().expect("BABE pre-runtime is decoded").0;
+		assert_eq!(payload["type"].as_str(), Some("SecondaryPlain"));
+		assert_eq!(payload["authority_index"].as_u64(), Some(7));
+		assert_eq!(payload["slot"].as_u64(), Some(42));
+		assert_eq!(items[1].kind, "Seal");
+		assert_eq!(items[1].payload, None);
+		Ok(())
+	}
+
+	#[test]
+	fn should_split_event_records() -> Result<(), Error> {
+		let records = serde_json::json!([
+			{ "phase": { "ApplyExtrinsic": 0 }, "event": { "System": { "ExtrinsicSuccess": [{ "weight": 1 }] } } },
+			{ "phase": { "ApplyExtrinsic": 1 }, "event": { "Balances": { "Transfer": ["0x01", "0x02", 100] } } },
+			{ "phase": "Finalization", "event": { "Treasury": "Rollover" } }
+		]);
+		let events = EventModel::from_json(&[0xAA; 32], 5, &records)?;
+		assert_eq!(events.len(), 3);
+		assert_eq!((events[1].idx, events[1].extrinsic_idx), (1, Some(1)));
+		assert_eq!((events[1].module.as_str(), events[1].event.as_str()), ("Balances", "Transfer"));
+		assert_eq!(events[1].params.0, serde_json::json!(["0x01", "0x02", 100]));
+		assert_eq!((events[2].phase.as_str(), events[2].extrinsic_idx), ("Finalization", None));
+		assert_eq!(events[2].event, "Rollover");
+		assert_eq!(events[2].params.0, serde_json::Value::Null);
+
+		assert!(EventModel::from_json(&[0xAA; 32], 5, &serde_json::json!([{ "event": "Rollover" }])).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn should_split_child_storage_changes() {
+		let child = StorageKey(b":child_storage:default:crowdloan".to_vec());
+		let storage = Storage::new([0xAA; 32], 5, false, vec![(StorageKey(vec![0x01]), None)])
+			.with_child_changes(vec![(child.clone(), vec![(StorageKey(vec![0x02]), Some(StorageData(vec![0x03])))])]);
+		let models = Vec::<StorageModel<[u8; 32]>>::from(storage);
+		assert_eq!(models.len(), 2);
+		assert_eq!(models[0].child(), None);
+		assert_eq!(models[1].child(), Some(&child));
+		assert_eq!(models[1].key(), &StorageKey(vec![0x02]));
+	}
 }
@@ -0,0 +1,154 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Transactional outbox of the task queue, kept in the `_job_outbox` table.
+//! Jobs enqueued with [`JobExt::enqueue_to`](sa_work_queue::JobExt::enqueue_to) a [`PgOutbox`]
+//! are committed along with the rest of its transaction, and published by [`relay`].
+
+use sa_work_queue::{EnqueueError, Job, JobExt, Outbox, QueueHandle};
+use sqlx::{Connection, PgConnection};
+
+use crate::error::Result;
+
+/// An outbox writing jobs to the `_job_outbox` table through a connection,
+/// usually one inside of a transaction.
+pub struct PgOutbox<'a> {
+	conn: &'a mut PgConnection,
+}
+
+impl<'a> PgOutbox<'a> {
+	pub fn new(conn: &'a mut PgConnection) -> Self {
+		Self { conn }
+	}
+}
+
+impl<'a> PgOutbox<'a> {
+	/// Store jobs which were encoded ahead of the transaction.
+	pub async fn store_all(&mut self, jobs: Vec<OutboxJob>) -> Result<(), EnqueueError> {
+		for job in jobs {
+			self.store(&job.job_type, job.payload, job.key.as_deref()).await?;
+		}
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl<'a> Outbox for PgOutbox<'a> {
+	async fn store(&mut self, job_type: &str, payload: Vec<u8>, key: Option<&str>) -> Result<(), EnqueueError> {
+		sqlx::query("INSERT INTO _job_outbox (job_type, payload, key) VALUES ($1, $2, $3)")
+			.bind(job_type)
+			.bind(payload)
+			.bind(key)
+			.execute(&mut *self.conn)
+			.await
+			.map_err(|e| EnqueueError::Outbox(Box::new(e)))?;
+		Ok(())
+	}
+}
+
+/// A job encoded to be stored in an outbox, I.E by a transaction which does not know the type of the job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxJob {
+	pub job_type: String,
+	pub payload: Vec<u8>,
+	/// Idempotency key the job is published with.
+	pub key: Option<String>,
+}
+
+impl OutboxJob {
+	/// Encode `job`, to be published with the idempotency key `key`.
+	pub fn new<J: Job>(job: &J, key: Option<String>) -> Result<Self, EnqueueError> {
+		Ok(Self { job_type: J::JOB_TYPE.to_string(), payload: job.outbox_payload()?, key })
+	}
+}
+
+/// Publish up to `limit` jobs from the outbox, oldest first.
+/// Jobs are removed from the outbox only once every one of them is confirmed,
+/// so a failure part-way publishes the jobs again on the next relay.
+/// Returns the number of jobs published.
+pub async fn relay(conn: &mut PgConnection, handle: &QueueHandle, limit: u32) -> Result<u64> {
+	let mut tx = conn.begin().await?;
+	let jobs = sqlx::query_as::<_, (i64, String, Vec<u8>, Option<String>)>(
+		"SELECT id, job_type, payload, key FROM _job_outbox ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+	)
+	.bind(i64::from(limit))
+	.fetch_all(&mut tx)
+	.await?;
+	let mut ids = Vec::with_capacity(jobs.len());
+	for (id, job_type, payload, key) in jobs {
+		handle.push_confirmed_with_key(&job_type, payload, key.as_deref()).await?;
+		ids.push(id);
+	}
+	let published = sqlx::query("DELETE FROM _job_outbox WHERE id = ANY ($1)").bind(ids).execute(&mut tx).await?;
+	tx.commit().await?;
+	Ok(published.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Error;
+	use async_std::task;
+	use test_common::{TestGuard, AMQP_CONN, PG_POOL, TASK_QUEUE};
+
+	async fn outbox_len(conn: &mut PgConnection) -> Result<i64, Error> {
+		let (len,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM _job_outbox").fetch_one(conn).await?;
+		Ok(len)
+	}
+
+	#[test]
+	fn should_not_lose_jobs_between_write_and_publish() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		let handle = QueueHandle::new(&AMQP_CONN, TASK_QUEUE)?;
+		handle.channel().confirm_select(Default::default()).wait()?;
+
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			// the job is committed together with the write that triggered it
+			let mut tx = conn.begin().await?;
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2)")
+				.bind(26_i32)
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(&mut tx)
+				.await?;
+			PgOutbox::new(&mut *tx)
+				.store("execute_block", b"block 1337".to_vec(), Some("execute_block/0x1337"))
+				.await?;
+			tx.commit().await?;
+			// and rolled back with it
+			let mut tx = conn.begin().await?;
+			PgOutbox::new(&mut *tx).store("execute_block", b"block 1338".to_vec(), None).await?;
+			tx.rollback().await?;
+
+			// the process crashes before publishing, so nothing is in the queue
+			assert!(handle.channel().basic_get(TASK_QUEUE, Default::default()).await?.is_none());
+			assert_eq!(outbox_len(&mut *conn).await?, 1);
+
+			// after restarting the relay publishes the job
+			assert_eq!(relay(&mut conn, &handle, 100).await?, 1);
+			assert_eq!(outbox_len(&mut *conn).await?, 0);
+			let message = handle.channel().basic_get(TASK_QUEUE, Default::default()).await?.expect("job is published");
+			assert_eq!(message.delivery.data, b"block 1337".to_vec());
+			// published with its idempotency key
+			let key = message.delivery.properties.message_id().as_ref().map(|id| id.as_str());
+			assert_eq!(key, Some("execute_block/0x1337"));
+			assert!(handle.channel().basic_get(TASK_QUEUE, Default::default()).await?.is_none());
+			Ok::<(), Error>(())
+		})?;
+		Ok(())
+	}
+}
@@ -17,13 +17,20 @@
 //! Common Sql queries on Archive Database abstracted into rust functions
 
 use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use codec::Decode;
 use futures::Stream;
 use hashbrown::HashSet;
 use itertools::Itertools;
-use sqlx::PgConnection;
-use std::collections::HashMap;
+use sc_executor::RuntimeVersion;
+use sp_storage::{StorageData, StorageKey};
+use sqlx::{types::Json, PgConnection};
+use std::{collections::HashMap, ops::RangeInclusive, time::Duration};
 
-use crate::{database::models::BlockModel, error::Result};
+use crate::{
+	database::models::{BlockModel, DigestItemModel, StorageModel},
+	error::Result,
+};
 
 /// Return type of queries that `SELECT version`
 struct Version {
@@ -50,14 +57,6 @@ struct BlockNum {
 	block_num: i32,
 }
 
-// Return type of queries that `SELECT block_num, ext`
-struct BlockExtrinsics {
-	block_num: i32,
-	hash: Vec<u8>,
-	ext: Vec<u8>,
-	spec: i32,
-}
-
 /// Return type of queries that `SELECT meta`
 struct Meta {
 	pub meta: Vec<u8>,
@@ -78,15 +77,48 @@ struct PastAndPresentVersion {
 	pub past_metadata: Option<Vec<u8>>,
 }
 
+/// What is missing of a block, as found by [`indexing_gaps`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexingGap {
+	pub block_num: u32,
+	/// The block is not in the `blocks` table. Nothing else of it is reported missing.
+	pub block: bool,
+	/// The block was not executed, and is not marked as unexecutable.
+	pub storage: bool,
+	/// The extrinsics of the block were not decoded.
+	pub extrinsics: bool,
+	/// The block was executed, but its events were not decoded.
+	pub events: bool,
+}
+
+/// An extrinsic found by [`extrinsics_by_call`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtrinsicCall {
+	pub block_num: u32,
+	/// Hash of the block the extrinsic is contained in.
+	pub hash: Vec<u8>,
+	/// Index of the extrinsic within its block.
+	pub index: u32,
+	/// Index of the matched call within the extrinsic. The outermost call is 0.
+	pub call_index: u32,
+	/// Number of wrapper calls (I.E `Utility::batch`) around the matched call.
+	pub depth: u32,
+	pub signed: bool,
+	/// JSON of the decoded extrinsic.
+	pub extrinsic: serde_json::Value,
+}
+
 /// Get missing blocks from the relational database between numbers `min` and
-/// MAX(block_num). LIMIT result to length `max_block_load`. The highest effective
-/// value for `min` is i32::MAX.
+/// the lesser of `max` and MAX(block_num). LIMIT result to length `max_block_load`.
+/// The highest effective value for `min` and `max` is i32::MAX.
 pub(crate) async fn missing_blocks_min_max(
 	conn: &mut PgConnection,
 	min: u32,
+	max: u32,
 	max_block_load: u32,
 ) -> Result<HashSet<u32>> {
 	let min = i32::try_from(min).unwrap_or(i32::MAX);
+	let max = i32::try_from(max).unwrap_or(i32::MAX);
 	let max_block_load = i64::try_from(max_block_load).unwrap_or(i64::MAX);
 	// Remove after launchbadge/sqlx#594 is fixed
 	#[allow(clippy::toplevel_ref_arg)]
@@ -95,13 +127,14 @@ pub(crate) async fn missing_blocks_min_max(
 		"
 		SELECT missing_num
 		FROM (SELECT MAX(block_num) AS max_num FROM blocks) max,
-			GENERATE_SERIES($1, max_num) AS missing_num
+			GENERATE_SERIES($1, LEAST(max_num, $3)) AS missing_num
 		WHERE
 		NOT EXISTS (SELECT id FROM blocks WHERE block_num = missing_num)
 		ORDER BY missing_num ASC
 		LIMIT $2",
 		min,
-		max_block_load
+		max_block_load,
+		max
 	)
 	.fetch_all(conn)
 	.await?
@@ -116,6 +149,179 @@ pub(crate) async fn max_block(conn: &mut PgConnection) -> Result<Option<u32>> {
 	Ok(max.max.map(|v| v as u32))
 }
 
+/// Get up to `limit` blocks numbered `from` or above, up to the highest indexed block,
+/// of which the block, its storage, extrinsics or events are not indexed.
+/// The events of a block are looked for in its `events_key` storage, so they are only missing once it is executed.
+/// Ordered from least to greatest number.
+pub async fn indexing_gaps(
+	conn: &mut PgConnection,
+	from: u32,
+	limit: u32,
+	events_key: &[u8],
+) -> Result<Vec<IndexingGap>> {
+	let from = i32::try_from(from).unwrap_or(i32::MAX);
+	let gaps = sqlx::query_as::<_, (i32, bool, bool, bool, bool)>(
+		"
+		SELECT num, block, storage, extrinsics, events FROM (
+			SELECT
+				series.num,
+				blocks.hash IS NULL AS block,
+				blocks.hash IS NOT NULL
+					AND NOT EXISTS (SELECT 1 FROM storage WHERE storage.block_num = series.num)
+					AND NOT EXISTS (SELECT 1 FROM unexecutable_blocks WHERE unexecutable_blocks.hash = blocks.hash)
+					AS storage,
+				blocks.hash IS NOT NULL
+					AND NOT EXISTS (SELECT 1 FROM extrinsics WHERE extrinsics.number = series.num)
+					AS extrinsics,
+				blocks.hash IS NOT NULL
+					AND EXISTS (SELECT 1 FROM storage WHERE storage.hash = blocks.hash AND storage.key = $3)
+					AND NOT EXISTS (SELECT 1 FROM events WHERE events.hash = blocks.hash)
+					AS events
+			FROM GENERATE_SERIES($1, (SELECT MAX(block_num) FROM blocks)) AS series(num)
+			LEFT JOIN blocks ON blocks.block_num = series.num
+		) AS gaps
+		WHERE block OR storage OR extrinsics OR events
+		ORDER BY num ASC
+		LIMIT $2
+		",
+	)
+	.bind(from)
+	.bind(i64::from(limit))
+	.bind(events_key)
+	.fetch_all(conn)
+	.await?
+	.into_iter()
+	.map(|(block_num, block, storage, extrinsics, events)| IndexingGap {
+		block_num: block_num as u32,
+		block,
+		storage,
+		extrinsics,
+		events,
+	})
+	.collect();
+	Ok(gaps)
+}
+
+/// Count the blocks with a number between `from` and `to` (inclusive).
+/// Counted from the unique index on `block_num`, so the `blocks` table is never fully scanned.
+pub async fn block_count_in_range(conn: &mut PgConnection, from: u32, to: u32) -> Result<u64> {
+	let from = i32::try_from(from).unwrap_or(i32::MAX);
+	let to = i32::try_from(to).unwrap_or(i32::MAX);
+	let (count,): (i64,) = sqlx::query_as("SELECT COUNT(block_num) FROM blocks WHERE block_num BETWEEN $1 AND $2")
+		.bind(from)
+		.bind(to)
+		.fetch_one(conn)
+		.await?;
+	Ok(u64::try_from(count)?)
+}
+
+/// Count the blocks whose extrinsics failed to decode.
+pub(crate) async fn undecodable_block_count(conn: &mut PgConnection) -> Result<u64> {
+	let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM undecodable_extrinsics").fetch_one(conn).await?;
+	Ok(u64::try_from(count)?)
+}
+
+/// Estimate the number of rows of `table` from the statistics of the planner,
+/// which are updated by `VACUUM` and `ANALYZE` rather than by scanning the table.
+/// Tables which were never analyzed are estimated to be empty.
+pub(crate) async fn estimated_row_count(conn: &mut PgConnection, table: &str) -> Result<u64> {
+	let estimate: Option<(f32,)> =
+		sqlx::query_as("SELECT reltuples FROM pg_class WHERE relname = $1").bind(table).fetch_optional(conn).await?;
+	Ok(estimate.map(|(rows,)| rows.max(0.0) as u64).unwrap_or(0))
+}
+
+/// Count the blocks with a number between `from` and `to` (inclusive) which have no storage indexed.
+/// Blocks marked as unexecutable are not counted, since they never have storage.
+pub(crate) async fn missing_storage_count_in_range(conn: &mut PgConnection, from: u32, to: u32) -> Result<u64> {
+	let from = i32::try_from(from).unwrap_or(i32::MAX);
+	let to = i32::try_from(to).unwrap_or(i32::MAX);
+	let (count,): (i64,) = sqlx::query_as(
+		"
+		SELECT COUNT(block_num) FROM blocks
+		WHERE block_num BETWEEN $1 AND $2
+		AND NOT EXISTS
+			(SELECT block_num FROM storage WHERE storage.block_num = blocks.block_num)
+		AND NOT EXISTS
+			(SELECT hash FROM unexecutable_blocks WHERE unexecutable_blocks.hash = blocks.hash)
+		",
+	)
+	.bind(from)
+	.bind(to)
+	.fetch_one(conn)
+	.await?;
+	Ok(u64::try_from(count)?)
+}
+
+/// Get all runtime versions persisted by the runtime version cache,
+/// keyed by the hash of their WASM blob.
+pub(crate) async fn runtime_versions_cache(conn: &mut PgConnection) -> Result<Vec<(u64, RuntimeVersion)>> {
+	let versions: Vec<(i64, Vec<u8>)> =
+		sqlx::query_as("SELECT code_hash, version FROM runtime_versions_cache").fetch_all(conn).await?;
+	versions
+		.into_iter()
+		.map(|(code_hash, version)| Ok((code_hash as u64, RuntimeVersion::decode(&mut version.as_slice())?)))
+		.collect()
+}
+
+/// Get how many blocks the relational database is behind `best`.
+/// If no blocks are indexed yet, the lag is `best`.
+pub(crate) async fn tip_lag(conn: &mut PgConnection, best: u32) -> Result<u32> {
+	let indexed = max_block(conn).await?.unwrap_or(0);
+	Ok(best.saturating_sub(indexed))
+}
+
+/// Record a sample of the highest indexed block, for [`indexing_rate`].
+/// Samples older than `retention` are removed. Nothing is recorded if no blocks are indexed yet.
+pub(crate) async fn record_indexing_progress(conn: &mut PgConnection, retention: Duration) -> Result<()> {
+	sqlx::query(
+		"INSERT INTO indexing_progress (max_block) SELECT MAX(block_num) FROM blocks HAVING MAX(block_num) IS NOT NULL",
+	)
+	.execute(&mut *conn)
+	.await?;
+	sqlx::query("DELETE FROM indexing_progress WHERE sampled_at < now() - make_interval(secs => $1)")
+		.bind(retention.as_secs_f64())
+		.execute(conn)
+		.await?;
+	Ok(())
+}
+
+/// Get the average number of blocks indexed per second over the last `window`,
+/// from the first and last progress samples recorded within it.
+/// Returns `0.0` if fewer than two samples were recorded in the window.
+pub async fn indexing_rate(conn: &mut PgConnection, window: Duration) -> Result<f64> {
+	let samples = sqlx::query_as::<_, (f64, f64)>(
+		"SELECT EXTRACT(EPOCH FROM (last.sampled_at - first.sampled_at))::float8,
+			(last.max_block - first.max_block)::float8
+		FROM
+			(SELECT sampled_at, max_block FROM indexing_progress
+			WHERE sampled_at >= now() - make_interval(secs => $1) ORDER BY sampled_at ASC LIMIT 1) AS first,
+			(SELECT sampled_at, max_block FROM indexing_progress
+			WHERE sampled_at >= now() - make_interval(secs => $1) ORDER BY sampled_at DESC LIMIT 1) AS last",
+	)
+	.bind(window.as_secs_f64())
+	.fetch_optional(conn)
+	.await?;
+	match samples {
+		Some((elapsed, blocks)) if elapsed > 0.0 => Ok(blocks.max(0.0) / elapsed),
+		_ => Ok(0.0),
+	}
+}
+
+/// Estimate how long it takes to index up to `best`, at the [`indexing_rate`] over the last `window`.
+/// Returns `None` if nothing was indexed within the window.
+pub async fn indexing_eta(conn: &mut PgConnection, best: u32, window: Duration) -> Result<Option<Duration>> {
+	let lag = tip_lag(conn, best).await?;
+	if lag == 0 {
+		return Ok(Some(Duration::from_secs(0)));
+	}
+	let rate = indexing_rate(conn, window).await?;
+	if rate > 0.0 {
+		Ok(Some(Duration::from_secs_f64(f64::from(lag) / rate)))
+	} else {
+		Ok(None)
+	}
+}
+
 /// Get a block by id from the relational database
 pub(crate) async fn get_full_block_by_number(conn: &mut sqlx::PgConnection, block_num: i32) -> Result<BlockModel> {
 	#[allow(clippy::toplevel_ref_arg)]
@@ -133,6 +339,23 @@ pub(crate) async fn get_full_block_by_number(conn: &mut sqlx::PgConnection, bloc
 	.map_err(Into::into)
 }
 
+/// Get a block by its hash from the relational database.
+pub async fn get_full_block_by_hash(conn: &mut PgConnection, hash: &[u8]) -> Result<BlockModel> {
+	#[allow(clippy::toplevel_ref_arg)]
+	sqlx::query_as!(
+		BlockModel,
+		"
+        SELECT id, parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec
+        FROM blocks
+        WHERE hash = $1
+        ",
+		hash
+	)
+	.fetch_one(conn)
+	.await
+	.map_err(Into::into)
+}
+
 /// Get metadata according to spec version.
 pub async fn metadata(conn: &mut PgConnection, spec: i32) -> Result<Vec<u8>> {
 	sqlx::query_as!(Meta, "SELECT meta FROM metadata WHERE version = $1", spec)
@@ -178,6 +401,18 @@ pub(crate) async fn has_blocks(nums: &[u32], conn: &mut PgConnection) -> Result<
 		.collect())
 }
 
+/// Get a list of block numbers, out of the passed-in block numbers, which have storage indexed.
+pub(crate) async fn has_storage(nums: &[u32], conn: &mut PgConnection) -> Result<Vec<u32>> {
+	let nums: Vec<i32> = nums.iter().filter_map(|n| i32::try_from(*n).ok()).collect();
+	Ok(sqlx::query_as::<_, (i32,)>("SELECT DISTINCT block_num FROM storage WHERE block_num = ANY ($1)")
+		.bind(nums)
+		.fetch_all(conn)
+		.await?
+		.into_iter()
+		.map(|(block_num,)| block_num as u32)
+		.collect())
+}
+
 /// Get all the metadata versions stored in the relational database
 pub(crate) async fn get_versions(conn: &mut PgConnection) -> Result<Vec<u32>> {
 	#[allow(clippy::toplevel_ref_arg)]
@@ -189,21 +424,24 @@ pub(crate) async fn get_versions(conn: &mut PgConnection) -> Result<Vec<u32>> {
 		.collect())
 }
 
+/// Get up to 1000 blocks which have no storage indexed yet.
+/// Blocks marked as unexecutable are left out, since executing them again would fail.
 pub(crate) async fn missing_storage_blocks(conn: &mut sqlx::PgConnection) -> Result<Vec<u32>> {
-	let blocks: Vec<u32> = sqlx::query_as!(
-		BlockNum,
+	let blocks: Vec<u32> = sqlx::query_as::<_, (i32,)>(
 		r#"
          SELECT block_num FROM blocks
          WHERE NOT EXISTS
             (SELECT block_num FROM storage WHERE storage.block_num = blocks.block_num)
+         AND NOT EXISTS
+            (SELECT hash FROM unexecutable_blocks WHERE unexecutable_blocks.hash = blocks.hash)
         ORDER BY block_num ASC
 		LIMIT 1000;
-        "#
+        "#,
 	)
 	.fetch_all(conn)
 	.await?
 	.into_iter()
-	.map(|r| r.block_num as u32)
+	.map(|(block_num,)| block_num as u32)
 	.collect();
 	Ok(blocks)
 }
@@ -234,30 +472,160 @@ pub(crate) fn blocks_paginated<'a>(
 
 /// Get up to `max_block_load` extrinsics which are not present in the `extrinsics` table.
 /// Ordered from least to greatest number.
+/// Blocks of any spec in `skip_specs` and blocks whose extrinsics failed to decode are left out.
 pub(crate) async fn blocks_missing_extrinsics(
 	conn: &mut PgConnection,
 	max_block_load: u32,
+	skip_specs: &[i32],
 ) -> Result<Vec<(u32, Vec<u8>, Vec<u8>, u32)>> {
-	let blocks = sqlx::query_as!(
-		BlockExtrinsics,
+	let blocks = sqlx::query_as::<_, (i32, Vec<u8>, Vec<u8>, i32)>(
 		"
 		SELECT block_num, hash, ext, spec FROM blocks
 		WHERE NOT EXISTS
 			(SELECT number FROM extrinsics WHERE extrinsics.number = blocks.block_num)
+		AND NOT EXISTS
+			(SELECT hash FROM undecodable_extrinsics WHERE undecodable_extrinsics.hash = blocks.hash)
+		AND spec <> ALL($2)
 		ORDER BY block_num ASC
 		LIMIT $1
 		",
-		i64::from(max_block_load)
 	)
+	.bind(i64::from(max_block_load))
+	.bind(skip_specs)
+	.fetch_all(conn)
+	.await?
+	.into_iter()
+	.map(|(block_num, hash, ext, spec)| (block_num as u32, hash, ext, spec as u32))
+	.collect();
+
+	Ok(blocks)
+}
+
+/// Get up to `max_block_load` executed blocks whose events are not present in the `events` table,
+/// along with the value of the `System::Events` storage key in each block,
+/// as tuples of block number, hash, spec, value and whether the value is offloaded.
+/// Ordered from least to greatest number.
+/// Blocks of any spec in `skip_specs`, blocks in `skip_blocks` and blocks without events are left out.
+pub(crate) async fn blocks_missing_events(
+	conn: &mut PgConnection,
+	max_block_load: u32,
+	events_key: &[u8],
+	skip_specs: &[i32],
+	skip_blocks: &[i32],
+) -> Result<Vec<(u32, Vec<u8>, u32, Option<Vec<u8>>, bool)>> {
+	let blocks = sqlx::query_as::<_, (i32, Vec<u8>, i32, Option<Vec<u8>>, bool)>(
+		"
+		SELECT blocks.block_num, blocks.hash, blocks.spec, storage.storage, storage.offloaded FROM blocks
+		INNER JOIN storage ON storage.hash = blocks.hash AND storage.key = $2
+		WHERE NOT EXISTS
+			(SELECT 1 FROM events WHERE events.hash = blocks.hash)
+		AND NOT EXISTS
+			(SELECT 1 FROM blocks_without_events WHERE blocks_without_events.hash = blocks.hash)
+		AND blocks.spec <> ALL($3)
+		AND blocks.block_num <> ALL($4)
+		ORDER BY blocks.block_num ASC
+		LIMIT $1
+		",
+	)
+	.bind(i64::from(max_block_load))
+	.bind(events_key)
+	.bind(skip_specs)
+	.bind(skip_blocks)
 	.fetch_all(conn)
 	.await?
 	.into_iter()
-	.map(|b| (b.block_num as u32, b.hash, b.ext, b.spec as u32))
+	.map(|(block_num, hash, spec, value, offloaded)| (block_num as u32, hash, spec as u32, value, offloaded))
 	.collect();
 
 	Ok(blocks)
 }
 
+/// Get up to `max_block_load` blocks which were inserted without a spec version (`spec = 0`),
+/// as pairs of block number and hash. Ordered from least to greatest number.
+pub(crate) async fn blocks_without_spec(conn: &mut PgConnection, max_block_load: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+	let blocks = sqlx::query_as::<_, (i32, Vec<u8>)>(
+		"SELECT block_num, hash FROM blocks WHERE spec = 0 ORDER BY block_num ASC LIMIT $1",
+	)
+	.bind(i64::from(max_block_load))
+	.fetch_all(conn)
+	.await?
+	.into_iter()
+	.map(|(block_num, hash)| (block_num as u32, hash))
+	.collect();
+	Ok(blocks)
+}
+
+/// Set the spec version of the block with hash `hash`.
+/// The metadata of `spec` must already be present.
+pub(crate) async fn set_block_spec(conn: &mut PgConnection, hash: &[u8], spec: u32) -> Result<()> {
+	sqlx::query("UPDATE blocks SET spec = $1 WHERE hash = $2")
+		.bind(i32::try_from(spec)?)
+		.bind(hash)
+		.execute(conn)
+		.await?;
+	Ok(())
+}
+
+/// Delete the storage and child storage of the block numbered `block_num`, I.E before re-executing it.
+/// Returns the number of rows deleted.
+pub(crate) async fn delete_block_storage(conn: &mut PgConnection, block_num: u32) -> Result<u64> {
+	let block_num = i32::try_from(block_num)?;
+	let storage = sqlx::query("DELETE FROM storage WHERE block_num = $1").bind(block_num).execute(&mut *conn).await?;
+	let child =
+		sqlx::query("DELETE FROM child_storage WHERE block_num = $1").bind(block_num).execute(&mut *conn).await?;
+	Ok(storage.rows_affected() + child.rows_affected())
+}
+
+/// Set the timestamps of blocks, given as pairs of block hash and timestamp.
+pub(crate) async fn set_block_timestamps(
+	conn: &mut PgConnection,
+	timestamps: &[(Vec<u8>, DateTime<Utc>)],
+) -> Result<()> {
+	let (hashes, times): (Vec<Vec<u8>>, Vec<DateTime<Utc>>) = timestamps.iter().cloned().unzip();
+	sqlx::query(
+		"UPDATE blocks SET timestamp = t.timestamp
+		FROM UNNEST($1::bytea[], $2::timestamptz[]) AS t(hash, timestamp)
+		WHERE blocks.hash = t.hash",
+	)
+	.bind(hashes)
+	.bind(times)
+	.execute(conn)
+	.await?;
+	Ok(())
+}
+
+/// Record blocks, as pairs of hash and number, which emitted no events.
+pub(crate) async fn insert_blocks_without_events(conn: &mut PgConnection, blocks: &[(Vec<u8>, u32)]) -> Result<u64> {
+	let hashes = blocks.iter().map(|(hash, _)| hash.clone()).collect::<Vec<_>>();
+	let numbers = blocks.iter().map(|(_, number)| i32::try_from(*number)).collect::<Result<Vec<_>, _>>()?;
+	let rows = sqlx::query(
+		"INSERT INTO blocks_without_events (hash, block_num)
+		SELECT * FROM UNNEST($1::bytea[], $2::int[])
+		ON CONFLICT DO NOTHING",
+	)
+	.bind(hashes)
+	.bind(numbers)
+	.execute(conn)
+	.await?;
+	Ok(rows.rows_affected())
+}
+
+/// Get the highest block with a timestamp at or before `timestamp`.
+/// Only blocks of which the extrinsics are indexed have a timestamp.
+pub async fn block_at_timestamp(conn: &mut PgConnection, timestamp: DateTime<Utc>) -> Result<Option<BlockModel>> {
+	let block = sqlx::query_as::<_, BlockModel>(
+		"SELECT id, parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec
+		FROM blocks
+		WHERE timestamp <= $1
+		ORDER BY timestamp DESC, block_num DESC
+		LIMIT 1",
+	)
+	.bind(timestamp)
+	.fetch_optional(conn)
+	.await?;
+	Ok(block)
+}
+
 /// Get upgrade blocks starting from a spec.
 /// Will always return one previous to `from`.
 /// So if you want upgrade specs `from` 30 for polkadot,
@@ -308,13 +676,161 @@ pub async fn past_and_present_version(
 	Ok(version)
 }
 
+/// Get the storage changes recorded at exactly `block_num`, ordered by key.
+/// Returns at most `limit` key/value pairs, skipping the first `offset`.
+/// A `None` value means the key was deleted in that block.
+/// Values offloaded to the blob store are returned as their reference;
+/// use [`Database::storage_value`](super::Database::storage_value) to resolve them.
+pub async fn storage_keys_changed_at(
+	conn: &mut PgConnection,
+	block_num: u32,
+	limit: u32,
+	offset: u32,
+) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+	let changes = sqlx::query_as::<_, (Vec<u8>, Option<Vec<u8>>)>(
+		"SELECT key, storage FROM storage WHERE block_num = $1 ORDER BY key LIMIT $2 OFFSET $3",
+	)
+	.bind(i32::try_from(block_num)?)
+	.bind(i64::from(limit))
+	.bind(i64::from(offset))
+	.fetch_all(conn)
+	.await?;
+	Ok(changes)
+}
+
+/// Get the extrinsics of `module` dispatching `call` (I.E `Balances` and `transfer`) within the blocks `blocks`,
+/// ordered by block number and index. Names are matched case-insensitively.
+/// If `signed` is set, only signed (or only unsigned) extrinsics are returned.
+/// Returns at most `limit` extrinsics, skipping the first `offset`.
+/// Calls dispatched by wrapper calls (I.E by `Utility::batch`) are matched as well, if they were decoded,
+/// so an extrinsic is returned once for every matching call it contains.
+pub async fn extrinsics_by_call(
+	conn: &mut PgConnection,
+	module: &str,
+	call: &str,
+	blocks: RangeInclusive<u32>,
+	signed: Option<bool>,
+	limit: u32,
+	offset: u32,
+) -> Result<Vec<ExtrinsicCall>> {
+	let rows = sqlx::query_as::<_, (i32, Vec<u8>, i32, i32, i32, bool, Option<Json<serde_json::Value>>)>(
+		"
+		SELECT c.block_num, c.hash, c.extrinsic_index, c.call_index, c.depth, c.signed, e.extrinsics -> c.extrinsic_index
+		FROM extrinsic_calls c
+		INNER JOIN extrinsics e ON e.hash = c.hash
+		WHERE c.module = $1 AND c.call = $2
+		AND c.block_num BETWEEN $3 AND $4
+		AND ($5::boolean IS NULL OR c.signed = $5)
+		ORDER BY c.block_num, c.extrinsic_index, c.call_index
+		LIMIT $6 OFFSET $7
+		",
+	)
+	.bind(module.to_ascii_lowercase())
+	.bind(call.to_ascii_lowercase())
+	.bind(i32::try_from(*blocks.start()).unwrap_or(i32::MAX))
+	.bind(i32::try_from(*blocks.end()).unwrap_or(i32::MAX))
+	.bind(signed)
+	.bind(i64::from(limit))
+	.bind(i64::from(offset))
+	.fetch_all(conn)
+	.await?;
+	Ok(rows
+		.into_iter()
+		.map(|(block_num, hash, index, call_index, depth, signed, extrinsic)| ExtrinsicCall {
+			block_num: block_num as u32,
+			hash,
+			index: index as u32,
+			call_index: call_index as u32,
+			depth: depth as u32,
+			signed,
+			extrinsic: extrinsic.map(|e| e.0).unwrap_or_default(),
+		})
+		.collect())
+}
+
+/// Get the digest items of `engine` (I.E `BABE`) within the blocks `blocks`, ordered by block number and index.
+/// If `kind` is set, only items of that kind (I.E `PreRuntime`) are returned.
+/// Items are only indexed if `index_digest_items` is enabled.
+pub async fn digest_items(
+	conn: &mut PgConnection,
+	engine: &str,
+	kind: Option<&str>,
+	blocks: RangeInclusive<u32>,
+) -> Result<Vec<DigestItemModel>> {
+	let items = sqlx::query_as::<_, DigestItemModel>(
+		"
+		SELECT hash, block_num, idx, kind, engine, payload, data
+		FROM digest_items
+		WHERE engine = $1
+		AND ($2::text IS NULL OR kind = $2)
+		AND block_num BETWEEN $3 AND $4
+		ORDER BY block_num, idx
+		",
+	)
+	.bind(engine)
+	.bind(kind)
+	.bind(i32::try_from(*blocks.start()).unwrap_or(i32::MAX))
+	.bind(i32::try_from(*blocks.end()).unwrap_or(i32::MAX))
+	.fetch_all(conn)
+	.await?;
+	Ok(items)
+}
+
+/// Number of storage entries fetched at a time by [`storage_for_block`].
+const STORAGE_PAGE_SIZE: i64 = 1_000;
+
+/// Stream the storage changes recorded at exactly `block_num`, ordered by key.
+/// Entries are fetched a page at a time, each page starting after the last entry of the previous one,
+/// so the changes of a block are never all loaded into memory at once.
+/// Values offloaded to the blob store are returned as their reference;
+/// use [`Database::storage_value`](super::Database::storage_value) to resolve them.
+pub fn storage_for_block(
+	conn: &mut PgConnection,
+	block_num: u32,
+) -> impl Stream<Item = Result<StorageModel<Vec<u8>>>> + '_ {
+	Box::pin(try_stream! {
+		let num = i32::try_from(block_num)?;
+		// a key may be stored more than once in a block, so pages are keyed by the ID as well.
+		let mut last: Option<(Vec<u8>, i32)> = None;
+		loop {
+			let page = sqlx::query_as::<_, (i32, Vec<u8>, bool, Vec<u8>, Option<Vec<u8>>, bool)>(
+				"
+				SELECT id, hash, is_full, key, storage, offloaded FROM storage
+				WHERE block_num = $1 AND ($2::bytea IS NULL OR (key, id) > ($2, $3))
+				ORDER BY key, id
+				LIMIT $4
+				",
+			)
+			.bind(num)
+			.bind(last.as_ref().map(|(key, _)| key.as_slice()))
+			.bind(last.as_ref().map_or(0, |(_, id)| *id))
+			.bind(STORAGE_PAGE_SIZE)
+			.fetch_all(&mut *conn)
+			.await?;
+			let is_last_page = page.len() < STORAGE_PAGE_SIZE as usize;
+			for (id, hash, is_full, key, storage, offloaded) in page {
+				last = Some((key.clone(), id));
+				let (data, reference) = if offloaded { (None, storage) } else { (storage.map(StorageData), None) };
+				let mut model = StorageModel::new(hash, block_num, is_full, StorageKey(key), data);
+				if let Some(reference) = reference {
+					model.offload(reference);
+				}
+				yield model;
+			}
+			if is_last_page {
+				break;
+			}
+		}
+	})
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use crate::{
 		database::{
-			models::{BlockModelDecoder, StorageModel},
-			Database,
+			models::{BlockModelDecoder, ExtrinsicsModel},
+			BlobStoreConfig, Database, DatabaseConfig, PersistentVersions, Sink,
 		},
 		types::BatchBlock,
 	};
@@ -322,9 +838,8 @@ mod tests {
 	use async_std::task;
 	use futures::StreamExt;
 	use sp_api::{BlockT, HeaderT};
-	use sp_storage::StorageKey;
 	use sqlx::{pool::PoolConnection, postgres::Postgres};
-	use test_common::TestGuard;
+	use test_common::{TestGuard, PG_POOL};
 
 	use polkadot_service::{Block, Hash};
 
@@ -367,6 +882,61 @@ mod tests {
 		Ok(database.conn().await?)
 	}
 
+	#[test]
+	fn should_find_indexing_gaps() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			sqlx::query("DELETE FROM blocks WHERE block_num = $1").bind(3_000_805_i32).execute(&mut conn).await?;
+			// the mock storage key stands in for the events
+			let gaps = indexing_gaps(&mut conn, 3_000_790, 20, &[0xDE, 0xAD, 0xBE, 0xEF]).await?;
+			assert_eq!(gaps.len(), 20);
+			let gap = |block_num, block, storage, extrinsics, events| IndexingGap {
+				block_num,
+				block,
+				storage,
+				extrinsics,
+				events,
+			};
+			assert_eq!(gaps[0], gap(3_000_790, false, false, true, true));
+			assert_eq!(gaps[11], gap(3_000_801, false, true, true, false));
+			assert_eq!(gaps[15], gap(3_000_805, true, false, false, false));
+
+			assert!(indexing_gaps(&mut conn, 3_001_001, 20, &[]).await?.is_empty());
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_get_full_block_by_hash() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let by_number = get_full_block_by_number(&mut conn, 3_000_001).await?;
+			let by_hash = get_full_block_by_hash(&mut conn, &by_number.hash).await?;
+			assert_eq!(by_hash.block_num, 3_000_001);
+			assert_eq!(by_hash.hash, by_number.hash);
+			assert_eq!(by_hash.ext, by_number.ext);
+			assert!(get_full_block_by_hash(&mut conn, &[0xDE, 0xAD]).await.is_err());
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_delete_block_storage() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			assert_eq!(delete_block_storage(&mut conn, 3_000_001).await?, 1);
+			assert_eq!(delete_block_storage(&mut conn, 3_000_001).await?, 0);
+			assert_eq!(has_storage(&[3_000_001, 3_000_002], &mut conn).await?, vec![3_000_002]);
+			Ok(())
+		})
+	}
+
 	#[test]
 	fn should_get_missing_storage() -> Result<(), Error> {
 		crate::initialize();
@@ -380,6 +950,321 @@ mod tests {
 		Ok(())
 	}
 
+	#[test]
+	fn should_skip_blocks_of_specs() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let blocks = blocks_missing_extrinsics(&mut conn, 10, &[]).await?;
+			assert_eq!(blocks.len(), 10);
+			let spec = blocks[0].3 as i32;
+			let blocks = blocks_missing_extrinsics(&mut conn, 10, &[spec]).await?;
+			assert!(blocks.iter().all(|b| b.3 as i32 != spec));
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_get_storage_keys_changed_at_block() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let (hash, block_num): (Vec<u8>, i32) =
+				sqlx::query_as("SELECT hash, block_num FROM blocks WHERE block_num = $1")
+					.bind((BLOCK_START + 900) as i32)
+					.fetch_one(&mut conn)
+					.await?;
+			let hash = Hash::from_slice(&hash);
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let storage = (0u8..5)
+				.map(|i| {
+					let value = if i == 4 { None } else { Some(StorageData(vec![i; 4])) };
+					StorageModel::new(hash, block_num as u32, false, StorageKey(vec![i]), value)
+				})
+				.collect::<Vec<StorageModel<Hash>>>();
+			database.insert(storage).await?;
+
+			let changes = storage_keys_changed_at(&mut conn, block_num as u32, 10, 0).await?;
+			assert_eq!(changes.len(), 5);
+			assert_eq!(changes[0], (vec![0], Some(vec![0; 4])));
+			assert_eq!(changes[4], (vec![4], None));
+
+			let page = storage_keys_changed_at(&mut conn, block_num as u32, 2, 2).await?;
+			assert_eq!(page, vec![(vec![2], Some(vec![2; 4])), (vec![3], Some(vec![3; 4]))]);
+
+			// the mock storage of other blocks is left out
+			let other = storage_keys_changed_at(&mut conn, BLOCK_START as u32 + 1, 10, 0).await?;
+			assert_eq!(other, vec![(vec![0xDE, 0xAD, 0xBE, 0xEF], None)]);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_get_extrinsics_by_call() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let transfer = |signed: bool| {
+				let signature = if signed { serde_json::json!({ "address": "0x00" }) } else { serde_json::Value::Null };
+				serde_json::json!({ "signature": signature, "call": { "module": "Balances", "name": "transfer" } })
+			};
+			let timestamp = serde_json::json!({ "signature": null, "call": { "module": "Timestamp", "name": "set" } });
+			let batch = serde_json::json!({
+				"signature": { "address": "0x00" },
+				"call": {
+					"module": "Utility",
+					"name": "batch",
+					"args": [["calls", [{ "module": "Balances", "name": "transfer" }]]]
+				}
+			});
+			let mut extrinsics = Vec::new();
+			for block_num in [3_000_010_i32, 3_000_020, 3_000_030] {
+				let (hash,): (Vec<u8>,) = sqlx::query_as("SELECT hash FROM blocks WHERE block_num = $1")
+					.bind(block_num)
+					.fetch_one(&mut conn)
+					.await?;
+				let mut exts = vec![timestamp.clone(), transfer(true), transfer(block_num == 3_000_020)];
+				if block_num == 3_000_030 {
+					exts.push(batch.clone());
+				}
+				extrinsics.push(ExtrinsicsModel { id: None, hash, number: block_num, extrinsics: Json(exts) });
+			}
+			database.insert_extrinsics(extrinsics, Vec::new()).await?;
+
+			let found =
+				extrinsics_by_call(&mut conn, "Balances", "transfer", 3_000_000..=3_000_020, None, 10, 0).await?;
+			assert_eq!(found.len(), 4);
+			assert_eq!((found[0].block_num, found[0].index), (3_000_010, 1));
+			assert_eq!(found[0].extrinsic, transfer(true));
+			assert_eq!((found[3].block_num, found[3].index), (3_000_020, 2));
+
+			let unsigned =
+				extrinsics_by_call(&mut conn, "balances", "transfer", 3_000_000..=3_000_030, Some(false), 10, 0)
+					.await?;
+			let unsigned = unsigned.iter().map(|e| (e.block_num, e.index)).collect::<Vec<_>>();
+			assert_eq!(unsigned, [(3_000_010, 2), (3_000_030, 2)]);
+
+			// the transfer dispatched by the batch is found on its own
+			let batched =
+				extrinsics_by_call(&mut conn, "Balances", "transfer", 3_000_030..=3_000_030, Some(true), 10, 0).await?;
+			let batched = batched.iter().map(|e| (e.index, e.call_index, e.depth)).collect::<Vec<_>>();
+			assert_eq!(batched, [(1, 0, 0), (3, 1, 1)]);
+
+			let page = extrinsics_by_call(&mut conn, "Balances", "transfer", 0..=u32::MAX, Some(true), 2, 1).await?;
+			let page = page.iter().map(|e| (e.block_num, e.index)).collect::<Vec<_>>();
+			assert_eq!(page, [(3_000_020, 1), (3_000_020, 2)]);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_get_digest_items_of_engine() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let item = |block_num: i32, idx: i32, kind: &str, engine: Option<&str>| DigestItemModel {
+				hash: block_num.to_le_bytes().to_vec(),
+				block_num,
+				idx,
+				kind: kind.to_string(),
+				engine: engine.map(String::from),
+				payload: (kind == "PreRuntime").then(|| Json(serde_json::json!({ "slot": block_num }))),
+				data: Vec::new(),
+			};
+			let mut items = Vec::new();
+			for block_num in [10, 20, 30] {
+				items.push(item(block_num, 0, "PreRuntime", Some("BABE")));
+				items.push(item(block_num, 1, "Other", None));
+				items.push(item(block_num, 2, "Seal", Some("BABE")));
+			}
+			items.push(item(25, 0, "PreRuntime", Some("aura")));
+			database.insert(items).await?;
+
+			let found = digest_items(&mut conn, "BABE", Some("PreRuntime"), 10..=20).await?;
+			let slots = found.iter().map(|i| i.payload.as_ref().unwrap().0["slot"].as_u64()).collect::<Vec<_>>();
+			assert_eq!(slots, [Some(10), Some(20)]);
+
+			let found = digest_items(&mut conn, "BABE", None, 0..=u32::MAX).await?;
+			let found = found.iter().map(|i| (i.block_num, i.idx)).collect::<Vec<_>>();
+			assert_eq!(found, [(10, 0), (10, 2), (20, 0), (20, 2), (30, 0), (30, 2)]);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_stream_storage_for_block() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let (hash, block_num): (Vec<u8>, i32) =
+				sqlx::query_as("SELECT hash, block_num FROM blocks WHERE block_num = $1")
+					.bind((BLOCK_START + 950) as i32)
+					.fetch_one(&mut conn)
+					.await?;
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			// more entries than fit in a single page
+			let storage = (0u16..1_005)
+				.rev()
+				.map(|i| {
+					let key = StorageKey(i.to_be_bytes().to_vec());
+					StorageModel::new(Hash::from_slice(&hash), block_num as u32, false, key, Some(StorageData(vec![1])))
+				})
+				.collect::<Vec<StorageModel<Hash>>>();
+			database.insert(storage).await?;
+
+			let storage = storage_for_block(&mut conn, block_num as u32).collect::<Vec<_>>().await;
+			let storage = storage.into_iter().collect::<Result<Vec<_>>>()?;
+			assert_eq!(storage.len(), 1_005);
+			assert!(storage.iter().all(|s| s.block_num() == block_num as u32 && s.hash() == &hash));
+			let keys = storage.iter().map(|s| s.key().0.clone()).collect::<Vec<_>>();
+			assert_eq!(keys, (0u16..1_005).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>());
+
+			let other = storage_for_block(&mut conn, BLOCK_START as u32 + 1).collect::<Vec<_>>().await;
+			assert_eq!(other.len(), 1);
+			assert!(storage_for_block(&mut conn, BLOCK_START as u32 + 900).next().await.is_none());
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_offload_large_storage_values() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let (hash, block_num): (Vec<u8>, i32) =
+				sqlx::query_as("SELECT hash, block_num FROM blocks LIMIT 1").fetch_one(&mut conn).await?;
+			let hash = Hash::from_slice(&hash);
+
+			let blobs = tempfile::tempdir()?;
+			let mut config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			config.blob_store = Some(BlobStoreConfig { path: blobs.path().to_path_buf(), threshold: 16 });
+			let database = Database::with_config(&config).await?;
+
+			let large = vec![0xAB; 64];
+			let small = vec![0xCD; 8];
+			let mut storage = vec![
+				StorageModel::new(
+					hash,
+					block_num as u32,
+					false,
+					StorageKey(b":code".to_vec()),
+					Some(StorageData(large.clone())),
+				),
+				StorageModel::new(
+					hash,
+					block_num as u32,
+					false,
+					StorageKey(b"small".to_vec()),
+					Some(StorageData(small.clone())),
+				),
+			];
+			database.offload_storage(&mut storage).await?;
+			assert!(storage[0].is_offloaded());
+			assert!(!storage[1].is_offloaded());
+			database.insert(storage).await?;
+
+			let (stored, offloaded): (Vec<u8>, bool) =
+				sqlx::query_as("SELECT storage, offloaded FROM storage WHERE key = $1")
+					.bind(b":code".as_ref())
+					.fetch_one(&mut conn)
+					.await?;
+			assert!(offloaded);
+			assert_ne!(stored, large);
+			assert_eq!(database.storage_value(hash.as_ref(), b":code").await?, Some(large));
+			assert_eq!(database.storage_value(hash.as_ref(), b"small").await?, Some(small));
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_bulk_copy_storage() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let blocks: Vec<(Vec<u8>, i32)> =
+				sqlx::query_as("SELECT hash, block_num FROM blocks WHERE block_num < $1 ORDER BY block_num LIMIT 2")
+					.bind((BLOCK_START + 200) as i32)
+					.fetch_all(&mut conn)
+					.await?;
+			let storage = |(hash, block_num): &(Vec<u8>, i32)| {
+				(0u16..5000)
+					.map(|i| {
+						let key = StorageKey(i.to_be_bytes().to_vec());
+						StorageModel::new(
+							Hash::from_slice(hash),
+							*block_num as u32,
+							false,
+							key,
+							Some(StorageData(vec![0xAB; 32])),
+						)
+					})
+					.collect::<Vec<StorageModel<Hash>>>()
+			};
+			let config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			let upsert = Database::with_config(&config).await?;
+			let copy = Database::with_config(&config.bulk_copy(true)).await?;
+
+			let now = std::time::Instant::now();
+			assert_eq!(upsert.concurrent_insert(storage(&blocks[0])).await?, 5000);
+			log::info!("Upserting 5000 storage changes took {:?}", now.elapsed());
+			let now = std::time::Instant::now();
+			assert_eq!(copy.copy_storage(storage(&blocks[1])).await?, 5000);
+			log::info!("Copying 5000 storage changes took {:?}", now.elapsed());
+
+			// copying storage which was already inserted falls back to upserting it
+			assert_eq!(copy.copy_storage(storage(&blocks[1])).await?, 5000);
+			let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM storage WHERE block_num = $1")
+				.bind(blocks[1].1)
+				.fetch_one(&mut conn)
+				.await?;
+			assert_eq!(count, 5000);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_cache_storage_reads() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let (hash, block_num): (Vec<u8>, i32) =
+				sqlx::query_as("SELECT hash, block_num FROM blocks LIMIT 1").fetch_one(&mut conn).await?;
+			let hash = Hash::from_slice(&hash);
+
+			let mut config = DatabaseConfig::new(test_common::DATABASE_URL.to_string());
+			config.storage_cache_size = Some(16);
+			let database = Database::with_config(&config).await?;
+			let uncached = Database::new(&test_common::DATABASE_URL).await?;
+
+			let value = vec![0xAB; 8];
+			let storage = vec![StorageModel::new(
+				hash,
+				block_num as u32,
+				false,
+				StorageKey(b"hot".to_vec()),
+				Some(StorageData(value.clone())),
+			)];
+			database.insert(storage).await?;
+			assert_eq!(database.storage_value_at(block_num as u32, b"hot").await?, Some(value.clone()));
+
+			// the second read does not reach PostgreSQL
+			sqlx::query("DELETE FROM storage WHERE key = $1").bind(b"hot".as_ref()).execute(&mut conn).await?;
+			assert_eq!(database.storage_value_at(block_num as u32, b"hot").await?, Some(value));
+			assert_eq!(uncached.storage_value_at(block_num as u32, b"hot").await?, None);
+			Ok(())
+		})
+	}
+
 	#[test]
 	fn should_paginate_blocks() -> Result<(), Error> {
 		crate::initialize();
@@ -400,4 +1285,185 @@ mod tests {
 			Ok(())
 		})
 	}
+
+	#[test]
+	fn should_count_blocks_in_range() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			let start = BLOCK_START as u32;
+			assert_eq!(block_count_in_range(&mut conn, start + 1, start + 100).await?, 100);
+			assert_eq!(block_count_in_range(&mut conn, start + 901, start + 2000).await?, 100);
+			assert_eq!(block_count_in_range(&mut conn, 0, u32::MAX).await?, 1000);
+			assert_eq!(block_count_in_range(&mut conn, start + 50, start + 10).await?, 0);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_load_persisted_runtime_versions() -> Result<(), Error> {
+		use substrate_archive_backend::PersistVersion;
+
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		let persist = PersistentVersions::new(PG_POOL.clone());
+		let version = RuntimeVersion { spec_version: 9110, ..Default::default() };
+		// a hash which does not fit into a signed bigint
+		let code_hash = u64::MAX - 42;
+		persist.persist(code_hash, &[0xAB; 32], &version)?;
+		// persisting twice is a no-op
+		persist.persist(code_hash, &[0xCD; 32], &version)?;
+
+		// a cold start only has what was persisted.
+		let mut conn = task::block_on(PG_POOL.acquire())?;
+		let versions = task::block_on(runtime_versions_cache(&mut conn))?;
+		assert_eq!(versions, vec![(code_hash, version)]);
+		Ok(())
+	}
+
+	#[test]
+	fn should_compute_tip_lag() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			assert_eq!(tip_lag(&mut conn, 50).await?, 50);
+
+			let mut conn = setup_data_scheme().await?;
+			assert_eq!(tip_lag(&mut conn, 3_001_050).await?, 50);
+			assert_eq!(tip_lag(&mut conn, 3_001_000).await?, 0);
+			// the backend may lag behind what has been indexed
+			assert_eq!(tip_lag(&mut conn, 2_000_000).await?, 0);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_route_reads_to_replica() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let url = test_common::DATABASE_URL.to_string();
+			let separator = if url.contains('?') { '&' } else { '?' };
+			let mut config = DatabaseConfig::new(url.as_str());
+			config.read_replica_url = Some(format!("{}{}application_name=sa_read_replica", url, separator));
+
+			let app_name = "SELECT current_setting('application_name')";
+			let database = Database::with_config(&config).await?;
+			let (replica,): (String,) = sqlx::query_as(app_name).fetch_one(database.read_pool()).await?;
+			let (primary,): (String,) = sqlx::query_as(app_name).fetch_one(database.pool()).await?;
+			assert_eq!(replica, "sa_read_replica");
+			assert_ne!(primary, "sa_read_replica");
+
+			// without a replica, reads go to the primary.
+			let database = Database::new(&url).await?;
+			let (name,): (String,) = sqlx::query_as(app_name).fetch_one(database.read_pool()).await?;
+			assert_eq!(name, primary);
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_roll_back_failed_transaction() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2)")
+				.bind(26_i32)
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(database.pool())
+				.await?;
+			let blocks: Vec<BlockModel> = test_common::get_kusama_blocks()?.drain(0..1).map(BlockModel::from).collect();
+			let block = BlockModelDecoder::<Block>::with_vec(blocks)?.remove(0);
+			let hash = block.inner.block.hash();
+			let block_num = *block.inner.block.header().number();
+			let extrinsics = vec![ExtrinsicsModel::new(hash.as_ref().to_vec(), block_num, Vec::new())?];
+			let storage = vec![StorageModel::new(hash, block_num, false, StorageKey(vec![0xAB]), None)];
+
+			let res = database
+				.transaction(|conn| {
+					Box::pin(async move {
+						block.insert(conn).await?;
+						extrinsics.insert(conn).await?;
+						storage.insert(conn).await?;
+						Err::<(), _>(ArchiveError::from("failed mid-transaction"))
+					})
+				})
+				.await;
+			assert!(res.is_err());
+
+			let mut conn = database.conn().await?;
+			for table in &["blocks", "extrinsics", "storage"] {
+				let (count,): (i64,) =
+					sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table)).fetch_one(&mut conn).await?;
+				assert_eq!(count, 0, "{} was not rolled back", table);
+			}
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn should_get_block_at_timestamp() -> Result<(), Error> {
+		use chrono::{Duration, TimeZone};
+
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = setup_data_scheme().await?;
+			// one block every 6 seconds, starting at the first block of the dataset
+			let start = Utc.timestamp(1_600_000_000, 0);
+			let blocks: Vec<(i32, Vec<u8>)> =
+				sqlx::query_as("SELECT block_num, hash FROM blocks ORDER BY block_num").fetch_all(&mut conn).await?;
+			let timestamps = blocks
+				.into_iter()
+				.map(|(num, hash)| (hash, start + Duration::seconds(6 * i64::from(num - BLOCK_START as i32 - 1))))
+				.collect::<Vec<_>>();
+			set_block_timestamps(&mut conn, &timestamps).await?;
+
+			let block = block_at_timestamp(&mut conn, start + Duration::seconds(63)).await?.expect("block exists");
+			assert_eq!(block.block_num, 3_000_011);
+			let block = block_at_timestamp(&mut conn, start + Duration::seconds(66)).await?.expect("block exists");
+			assert_eq!(block.block_num, 3_000_012);
+			let block = block_at_timestamp(&mut conn, Utc::now()).await?.expect("block exists");
+			assert_eq!(block.block_num, 3_001_000);
+			assert!(block_at_timestamp(&mut conn, start - Duration::seconds(1)).await?.is_none());
+			Ok::<(), Error>(())
+		})?;
+		Ok(())
+	}
+
+	#[test]
+	fn should_compute_indexing_rate() -> Result<(), Error> {
+		use std::time::Duration;
+
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			assert_eq!(indexing_rate(&mut conn, Duration::from_secs(3600)).await?, 0.0);
+
+			// 9000 blocks in the first half hour, 3600 in the second
+			sqlx::query(
+				"INSERT INTO indexing_progress (sampled_at, max_block) VALUES
+				(now() - interval '3600 seconds', 1000),
+				(now() - interval '1800 seconds', 10000),
+				(now(), 13600)",
+			)
+			.execute(&mut conn)
+			.await?;
+			assert_eq!(indexing_rate(&mut conn, Duration::from_secs(2 * 3600)).await?, 3.5);
+			assert_eq!(indexing_rate(&mut conn, Duration::from_secs(45 * 60)).await?, 2.0);
+			// a single sample has no rate
+			assert_eq!(indexing_rate(&mut conn, Duration::from_secs(10 * 60)).await?, 0.0);
+
+			// no blocks are indexed, so the lag is the best block
+			let eta = indexing_eta(&mut conn, 7000, Duration::from_secs(2 * 3600)).await?;
+			assert_eq!(eta, Some(Duration::from_secs(2000)));
+			assert_eq!(indexing_eta(&mut conn, 7000, Duration::from_secs(10 * 60)).await?, None);
+			Ok::<(), Error>(())
+		})?;
+		Ok(())
+	}
 }
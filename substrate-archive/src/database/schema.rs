@@ -0,0 +1,116 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Description of the schema created by the migrations,
+//! for tooling which queries the database of the archive directly.
+
+use serde::Serialize;
+use sqlx::{migrate::Migrator, PgConnection};
+
+use crate::error::Result;
+
+/// Migrations of the archive database.
+pub(crate) static MIGRATOR: Migrator = sqlx::migrate!("./src/migrations/");
+
+/// Tables of the archive database and the migration versions they were created by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaDescription {
+	/// Version of the latest migration applied to the database, if any were.
+	pub version: Option<i64>,
+	/// Version of the latest migration this release of the archive expects.
+	pub expected_version: i64,
+	/// Tables, ordered by name.
+	pub tables: Vec<TableDescription>,
+}
+
+impl SchemaDescription {
+	/// Get the table named `name`.
+	pub fn table(&self, name: &str) -> Option<&TableDescription> {
+		self.tables.iter().find(|t| t.name == name)
+	}
+}
+
+/// A table of the archive database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TableDescription {
+	pub name: String,
+	/// Columns, in the order they are defined in.
+	pub columns: Vec<ColumnDescription>,
+}
+
+/// A column of an archive table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnDescription {
+	pub name: String,
+	/// PostgreSQL type of the column (I.E `bytea` or `integer`).
+	pub data_type: String,
+	pub nullable: bool,
+}
+
+/// Describe the schema of the database `conn` is connected to.
+/// The bookkeeping table of the migrations is left out.
+pub async fn describe(conn: &mut PgConnection) -> Result<SchemaDescription> {
+	let (version,): (Option<i64>,) =
+		sqlx::query_as("SELECT MAX(version) FROM _sqlx_migrations WHERE success").fetch_one(&mut *conn).await?;
+	let expected_version = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+
+	let columns = sqlx::query_as::<_, (String, String, String, String)>(
+		"SELECT table_name::text, column_name::text, data_type::text, is_nullable::text
+		FROM information_schema.columns
+		WHERE table_schema = current_schema() AND table_name != '_sqlx_migrations'
+		ORDER BY table_name, ordinal_position",
+	)
+	.fetch_all(conn)
+	.await?;
+	let mut tables: Vec<TableDescription> = Vec::new();
+	for (table, name, data_type, nullable) in columns {
+		let column = ColumnDescription { name, data_type, nullable: nullable == "YES" };
+		match tables.last_mut() {
+			Some(t) if t.name == table => t.columns.push(column),
+			_ => tables.push(TableDescription { name: table, columns: vec![column] }),
+		}
+	}
+	Ok(SchemaDescription { version, expected_version, tables })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Error;
+	use async_std::task;
+	use test_common::{TestGuard, PG_POOL};
+
+	#[test]
+	fn should_describe_core_tables() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		task::block_on(async {
+			let mut conn = PG_POOL.acquire().await?;
+			let schema = describe(&mut conn).await?;
+			assert_eq!(schema.version, Some(schema.expected_version));
+			for table in ["blocks", "storage", "metadata", "extrinsics", "state_traces"] {
+				assert!(schema.table(table).is_some(), "missing table {}", table);
+			}
+			assert!(schema.table("_sqlx_migrations").is_none());
+			let blocks = schema.table("blocks").unwrap();
+			let hash = blocks.columns.iter().find(|c| c.name == "hash").expect("blocks have a hash");
+			assert_eq!(hash.data_type, "bytea");
+			assert!(!hash.nullable);
+			Ok::<(), Error>(())
+		})?;
+		Ok(())
+	}
+}
@@ -0,0 +1,195 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Destinations for indexed data.
+//! PostgreSQL is the default sink, but anything implementing [`Sink`] may be plugged
+//! into the `DatabaseActor` instead.
+
+use std::time::Duration;
+
+use futures_timer::Delay;
+use hashbrown::HashSet;
+
+use super::{
+	models::{BlockModel, DigestItemModel, EventModel, ExtrinsicsModel, RuntimeUpgradeModel, StorageModel},
+	outbox::{OutboxJob, PgOutbox},
+	queries, Database, Insert,
+};
+use crate::error::Result;
+pub use crate::{
+	types::{Metadata, UndecodableBlock, UnexecutableBlock},
+	wasm_tracing::Traces,
+};
+
+/// Somewhere indexed data is written to.
+/// Every method returns the number of rows written.
+#[async_trait::async_trait]
+pub trait Sink: Send + Sync {
+	/// Insert blocks, along with the items of their digests.
+	/// `jobs` are the jobs executing the blocks, which are stored in the outbox along with them.
+	async fn insert_blocks(
+		&self,
+		blocks: Vec<BlockModel>,
+		digest_items: Vec<DigestItemModel>,
+		jobs: Vec<OutboxJob>,
+	) -> Result<u64>;
+
+	/// Insert the storage changes of executed blocks.
+	async fn insert_storage(&self, storage: Vec<StorageModel<Vec<u8>>>) -> Result<u64>;
+
+	/// Insert decoded extrinsics, along with the runtime upgrades dispatched by them.
+	async fn insert_extrinsics(
+		&self,
+		extrinsics: Vec<ExtrinsicsModel>,
+		upgrades: Vec<RuntimeUpgradeModel>,
+	) -> Result<u64>;
+
+	/// Insert the traces collected while executing a block.
+	async fn insert_traces(&self, traces: Traces) -> Result<u64>;
+
+	/// Insert the events emitted in executed blocks. Ignored by default.
+	async fn insert_events(&self, _events: Vec<EventModel>) -> Result<u64> {
+		Ok(0)
+	}
+
+	/// Record executed blocks, as pairs of hash and number, which emitted no events. Ignored by default.
+	async fn insert_blocks_without_events(&self, _blocks: Vec<(Vec<u8>, u32)>) -> Result<u64> {
+		Ok(0)
+	}
+
+	/// Insert the metadata of a runtime version. Ignored by default.
+	async fn insert_metadata(&self, _meta: Metadata) -> Result<u64> {
+		Ok(0)
+	}
+
+	/// Record a block which can not be executed. Ignored by default.
+	async fn insert_unexecutable_block(&self, _block: UnexecutableBlock<Vec<u8>>) -> Result<u64> {
+		Ok(0)
+	}
+
+	/// Record blocks whose extrinsics can not be decoded. Ignored by default.
+	async fn insert_undecodable_blocks(&self, _blocks: Vec<UndecodableBlock>) -> Result<u64> {
+		Ok(0)
+	}
+}
+
+#[async_trait::async_trait]
+impl Sink for Database {
+	async fn insert_blocks(
+		&self,
+		blocks: Vec<BlockModel>,
+		digest_items: Vec<DigestItemModel>,
+		jobs: Vec<OutboxJob>,
+	) -> Result<u64> {
+		// blocks reference the metadata of their spec
+		let specs: HashSet<u32> = blocks.iter().map(|b| b.spec as u32).collect();
+		let mut conn = self.conn().await?;
+		while !specs.is_subset(&queries::get_versions(&mut conn).await?.into_iter().collect()) {
+			log::info!("Doesn't contain metadata");
+			Delay::new(Duration::from_millis(50)).await;
+		}
+		std::mem::drop(conn);
+		self.transaction(|conn| {
+			Box::pin(async move {
+				let rows = blocks.insert(conn).await?;
+				if !digest_items.is_empty() {
+					digest_items.insert(conn).await?;
+				}
+				// a crash after the commit can't lose the execution of the blocks
+				PgOutbox::new(conn).store_all(jobs).await?;
+				Ok(rows)
+			})
+		})
+		.await
+	}
+
+	async fn insert_storage(&self, mut storage: Vec<StorageModel<Vec<u8>>>) -> Result<u64> {
+		// storage references the blocks it was changed in
+		let mut block_nums = storage.iter().map(|s| s.block_num()).collect::<Vec<_>>();
+		block_nums.sort_unstable();
+		block_nums.dedup();
+		let mut conn = self.conn().await?;
+		let now = std::time::Instant::now();
+		while queries::has_blocks(block_nums.as_slice(), &mut conn).await?.len() != block_nums.len() {
+			Delay::new(Duration::from_millis(50)).await;
+		}
+		log::debug!("Insert Integrity Query Check took {:?}", now.elapsed());
+		// we drop the connection early so that the insert() has the use of all db connections
+		std::mem::drop(conn);
+		self.offload_storage(&mut storage).await?;
+		if self.bulk_copy {
+			self.copy_storage(storage).await
+		} else {
+			self.concurrent_insert(storage).await
+		}
+	}
+
+	async fn insert_extrinsics(
+		&self,
+		extrinsics: Vec<ExtrinsicsModel>,
+		upgrades: Vec<RuntimeUpgradeModel>,
+	) -> Result<u64> {
+		let mut timestamps = Vec::new();
+		let mut calls = Vec::new();
+		for ext in extrinsics.iter() {
+			if let Some(timestamp) = ext.timestamp()? {
+				timestamps.push((ext.hash.clone(), timestamp));
+			}
+			calls.extend(ext.calls());
+		}
+		// the extrinsics, their calls, the runtime upgrades found in them and the block timestamps are committed together
+		self.transaction(|conn| {
+			Box::pin(async move {
+				let rows = extrinsics.insert(conn).await?;
+				calls.insert(conn).await?;
+				if !upgrades.is_empty() {
+					log::info!("Indexing {} runtime upgrades", upgrades.len());
+					upgrades.insert(conn).await?;
+				}
+				if !timestamps.is_empty() {
+					queries::set_block_timestamps(conn, &timestamps).await?;
+				}
+				Ok(rows)
+			})
+		})
+		.await
+	}
+
+	async fn insert_traces(&self, traces: Traces) -> Result<u64> {
+		self.insert(traces).await
+	}
+
+	async fn insert_events(&self, events: Vec<EventModel>) -> Result<u64> {
+		self.insert(events).await
+	}
+
+	async fn insert_blocks_without_events(&self, blocks: Vec<(Vec<u8>, u32)>) -> Result<u64> {
+		let mut conn = self.conn().await?;
+		queries::insert_blocks_without_events(&mut conn, &blocks).await
+	}
+
+	async fn insert_metadata(&self, meta: Metadata) -> Result<u64> {
+		self.insert(meta).await
+	}
+
+	async fn insert_unexecutable_block(&self, block: UnexecutableBlock<Vec<u8>>) -> Result<u64> {
+		self.insert(block).await
+	}
+
+	async fn insert_undecodable_blocks(&self, blocks: Vec<UndecodableBlock>) -> Result<u64> {
+		self.insert(blocks).await
+	}
+}
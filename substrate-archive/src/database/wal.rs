@@ -0,0 +1,82 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Write-ahead log of block storage which has been computed but is not yet committed to PostgreSQL.
+//! Entries are replayed on startup, so blocks executed before a crash do not have to be executed again.
+
+use std::{io, path::PathBuf};
+
+use async_std::{fs, prelude::*};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{error::Result, types::Storage};
+
+/// Write-ahead log on the local filesystem, with one file per block.
+#[derive(Clone, Debug)]
+pub struct WriteAheadLog {
+	path: PathBuf,
+}
+
+impl WriteAheadLog {
+	pub async fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+		let path = path.into();
+		fs::create_dir_all(&path).await?;
+		Ok(Self { path })
+	}
+
+	/// Durably record the storage changes of a block.
+	pub async fn append<H: Serialize + AsRef<[u8]>>(&self, storage: &Storage<H>) -> Result<()> {
+		let path = self.entry_path(storage.block_num(), storage.hash());
+		// write to a temporary file first, so a partially written entry is never replayed.
+		let tmp = path.with_extension("tmp");
+		let mut file = fs::File::create(&tmp).await?;
+		file.write_all(&serde_json::to_vec(storage)?).await?;
+		file.sync_all().await?;
+		fs::rename(&tmp, &path).await?;
+		Ok(())
+	}
+
+	/// Remove the entries of blocks whose storage has been committed to PostgreSQL.
+	pub async fn commit<H: AsRef<[u8]>>(&self, blocks: &[(u32, H)]) -> Result<()> {
+		for (block_num, hash) in blocks {
+			match fs::remove_file(self.entry_path(*block_num, hash)).await {
+				Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+				_ => (),
+			}
+		}
+		Ok(())
+	}
+
+	/// Read back all entries which were never committed, ordered by block number.
+	pub async fn replay<H: DeserializeOwned>(&self) -> Result<Vec<Storage<H>>> {
+		let mut storages = Vec::new();
+		let mut entries = fs::read_dir(&self.path).await?;
+		while let Some(entry) = entries.next().await {
+			let path = entry?.path();
+			match path.extension().and_then(|ext| ext.to_str()) {
+				Some("json") => storages.push(serde_json::from_slice::<Storage<H>>(&fs::read(&path).await?)?),
+				Some("tmp") => fs::remove_file(&path).await?,
+				_ => (),
+			}
+		}
+		storages.sort_by_key(|s| s.block_num());
+		Ok(storages)
+	}
+
+	fn entry_path<H: AsRef<[u8]>>(&self, block_num: u32, hash: &H) -> async_std::path::PathBuf {
+		self.path.join(format!("{}-{}.json", block_num, hex::encode(hash))).into()
+	}
+}
@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::{env, fmt, io, num};
+use std::{env, fmt, io, num, path::PathBuf, time::Duration};
 use thiserror::Error;
 
 pub type Result<T, E = ArchiveError> = std::result::Result<T, E>;
@@ -85,8 +85,27 @@ pub enum ArchiveError {
 	#[error("Previous Spec {0} not found")]
 	PrevSpecNotFound(u32),
 
+	#[error("Execution of a block with spec {spec} timed out after {timeout:?}")]
+	ExecutionTimeout { spec: u32, timeout: Duration },
+	#[error("Blocks with spec {0} are quarantined after repeatedly timing out")]
+	SpecQuarantined(u32),
+	#[error("{0} timed out block executions are still running, no more blocks are executed until they stop")]
+	RunawayExecutions(usize),
+	#[error("State of the parent of block {block} is not available, it may have been pruned")]
+	ParentStatePruned { block: u32 },
+
 	#[error(transparent)]
 	Desub(#[from] desub::Error),
+
+	#[error("Metadata: {0}")]
+	Metadata(#[from] MetadataError),
+
+	#[error("Invalid configuration: {0}")]
+	Config(#[from] ConfigError),
+
+	#[cfg(feature = "metrics")]
+	#[error("Metrics: {0}")]
+	Prometheus(#[from] prometheus::Error),
 }
 
 #[derive(Error, Debug)]
@@ -99,6 +118,31 @@ pub enum TracingError {
 	ParentNotFound,
 	#[error("Wrong Type")]
 	TypeError,
+	#[error("Invalid tracing targets: {0}")]
+	InvalidTargets(#[from] tracing_subscriber::filter::ParseError),
+}
+
+#[derive(Error, Debug)]
+pub enum MetadataError {
+	#[error("Metadata for spec {spec} is corrupt or unsupported: {reason}")]
+	Undecodable { spec: u32, reason: String },
+}
+
+/// A problem with the configuration of the archive, as reported by `ArchiveBuilder::validate`.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ConfigError {
+	#[error("No chain data path was given and `CHAIN_DATA_DB` is not set")]
+	MissingChainPath,
+	#[error("Chain data path `{0}` does not exist")]
+	ChainPathNotFound(PathBuf),
+	#[error("No database url was given and `DATABASE_URL` is not set")]
+	MissingDatabaseUrl,
+	#[error("Invalid {field} `{url}`: {reason}")]
+	InvalidUrl { field: &'static str, url: String, reason: String },
+	#[error("Invalid {field}: {reason}")]
+	InvalidValue { field: &'static str, reason: String },
+	#[error("Tracing folder `{path}` is not readable: {reason}")]
+	TracingFolder { path: PathBuf, reason: String },
 }
 
 impl From<sp_blockchain::Error> for ArchiveError {
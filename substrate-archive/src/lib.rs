@@ -36,21 +36,37 @@
 // Re-Exports
 pub use sp_blockchain::Error as BlockchainError;
 pub use sp_runtime::MultiSignature;
-pub use substrate_archive_backend::{ExecutionMethod, ReadOnlyDb, RuntimeConfig, SecondaryRocksDb};
+pub use substrate_archive_backend::{
+	BackendError, CatchUpStats, ExecutionMethod, ReadOnlyBackend, ReadOnlyDb, RuntimeConfig, SecondaryRocksDb,
+};
 
 mod actors;
 pub mod archive;
+mod checkpoint;
 pub mod database;
 mod error;
 mod logger;
+#[cfg(feature = "rpc")]
+mod rpc;
+mod slow_log;
 mod tasks;
 mod types;
 mod wasm_tracing;
 
-pub use self::actors::{ControlConfig, System};
-pub use self::archive::{Archive, ArchiveBuilder, ArchiveConfig, ChainConfig, TracingConfig};
-pub use self::database::{queries, DatabaseConfig};
-pub use self::error::ArchiveError;
+#[cfg(feature = "health")]
+pub use self::actors::HealthActor;
+#[cfg(feature = "metrics")]
+pub use self::actors::MetricsActor;
+pub use self::actors::{ControlConfig, DuplicateEnqueues, System};
+#[cfg(feature = "health")]
+pub use self::archive::HealthConfig;
+pub use self::archive::{Archive, ArchiveBuilder, ArchiveConfig, BackendStats, ChainConfig, TracingConfig};
+pub use self::database::{queries, BlobStoreConfig, DatabaseConfig};
+pub use self::error::{ArchiveError, ConfigError};
+#[cfg(feature = "rpc")]
+pub use self::rpc::RpcServer;
+pub use self::slow_log::{slow_count, SlowOperation};
+pub use self::wasm_tracing::TraceOutput;
 
 pub mod chain_traits {
 	//! Traits defining functions on the client needed for indexing
@@ -89,7 +105,9 @@ mod test {
 			pretty_env_logger::init();
 			let url: &str = &DATABASE_URL;
 			task::block_on(async {
-				crate::database::setup(url, Default::default(), vec![]).await.unwrap();
+				crate::database::setup(&crate::database::DatabaseConfig::new(url), Default::default(), vec![])
+					.await
+					.unwrap();
 			});
 		});
 	}
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS runtime_upgrade_events (
+	id SERIAL NOT NULL PRIMARY KEY,
+	hash bytea NOT NULL,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	call text NOT NULL,
+	code_hash bytea NOT NULL,
+	code_size int check (code_size >= 0) NOT NULL,
+	UNIQUE (block_num, code_hash)
+);
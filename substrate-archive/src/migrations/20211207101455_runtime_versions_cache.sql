@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS runtime_versions_cache (
+	-- twox64 hash of the WASM blob, stored as a signed bigint
+	code_hash bigint NOT NULL PRIMARY KEY,
+	-- hash of the block the version was first found at
+	block_hash bytea NOT NULL,
+	spec_version int NOT NULL,
+	-- SCALE-encoded `RuntimeVersion`
+	version bytea NOT NULL
+);
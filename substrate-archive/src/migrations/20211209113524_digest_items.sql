@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS digest_items (
+	id SERIAL NOT NULL PRIMARY KEY,
+	hash bytea NOT NULL,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	idx int check (idx >= 0) NOT NULL,
+	kind text NOT NULL,
+	engine text,
+	payload jsonb,
+	data bytea NOT NULL,
+	UNIQUE (hash, idx)
+);
+
+CREATE INDEX IF NOT EXISTS digest_items_block_num_idx ON digest_items (block_num);
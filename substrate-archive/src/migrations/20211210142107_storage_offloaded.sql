@@ -0,0 +1,2 @@
+-- When true, `storage` holds the blob store reference of the value rather than the value itself.
+ALTER TABLE storage ADD COLUMN IF NOT EXISTS offloaded boolean NOT NULL DEFAULT false;
@@ -0,0 +1,7 @@
+-- Blocks which can not be executed (I.E because the state of their parent was pruned).
+-- Their header is kept in `blocks`, but storage is never indexed for them.
+CREATE TABLE IF NOT EXISTS unexecutable_blocks (
+	hash bytea NOT NULL PRIMARY KEY REFERENCES blocks(hash) ON DELETE CASCADE,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	reason text NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Time set by the `Timestamp::set` inherent of a block, if the chain has one.
+-- Filled in once the extrinsics of the block are indexed.
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS timestamp TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS blocks_timestamp_index ON blocks (timestamp, block_num);
@@ -0,0 +1,8 @@
+-- Jobs waiting to be published to the task queue.
+-- Written in the same transaction as the write which triggered them, and removed once published.
+CREATE TABLE IF NOT EXISTS _job_outbox (
+	id BIGSERIAL NOT NULL PRIMARY KEY,
+	job_type text NOT NULL,
+	payload bytea NOT NULL,
+	created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,8 @@
+-- Periodic samples of the highest indexed block, to compute the indexing rate from.
+CREATE TABLE IF NOT EXISTS indexing_progress (
+	id BIGSERIAL NOT NULL PRIMARY KEY,
+	sampled_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	max_block int NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS indexing_progress_sampled_at_index ON indexing_progress (sampled_at);
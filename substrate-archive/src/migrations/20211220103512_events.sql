@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS events (
+	id SERIAL NOT NULL PRIMARY KEY,
+	hash bytea NOT NULL,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	idx int check (idx >= 0) NOT NULL,
+	phase text NOT NULL,
+	extrinsic_idx int,
+	module text NOT NULL,
+	event text NOT NULL,
+	params jsonb,
+	UNIQUE (hash, idx)
+);
+
+CREATE INDEX IF NOT EXISTS events_block_num_idx ON events (block_num);
+CREATE INDEX IF NOT EXISTS events_module_event_idx ON events (module, event);
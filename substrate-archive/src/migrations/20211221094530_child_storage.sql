@@ -0,0 +1,14 @@
+-- Changes of child tries (I.E of crowdloans and contracts), keyed by the storage key of their child trie.
+CREATE TABLE IF NOT EXISTS child_storage (
+	id SERIAL PRIMARY KEY,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+	is_full boolean NOT NULL,
+	child_key bytea NOT NULL,
+	key bytea NOT NULL,
+	storage bytea,
+	offloaded boolean NOT NULL DEFAULT false
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS only_unique_hash_child_key_storage ON child_storage (hash, child_key, key, md5(storage));
+CREATE INDEX IF NOT EXISTS child_storage_block_num_idx ON child_storage (block_num);
@@ -0,0 +1,2 @@
+-- IDs of the spans a span follows from (I.E the span of the task which spawned it), if any.
+ALTER TABLE state_traces ADD COLUMN IF NOT EXISTS follows_from int[];
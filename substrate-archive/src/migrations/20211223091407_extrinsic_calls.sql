@@ -0,0 +1,14 @@
+-- The pallet and call of each extrinsic of the `extrinsics` table, to look up extrinsics by their call.
+-- Filled in as extrinsics are indexed. Names are lowercase, I.E `balances` and `transfer`.
+CREATE TABLE IF NOT EXISTS extrinsic_calls (
+	id SERIAL PRIMARY KEY,
+	hash bytea NOT NULL REFERENCES extrinsics(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	extrinsic_index int check (extrinsic_index >= 0) NOT NULL,
+	module varchar NOT NULL,
+	call varchar NOT NULL,
+	signed boolean NOT NULL,
+	UNIQUE (hash, extrinsic_index)
+);
+
+CREATE INDEX IF NOT EXISTS extrinsic_calls_module_call_block_num_idx ON extrinsic_calls (module, call, block_num);
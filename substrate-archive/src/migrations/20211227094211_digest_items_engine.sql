@@ -0,0 +1,2 @@
+-- Look up the digest items of a consensus engine, I.E BABE pre-digests for slot numbers.
+CREATE INDEX IF NOT EXISTS digest_items_engine_kind_idx ON digest_items (engine, kind, block_num);
@@ -0,0 +1,2 @@
+-- Idempotency key jobs are published with, so workers drop jobs the relay publishes more than once.
+ALTER TABLE _job_outbox ADD COLUMN IF NOT EXISTS key text;
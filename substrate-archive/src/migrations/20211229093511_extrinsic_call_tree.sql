@@ -0,0 +1,10 @@
+-- Every call of an extrinsic gets a row of its own, including the calls dispatched by wrapper calls
+-- (I.E the calls of a `Utility::batch`), numbered in the order they appear within the extrinsic.
+-- `parent_index` is the `call_index` of the wrapper call, and `depth` the number of wrappers around the call.
+ALTER TABLE extrinsic_calls ADD COLUMN IF NOT EXISTS call_index int check (call_index >= 0) NOT NULL DEFAULT 0;
+ALTER TABLE extrinsic_calls ADD COLUMN IF NOT EXISTS parent_index int check (parent_index >= 0);
+ALTER TABLE extrinsic_calls ADD COLUMN IF NOT EXISTS depth int check (depth >= 0) NOT NULL DEFAULT 0;
+
+ALTER TABLE extrinsic_calls DROP CONSTRAINT IF EXISTS extrinsic_calls_hash_extrinsic_index_key;
+ALTER TABLE extrinsic_calls ADD CONSTRAINT extrinsic_calls_hash_extrinsic_index_call_index_key
+	UNIQUE (hash, extrinsic_index, call_index);
@@ -0,0 +1,8 @@
+-- Blocks whose extrinsics failed to decode against the metadata of their spec.
+-- They are not crawled again; delete their rows to decode them once more.
+CREATE TABLE IF NOT EXISTS undecodable_extrinsics (
+	hash bytea NOT NULL PRIMARY KEY REFERENCES blocks(hash) ON DELETE CASCADE,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+	spec integer NOT NULL,
+	reason text NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Blocks which emitted no events, or whose `System::Events` storage was removed.
+-- Their events are indexed as much as they can be, so they are not crawled again.
+CREATE TABLE IF NOT EXISTS blocks_without_events (
+	hash bytea NOT NULL PRIMARY KEY REFERENCES blocks(hash) ON DELETE CASCADE,
+	block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL
+);
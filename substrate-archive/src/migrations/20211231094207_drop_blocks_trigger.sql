@@ -0,0 +1,4 @@
+-- Blocks are enqueued for execution in the transaction which inserts them,
+-- so nothing listens to notifications about new blocks anymore.
+DROP TRIGGER IF EXISTS new_block_trigger ON blocks;
+DROP FUNCTION IF EXISTS table_update_trigger_fn();
@@ -0,0 +1,212 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! JSON-RPC server exposing the data indexed by the archive,
+//! so that consumers do not need to query Postgres directly.
+
+use std::{future::Future, net::SocketAddr};
+
+use jsonrpc_http_server::{
+	jsonrpc_core::{Error as RpcError, ErrorCode, IoHandler, Params, Value},
+	Server, ServerBuilder,
+};
+use serde::{
+	de::{DeserializeOwned, IgnoredAny},
+	Serialize,
+};
+use sp_core::Bytes;
+
+use crate::{
+	database::{queries, BlockModel, Database},
+	error::{ArchiveError, Result},
+};
+
+/// Maximum amount of storage changes returned by a single `archive_getStorage` call.
+const MAX_STORAGE_PAGE: u32 = 1000;
+
+/// Handle to a running RPC server.
+///
+/// | Method | Params | Result |
+/// | -- | -- | -- |
+/// | `archive_status` | | highest indexed block and indexed runtime versions |
+/// | `archive_getBlock` | block number | block, with bytes hex-encoded |
+/// | `archive_getStorage` | block number, limit, offset | storage changes of the block |
+/// | `archive_storageAt` | block number, key | value of the key as of the block |
+pub struct RpcServer {
+	server: Server,
+}
+
+impl RpcServer {
+	/// Serve archive queries against `database` on `addr`.
+	pub fn start(addr: &SocketAddr, database: Database) -> Result<Self> {
+		let server = ServerBuilder::new(io_handler(database)).threads(1).start_http(addr)?;
+		log::info!("RPC server listening on {}", server.address());
+		Ok(Self { server })
+	}
+
+	/// Address the server is listening on.
+	pub fn address(&self) -> &SocketAddr {
+		self.server.address()
+	}
+
+	pub fn close(self) {
+		self.server.close()
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+	max_block: Option<u32>,
+	spec_versions: Vec<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcBlock {
+	hash: Bytes,
+	parent_hash: Bytes,
+	block_num: u32,
+	state_root: Bytes,
+	extrinsics_root: Bytes,
+	digest: Bytes,
+	extrinsics: Bytes,
+	spec: u32,
+}
+
+impl From<BlockModel> for RpcBlock {
+	fn from(block: BlockModel) -> Self {
+		Self {
+			hash: block.hash.into(),
+			parent_hash: block.parent_hash.into(),
+			block_num: block.block_num as u32,
+			state_root: block.state_root.into(),
+			extrinsics_root: block.extrinsics_root.into(),
+			digest: block.digest.into(),
+			extrinsics: block.ext.into(),
+			spec: block.spec as u32,
+		}
+	}
+}
+
+fn io_handler(database: Database) -> IoHandler {
+	let mut io = IoHandler::new();
+
+	method(&mut io, "archive_status", &database, |db, _: IgnoredAny| async move {
+		let mut conn = db.read_pool().acquire().await?;
+		let max_block = queries::max_block(&mut conn).await?;
+		let spec_versions = queries::get_versions(&mut conn).await?;
+		Ok(Status { max_block, spec_versions })
+	});
+
+	method(&mut io, "archive_getBlock", &database, |db, (block_num,): (u32,)| async move {
+		let mut conn = db.read_pool().acquire().await?;
+		let block = queries::get_full_block_by_number(&mut conn, i32::try_from(block_num)?).await?;
+		Ok(RpcBlock::from(block))
+	});
+
+	method(&mut io, "archive_getStorage", &database, |db, (block_num, limit, offset): (u32, u32, u32)| async move {
+		let mut conn = db.read_pool().acquire().await?;
+		let changes =
+			queries::storage_keys_changed_at(&mut conn, block_num, limit.min(MAX_STORAGE_PAGE), offset).await?;
+		Ok(changes.into_iter().map(|(key, value)| (Bytes(key), value.map(Bytes))).collect::<Vec<_>>())
+	});
+
+	method(&mut io, "archive_storageAt", &database, |db, (block_num, key): (u32, Bytes)| async move {
+		Ok(db.storage_value_at(block_num, &key).await?.map(Bytes))
+	});
+
+	io
+}
+
+/// Register an RPC method which parses its params into `P`, and serializes the `R` it resolves to.
+fn method<P, R, F, Fut>(io: &mut IoHandler, name: &str, database: &Database, f: F)
+where
+	P: DeserializeOwned,
+	R: Serialize,
+	F: Fn(Database, P) -> Fut + Send + Sync + 'static,
+	Fut: Future<Output = Result<R>> + Send + 'static,
+{
+	let database = database.clone();
+	io.add_method(name, move |params: Params| {
+		let call = parse_params::<P>(params).map(|params| f(database.clone(), params));
+		async move {
+			let value = call?.await.map_err(internal_error)?;
+			serde_json::to_value(value).map_err(|e| internal_error(e.into()))
+		}
+	});
+}
+
+fn parse_params<P: DeserializeOwned>(params: Params) -> Result<P, RpcError> {
+	let value = match params {
+		Params::None => Value::Array(Vec::new()),
+		params => params.into(),
+	};
+	serde_json::from_value(value).map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+fn internal_error(e: ArchiveError) -> RpcError {
+	RpcError { code: ErrorCode::InternalError, message: e.to_string(), data: None }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Error;
+	use async_std::task;
+	use std::{
+		io::{Read, Write},
+		net::TcpStream,
+	};
+	use test_common::TestGuard;
+
+	fn call(addr: &SocketAddr, body: &str) -> Result<Value, Error> {
+		let mut stream = TcpStream::connect(addr)?;
+		write!(
+			stream,
+			"POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+			addr,
+			body.len(),
+			body
+		)?;
+		let mut response = String::new();
+		stream.read_to_string(&mut response)?;
+		let body = response.split("\r\n\r\n").nth(1).ok_or_else(|| anyhow::anyhow!("malformed response"))?;
+		Ok(serde_json::from_str(body)?)
+	}
+
+	#[test]
+	fn should_serve_archive_status() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		let database = task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES ($1, $2)")
+				.bind(26_i32)
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(&mut database.conn().await?)
+				.await?;
+			Ok::<_, Error>(database)
+		})?;
+
+		let server = RpcServer::start(&"127.0.0.1:0".parse()?, database)?;
+		let response = call(server.address(), r#"{"jsonrpc":"2.0","method":"archive_status","params":[],"id":1}"#)?;
+		server.close();
+
+		assert_eq!(response["result"], serde_json::json!({ "maxBlock": null, "specVersions": [26] }));
+		Ok(())
+	}
+}
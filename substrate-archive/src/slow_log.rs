@@ -0,0 +1,118 @@
+// Copyright 2017-2021 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Logging of database queries and block executions which exceed a duration threshold.
+
+use std::{
+	fmt,
+	future::Future,
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+static SLOW_BLOCK_EXECUTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Kinds of operations that are timed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowOperation {
+	/// A write of indexed data by the database actor.
+	Query,
+	/// The execution of a block.
+	BlockExecution,
+}
+
+impl SlowOperation {
+	fn counter(self) -> &'static AtomicU64 {
+		match self {
+			Self::Query => &SLOW_QUERIES,
+			Self::BlockExecution => &SLOW_BLOCK_EXECUTIONS,
+		}
+	}
+}
+
+impl fmt::Display for SlowOperation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Query => write!(f, "query"),
+			Self::BlockExecution => write!(f, "block execution"),
+		}
+	}
+}
+
+/// The number of operations of kind `op` which exceeded the slow threshold since the process started.
+pub fn slow_count(op: SlowOperation) -> u64 {
+	op.counter().load(Ordering::Relaxed)
+}
+
+/// Logs and counts operations of one kind which take longer than a threshold.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SlowLog {
+	op: SlowOperation,
+	/// If `None`, nothing is considered slow.
+	threshold: Option<Duration>,
+}
+
+impl SlowLog {
+	pub fn new(op: SlowOperation, threshold: Option<Duration>) -> Self {
+		Self { op, threshold }
+	}
+
+	/// Record that `name` took `elapsed`, logging a warning if that exceeds the threshold.
+	/// Returns whether the operation was slow.
+	pub fn record(&self, name: impl fmt::Display, elapsed: Duration) -> bool {
+		match self.threshold {
+			Some(threshold) if elapsed > threshold => {
+				self.op.counter().fetch_add(1, Ordering::Relaxed);
+				log::warn!("Slow {} {} took {:?} (threshold {:?})", self.op, name, elapsed, threshold);
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Await `fut`, recording how long it took under `name`.
+	pub async fn time<F: Future>(&self, name: impl fmt::Display, fut: F) -> F::Output {
+		let now = Instant::now();
+		let out = fut.await;
+		self.record(name, now.elapsed());
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_std::task;
+
+	#[test]
+	fn should_log_slow_operation() {
+		crate::initialize();
+		let slow_log = SlowLog::new(SlowOperation::BlockExecution, Some(Duration::from_millis(20)));
+		let before = slow_count(SlowOperation::BlockExecution);
+
+		task::block_on(slow_log.time("block 1337", task::sleep(Duration::from_millis(50))));
+		assert_eq!(slow_count(SlowOperation::BlockExecution), before + 1);
+
+		task::block_on(slow_log.time("block 1338", async {}));
+		assert_eq!(slow_count(SlowOperation::BlockExecution), before + 1);
+
+		// without a threshold nothing is slow
+		let disabled = SlowLog::new(SlowOperation::BlockExecution, None);
+		assert!(!disabled.record("block 1339", Duration::from_secs(60)));
+		assert_eq!(slow_count(SlowOperation::BlockExecution), before + 1);
+	}
+}
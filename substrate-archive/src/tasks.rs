@@ -17,7 +17,16 @@
 //! Background tasks that take their parameters from Postgres, and are either
 //! executed on a threadpool or spawned onto the executor.
 
-use std::{marker::PhantomData, panic::AssertUnwindSafe, sync::Arc};
+use std::{
+	collections::HashMap,
+	marker::PhantomData,
+	panic::AssertUnwindSafe,
+	sync::{
+		atomic::{AtomicU8, AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
 
 use async_std::task;
 use parking_lot::Mutex;
@@ -32,13 +41,16 @@ use sp_runtime::{
 	traits::{Block as BlockT, Header, NumberFor},
 };
 
-use substrate_archive_backend::{ApiAccess, ReadOnlyBackend as Backend, ReadOnlyDb};
+use substrate_archive_backend::{
+	ApiAccess, BackendError, Interrupt, PinExecutor, ReadOnlyBackend as Backend, ReadOnlyDb,
+};
 
 use crate::{
 	actors::StorageAggregator,
 	error::ArchiveError,
-	types::Storage,
-	wasm_tracing::{SpansAndEvents, TraceHandler, Traces},
+	slow_log::{SlowLog, SlowOperation},
+	types::{Storage, UnexecutableBlock},
+	wasm_tracing::{SpansAndEvents, TraceHandler, TraceSampling, Traces, DEFAULT_MAX_FIELD_LENGTH},
 };
 
 /// The environment passed to each task
@@ -47,9 +59,19 @@ pub struct Environment<B: Send + 'static, H: Send + Sync + 'static, R, C, D> {
 	// if `Some` will trace the execution of the block
 	// and traces will be sent to the [`StorageAggregator`].
 	tracing_targets: Option<String>,
+	// Which blocks are traced, if tracing is enabled.
+	trace_sampling: TraceSampling,
+	// Maximum length of the string values of traces.
+	max_trace_field_length: usize,
 	backend: Arc<Backend<B, D>>,
 	client: Arc<C>,
 	storage: Address<StorageAggregator<H>>,
+	// Time and stack blocks execute with.
+	limits: ExecutionLimits,
+	// Specs whose blocks keep timing out.
+	breaker: SpecCircuitBreaker,
+	// Logs blocks which exceed the slow threshold while executing.
+	slow_log: SlowLog,
 	_marker: PhantomData<R>,
 }
 
@@ -60,8 +82,196 @@ impl<B: Send, H: Send + Sync + 'static, R, C, D> Environment<B, H, R, C, D> {
 		client: Arc<C>,
 		storage: Address<StorageAggregator<H>>,
 		tracing_targets: Option<String>,
+		execution_timeout: Option<Duration>,
+		slow_threshold: Option<Duration>,
 	) -> Self {
-		Self { backend, client, storage, tracing_targets, _marker: PhantomData }
+		let breaker = SpecCircuitBreaker::new(SPEC_TIMEOUT_THRESHOLD, SPEC_QUARANTINE);
+		let slow_log = SlowLog::new(SlowOperation::BlockExecution, slow_threshold);
+		Self {
+			backend,
+			client,
+			storage,
+			tracing_targets,
+			trace_sampling: TraceSampling::default(),
+			max_trace_field_length: DEFAULT_MAX_FIELD_LENGTH,
+			limits: ExecutionLimits::new(execution_timeout),
+			breaker,
+			slow_log,
+			_marker: PhantomData,
+		}
+	}
+
+	/// Only trace the blocks sampled by `sampling`.
+	pub fn with_trace_sampling(mut self, sampling: TraceSampling) -> Self {
+		self.trace_sampling = sampling;
+		self
+	}
+
+	/// Truncate string values of traces longer than `max_len` bytes.
+	pub fn with_max_trace_field_length(mut self, max_len: usize) -> Self {
+		self.max_trace_field_length = max_len;
+		self
+	}
+
+	/// Execute blocks on threads with a stack of `size` bytes, rather than the default of the platform.
+	pub fn with_stack_size(mut self, size: Option<usize>) -> Self {
+		self.limits.stack_size = size;
+		self
+	}
+}
+
+/// Number of consecutive execution timeouts after which a spec is quarantined.
+const SPEC_TIMEOUT_THRESHOLD: u32 = 3;
+/// How long blocks of a quarantined spec are rejected for.
+/// Rejected blocks are picked up again when missing storage is restored.
+const SPEC_QUARANTINE: Duration = Duration::from_secs(10 * 60);
+
+/// Circuit breaker which quarantines runtime specs whose blocks repeatedly time out during execution,
+/// so that a hanging runtime does not occupy every worker while blocks of other specs wait.
+pub struct SpecCircuitBreaker {
+	threshold: u32,
+	quarantine: Duration,
+	specs: Mutex<HashMap<u32, SpecState>>,
+}
+
+#[derive(Default)]
+struct SpecState {
+	/// Consecutive timeouts.
+	timeouts: u32,
+	/// Blocks of the spec are rejected until this time.
+	quarantined_until: Option<Instant>,
+}
+
+impl SpecCircuitBreaker {
+	pub fn new(threshold: u32, quarantine: Duration) -> Self {
+		Self { threshold, quarantine, specs: Mutex::new(HashMap::new()) }
+	}
+
+	/// Whether blocks of `spec` should be rejected without executing them.
+	/// Once the quarantine has passed, blocks of the spec are tried again,
+	/// but a single further timeout quarantines the spec again.
+	pub fn is_quarantined(&self, spec: u32) -> bool {
+		let mut specs = self.specs.lock();
+		let state = match specs.get_mut(&spec) {
+			Some(state) => state,
+			None => return false,
+		};
+		match state.quarantined_until {
+			Some(until) if Instant::now() < until => true,
+			Some(_) => {
+				state.quarantined_until = None;
+				state.timeouts = self.threshold.saturating_sub(1);
+				false
+			}
+			None => false,
+		}
+	}
+
+	pub fn record_success(&self, spec: u32) {
+		self.specs.lock().remove(&spec);
+	}
+
+	pub fn record_timeout(&self, spec: u32) {
+		let mut specs = self.specs.lock();
+		let state = specs.entry(spec).or_default();
+		state.timeouts += 1;
+		if state.timeouts >= self.threshold && state.quarantined_until.is_none() {
+			log::warn!(
+				"Quarantining spec {} for {:?} after {} execution timeouts",
+				spec,
+				self.quarantine,
+				state.timeouts
+			);
+			state.quarantined_until = Some(Instant::now() + self.quarantine);
+		}
+	}
+}
+
+/// Maximum number of timed out executions which may still be running at once.
+/// Runtime code looping without reading state can't be interrupted, so its thread keeps running.
+/// Once this many are left running, blocks are no longer executed rather than leaking more threads.
+const MAX_RUNAWAY_EXECUTIONS: usize = 16;
+
+/// Time and stack blocks execute with.
+pub struct ExecutionLimits {
+	/// Maximum time a block may take to execute.
+	/// if `None` blocks may execute indefinitely, on the thread of the worker.
+	timeout: Option<Duration>,
+	/// Stack size of the threads executing blocks, if not the default of the platform.
+	stack_size: Option<usize>,
+	/// Timed out executions which are still running.
+	runaway: Arc<AtomicUsize>,
+	max_runaway: usize,
+}
+
+impl ExecutionLimits {
+	pub fn new(timeout: Option<Duration>) -> Self {
+		Self { timeout, stack_size: None, runaway: Arc::new(AtomicUsize::new(0)), max_runaway: MAX_RUNAWAY_EXECUTIONS }
+	}
+}
+
+// states of a bounded execution, to count the executions which keep running after they timed out.
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+/// Execute a block of `spec` on its own thread, giving up on it after the timeout of `limits`.
+/// A timed out execution is interrupted, failing at its next read of state, and the worker is free to execute
+/// other blocks meanwhile.
+/// Blocks of quarantined specs are rejected immediately, as are all blocks once too many timed out executions
+/// are still running.
+fn execute_bounded<T, F>(
+	breaker: &SpecCircuitBreaker,
+	spec: u32,
+	limits: &ExecutionLimits,
+	execute: F,
+) -> Result<T, ArchiveError>
+where
+	T: Send + 'static,
+	F: FnOnce() -> Result<T, ArchiveError> + Send + 'static,
+{
+	if breaker.is_quarantined(spec) {
+		return Err(ArchiveError::SpecQuarantined(spec));
+	}
+	let timeout = match limits.timeout {
+		Some(timeout) => timeout,
+		None => return execute(),
+	};
+	let runaway = limits.runaway.load(Ordering::SeqCst);
+	if runaway >= limits.max_runaway {
+		return Err(ArchiveError::RunawayExecutions(runaway));
+	}
+	let (tx, rx) = flume::bounded(1);
+	let interrupt = Interrupt::new();
+	let scope = interrupt.clone();
+	let state = Arc::new(AtomicU8::new(RUNNING));
+	let (thread_state, thread_runaway) = (state.clone(), limits.runaway.clone());
+	let mut thread = std::thread::Builder::new().name(format!("execute-spec-{}", spec));
+	if let Some(size) = limits.stack_size {
+		thread = thread.stack_size(size);
+	}
+	thread.spawn(move || {
+		let _ = tx.send(scope.scope(execute));
+		if thread_state.swap(FINISHED, Ordering::SeqCst) == ABANDONED {
+			thread_runaway.fetch_sub(1, Ordering::SeqCst);
+		}
+	})?;
+	match rx.recv_timeout(timeout) {
+		Ok(res) => {
+			breaker.record_success(spec);
+			res
+		}
+		Err(flume::RecvTimeoutError::Timeout) => {
+			// counted before it is abandoned, so the thread never uncounts itself first
+			limits.runaway.fetch_add(1, Ordering::SeqCst);
+			if state.swap(ABANDONED, Ordering::SeqCst) == FINISHED {
+				limits.runaway.fetch_sub(1, Ordering::SeqCst);
+			}
+			interrupt.trigger();
+			breaker.record_timeout(spec);
+			Err(ArchiveError::ExecutionTimeout { spec, timeout })
+		}
+		Err(flume::RecvTimeoutError::Disconnected) => Err(ArchiveError::Msg("block execution panicked".into())),
 	}
 }
 
@@ -93,17 +303,19 @@ where
 
 		let hash = changes.hash;
 		let num: u32 = changes.number.into();
-
-		Storage::new(
-			hash,
-			num,
-			false,
-			changes
-				.storage_changes
+		let convert = |collection: StorageCollection| {
+			collection
 				.into_iter()
 				.map(|s| (StorageKey(s.0), s.1.map(StorageData)))
-				.collect::<Vec<(StorageKey, Option<StorageData>)>>(),
-		)
+				.collect::<Vec<(StorageKey, Option<StorageData>)>>()
+		};
+		let child_changes = changes
+			.child_storage
+			.into_iter()
+			.map(|(child, collection)| (StorageKey(child), convert(collection)))
+			.collect();
+
+		Storage::new(hash, num, false, convert(changes.storage_changes)).with_child_changes(child_changes)
 	}
 }
 
@@ -154,13 +366,8 @@ where
 
 		let state = backend.state_at(*id)?;
 
-		// Wasm runtime calculates a different number of digest items
-		// than what we have in the block
-		// We don't do anything with consensus
-		// so digest isn't very important (we don't currently index digest items anyway)
-		// popping a digest item has no effect on storage changes afaik
 		let (mut header, ext) = block.deconstruct();
-		header.digest_mut().pop();
+		strip_seal(&mut header);
 		Ok(BlockPrep { block: Block::new(header, ext), state, hash, parent_hash, number })
 	}
 
@@ -180,12 +387,16 @@ where
 		})
 	}
 
-	fn execute_with_tracing(self, targets: &str) -> Result<(BlockChanges<Block>, Traces), ArchiveError> {
+	fn execute_with_tracing(
+		self,
+		targets: &str,
+		max_field_length: usize,
+	) -> Result<(BlockChanges<Block>, Traces), ArchiveError> {
 		let BlockExecutor { block, backend, id, api } = self;
 		let BlockPrep { block, state, hash, parent_hash, number } = Self::prepare_block(block, backend, &id)?;
 
-		let span_events = Arc::new(Mutex::new(SpansAndEvents { spans: Vec::new(), events: Vec::new() }));
-		let handler = TraceHandler::new(targets, span_events);
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new(targets, span_events)?.max_field_length(max_field_length);
 		let dispatcher_span = tracing::debug_span!(
 			target: "state_tracing",
 			"execute_block",
@@ -210,6 +421,44 @@ where
 	}
 }
 
+/// Check the state of the parent of `block` is available to execute the block on.
+/// A parent which is not in the backend yet, I.E because the secondary database has not caught up with the node,
+/// fails with an error after which the block is executed again. A parent whose state was pruned fails with
+/// `ArchiveError::ParentStatePruned`, since the block can never be executed.
+fn check_parent_state<B, D>(backend: &Backend<B, D>, block: &B) -> Result<(), ArchiveError>
+where
+	B: BlockT,
+	D: ReadOnlyDb + 'static,
+	NumberFor<B>: Into<u32>,
+{
+	backend.check_state(BlockId::Hash(*block.header().parent_hash())).map_err(|e| match e {
+		BackendError::StateUnavailable { .. } => {
+			ArchiveError::ParentStatePruned { block: (*block.header().number()).into() }
+		}
+		e => e.into(),
+	})
+}
+
+/// Remove the seal from the digest of an imported block.
+/// The seal is added by the author after the block was built, so the runtime expects the digest
+/// it computes to match the one of the block without it.
+/// A digest which does not end in a seal is kept as-is: the runtime then reports the mismatch,
+/// rather than the block being executed with a digest other than the one it was built with.
+fn strip_seal<H: Header>(header: &mut H) {
+	match header.digest().logs().last().map(|item| item.as_seal().is_some()) {
+		Some(true) => {
+			header.digest_mut().pop();
+		}
+		Some(false) => log::warn!(
+			"Block {} ({:?}) has no seal, executing it with its digest unchanged. Last digest item: {:?}",
+			header.number(),
+			header.hash(),
+			header.digest().logs().last(),
+		),
+		None => (),
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct TaskExecutor;
 
@@ -251,32 +500,46 @@ where
 	RA::RuntimeApi: BlockBuilderApi<B> + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B, D>, B>>,
 	Api: ApiAccess<B, Backend<B, D>, RA> + 'static,
 {
-	let api = env.client.runtime_api();
-
 	if *block.header().parent_hash() == Default::default() {
 		return Ok(());
 	}
 
 	let (hash, number) = (block.header().hash(), *block.header().number());
-	log::debug!(
-		"Executing Block: {}:{}, version {}",
-		number,
-		hash,
-		env.client.runtime_version_at(&BlockId::Hash(block.hash())).map_err(|e| format!("{:?}", e))?.spec_version,
-	);
-
-	let block = BlockExecutor::new(api, &env.backend, block);
-
+	let spec =
+		env.client.runtime_version_at(&BlockId::Hash(block.hash())).map_err(|e| format!("{:?}", e))?.spec_version;
+	log::debug!("Executing Block: {}:{}, version {}", number, hash, spec);
+
+	let client = env.client.clone();
+	let backend = env.backend.clone();
+	// blocks which are not sampled execute without a trace handler, so none of their spans are recorded,
+	// rather than being opened and left without a parent.
+	let targets = env.tracing_targets.clone().filter(|_| env.trace_sampling.should_trace(number.into()));
+	let max_field_length = env.max_trace_field_length;
 	let now = std::time::Instant::now();
-	let (storage, traces) = if let Some(targets) = env.tracing_targets.as_ref() {
-		block.execute_with_tracing(targets)?
-	} else {
-		(block.execute()?, Default::default())
+	// the WASM runtime overrides may be reloaded, but not while the block executes.
+	// The pin is held by the worker rather than the execution, so an execution which times out
+	// no longer keeps the overrides from being reloaded.
+	let pin = env.client.pin_executor();
+	let res = execute_bounded(&env.breaker, spec, &env.limits, move || {
+		check_parent_state(&backend, &block)?;
+		let block = BlockExecutor::new(client.runtime_api(), &backend, block);
+		if let Some(targets) = targets.as_ref() {
+			block.execute_with_tracing(targets, max_field_length)
+		} else {
+			Ok((block.execute()?, Default::default()))
+		}
+	});
+	drop(pin);
+	let (storage, traces) = match res {
+		Err(e @ ArchiveError::ParentStatePruned { .. }) => {
+			log::warn!("Skipping execution of block {}: {}. Its storage will not be indexed.", number, e);
+			let block = UnexecutableBlock { hash, block_num: number.into(), reason: e.to_string() };
+			task::block_on(env.storage.send(block))?;
+			return Ok(());
+		}
+		res => res?,
 	};
-	let elapsed = now.elapsed();
-	if now.elapsed() > std::time::Duration::from_millis(1000) {
-		log::warn!("Took {:?} to execute block {} of hash {}", elapsed, number, hash);
-	}
+	env.slow_log.record(format_args!("of block {} ({})", number, hash), now.elapsed());
 
 	let now = std::time::Instant::now();
 	task::block_on(env.storage.send(Storage::from(storage)))?;
@@ -287,3 +550,306 @@ where
 	log::debug!("Took {:?} to insert & send finished task", now.elapsed());
 	Ok(())
 }
+
+/// Execute a block without indexing anything, returning the changes it makes to storage.
+/// Unlike [`execute_block`], the execution is neither bounded by a timeout nor traced.
+pub(crate) fn dry_execute<B, RA, Api, D>(
+	client: &Arc<Api>,
+	backend: &Arc<Backend<B, D>>,
+	block: B,
+) -> Result<Storage<B::Hash>, ArchiveError>
+where
+	D: ReadOnlyDb + 'static,
+	B: BlockT,
+	NumberFor<B>: Into<u32>,
+	RA: ConstructRuntimeApi<B, Api> + Send + Sync + 'static,
+	RA::RuntimeApi: BlockBuilderApi<B> + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B, D>, B>>,
+	Api: ApiAccess<B, Backend<B, D>, RA> + 'static,
+{
+	let _pin = client.pin_executor();
+	check_parent_state(backend, &block)?;
+	let changes = BlockExecutor::new(client.runtime_api(), backend, block).execute()?;
+	Ok(Storage::from(changes))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Error;
+	use codec::Encode;
+	use polkadot_service::{kusama_runtime::RuntimeApi, Block as PolkadotBlock};
+	use polkadot_test_client::{
+		ClientBlockImportExt, InitPolkadotBlockBuilder, TestClientBuilder, TestClientBuilderExt,
+	};
+	use sp_consensus::BlockOrigin;
+	use sp_core::H256;
+	use std::{
+		io,
+		path::{Path, PathBuf},
+	};
+	use substrate_archive_backend::{runtime_api, KeyValuePair, RuntimeConfig, SecondaryRocksDb};
+	use test_common::TestGuard;
+
+	use crate::database::{queries, Database, StorageModel};
+
+	// columns of the database of the node
+	const KEY_LOOKUP: u32 = 3;
+	const HEADER: u32 = 4;
+
+	/// An in-memory database of the node, keyed by column and key.
+	#[derive(Default)]
+	struct MockDb(HashMap<(u32, Vec<u8>), Vec<u8>>);
+
+	impl ReadOnlyDb for MockDb {
+		fn get(&self, col: u32, key: &[u8]) -> Option<Vec<u8>> {
+			self.0.get(&(col, key.to_vec())).cloned()
+		}
+
+		fn iter<'a>(&'a self, _col: u32) -> Box<dyn Iterator<Item = KeyValuePair> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn catch_up_with_primary(&self) -> io::Result<()> {
+			Ok(())
+		}
+
+		fn open_database(_path: &str, _cache_size: usize, _db_path: PathBuf) -> io::Result<Self> {
+			Ok(Self::default())
+		}
+	}
+
+	#[test]
+	fn should_not_execute_block_on_pruned_parent_state() -> Result<(), Error> {
+		// the header of block 1 is known, but the node pruned its state
+		let parent = <<PolkadotBlock as BlockT>::Header as Header>::new(
+			1,
+			Default::default(),
+			H256::repeat_byte(1),
+			Default::default(),
+			Default::default(),
+		);
+		let lookup_key = vec![0, 0, 0, 1];
+		let mut db = MockDb::default();
+		db.0.insert((KEY_LOOKUP, parent.hash().as_ref().to_vec()), lookup_key.clone());
+		db.0.insert((HEADER, lookup_key), parent.encode());
+
+		let config = RuntimeConfig::default();
+		let backend = Arc::new(Backend::<PolkadotBlock, _>::new(Arc::new(db), true, config.storage_mode));
+		let client = Arc::new(runtime_api::<PolkadotBlock, RuntimeApi, _>(config, backend.clone(), TaskExecutor)?);
+		let block = |parent_hash| {
+			let header = <<PolkadotBlock as BlockT>::Header as Header>::new(
+				2,
+				Default::default(),
+				Default::default(),
+				parent_hash,
+				Default::default(),
+			);
+			PolkadotBlock::new(header, Vec::new())
+		};
+
+		let pruned = dry_execute::<_, RuntimeApi, _, _>(&client, &backend, block(parent.hash()));
+		assert!(matches!(pruned, Err(ArchiveError::ParentStatePruned { block: 2 })), "{:?}", pruned);
+
+		// a parent the secondary database has not caught up with yet is executed again later
+		let unknown = dry_execute::<_, RuntimeApi, _, _>(&client, &backend, block(H256::repeat_byte(2)));
+		assert!(matches!(unknown, Err(ArchiveError::Backend(BackendError::BlockNotFound(_)))), "{:?}", unknown);
+		Ok(())
+	}
+
+	/// Import a block built on genesis into the RocksDB database of a node at `path`, and return the block.
+	fn import_block(path: &Path) -> Result<PolkadotBlock, Error> {
+		let backend = sc_client_db::Backend::new(
+			sc_client_db::DatabaseSettings {
+				state_cache_size: 16 * 1024 * 1024,
+				state_cache_child_ratio: None,
+				state_pruning: sc_client_db::PruningMode::ArchiveAll,
+				source: sc_client_db::DatabaseSource::RocksDb { path: path.to_path_buf(), cache_size: 128 },
+				keep_blocks: sc_client_db::KeepBlocks::All,
+				transaction_storage: sc_client_db::TransactionStorageMode::BlockBody,
+			},
+			0,
+		)?;
+		let mut client = TestClientBuilder::with_backend(Arc::new(backend)).build();
+		let block = client.init_polkadot_block_builder().build()?.block;
+		futures::executor::block_on(client.import_as_final(BlockOrigin::Own, block.clone()))?;
+		Ok(block)
+	}
+
+	#[test]
+	fn should_dry_execute_block_like_it_is_indexed() -> Result<(), Error> {
+		crate::initialize();
+		let _guard = TestGuard::lock();
+		let (chain, secondary) = (tempfile::tempdir()?, tempfile::tempdir()?);
+		let block = import_block(chain.path())?;
+		let hash = block.header().hash();
+
+		let db = SecondaryRocksDb::open_database(
+			chain.path().to_str().expect("temporary path is valid"),
+			128,
+			secondary.path().to_path_buf(),
+		)?;
+		db.catch_up_with_primary()?;
+		let config = RuntimeConfig::default();
+		let backend = Arc::new(Backend::<PolkadotBlock, _>::new(Arc::new(db), true, config.storage_mode));
+		let client = Arc::new(runtime_api::<PolkadotBlock, polkadot_test_runtime::RuntimeApi, _>(
+			config,
+			backend.clone(),
+			TaskExecutor,
+		)?);
+
+		let storage = dry_execute::<_, polkadot_test_runtime::RuntimeApi, _, _>(&client, &backend, block.clone())?;
+		let mut changes = storage
+			.changes
+			.into_iter()
+			.map(|(key, value)| (key.0, value.map(|v| v.0)))
+			.collect::<Vec<(Vec<u8>, Option<Vec<u8>>)>>();
+		changes.sort();
+		assert!(!changes.is_empty());
+		// the changes are those the node made to its state when importing the block
+		for (key, value) in changes.iter() {
+			assert_eq!(&backend.storage_at(BlockId::Hash(hash), key)?, value);
+		}
+
+		task::block_on(async {
+			let database = Database::new(&test_common::DATABASE_URL.to_string()).await?;
+			let mut conn = database.conn().await?;
+			// dry execution indexes nothing
+			assert!(queries::storage_keys_changed_at(&mut conn, 1, u32::MAX, 0).await?.is_empty());
+
+			sqlx::query("INSERT INTO metadata (version, meta) VALUES (0, $1)")
+				.bind(vec![0xDE, 0xAD, 0xBE, 0xEF])
+				.execute(&mut conn)
+				.await?;
+			sqlx::query(
+				"INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec)
+				VALUES ($1, $2, 1, $3, $4, $5, $6, 0)",
+			)
+			.bind(block.header().parent_hash().as_ref())
+			.bind(hash.as_ref())
+			.bind(block.header().state_root().as_ref())
+			.bind(block.header().extrinsics_root().as_ref())
+			.bind(block.header().digest().encode())
+			.bind(block.extrinsics().encode())
+			.execute(&mut conn)
+			.await?;
+			// index the block like the `execute_block` task does
+			let executed = BlockExecutor::new(client.runtime_api(), &backend, block).execute()?;
+			database.insert(Vec::<StorageModel<_>>::from(Storage::from(executed))).await?;
+
+			let indexed = queries::storage_keys_changed_at(&mut conn, 1, u32::MAX, 0).await?;
+			assert_eq!(indexed, changes);
+			Ok::<(), Error>(())
+		})
+	}
+
+	#[test]
+	fn should_only_strip_seal() {
+		use polkadot_service::Header as PolkadotHeader;
+		use sp_runtime::generic::DigestItem;
+
+		let pre_runtime = DigestItem::PreRuntime(*b"BABE", vec![1, 2, 3]);
+		let mut header = <<PolkadotBlock as BlockT>::Header as Header>::new(
+			5,
+			Default::default(),
+			Default::default(),
+			Default::default(),
+			Default::default(),
+		);
+		header.digest_mut().push(pre_runtime.clone());
+		header.digest_mut().push(DigestItem::Seal(*b"BABE", vec![0xAA; 64]));
+		strip_seal(&mut header);
+		assert_eq!(header.digest().logs(), [pre_runtime.clone()]);
+
+		// a digest without seal is left untouched
+		strip_seal(&mut header);
+		assert_eq!(header.digest().logs(), [pre_runtime]);
+	}
+
+	#[test]
+	fn should_quarantine_hanging_spec() -> Result<(), Error> {
+		crate::initialize();
+		let breaker = Arc::new(SpecCircuitBreaker::new(2, Duration::from_secs(60)));
+		let limits = Arc::new(ExecutionLimits::new(Some(Duration::from_millis(100))));
+		// runs until it is interrupted, as an execution reading state would
+		let (stopped_tx, stopped_rx) = flume::unbounded();
+
+		let hanging = {
+			let (breaker, limits) = (breaker.clone(), limits.clone());
+			std::thread::spawn(move || {
+				(0..2)
+					.map(|_| {
+						let stopped_tx = stopped_tx.clone();
+						execute_bounded(&breaker, 1, &limits, move || {
+							while !Interrupt::is_interrupted() {
+								std::thread::sleep(Duration::from_millis(1));
+							}
+							stopped_tx.send(()).unwrap();
+							Err(ArchiveError::Msg("interrupted".into()))
+						})
+					})
+					.collect::<Vec<_>>()
+			})
+		};
+		let progressing =
+			(0..10).map(|i| execute_bounded(&breaker, 2, &limits, move || Ok(i))).collect::<Result<Vec<_>, _>>()?;
+		assert_eq!(progressing, (0..10).collect::<Vec<_>>());
+
+		for res in hanging.join().unwrap() {
+			assert!(matches!(res, Err(ArchiveError::ExecutionTimeout { spec: 1, .. })));
+		}
+		// the timed out executions were interrupted, rather than left running
+		for _ in 0..2 {
+			stopped_rx.recv_timeout(Duration::from_secs(1))?;
+		}
+		assert!(breaker.is_quarantined(1));
+		assert!(!breaker.is_quarantined(2));
+		let now = Instant::now();
+		assert!(matches!(execute_bounded(&breaker, 1, &limits, || Ok(())), Err(ArchiveError::SpecQuarantined(1))));
+		assert!(now.elapsed() < Duration::from_millis(100));
+		assert_eq!(execute_bounded(&breaker, 2, &limits, || Ok(4))?, 4);
+		Ok(())
+	}
+
+	#[test]
+	fn should_stop_executing_once_too_many_executions_run_away() -> Result<(), Error> {
+		let breaker = SpecCircuitBreaker::new(10, Duration::from_secs(60));
+		let limits = ExecutionLimits { max_runaway: 1, ..ExecutionLimits::new(Some(Duration::from_millis(100))) };
+		// loops without reading state, so it is not interrupted
+		let (release_tx, release_rx) = flume::bounded::<()>(1);
+		let runaway = execute_bounded(&breaker, 1, &limits, move || {
+			release_rx.recv().unwrap();
+			Ok(())
+		});
+		assert!(matches!(runaway, Err(ArchiveError::ExecutionTimeout { spec: 1, .. })));
+		assert!(matches!(execute_bounded(&breaker, 2, &limits, || Ok(())), Err(ArchiveError::RunawayExecutions(1))));
+
+		// blocks execute again once the runaway execution stopped
+		release_tx.send(())?;
+		let now = Instant::now();
+		while limits.runaway.load(Ordering::SeqCst) > 0 {
+			assert!(now.elapsed() < Duration::from_secs(1), "runaway execution was not uncounted");
+			std::thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(execute_bounded(&breaker, 2, &limits, || Ok(4))?, 4);
+		Ok(())
+	}
+
+	fn recurse(depth: usize, seed: u8) -> usize {
+		let mut frame = [0u8; 4096];
+		frame[depth % frame.len()] = seed;
+		if depth == 0 {
+			return frame.iter().map(|b| *b as usize).sum();
+		}
+		recurse(depth - 1, seed) + frame[(depth * 7) % frame.len()] as usize
+	}
+
+	#[test]
+	fn should_execute_with_configured_stack_size() -> Result<(), Error> {
+		let breaker = SpecCircuitBreaker::new(10, Duration::from_secs(60));
+		let mut limits = ExecutionLimits::new(Some(Duration::from_secs(10)));
+		limits.stack_size = Some(64 * 1024 * 1024);
+		// needs about 16 MiB of stack, overflowing the default of 2 MiB
+		assert!(execute_bounded(&breaker, 1, &limits, || Ok(recurse(4096, 1)))? > 0);
+		Ok(())
+	}
+}
@@ -15,17 +15,20 @@
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
 use codec::{Decode, Encode};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use xtra::Message;
 
 use sp_runtime::{generic::SignedBlock, traits::Block as BlockT};
 use sp_storage::{StorageData, StorageKey};
 
-use crate::database::models::ExtrinsicsModel;
+use crate::{
+	database::models::{EventModel, ExtrinsicsModel, RuntimeUpgradeModel},
+	error::Result,
+};
 
-pub trait Hash: Copy + Send + Sync + Unpin + AsRef<[u8]> + 'static {}
+pub trait Hash: Copy + Send + Sync + Unpin + AsRef<[u8]> + Serialize + DeserializeOwned + 'static {}
 
-impl<T> Hash for T where T: Copy + Send + Sync + Unpin + AsRef<[u8]> + 'static {}
+impl<T> Hash for T where T: Copy + Send + Sync + Unpin + AsRef<[u8]> + Serialize + DeserializeOwned + 'static {}
 
 #[derive(Debug)]
 pub struct Metadata {
@@ -94,6 +97,9 @@ pub struct Storage<Hash> {
 	block_num: u32,
 	full_storage: bool,
 	pub changes: Vec<(StorageKey, Option<StorageData>)>,
+	/// Changes of child tries, keyed by the storage key of the child trie.
+	#[serde(default)]
+	pub child_changes: Vec<(StorageKey, Vec<(StorageKey, Option<StorageData>)>)>,
 }
 
 impl<Hash> Storage<Hash> {
@@ -103,7 +109,16 @@ impl<Hash> Storage<Hash> {
 		full_storage: bool,
 		changes: Vec<(StorageKey, Option<StorageData>)>,
 	) -> Self {
-		Self { hash, block_num, full_storage, changes }
+		Self { hash, block_num, full_storage, changes, child_changes: Vec::new() }
+	}
+
+	/// Set the changes of child tries, keyed by the storage key of the child trie.
+	pub fn with_child_changes(
+		mut self,
+		child_changes: Vec<(StorageKey, Vec<(StorageKey, Option<StorageData>)>)>,
+	) -> Self {
+		self.child_changes = child_changes;
+		self
 	}
 
 	pub fn is_full(&self) -> bool {
@@ -121,8 +136,27 @@ impl<Hash> Storage<Hash> {
 	pub fn changes(&self) -> &[(StorageKey, Option<StorageData>)] {
 		self.changes.as_slice()
 	}
+
+	/// Keep only the changes of keys starting with one of `prefixes`. No prefixes keep every change.
+	/// Child tries are kept if the storage key of the child trie starts with one of `prefixes`.
+	/// `System::Number` is changed by every block and always kept,
+	/// so a block whose storage was indexed is not taken for one which was never executed.
+	pub fn retain_prefixes(&mut self, prefixes: &[Vec<u8>]) {
+		if prefixes.is_empty() {
+			return;
+		}
+		self.changes
+			.retain(|(key, _)| key.0 == SYSTEM_NUMBER_KEY || prefixes.iter().any(|prefix| key.0.starts_with(prefix)));
+		self.child_changes.retain(|(child, _)| prefixes.iter().any(|prefix| child.0.starts_with(prefix)));
+	}
 }
 
+/// Storage key of `System::Number`, `twox128("System") ++ twox128("Number")`.
+const SYSTEM_NUMBER_KEY: [u8; 32] = [
+	0x26, 0xaa, 0x39, 0x4e, 0xea, 0x56, 0x30, 0xe0, 0x7c, 0x48, 0xae, 0x0c, 0x95, 0x58, 0xce, 0xf7, 0x02, 0xa5, 0xc1,
+	0xb1, 0x9a, 0xb7, 0xa0, 0x4f, 0x53, 0x6c, 0x51, 0x9a, 0xca, 0x49, 0x83, 0xac,
+];
+
 impl<Hash: Send + 'static> Message for Storage<Hash> {
 	type Result = ();
 }
@@ -143,17 +177,46 @@ impl<Hash> BatchStorage<Hash> {
 }
 
 impl<Hash: Send + Sync + 'static> Message for BatchStorage<Hash> {
+	type Result = Result<()>;
+}
+
+/// A block which can not be executed, so its storage is never indexed.
+#[derive(Debug)]
+pub struct UnexecutableBlock<Hash> {
+	pub hash: Hash,
+	pub block_num: u32,
+	pub reason: String,
+}
+
+impl<Hash: Send + 'static> Message for UnexecutableBlock<Hash> {
 	type Result = ();
 }
 
+/// A block whose extrinsics failed to decode, so it is not crawled again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndecodableBlock {
+	pub hash: Vec<u8>,
+	pub block_num: u32,
+	pub spec: u32,
+	pub reason: String,
+}
+
 #[derive(Debug)]
 pub struct BatchExtrinsics {
 	pub inner: Vec<ExtrinsicsModel>,
+	/// Blocks of the batch whose extrinsics failed to decode.
+	pub undecodable: Vec<UndecodableBlock>,
 }
 
 impl BatchExtrinsics {
 	pub fn new(extrinsics: Vec<ExtrinsicsModel>) -> Self {
-		Self { inner: extrinsics }
+		Self { inner: extrinsics, undecodable: Vec::new() }
+	}
+
+	/// Record the blocks of the batch which failed to decode along with the extrinsics.
+	pub fn with_undecodable(mut self, blocks: Vec<UndecodableBlock>) -> Self {
+		self.undecodable = blocks;
+		self
 	}
 
 	pub fn inner(self) -> Vec<ExtrinsicsModel> {
@@ -163,12 +226,53 @@ impl BatchExtrinsics {
 	pub fn len(&self) -> usize {
 		self.inner.len()
 	}
+
+	/// Runtime upgrades dispatched by the extrinsics in this batch.
+	pub fn runtime_upgrades(&self) -> Result<Vec<RuntimeUpgradeModel>> {
+		let mut upgrades = Vec::new();
+		for extrinsics in self.inner.iter() {
+			upgrades.extend(extrinsics.runtime_upgrades()?);
+		}
+		Ok(upgrades)
+	}
 }
 
 impl Message for BatchExtrinsics {
 	type Result = ();
 }
 
+/// Events decoded from the `System::Events` storage of executed blocks.
+#[derive(Debug)]
+pub struct BatchEvents {
+	pub inner: Vec<EventModel>,
+	/// Hash and number of the blocks of the batch which emitted no events.
+	pub empty: Vec<(Vec<u8>, u32)>,
+}
+
+impl BatchEvents {
+	pub fn new(events: Vec<EventModel>) -> Self {
+		Self { inner: events, empty: Vec::new() }
+	}
+
+	/// Record the blocks of the batch which emitted no events along with the events.
+	pub fn with_empty(mut self, blocks: Vec<(Vec<u8>, u32)>) -> Self {
+		self.empty = blocks;
+		self
+	}
+
+	pub fn inner(self) -> Vec<EventModel> {
+		self.inner
+	}
+
+	pub fn len(&self) -> usize {
+		self.inner.len()
+	}
+}
+
+impl Message for BatchEvents {
+	type Result = ();
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Die;
 impl Message for Die {
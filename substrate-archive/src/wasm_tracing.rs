@@ -18,27 +18,97 @@
 /// Tracing allows for collecting more detailed information
 /// about the execution of blocks, associated values for extrinsics being executed,
 /// as well as more information about how storage was collected.
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use async_std::{fs, prelude::*};
 use chrono::{DateTime, Utc};
 use parking_lot::Mutex;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use sp_tracing::{WASM_NAME_KEY, WASM_TARGET_KEY, WASM_TRACE_IDENTIFIER};
 use tracing::{
 	dispatcher,
 	event::Event,
 	field::{Field, Visit},
 	span::{Attributes, Id, Record},
-	Dispatch, Level, Metadata,
+	Dispatch, Level,
 };
 use tracing_subscriber::{
+	filter::{EnvFilter, LevelFilter},
 	layer::{Context, SubscriberExt},
 	Layer, Registry,
 };
 
-use crate::error::{Result, TracingError};
+use crate::error::{ArchiveError, Result, TracingError};
+
+/// Default maximum amount of spans kept in memory while tracing a single block.
+pub const DEFAULT_MAX_SPANS: usize = 1_000_000;
+
+/// Default maximum length in bytes of the string values of a traced span or event.
+pub const DEFAULT_MAX_FIELD_LENGTH: usize = 4096;
+
+/// Appended to string values of traces which were truncated.
+pub const TRUNCATED_MARKER: &str = "…(truncated)";
+
+/// Number of blocks whose traces are written to the same file.
+pub const BLOCKS_PER_TRACE_FILE: u32 = 10_000;
+
+/// Which executed blocks are traced. Every block is traced by default.
+#[derive(Clone, Debug, Default)]
+pub struct TraceSampling {
+	every: Option<u32>,
+	blocks: HashSet<u32>,
+}
+
+impl TraceSampling {
+	/// Trace blocks whose number is a multiple of `every`, and the blocks in `blocks`.
+	/// Every block is traced if neither is given.
+	pub fn new(every: Option<u32>, blocks: impl IntoIterator<Item = u32>) -> Self {
+		Self { every, blocks: blocks.into_iter().collect() }
+	}
+
+	/// Whether the block numbered `block_num` is traced.
+	pub fn should_trace(&self, block_num: u32) -> bool {
+		if self.every.is_none() && self.blocks.is_empty() {
+			return true;
+		}
+		self.every.map_or(false, |every| every != 0 && block_num % every == 0) || self.blocks.contains(&block_num)
+	}
+}
+
+/// Where the traces of executed blocks are written to.
+#[derive(Clone, Debug, Deserialize)]
+pub enum TraceOutput {
+	/// The `state_traces` table.
+	Database,
+	/// Newline-delimited JSON files in a directory, see [`TraceFiles`].
+	File(PathBuf),
+	/// Both the `state_traces` table and newline-delimited JSON files in a directory.
+	Both(PathBuf),
+}
+
+impl Default for TraceOutput {
+	fn default() -> Self {
+		Self::Database
+	}
+}
+
+impl TraceOutput {
+	/// Whether traces are inserted into the database.
+	pub fn database(&self) -> bool {
+		matches!(self, Self::Database | Self::Both(_))
+	}
+
+	/// Directory traces are written to, if any.
+	pub fn dir(&self) -> Option<&Path> {
+		match self {
+			Self::Database => None,
+			Self::File(dir) | Self::Both(dir) => Some(dir),
+		}
+	}
+}
 
 /// The Event a tracing subscriber collects before sending data to the TracingActor.
 #[derive(Debug)]
@@ -66,6 +136,8 @@ pub struct SpanMessage {
 	pub overall_time: chrono::Duration,
 	pub file: Option<String>,
 	pub line: Option<u32>,
+	/// Spans this span follows from, I.E the span of the task which spawned it.
+	pub follows_from: Vec<Id>,
 }
 
 /// Finished Trace Data Format. Ready for insertion into a relational database.
@@ -91,29 +163,182 @@ impl Traces {
 	pub fn block_num(&self) -> u32 {
 		self.block_num
 	}
+
+	/// Format the spans and events the way they are stored, spans first.
+	pub fn records(&self) -> Result<Vec<TraceRecord<'_>>> {
+		let id = |id: &Id| i32::try_from(id.into_u64());
+		let spans = self.spans.iter().map(|span| -> Result<TraceRecord<'_>> {
+			let duration = span.overall_time.to_std().map_err(|_| ArchiveError::TimestampOutOfRange)?;
+			Ok(TraceRecord {
+				block_num: self.block_num,
+				hash: &self.hash,
+				is_event: false,
+				timestamp: span.start_time,
+				duration: Some(i64::try_from(duration.as_nanos())?),
+				file: span.file.as_deref(),
+				line: span.line,
+				trace_id: Some(id(&span.id)?),
+				trace_parent_id: span.parent_id.as_ref().map(id).transpose()?,
+				follows_from: Some(span.follows_from.iter().map(id).collect::<Result<Vec<_>, _>>()?)
+					.filter(|ids| !ids.is_empty()),
+				target: &span.target,
+				name: &span.name,
+				traces: &span.values,
+			})
+		});
+		let events = self.events.iter().map(|event| -> Result<TraceRecord<'_>> {
+			Ok(TraceRecord {
+				block_num: self.block_num,
+				hash: &self.hash,
+				is_event: true,
+				timestamp: event.time,
+				// an event won't have a duration
+				duration: None,
+				file: event.file.as_deref(),
+				line: event.line,
+				// events have no ID
+				trace_id: None,
+				trace_parent_id: event.parent_id.as_ref().map(id).transpose()?,
+				follows_from: None,
+				target: &event.target,
+				name: &event.name,
+				traces: &event.values,
+			})
+		});
+		spans.chain(events).collect()
+	}
 }
 
-#[derive(Debug)]
+/// A span or event of [`Traces`], formatted the way it is stored,
+/// both as a row of `state_traces` and as a line of a trace file.
+#[derive(Debug, Serialize)]
+pub struct TraceRecord<'a> {
+	pub block_num: u32,
+	#[serde(serialize_with = "serialize_hex")]
+	pub hash: &'a [u8],
+	pub is_event: bool,
+	pub timestamp: DateTime<Utc>,
+	/// Nanoseconds a span took.
+	pub duration: Option<i64>,
+	pub file: Option<&'a str>,
+	pub line: Option<u32>,
+	pub trace_id: Option<i32>,
+	pub trace_parent_id: Option<i32>,
+	/// IDs of the spans a span follows from, if any.
+	pub follows_from: Option<Vec<i32>>,
+	pub target: &'a str,
+	pub name: &'a str,
+	pub traces: &'a TraceData,
+}
+
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+	serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+}
+
+/// Writes traces to newline-delimited JSON files, one [`TraceRecord`] per line.
+/// Each file holds the traces of a range of [`BLOCKS_PER_TRACE_FILE`] blocks, I.E `traces-0-9999.ndjson`.
+#[derive(Clone, Debug)]
+pub struct TraceFiles {
+	dir: PathBuf,
+}
+
+impl TraceFiles {
+	pub async fn open<P: Into<PathBuf>>(dir: P) -> std::io::Result<Self> {
+		let dir = dir.into();
+		fs::create_dir_all(&dir).await?;
+		Ok(Self { dir })
+	}
+
+	/// Append the traces of a block to the file of its range.
+	pub async fn write(&self, traces: &Traces) -> Result<()> {
+		let mut lines = Vec::new();
+		for record in traces.records()? {
+			serde_json::to_writer(&mut lines, &record)?;
+			lines.push(b'\n');
+		}
+		let mut file =
+			fs::OpenOptions::new().create(true).append(true).open(self.file_path(traces.block_num())).await?;
+		file.write_all(&lines).await?;
+		Ok(())
+	}
+
+	fn file_path(&self, block_num: u32) -> PathBuf {
+		let start = block_num - block_num % BLOCKS_PER_TRACE_FILE;
+		let end = start.saturating_add(BLOCKS_PER_TRACE_FILE - 1);
+		self.dir.join(format!("traces-{}-{}.ndjson", start, end))
+	}
+}
+
+#[derive(Debug, Default)]
 pub struct SpansAndEvents {
 	pub spans: Vec<SpanMessage>,
 	pub events: Vec<EventMessage>,
+	/// IDs of the spans which were not closed yet.
+	unclosed: HashSet<u64>,
 }
 
-/// Collects traces and filters based on target.
+/// Collects traces and filters them with `EnvFilter` directives, I.E `pallet_staking[bond]=debug`.
+/// Spans from WASM are filtered after the fact by the target and level of the directives,
+/// since span and field filters can not be matched against them.
 /// The Layer implementation is blocking. It uses Mutex primitives to coalesce traces before
 /// sending them to the appropriate actor.
 /// Therefore, one must be careful not to block the async executor when adding tracing spans
 /// using this subscriber implementation anywhere inside an async context in substrate-archive.
 pub struct TraceHandler {
 	span_events: Arc<Mutex<SpansAndEvents>>,
-	targets: Vec<(String, Level)>,
+	/// Filters native spans and events. Taken once tracing starts.
+	filter: Option<EnvFilter>,
+	/// Targets and levels of the directives, which spans from WASM are matched against.
+	wasm_targets: Vec<(String, LevelFilter)>,
+	max_spans: usize,
+	max_field_length: usize,
 }
 
 impl TraceHandler {
-	pub fn new(targets: &str, span_events: Arc<Mutex<SpansAndEvents>>) -> Self {
-		let mut targets: Vec<_> = targets.split(',').map(parse_target).collect();
-		targets.push((WASM_TRACE_IDENTIFIER.to_string(), Level::TRACE));
-		Self { span_events, targets }
+	/// Trace the targets of the comma-separated `EnvFilter` directives in `targets`.
+	pub fn new(targets: &str, span_events: Arc<Mutex<SpansAndEvents>>) -> Result<Self> {
+		// spans from WASM all have the same native target, and are filtered by `is_enabled` instead.
+		let wasm = format!("{}=trace", WASM_TRACE_IDENTIFIER).parse().map_err(TracingError::from)?;
+		let filter = EnvFilter::try_new(targets).map_err(TracingError::from)?.add_directive(wasm);
+		let wasm_targets = targets
+			.split(',')
+			.map(str::trim)
+			.filter(|d| !d.is_empty())
+			.map(parse_directive)
+			.filter(|(target, _)| target.as_str() != WASM_TRACE_IDENTIFIER)
+			.collect();
+		Ok(Self {
+			span_events,
+			filter: Some(filter),
+			wasm_targets,
+			max_spans: DEFAULT_MAX_SPANS,
+			max_field_length: DEFAULT_MAX_FIELD_LENGTH,
+		})
+	}
+
+	/// Set the maximum amount of spans kept in memory.
+	/// Once reached, spans which were never closed and are not an ancestor of
+	/// the span being recorded are considered orphaned and evicted, all at once.
+	/// If evicting them would not bring the spans down to the low-water mark
+	/// (a tenth below the maximum), new spans are dropped instead.
+	///
+	/// # Default
+	/// Defaults to [`DEFAULT_MAX_SPANS`]
+	#[must_use]
+	pub fn max_spans(mut self, max_spans: usize) -> Self {
+		self.max_spans = max_spans;
+		self
+	}
+
+	/// Set the maximum length in bytes of the string values of spans and events.
+	/// Longer values are truncated and end with [`TRUNCATED_MARKER`].
+	///
+	/// # Default
+	/// Defaults to [`DEFAULT_MAX_FIELD_LENGTH`]
+	#[must_use]
+	pub fn max_field_length(mut self, max_field_length: usize) -> Self {
+		self.max_field_length = max_field_length;
+		self
 	}
 
 	/// Formats an event as an [`EventMessage`] and stores it in the [`SpansAndEvents`]
@@ -135,6 +360,7 @@ impl TraceHandler {
 			None => Ok(None),
 			_ => Err(TracingError::TypeError),
 		}?;
+		values.truncate(self.max_field_length);
 
 		let event = EventMessage { level: *meta.level(), target, name, parent_id, values, time, file, line };
 		self.span_events.lock().events.push(event);
@@ -142,16 +368,18 @@ impl TraceHandler {
 	}
 
 	// we need this because we don't know the values until after tracing has been executed
-	/// Returns true if a span is part of an enabled Target. Checks WASM in addition to the spans target.
-	#[allow(clippy::suspicious_operation_groupings)]
+	/// Returns true if a span from WASM is part of an enabled target. The most specific target decides,
+	/// like with `EnvFilter`. Native spans were already filtered, so they are always enabled.
 	fn is_enabled(&self, span: &SpanMessage) -> bool {
-		let wasm_target = span.values.0.get(WASM_TARGET_KEY).map(|s| s.to_string());
-		self.targets.iter().filter(|t| t.0.as_str() != "wasm_tracing").any(|t| {
-			let wanted_target = &t.0.as_str();
-			let valid_native_target = span.target.starts_with(wanted_target);
-			let valid_wasm_target = wasm_target.as_ref().map(|wt| wt.starts_with(wanted_target)).unwrap_or(false);
-			(valid_native_target || valid_wasm_target) && span.level <= t.1
-		})
+		if span.target != WASM_TRACE_IDENTIFIER {
+			return true;
+		}
+		let wasm_target = span.values.0.get(WASM_TARGET_KEY).map(|s| s.to_string()).unwrap_or_default();
+		self.wasm_targets
+			.iter()
+			.filter(|(target, _)| wasm_target.starts_with(target.as_str()))
+			.max_by_key(|(target, _)| target.len())
+			.map_or(false, |(_, level)| span.level <= *level)
 	}
 
 	/// Formats spans based upon data types that are more useful for querying in the context
@@ -171,22 +399,45 @@ impl TraceHandler {
 				_ => Err(TracingError::TypeError),
 			}?;
 		}
+		span.values.truncate(self.max_field_length);
 
-		self.span_events.lock().spans.push(span);
+		let mut span_events = self.span_events.lock();
+		let SpansAndEvents { spans, unclosed, .. } = &mut *span_events;
+		// evicting is a pass over every span, so it is only worth it if it frees a batch of them
+		if spans.len() >= self.max_spans && spans.len() - unclosed.len() <= self.low_water_mark() {
+			evict_orphans(spans, unclosed, span.parent_id.as_ref());
+		}
+		if spans.len() >= self.max_spans {
+			log::warn!("Span limit of {} reached, dropping span {}", self.max_spans, span.name);
+			return Ok(());
+		}
+		unclosed.insert(span.id.into_u64());
+		spans.push(span);
 		Ok(())
 	}
 
+	/// Amount of spans evicting orphans should bring the spans down to.
+	fn low_water_mark(&self) -> usize {
+		self.max_spans - (self.max_spans / 10).max(1).min(self.max_spans)
+	}
+
 	/// Start tracing with the predicate `fun`.
 	/// Consumes this TraceHandler.
-	pub fn scoped_trace<T>(self, fun: impl FnOnce() -> Result<T>) -> Result<(Vec<SpanMessage>, Vec<EventMessage>, T)> {
+	pub fn scoped_trace<T>(
+		mut self,
+		fun: impl FnOnce() -> Result<T>,
+	) -> Result<(Vec<SpanMessage>, Vec<EventMessage>, T)> {
 		let span_events = self.span_events.clone();
-		let subscriber = Registry::default().with(self);
+		let filter = self.filter.take().expect("filter is only taken here; qed");
+		// the filter is the outer layer, so spans and events it disables never reach the handler.
+		let subscriber = Registry::default().with(self).with(filter);
 		let dispatch = Dispatch::new(subscriber);
 		let res = dispatcher::with_default(&dispatch, fun)?;
 
 		let mut traces = span_events.lock();
 		let spans = traces.spans.drain(..).collect::<Vec<SpanMessage>>();
 		let events = traces.events.drain(..).collect::<Vec<EventMessage>>();
+		traces.unclosed.clear();
 
 		Ok((spans, events, res))
 	}
@@ -222,6 +473,24 @@ impl From<DataType> for String {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct TraceData(HashMap<String, DataType>);
 
+impl TraceData {
+	/// Truncate string values longer than `max_len` bytes, ending them with [`TRUNCATED_MARKER`].
+	fn truncate(&mut self, max_len: usize) {
+		for value in self.0.values_mut() {
+			if let DataType::String(s) = value {
+				if s.len() > max_len {
+					let mut end = max_len;
+					while !s.is_char_boundary(end) {
+						end -= 1;
+					}
+					s.truncate(end);
+					s.push_str(TRUNCATED_MARKER);
+				}
+			}
+		}
+	}
+}
+
 impl Visit for TraceData {
 	fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
 		self.0.insert(field.name().to_string(), DataType::String(format!("{:?}", value)));
@@ -245,10 +514,6 @@ impl Visit for TraceData {
 }
 
 impl Layer<Registry> for TraceHandler {
-	fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, Registry>) -> bool {
-		self.targets.iter().any(|(t, _l)| metadata.target().starts_with(t.as_str()))
-	}
-
 	fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, Registry>) {
 		let meta = attrs.metadata();
 		let mut values = TraceData::default();
@@ -265,15 +530,18 @@ impl Layer<Registry> for TraceHandler {
 			file: None,
 			line: None,
 			values,
+			follows_from: Vec::new(),
 		};
 		if self.is_enabled(&span_message) {
 			self.gather_span(span_message).unwrap_or_else(|e| log::error!("{}", e.to_string()));
 		}
 	}
 
+	// spans are nested, so the spans being recorded and closed are usually the latest ones.
 	fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, Registry>) {
-		if let Some(span) = self.span_events.lock().spans.iter_mut().find(|span| &span.id == id) {
+		if let Some(span) = self.span_events.lock().spans.iter_mut().rev().find(|span| &span.id == id) {
 			values.record(&mut span.values);
+			span.values.truncate(self.max_field_length);
 		}
 	}
 
@@ -284,31 +552,63 @@ impl Layer<Registry> for TraceHandler {
 		}
 	}
 
+	fn on_follows_from(&self, id: &Id, follows: &Id, _ctx: Context<'_, Registry>) {
+		if let Some(span) = self.span_events.lock().spans.iter_mut().rev().find(|span| &span.id == id) {
+			span.follows_from.push(follows.clone());
+		}
+	}
+
 	fn on_close(&self, id: Id, _ctx: Context<'_, Registry>) {
 		let end_time = Utc::now();
-		if let Some(span) = self.span_events.lock().spans.iter_mut().find(|span| span.id == id) {
-			span.overall_time = end_time - span.start_time;
+		let mut span_events = self.span_events.lock();
+		if span_events.unclosed.remove(&id.into_u64()) {
+			if let Some(span) = span_events.spans.iter_mut().rev().find(|span| span.id == id) {
+				span.overall_time = end_time - span.start_time;
+			}
 		}
 	}
 }
 
-// Default to TRACE if no level given or unable to parse Level
-// We do not support a global `Level` currently
-fn parse_target(s: &str) -> (String, Level) {
-	match s.find('=') {
-		Some(i) => {
-			let target = s[0..i].to_string();
-			if s.len() > i {
-				let level = s[i + 1..s.len()].parse::<Level>().unwrap_or(Level::TRACE);
-				(target, level)
-			} else {
-				(target, Level::TRACE)
-			}
-		}
-		None => (s.to_string(), Level::TRACE),
+/// Remove spans which have not been closed and are not an ancestor of `parent`.
+/// Spans are executed in a nested fashion, so any unclosed span outside of the current
+/// span's ancestry will never be closed (I.E a block that panicked mid-execution).
+/// Ancestors are unclosed themselves, so only the parents of `unclosed` spans are looked up.
+fn evict_orphans(spans: &mut Vec<SpanMessage>, unclosed: &mut HashSet<u64>, parent: Option<&Id>) {
+	let parents: HashMap<u64, Option<&Id>> = spans
+		.iter()
+		.filter(|s| unclosed.contains(&s.id.into_u64()))
+		.map(|s| (s.id.into_u64(), s.parent_id.as_ref()))
+		.collect();
+	let mut ancestors = HashSet::new();
+	let mut current = parent;
+	while let Some(id) = current.take() {
+		current = parents.get(&id.into_u64()).copied().flatten();
+		ancestors.insert(id.into_u64());
+	}
+	let len = spans.len();
+	spans.retain(|s| !unclosed.contains(&s.id.into_u64()) || ancestors.contains(&s.id.into_u64()));
+	unclosed.retain(|id| ancestors.contains(id));
+	if spans.len() != len {
+		log::warn!("Evicted {} orphaned spans", len - spans.len());
 	}
 }
 
+// The target and level of a directive, I.E `target[span{field=value}]=level`, ignoring span and field filters.
+// A directive of only a level applies to every target. Defaults to TRACE if no level is given.
+fn parse_directive(directive: &str) -> (String, LevelFilter) {
+	let (target, level) = match directive.rfind('=') {
+		// an `=` after the span filter separates the level, rather than a field value.
+		Some(i) if !directive[i..].contains(']') => {
+			(&directive[..i], directive[i + 1..].parse().unwrap_or(LevelFilter::TRACE))
+		}
+		_ => match directive.parse::<LevelFilter>() {
+			Ok(level) => ("", level),
+			Err(_) => (directive, LevelFilter::TRACE),
+		},
+	};
+	(target.split('[').next().unwrap_or_default().to_string(), level)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -320,6 +620,61 @@ mod tests {
 
 	const TARGETS: &str = "wasm_tracing,test_wasm";
 
+	#[test]
+	fn should_sample_blocks_to_trace() {
+		assert!((0..10).all(|n| TraceSampling::default().should_trace(n)));
+		let every = TraceSampling::new(Some(4), None);
+		assert_eq!((0..10).filter(|n| every.should_trace(*n)).collect::<Vec<_>>(), [0, 4, 8]);
+		let blocks = TraceSampling::new(None, vec![3, 5]);
+		assert_eq!((0..10).filter(|n| blocks.should_trace(*n)).collect::<Vec<_>>(), [3, 5]);
+		let both = TraceSampling::new(Some(4), vec![5]);
+		assert_eq!((0..10).filter(|n| both.should_trace(*n)).collect::<Vec<_>>(), [0, 4, 5, 8]);
+	}
+
+	#[test]
+	fn should_write_traces_to_files() -> Result<(), Error> {
+		let dir = tempfile::tempdir()?;
+		let span = SpanMessage {
+			id: Id::from_u64(2),
+			parent_id: None,
+			name: "execute_block".into(),
+			target: "test_wasm".into(),
+			level: Level::INFO,
+			values: TraceData::default(),
+			start_time: Utc::now(),
+			overall_time: chrono::Duration::milliseconds(5),
+			file: None,
+			line: None,
+			follows_from: vec![Id::from_u64(1)],
+		};
+		let event = EventMessage {
+			name: "event".into(),
+			target: "test_wasm".into(),
+			level: Level::INFO,
+			values: TraceData::default(),
+			parent_id: Some(Id::from_u64(2)),
+			time: Utc::now(),
+			file: Some("lib.rs".into()),
+			line: Some(7),
+		};
+		let files = async_std::task::block_on(TraceFiles::open(dir.path()))?;
+		let traces = Traces::new(10_001, vec![0xAB], vec![event], vec![span.clone()]);
+		async_std::task::block_on(files.write(&traces))?;
+		async_std::task::block_on(files.write(&Traces::new(19_999, vec![0xCD], Vec::new(), vec![span])))?;
+
+		let lines = std::fs::read_to_string(dir.path().join("traces-10000-19999.ndjson"))?;
+		let lines = lines.lines().map(serde_json::from_str).collect::<Result<Vec<serde_json::Value>, _>>()?;
+		assert_eq!(lines.len(), 3);
+		assert_eq!(lines[0]["hash"], "0xab");
+		assert_eq!(lines[0]["duration"], 5_000_000);
+		assert_eq!(lines[0]["follows_from"], serde_json::json!([1]));
+		assert_eq!(lines[1]["is_event"], true);
+		assert_eq!(lines[1]["trace_parent_id"], 2);
+		assert!(lines[1]["follows_from"].is_null());
+		assert_eq!(lines[2]["block_num"], 19_999);
+		Ok(())
+	}
+
 	#[test]
 	fn should_collect_spans_and_events_in_wasm() -> Result<(), Error> {
 		crate::initialize();
@@ -329,8 +684,8 @@ mod tests {
 		let executor =
 			WasmExecutor::<sp_io::SubstrateHostFunctions>::new(WasmExecutionMethod::Compiled, Some(1024), 8, None, 128);
 
-		let span_events = Arc::new(Mutex::new(SpansAndEvents { spans: Vec::new(), events: Vec::new() }));
-		let handler = TraceHandler::new(TARGETS, span_events);
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new(TARGETS, span_events)?;
 		let (spans, events, _) = handler.scoped_trace(|| {
 			executor
 				.uncached_call(
@@ -352,4 +707,149 @@ mod tests {
 		assert_eq!(events[0].target, "test_wasm");
 		Ok(())
 	}
+
+	// names of the spans traced with `directives`.
+	fn traced_spans(directives: &str) -> Result<Vec<String>, Error> {
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new(directives, span_events)?;
+		let (spans, _, _) = handler.scoped_trace(|| {
+			tracing::info_span!(target: "pallet_staking", "bond").in_scope(|| {
+				tracing::debug_span!(target: "pallet_staking", "bond_debug").in_scope(|| ());
+				tracing::trace_span!(target: "pallet_staking", "bond_trace").in_scope(|| ());
+			});
+			tracing::info_span!(target: "pallet_staking", "unbond").in_scope(|| ());
+			tracing::info_span!(target: "pallet_balances", "transfer").in_scope(|| ());
+			Ok(())
+		})?;
+		Ok(spans.into_iter().map(|s| s.name).collect())
+	}
+
+	#[test]
+	fn should_filter_spans_with_directives() -> Result<(), Error> {
+		crate::initialize();
+		assert_eq!(traced_spans("pallet_staking=info")?, ["bond", "unbond"]);
+		assert_eq!(traced_spans("pallet_staking[bond]=debug")?, ["bond", "bond_debug"]);
+		assert_eq!(traced_spans("pallet_staking=off,pallet_balances")?, ["transfer"]);
+		assert_eq!(traced_spans("info")?, ["bond", "unbond", "transfer"]);
+		assert!(traced_spans("")?.is_empty());
+		assert!(TraceHandler::new("pallet_staking=loud", Arc::new(Mutex::new(SpansAndEvents::default()))).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn should_parse_directives_for_wasm() {
+		assert_eq!(parse_directive("frame"), ("frame".into(), LevelFilter::TRACE));
+		assert_eq!(parse_directive("pallet=off"), ("pallet".into(), LevelFilter::OFF));
+		assert_eq!(parse_directive("debug"), ("".into(), LevelFilter::DEBUG));
+		assert_eq!(parse_directive("pallet_staking[bond{stash=1}]"), ("pallet_staking".into(), LevelFilter::TRACE));
+		assert_eq!(parse_directive("pallet_staking[bond{stash=1}]=info"), ("pallet_staking".into(), LevelFilter::INFO));
+	}
+
+	#[test]
+	fn should_filter_wasm_spans_by_their_target() -> Result<(), Error> {
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new("wasm_tracing,pallet=info,pallet_staking=debug", span_events)?;
+		let wasm_span = |target: &str, level| {
+			let mut values = TraceData::default();
+			values.0.insert(WASM_TARGET_KEY.to_string(), DataType::String(target.to_string()));
+			SpanMessage {
+				id: Id::from_u64(1),
+				parent_id: None,
+				name: WASM_TRACE_IDENTIFIER.into(),
+				target: WASM_TRACE_IDENTIFIER.into(),
+				level,
+				values,
+				start_time: Utc::now(),
+				overall_time: chrono::Duration::zero(),
+				file: None,
+				line: None,
+				follows_from: Vec::new(),
+			}
+		};
+		assert!(handler.is_enabled(&wasm_span("pallet_staking", Level::DEBUG)));
+		assert!(!handler.is_enabled(&wasm_span("pallet_balances", Level::DEBUG)));
+		assert!(handler.is_enabled(&wasm_span("pallet_balances", Level::INFO)));
+		assert!(!handler.is_enabled(&wasm_span("frame", Level::INFO)));
+		Ok(())
+	}
+
+	#[test]
+	fn should_record_follows_from() -> Result<(), Error> {
+		crate::initialize();
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new("test_target", span_events)?;
+		let (spans, _, _) = handler.scoped_trace(|| {
+			let spawner = tracing::span!(target: "test_target", Level::TRACE, "spawner");
+			let task = tracing::span!(target: "test_target", Level::TRACE, "task");
+			task.follows_from(&spawner);
+			Ok(())
+		})?;
+		assert_eq!(spans[1].name, "task");
+		assert_eq!(spans[1].follows_from, vec![spans[0].id.clone()]);
+		assert!(spans[0].follows_from.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn should_truncate_long_values() -> Result<(), Error> {
+		crate::initialize();
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new("test_target", span_events)?.max_field_length(4);
+		let (spans, events, _) = handler.scoped_trace(|| {
+			let span = tracing::span!(target: "test_target", Level::TRACE, "span", short = "abc", long = "abcdé");
+			let _enter = span.enter();
+			tracing::event!(target: "test_target", Level::TRACE, long = "ééé");
+			Ok(())
+		})?;
+		assert_eq!(spans[0].values.0["short"].to_string(), "abc");
+		assert_eq!(spans[0].values.0["long"].to_string(), format!("abcd{}", TRUNCATED_MARKER));
+		// values are cut at a character boundary.
+		assert_eq!(events[0].values.0["long"].to_string(), format!("éé{}", TRUNCATED_MARKER));
+		Ok(())
+	}
+
+	#[test]
+	fn should_evict_unterminated_spans() -> Result<(), Error> {
+		crate::initialize();
+		let span_events = Arc::new(Mutex::new(SpansAndEvents::default()));
+		let handler = TraceHandler::new("test_target", span_events)?.max_spans(4);
+		let (spans, _, _) = handler.scoped_trace(|| {
+			// spans which are never closed, like a block execution that never exits.
+			for _ in 0..3 {
+				std::mem::forget(tracing::span!(target: "test_target", Level::TRACE, "orphan"));
+			}
+			for _ in 0..2 {
+				let span = tracing::span!(target: "test_target", Level::TRACE, "terminated");
+				let _enter = span.enter();
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+			Ok(())
+		})?;
+		assert!(spans.iter().all(|s| s.name == "terminated"));
+		assert_eq!(spans.len(), 2);
+		Ok(())
+	}
+
+	#[test]
+	fn should_keep_ancestors_when_evicting_orphans() {
+		let span = |id: u64, parent: Option<u64>| SpanMessage {
+			id: Id::from_u64(id),
+			parent_id: parent.map(Id::from_u64),
+			name: id.to_string(),
+			target: "test_wasm".into(),
+			level: Level::INFO,
+			values: TraceData::default(),
+			start_time: Utc::now(),
+			overall_time: chrono::Duration::zero(),
+			file: None,
+			line: None,
+			follows_from: Vec::new(),
+		};
+		// 1 and its child 2 are still being executed, 3 is orphaned and 4 was closed
+		let mut spans = vec![span(1, None), span(2, Some(1)), span(3, Some(1)), span(4, Some(2))];
+		let mut unclosed = HashSet::from([1, 2, 3]);
+		evict_orphans(&mut spans, &mut unclosed, Some(&Id::from_u64(2)));
+		assert_eq!(spans.iter().map(|s| s.id.into_u64()).collect::<Vec<_>>(), [1, 2, 4]);
+		assert_eq!(unclosed, HashSet::from([1, 2]));
+	}
 }
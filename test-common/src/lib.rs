@@ -50,7 +50,14 @@ impl<'a> Drop for TestGuard<'a> {
                 TRUNCATE TABLE metadata CASCADE;
                 TRUNCATE TABLE storage CASCADE;
                 TRUNCATE TABLE blocks CASCADE;
+                TRUNCATE TABLE extrinsics CASCADE;
+                TRUNCATE TABLE events;
                 TRUNCATE TABLE state_traces CASCADE;
+                TRUNCATE TABLE runtime_upgrade_events;
+                TRUNCATE TABLE runtime_versions_cache;
+                TRUNCATE TABLE digest_items;
+                TRUNCATE TABLE _job_outbox;
+                TRUNCATE TABLE indexing_progress;
                 TRUNCATE TABLE _sa_config;
                 ",
 			)
@@ -130,3 +130,113 @@ fn proc_macro_accepts_arbitrary_where_clauses() {
 		runner.wait_for_all_tasks().unwrap();
 	});
 }
+
+#[test]
+fn jobs_can_be_associated_functions() {
+	crate::initialize();
+	struct Greeter;
+
+	#[sa_work_queue::background_job]
+	impl Greeter {
+		fn greet(env: &String, name: String) -> Result<(), PerformError> {
+			if env == &name {
+				Ok(())
+			} else {
+				Err("name wasn't env!".into())
+			}
+		}
+
+		fn greet_both(env: &String, first: String, second: String) -> Result<(), PerformError> {
+			if env == &first && env == &second {
+				Ok(())
+			} else {
+				Err("names weren't env!".into())
+			}
+		}
+	}
+
+	assert_eq!(<greeter_jobs::greet::Job as Job>::JOB_TYPE, concat!(module_path!(), "::Greeter::greet"));
+	assert_eq!(<greeter_jobs::greet_both::Job as Job>::JOB_TYPE, concat!(module_path!(), "::Greeter::greet_both"));
+
+	let runner = TestGuard::runner("a".to_string());
+	smol::block_on(async {
+		let conn = runner.handle();
+		Greeter::greet("a".into()).enqueue(conn).await.unwrap();
+		Greeter::greet_both("a".into(), "a".into()).enqueue(conn).await.unwrap();
+
+		runner.run_pending_tasks().unwrap();
+		runner.wait_for_all_tasks().unwrap();
+	});
+}
+
+#[test]
+fn jobs_are_pushed_to_the_queue_of_the_attribute() {
+	crate::initialize();
+	const QUEUE: &str = "SA_TEST_STORAGE_QUEUE";
+
+	#[sa_work_queue::background_job(queue = "SA_TEST_STORAGE_QUEUE")]
+	fn index_storage(env: &String, block: String) -> Result<(), PerformError> {
+		if env == &block {
+			Ok(())
+		} else {
+			Err("block wasn't env!".into())
+		}
+	}
+
+	assert_eq!(<index_storage::Job as Job>::QUEUE, Some(QUEUE));
+	assert_eq!(<failure_job::Job as Job>::QUEUE, None);
+
+	let runner = TestGuard::runner("a".to_string());
+	// the queue is declared by the runner consuming it
+	let storage = sa_work_queue::Runner::builder("a".to_string(), test_common::AMQP_URL)
+		.queue_name(QUEUE)
+		.num_threads(1)
+		.build()
+		.unwrap();
+	smol::block_on(async {
+		index_storage("a".into()).enqueue(runner.handle()).await.unwrap();
+		let delay = std::time::Duration::from_secs(1);
+		let delayed = sa_work_queue::JobExt::enqueue_in(index_storage("a".into()), runner.handle(), delay).await;
+		assert!(matches!(delayed, Err(sa_work_queue::EnqueueError::DelayToOtherQueue(queue)) if queue == QUEUE));
+	});
+
+	storage.run_pending_tasks().unwrap();
+	storage.wait_for_all_tasks().unwrap();
+	assert_eq!(storage.completed_count(), 1);
+	runner.run_pending_tasks().unwrap();
+	assert_eq!(runner.completed_count(), 0);
+	storage.handle().channel().queue_delete(QUEUE, Default::default()).wait().unwrap();
+}
+
+#[test]
+fn jobs_can_enqueue_follow_up_jobs() {
+	crate::initialize();
+
+	#[sa_work_queue::background_job]
+	fn store_block(env: &String, block: String) -> Result<(), PerformError> {
+		if env == &block {
+			Ok(())
+		} else {
+			Err("block wasn't env!".into())
+		}
+	}
+
+	#[sa_work_queue::background_job]
+	fn execute_block(env: &String, handle: &sa_work_queue::QueueHandle, block: String) -> Result<(), PerformError> {
+		assert_eq!(env, &block);
+		smol::block_on(store_block(block).enqueue(handle))?;
+		Ok(())
+	}
+
+	// the handle is not part of the arguments of the job
+	assert!(execute_block("a".into()).perform(&"a".to_string()).is_err());
+
+	let runner = TestGuard::runner("a".to_string());
+	smol::block_on(execute_block("a".into()).enqueue(runner.handle())).unwrap();
+	runner.run_pending_tasks().unwrap();
+	runner.wait_for_all_tasks().unwrap();
+	runner.run_pending_tasks().unwrap();
+	runner.wait_for_all_tasks().unwrap();
+
+	assert_eq!(runner.completed_count(), 2);
+}
@@ -77,6 +77,24 @@ fn enqueue_8_jobs_limited_size() {
 	runner.wait_for_all_tasks().unwrap();
 }
 
+#[test]
+fn drain_once_processes_queued_jobs() {
+	initialize();
+	let runner = TestGuard::runner(());
+	let handle = runner.handle();
+
+	smol::block_on(async {
+		resize_image("lightsource".to_string()).enqueue(handle).await.unwrap();
+		resize_image("gambit".to_string()).enqueue(handle).await.unwrap();
+		resize_image("chess".to_string()).enqueue(handle).await.unwrap();
+		resize_image("checkers".to_string()).enqueue(handle).await.unwrap();
+		resize_image("sinks".to_string()).enqueue(handle).await.unwrap();
+	});
+
+	assert_eq!(runner.drain_once().unwrap(), 5);
+	assert_eq!(runner.drain_once().unwrap(), 0);
+}
+
 #[test]
 fn generic_jobs_can_be_enqueued() {
 	initialize();
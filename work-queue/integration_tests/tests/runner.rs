@@ -17,9 +17,15 @@
 use anyhow::Result;
 use assert_matches::assert_matches;
 use futures::{future::FutureExt, StreamExt};
+use lapin::{
+	options::{QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions},
+	types::FieldTable,
+};
 use std::thread;
 use std::time::Duration;
 
+use sa_work_queue::{Error, JobExt, Runner, TlsConfig};
+
 use crate::dummy_jobs::*;
 use crate::sync::Barrier;
 use crate::test_guard::TestGuard;
@@ -92,6 +98,21 @@ fn panicking_jobs_are_caught_and_treated_as_failures() -> Result<()> {
 	Ok(())
 }
 
+#[test]
+fn jobs_are_enqueued_blocking_outside_of_executors() -> Result<()> {
+	crate::initialize();
+	let runner = TestGuard::dummy_runner();
+	failure_job().enqueue_blocking(runner.handle())?;
+
+	let in_executor = futures::executor::block_on(async { failure_job().enqueue_blocking(runner.handle()) });
+	assert_matches!(in_executor, Err(sa_work_queue::EnqueueError::BlockingInAsyncContext));
+
+	runner.run_pending_tasks()?;
+	runner.wait_for_all_tasks().unwrap();
+	assert_eq!(runner.completed_count(), 1);
+	Ok(())
+}
+
 #[test]
 fn run_all_pending_jobs_errs_if_jobs_dont_start_in_timeout() -> Result<()> {
 	crate::initialize();
@@ -112,3 +133,105 @@ fn run_all_pending_jobs_errs_if_jobs_dont_start_in_timeout() -> Result<()> {
 	runner.wait_for_all_tasks().unwrap();
 	Ok(())
 }
+
+#[test]
+fn job_types_publish_with_their_routing_keys() -> Result<()> {
+	crate::initialize();
+	const EXCHANGE: &str = "SA_TEST_ROUTING_EXCHANGE";
+	let runner = TestGuard::builder(())
+		.exchange(EXCHANGE)
+		.register_job_with_routing_key::<failure_job::Job>("storage.{job_type}")
+		.register_job_with_routing_key::<panic_job::Job>("block.{job_type}")
+		.build();
+	let handle = runner.handle();
+	let channel = handle.channel();
+
+	let bind = |queue: &str, key: &str| -> Result<()> {
+		channel.queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default()).wait()?;
+		channel.queue_bind(queue, EXCHANGE, key, QueueBindOptions::default(), FieldTable::default()).wait()?;
+		Ok(())
+	};
+	bind("SA_TEST_STORAGE_QUEUE", "storage.#")?;
+	bind("SA_TEST_BLOCK_QUEUE", "block.#")?;
+
+	smol::block_on(async {
+		failure_job().enqueue(handle).await?;
+		panic_job().enqueue(handle).await?;
+		panic_job().enqueue(handle).await
+	})?;
+
+	let count = |queue: &str| -> Result<u32> {
+		let options = QueueDeclareOptions { passive: true, ..Default::default() };
+		Ok(channel.queue_declare(queue, options, FieldTable::default()).wait()?.message_count())
+	};
+	assert_eq!(1, count("SA_TEST_STORAGE_QUEUE")?);
+	assert_eq!(2, count("SA_TEST_BLOCK_QUEUE")?);
+
+	channel.queue_delete("SA_TEST_STORAGE_QUEUE", QueueDeleteOptions::default()).wait()?;
+	channel.queue_delete("SA_TEST_BLOCK_QUEUE", QueueDeleteOptions::default()).wait()?;
+	channel.exchange_delete(EXCHANGE, Default::default()).wait()?;
+	Ok(())
+}
+
+#[test]
+fn delayed_jobs_are_delivered_after_their_delay() -> Result<()> {
+	crate::initialize();
+	let runner = match TestGuard::builder(()).delayed_jobs().register_job::<failure_job::Job>().try_build() {
+		Ok(runner) => runner,
+		// the broker does not have the delayed message plugin, which `build` must detect
+		Err(e) => {
+			assert_matches!(e, Error::DelayedJobsUnsupported(_));
+			return Ok(());
+		}
+	};
+	let handle = runner.handle();
+	let channel = handle.channel();
+	let count = || -> Result<u32> {
+		let options = QueueDeclareOptions { passive: true, ..Default::default() };
+		Ok(channel.queue_declare(handle.name(), options, FieldTable::default()).wait()?.message_count())
+	};
+
+	smol::block_on(failure_job().enqueue_in(handle, Duration::from_millis(500)))?;
+	assert_eq!(0, count()?);
+	thread::sleep(Duration::from_millis(1000));
+	assert_eq!(1, count()?);
+
+	runner.run_pending_tasks()?;
+	runner.wait_for_all_tasks().unwrap();
+	channel.exchange_delete(&format!("{}.delayed", handle.name()), Default::default()).wait()?;
+	Ok(())
+}
+
+#[test]
+fn delayed_jobs_must_be_enabled() -> Result<()> {
+	crate::initialize();
+	let runner = TestGuard::dummy_runner();
+	let result = smol::block_on(failure_job().enqueue_in(runner.handle(), Duration::from_secs(1)));
+	assert_matches!(result, Err(sa_work_queue::EnqueueError::DelayNotEnabled));
+	Ok(())
+}
+
+// Requires a broker accepting TLS connections at `AMQPS_URL` (I.E `amqps://localhost:5671`),
+// with a certificate signed by the CA in the PEM file at `AMQPS_CA_CERT`. Skipped if they are not set.
+#[test]
+fn connects_to_broker_over_tls() -> Result<()> {
+	crate::initialize();
+	let (url, ca_cert) = match (std::env::var("AMQPS_URL"), std::env::var("AMQPS_CA_CERT")) {
+		(Ok(url), Ok(ca_cert)) => (url, std::fs::read_to_string(ca_cert)?),
+		_ => {
+			log::warn!("AMQPS_URL or AMQPS_CA_CERT not set, skipping TLS test");
+			return Ok(());
+		}
+	};
+	let builder = || Runner::builder((), &url).queue_name("SA_TEST_TLS_QUEUE").num_threads(1);
+
+	let runner = builder().tls_config(TlsConfig::new().ca_cert(ca_cert)).build()?;
+	smol::block_on(failure_job().enqueue(runner.handle()))?;
+	runner.run_pending_tasks()?;
+	runner.wait_for_all_tasks().unwrap();
+	runner.handle().channel().queue_delete("SA_TEST_TLS_QUEUE", QueueDeleteOptions::default()).wait()?;
+
+	// the CA of the test broker is not amongst the root certificates of the system
+	assert!(matches!(builder().tls_config(TlsConfig::new()).build(), Err(Error::Tls(_))));
+	Ok(())
+}
@@ -59,6 +59,21 @@ impl<Env> GuardBuilder<Env> {
 		self
 	}
 
+	pub fn register_job_with_routing_key<T: sa_work_queue::Job + 'static + Send>(mut self, template: &str) -> Self {
+		self.builder = self.builder.register_job_with_routing_key::<T, _>(template);
+		self
+	}
+
+	pub fn exchange(mut self, name: &str) -> Self {
+		self.builder = self.builder.exchange(name);
+		self
+	}
+
+	pub fn delayed_jobs(mut self) -> Self {
+		self.builder = self.builder.delayed_jobs();
+		self
+	}
+
 	pub fn num_threads(mut self, threads: usize) -> Self {
 		self.builder = self.builder.num_threads(threads);
 		self
@@ -72,7 +87,12 @@ impl<Env> GuardBuilder<Env> {
 	}
 
 	pub fn build<'a>(self) -> TestGuard<'a, Env> {
-		TestGuard { _lock: TEST_MUTEX.lock(), runner: self.builder.build().unwrap() }
+		self.try_build().unwrap()
+	}
+
+	pub fn try_build<'a>(self) -> Result<TestGuard<'a, Env>, sa_work_queue::Error> {
+		let _lock = TEST_MUTEX.lock();
+		Ok(TestGuard { _lock, runner: self.builder.build()? })
 	}
 }
 
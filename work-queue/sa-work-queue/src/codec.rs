@@ -0,0 +1,163 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Serialization of jobs into the payload of messages.
+//! Payloads serialized with codecs other than JSON start with a magic byte identifying the codec,
+//! which can never start a JSON payload, so messages of different codecs may share a queue while it migrates.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+	error::{EnqueueError, FetchError, PerformError},
+	job::BackgroundJob,
+};
+
+/// Magic byte prefixed to payloads serialized with bincode.
+const BINCODE_MAGIC: u8 = 0x01;
+
+/// Serialization of the jobs published to the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+	/// Serialize jobs as JSON. Payloads are readable by runners of every version.
+	Json,
+	/// Serialize jobs with bincode, which is more compact for jobs holding binary data.
+	/// Payloads can only be read by runners which support bincode.
+	Bincode,
+}
+
+impl Default for Codec {
+	fn default() -> Self {
+		Codec::Json
+	}
+}
+
+/// Data of a job, serialized with the codec of the message it was delivered in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum JobData {
+	/// Data of a job serialized as JSON.
+	Json(serde_json::Value),
+	/// Data of a job serialized with bincode.
+	Bincode(Vec<u8>),
+}
+
+impl From<serde_json::Value> for JobData {
+	fn from(value: serde_json::Value) -> Self {
+		JobData::Json(value)
+	}
+}
+
+impl JobData {
+	pub(crate) fn encode<T: Serialize>(codec: Codec, value: &T) -> Result<Self, EnqueueError> {
+		Ok(match codec {
+			Codec::Json => JobData::Json(serde_json::to_value(value)?),
+			Codec::Bincode => JobData::Bincode(bincode::serialize(value)?),
+		})
+	}
+
+	/// Deserialize the data of the job.
+	pub fn decode<T: DeserializeOwned>(self) -> Result<T, PerformError> {
+		Ok(match self {
+			JobData::Json(value) => serde_json::from_value(value)?,
+			JobData::Bincode(bytes) => bincode::deserialize(&bytes)?,
+		})
+	}
+}
+
+/// Envelope of jobs serialized with bincode, which does not support the skipped fields of [`BackgroundJob`].
+#[derive(Serialize, Deserialize)]
+struct BincodeJob {
+	job_type: String,
+	data: Vec<u8>,
+	retry_count: u32,
+	retry_at: Option<u64>,
+	priority: u8,
+}
+
+/// Serialize `job` into a payload, with the codec its data was serialized with.
+pub(crate) fn encode_job(job: &BackgroundJob) -> Result<Vec<u8>, EnqueueError> {
+	match &job.data {
+		JobData::Json(_) => Ok(serde_json::to_vec(job)?),
+		JobData::Bincode(data) => {
+			let envelope = BincodeJob {
+				job_type: job.job_type.clone(),
+				data: data.clone(),
+				retry_count: job.retry_count,
+				retry_at: job.retry_at,
+				priority: job.priority,
+			};
+			let mut payload = vec![BINCODE_MAGIC];
+			bincode::serialize_into(&mut payload, &envelope)?;
+			Ok(payload)
+		}
+	}
+}
+
+/// Deserialize a job from a payload, with the codec identified by its magic byte.
+pub(crate) fn decode_job(payload: &[u8]) -> Result<BackgroundJob, FetchError> {
+	match payload.split_first() {
+		Some((&BINCODE_MAGIC, envelope)) => {
+			let job: BincodeJob = bincode::deserialize(envelope)?;
+			Ok(BackgroundJob {
+				job_type: job.job_type,
+				data: JobData::Bincode(job.data),
+				retry_count: job.retry_count,
+				retry_at: job.retry_at,
+				priority: job.priority,
+			})
+		}
+		_ => Ok(serde_json::from_slice(payload)?),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Serialize, Deserialize, Debug, PartialEq)]
+	struct Block {
+		number: u32,
+		extrinsics: Vec<Vec<u8>>,
+	}
+
+	#[test]
+	fn jobs_are_decoded_with_the_codec_they_were_encoded_with() {
+		let block = Block { number: 1337, extrinsics: vec![vec![0xde, 0xad], vec![0xbe, 0xef]] };
+		let mut payloads = Vec::new();
+		for codec in [Codec::Json, Codec::Bincode] {
+			let job = BackgroundJob {
+				job_type: "execute_block".into(),
+				data: JobData::encode(codec, &block).unwrap(),
+				retry_count: 1,
+				retry_at: Some(42),
+				priority: 3,
+			};
+			payloads.push(encode_job(&job).unwrap());
+		}
+		// payloads of both codecs may share a queue
+		for payload in payloads.iter() {
+			let job = decode_job(payload).unwrap();
+			assert_eq!(
+				(job.job_type.as_str(), job.retry_count, job.retry_at, job.priority),
+				("execute_block", 1, Some(42), 3)
+			);
+			assert_eq!(job.data.decode::<Block>().unwrap(), block);
+		}
+		assert_eq!(payloads[0][0], b'{');
+		assert_eq!(payloads[1][0], BINCODE_MAGIC);
+		assert!(payloads[1].len() < payloads[0].len());
+	}
+}
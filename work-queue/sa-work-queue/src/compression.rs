@@ -0,0 +1,78 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive. If not, see <http://www.gnu.org/licenses/>.
+
+//! Compression of job payloads sent over AMQP.
+//! The algorithm a payload was compressed with is stored in the `content_encoding`
+//! property of the message, so compressed and uncompressed messages may share a queue.
+
+use std::{
+	borrow::Cow,
+	io::{self, Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+const GZIP: &str = "gzip";
+
+/// Compression applied to a job payload before it is published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionKind {
+	/// Publish payloads as-is.
+	None,
+	/// Compress payloads with gzip.
+	Gzip,
+}
+
+impl Default for CompressionKind {
+	fn default() -> Self {
+		CompressionKind::None
+	}
+}
+
+impl CompressionKind {
+	/// The `content_encoding` a message compressed with this kind is tagged with.
+	pub(crate) fn content_encoding(&self) -> Option<&'static str> {
+		match self {
+			CompressionKind::None => None,
+			CompressionKind::Gzip => Some(GZIP),
+		}
+	}
+
+	/// Compress `payload`.
+	pub(crate) fn compress(&self, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+		match self {
+			CompressionKind::None => Ok(payload),
+			CompressionKind::Gzip => {
+				let mut encoder = GzEncoder::new(Vec::with_capacity(payload.len() / 2), Compression::default());
+				encoder.write_all(&payload)?;
+				encoder.finish()
+			}
+		}
+	}
+}
+
+/// Decompress a payload according to the `content_encoding` of the message it was delivered in.
+pub(crate) fn decompress<'a>(encoding: Option<&str>, payload: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+	match encoding {
+		None => Ok(Cow::Borrowed(payload)),
+		Some(GZIP) => {
+			let mut decompressed = Vec::with_capacity(payload.len() * 2);
+			GzDecoder::new(payload).read_to_end(&mut decompressed)?;
+			Ok(Cow::Owned(decompressed))
+		}
+		Some(other) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown content encoding `{}`", other))),
+	}
+}
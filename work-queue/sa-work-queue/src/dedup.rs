@@ -0,0 +1,83 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! De-duplication of jobs enqueued with an idempotency key.
+//! The key is sent as the `message_id` of the message, and workers drop messages with the key of a job
+//! consumed recently. Only keys consumed since the runner was built are remembered.
+
+use std::collections::{HashSet, VecDeque};
+
+/// Default number of keys remembered.
+pub(crate) const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Idempotency keys of recently consumed jobs, bounded by evicting the least recently seen key.
+pub(crate) struct RecentKeys {
+	capacity: usize,
+	keys: HashSet<String>,
+	/// Keys from least to most recently seen.
+	order: VecDeque<String>,
+}
+
+impl RecentKeys {
+	pub fn new(capacity: usize) -> Self {
+		Self { capacity, keys: HashSet::new(), order: VecDeque::new() }
+	}
+
+	/// Record that a job with `key` was consumed. Returns whether a job with `key` was consumed before.
+	pub fn seen(&mut self, key: &str) -> bool {
+		if self.keys.contains(key) {
+			// duplicates are rare, so moving the key to the back does not need to be fast
+			if let Some(pos) = self.order.iter().position(|k| k == key) {
+				let key = self.order.remove(pos).expect("position is within the bounds; qed");
+				self.order.push_back(key);
+			}
+			return true;
+		}
+		if self.capacity == 0 {
+			return false;
+		}
+		if self.order.len() >= self.capacity {
+			if let Some(evicted) = self.order.pop_front() {
+				self.keys.remove(&evicted);
+			}
+		}
+		self.keys.insert(key.to_string());
+		self.order.push_back(key.to_string());
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn least_recently_seen_keys_are_evicted() {
+		let mut keys = RecentKeys::new(2);
+		assert!(!keys.seen("a"));
+		assert!(!keys.seen("b"));
+		assert!(keys.seen("a"));
+		// `b` is the least recently seen key
+		assert!(!keys.seen("c"));
+		assert!(keys.seen("a"));
+		assert!(keys.seen("c"));
+		assert!(!keys.seen("b"));
+
+		let mut disabled = RecentKeys::new(0);
+		assert!(!disabled.seen("a"));
+		assert!(!disabled.seen("a"));
+	}
+}
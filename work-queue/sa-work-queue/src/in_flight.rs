@@ -0,0 +1,157 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tracking of the jobs being run by workers, against the timeout of their job type.
+
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+/// The least amount of time to wait for an in-flight job.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+struct Running {
+	job_type: String,
+	started: Instant,
+	timeout: Duration,
+}
+
+impl Running {
+	fn remaining(&self) -> Duration {
+		self.timeout.saturating_sub(self.started.elapsed())
+	}
+}
+
+/// Jobs which are currently running.
+pub(crate) struct InFlight {
+	/// Job Type -> Timeout
+	timeouts: HashMap<String, Duration>,
+	/// Timeout of job types without one of their own.
+	default_timeout: Duration,
+	jobs: Mutex<HashMap<u64, Running>>,
+	next_id: AtomicU64,
+}
+
+impl InFlight {
+	pub fn new(timeouts: HashMap<String, Duration>, default_timeout: Duration) -> Self {
+		Self { timeouts, default_timeout, jobs: Default::default(), next_id: AtomicU64::new(0) }
+	}
+
+	/// Timeout of jobs of `job_type`.
+	pub fn timeout(&self, job_type: &str) -> Duration {
+		self.timeouts.get(job_type).copied().unwrap_or(self.default_timeout)
+	}
+
+	/// Record that a job of `job_type` started running.
+	/// Returns the id to [`finish`](Self::finish) it with.
+	pub fn start(&self, job_type: &str) -> u64 {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let running =
+			Running { job_type: job_type.to_string(), started: Instant::now(), timeout: self.timeout(job_type) };
+		self.jobs.lock().expect("lock is never poisoned; qed").insert(id, running);
+		id
+	}
+
+	/// Record that the job `id` finished running.
+	pub fn finish(&self, id: u64) {
+		self.jobs.lock().expect("lock is never poisoned; qed").remove(&id);
+	}
+
+	/// Number of jobs currently running.
+	pub fn count(&self) -> usize {
+		self.jobs.lock().expect("lock is never poisoned; qed").len()
+	}
+
+	/// Number of jobs currently running, by job type.
+	pub fn count_by_type(&self) -> HashMap<String, usize> {
+		let jobs = self.jobs.lock().expect("lock is never poisoned; qed");
+		jobs.values().fold(HashMap::new(), |mut counts, job| {
+			*counts.entry(job.job_type.clone()).or_insert(0) += 1;
+			counts
+		})
+	}
+
+	/// How long to wait for the next event from workers: until the first in-flight job exceeds its timeout,
+	/// or the default timeout if there are none.
+	pub fn wait_time(&self) -> Duration {
+		let jobs = self.jobs.lock().expect("lock is never poisoned; qed");
+		jobs.values().map(Running::remaining).min().map_or(self.default_timeout, |remaining| remaining.max(MIN_WAIT))
+	}
+
+	/// Whether waiting for workers timed out: either no job is running, or one exceeded its timeout.
+	/// Logs the job types of jobs which exceeded their timeout.
+	pub fn timed_out(&self) -> bool {
+		let jobs = self.jobs.lock().expect("lock is never poisoned; qed");
+		let overdue = jobs.values().filter(|job| job.remaining().is_zero()).collect::<Vec<_>>();
+		for job in overdue.iter() {
+			log::warn!("Job `{}` exceeded its timeout of {:?}", job.job_type, job.timeout);
+		}
+		jobs.is_empty() || !overdue.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn jobs_time_out_after_their_own_timeout() {
+		let timeouts = vec![("execute_block".to_string(), Duration::from_secs(60))].into_iter().collect();
+		let in_flight = InFlight::new(timeouts, Duration::from_millis(20));
+		assert_eq!(in_flight.timeout("execute_block"), Duration::from_secs(60));
+		assert_eq!(in_flight.timeout("other"), Duration::from_millis(20));
+		// nothing is running, so there is nothing to wait for
+		assert!(in_flight.timed_out());
+
+		let block = in_flight.start("execute_block");
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(!in_flight.timed_out());
+		assert!(in_flight.wait_time() > Duration::from_secs(59));
+		assert_eq!(in_flight.count(), 1);
+
+		let other = in_flight.start("other");
+		assert!(in_flight.wait_time() <= Duration::from_millis(20));
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(in_flight.timed_out());
+		assert_eq!(in_flight.wait_time(), MIN_WAIT);
+
+		in_flight.finish(other);
+		assert!(!in_flight.timed_out());
+		in_flight.finish(block);
+		assert!(in_flight.timed_out());
+		assert_eq!(in_flight.count(), 0);
+	}
+
+	#[test]
+	fn running_jobs_are_counted_by_type() {
+		let in_flight = InFlight::new(HashMap::new(), Duration::from_secs(1));
+		let first = in_flight.start("execute_block");
+		in_flight.start("execute_block");
+		in_flight.start("index_storage");
+		let counts = in_flight.count_by_type();
+		assert_eq!(counts.len(), 2);
+		assert_eq!(counts["execute_block"], 2);
+		assert_eq!(counts["index_storage"], 1);
+
+		in_flight.finish(first);
+		assert_eq!(in_flight.count_by_type()["execute_block"], 1);
+	}
+}
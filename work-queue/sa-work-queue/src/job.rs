@@ -14,12 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with sa-work-queue.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::time::Duration;
+
 use futures::stream::{self, StreamExt, TryStreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
+	codec::{self, Codec, JobData},
 	error::{EnqueueError, PerformError},
-	runner::QueueHandle,
+	outbox::Outbox,
+	runner::{self, PushOptions, QueueHandle},
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,7 +31,16 @@ pub struct BackgroundJob {
 	/// Where this job comes from (generally the name of the job function from the proc-macro)
 	pub job_type: String,
 	/// Raw function data
-	pub data: serde_json::Value,
+	pub data: JobData,
+	/// Number of times this job has been retried after failing.
+	#[serde(default)]
+	pub retry_count: u32,
+	/// Milliseconds since the UNIX epoch before which the job should not be retried.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub retry_at: Option<u64>,
+	/// Priority of the job, up to the maximum priority of the queue. Jobs with a higher priority are run first.
+	#[serde(default)]
+	pub priority: u8,
 }
 
 /// Background job
@@ -40,21 +53,39 @@ pub trait Job: Serialize + DeserializeOwned {
 	type Environment: 'static + Send + Sync;
 
 	/// The key to use for storing this job.
-	/// Typically this is the name of your struct in `snake_case`.
+	/// Jobs generated by `#[background_job]` use the module path of the function,
+	/// I.E `my_crate::jobs::my_function`, so jobs of the same name in different modules do not collide.
 	const JOB_TYPE: &'static str;
 
+	/// The maximum amount of time a job of this type may run before the runner gives up waiting for it,
+	/// failing `run_pending_tasks` with `FetchError::Timeout`.
+	/// Defaults to the [`timeout`](crate::Builder::timeout) of the runner.
+	const TIMEOUT: Option<Duration> = None;
+
+	/// The queue jobs of this type are pushed to, rather than the queue of the handle they are enqueued with.
+	/// Set with `#[background_job(queue = "<queue name>")]`. The queue must have been declared,
+	/// I.E by a runner consuming it, otherwise jobs pushed to it are dropped by the broker.
+	const QUEUE: Option<&'static str> = None;
+
 	#[doc(hidden)]
 	/// Inserts the job into the Postgres Database
 	async fn enqueue(self, handle: &QueueHandle) -> Result<(), EnqueueError> {
-		let job = BackgroundJob { job_type: Self::JOB_TYPE.to_string(), data: serde_json::to_value(&self)? };
-		let job = serde_json::to_vec(&job)?;
-		handle.push(job).await?;
+		let options = PushOptions { queue: Self::QUEUE, ..Default::default() };
+		handle.push(Self::JOB_TYPE, encode(&self, handle.codec(), 0)?, options).await?;
 		Ok(())
 	}
 
 	/// Logic for running a synchronous job
 	#[doc(hidden)]
 	fn perform(self, _: &Self::Environment) -> Result<(), PerformError>;
+
+	/// Logic for running a synchronous job, with a handle to enqueue follow-up jobs with.
+	/// Runners perform jobs with this, passing a handle on their current connection.
+	/// Defaults to [`Job::perform`], for jobs which don't enqueue other jobs.
+	#[doc(hidden)]
+	fn perform_with(self, env: &Self::Environment, _: &QueueHandle) -> Result<(), PerformError> {
+		self.perform(env)
+	}
 }
 
 /// Extra/Optional functions for Job
@@ -63,9 +94,101 @@ pub trait JobExt: Job {
 	/// Enqueue a batch of jobs.
 	/// Optimized over just using `enqueue` since the jobs may be enqueued using concurrent connections.
 	async fn enqueue_batch(conn: &QueueHandle, jobs: Vec<Self>) -> Result<(), EnqueueError> {
-		stream::iter(jobs).map(Ok).try_for_each_concurrent(16, |job| job.enqueue(conn)).await?;
+		Self::enqueue_batch_with_priority(conn, jobs, 0).await
+	}
+
+	/// Enqueue the job with `priority`, to run before jobs of a lower priority.
+	/// Priorities are capped at the [`max_priority`](crate::Builder::max_priority) of the queue,
+	/// and have no effect if the queue was not declared with one.
+	async fn enqueue_with_priority(self, handle: &QueueHandle, priority: u8) -> Result<(), EnqueueError> {
+		let options = PushOptions { priority, queue: Self::QUEUE, ..Default::default() };
+		handle.push(Self::JOB_TYPE, encode(&self, handle.codec(), priority)?, options).await?;
+		Ok(())
+	}
+
+	/// Enqueue a batch of jobs, all with `priority`.
+	async fn enqueue_batch_with_priority(
+		conn: &QueueHandle,
+		jobs: Vec<Self>,
+		priority: u8,
+	) -> Result<(), EnqueueError> {
+		stream::iter(jobs).map(Ok).try_for_each_concurrent(16, |job| job.enqueue_with_priority(conn, priority)).await?;
+		Ok(())
+	}
+
+	/// Enqueue the job to be run once `delay` has passed.
+	/// Requires delayed jobs to be enabled with [`Builder::delayed_jobs`](crate::Builder::delayed_jobs),
+	/// and fails with `EnqueueError::DelayNotEnabled` otherwise.
+	/// Jobs of a [`Job::QUEUE`] other than the queue of `handle` can't be delayed.
+	/// Jobs are not guaranteed to run in the order they were enqueued,
+	/// neither amongst delayed jobs nor relative to jobs enqueued without a delay.
+	async fn enqueue_in(self, handle: &QueueHandle, delay: Duration) -> Result<(), EnqueueError> {
+		let options = PushOptions { delay: Some(delay), queue: Self::QUEUE, ..Default::default() };
+		handle.push(Self::JOB_TYPE, encode(&self, handle.codec(), 0)?, options).await?;
 		Ok(())
 	}
+
+	/// Enqueue the job with the idempotency key `key`.
+	/// Workers drop the job if a job with the same key was consumed recently,
+	/// up to the [`dedup_capacity`](crate::Builder::dedup_capacity) of the runner.
+	/// Duplicates are only detected once they are consumed, so both jobs are in the queue until then.
+	async fn enqueue_with_key<K: AsRef<str> + Send>(self, handle: &QueueHandle, key: K) -> Result<(), EnqueueError> {
+		let options = PushOptions { key: Some(key.as_ref()), queue: Self::QUEUE, ..Default::default() };
+		handle.push(Self::JOB_TYPE, encode(&self, handle.codec(), 0)?, options).await?;
+		Ok(())
+	}
+
+	/// Enqueue the job from synchronous code, blocking until the broker confirms it.
+	/// Fails with `EnqueueError::Nacked` if the broker rejects the job.
+	/// Without confirms enabled on the channel of `handle`, it returns once the job is published.
+	///
+	/// Blocking stalls the executor it is called from, so this fails with `EnqueueError::BlockingInAsyncContext`
+	/// from within an `async-std` task or a `futures` executor. Other executors, I.E `smol`, are not detected,
+	/// and the job should be enqueued with `.await` there instead.
+	fn enqueue_blocking(self, handle: &QueueHandle) -> Result<(), EnqueueError> {
+		if async_std::task::try_current().is_some() || futures::executor::enter().is_err() {
+			return Err(EnqueueError::BlockingInAsyncContext);
+		}
+		let payload = encode(&self, handle.codec(), 0)?;
+		let options = PushOptions { queue: Self::QUEUE, ..Default::default() };
+		async_std::task::block_on(async {
+			runner::confirmed(handle.push(Self::JOB_TYPE, payload, options).await?).await
+		})
+	}
+
+	/// Store the job in `outbox` rather than publishing it.
+	/// It is published once a relay picks it up from the outbox, to the queue of the relay.
+	/// Jobs are stored as JSON.
+	async fn enqueue_to<O: Outbox>(self, outbox: &mut O) -> Result<(), EnqueueError> {
+		outbox.store(Self::JOB_TYPE, self.outbox_payload()?, None).await
+	}
+
+	/// Store the job in `outbox` with the idempotency key `key`.
+	/// See [`JobExt::enqueue_to`] and [`JobExt::enqueue_with_key`].
+	async fn enqueue_to_with_key<O: Outbox, K: AsRef<str> + Send>(
+		self,
+		outbox: &mut O,
+		key: K,
+	) -> Result<(), EnqueueError> {
+		outbox.store(Self::JOB_TYPE, self.outbox_payload()?, Some(key.as_ref())).await
+	}
+
+	/// Encode the job as it is stored in an outbox, for outboxes which are written to synchronously.
+	fn outbox_payload(&self) -> Result<Vec<u8>, EnqueueError> {
+		encode(self, Codec::Json, 0)
+	}
 }
 
 impl<T> JobExt for T where T: Job {}
+
+/// Encode a job with `priority` as the payload of a message, serialized with `codec`.
+fn encode<J: Job>(job: &J, codec: Codec, priority: u8) -> Result<Vec<u8>, EnqueueError> {
+	let job = BackgroundJob {
+		job_type: J::JOB_TYPE.to_string(),
+		data: JobData::encode(codec, job)?,
+		retry_count: 0,
+		retry_at: None,
+		priority,
+	};
+	codec::encode_job(&job)
+}
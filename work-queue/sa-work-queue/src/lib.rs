@@ -77,15 +77,32 @@ pub use serde::{de::DeserializeOwned, Deserialize, Serialize};
 #[doc(hidden)]
 pub use registry::JobVTable;
 
+mod codec;
+mod compression;
+mod dedup;
 mod error;
+mod in_flight;
 mod job;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod middleware;
+mod outbox;
 mod registry;
+mod retry;
 mod runner;
 mod threadpool;
+mod tls;
 
+pub use crate::codec::{Codec, JobData};
+pub use crate::compression::CompressionKind;
 pub use crate::error::*;
 pub use crate::job::*;
-pub use runner::{Builder, Event, QueueHandle, Runner};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::QueueMetrics;
+pub use crate::middleware::JobMiddleware;
+pub use crate::outbox::Outbox;
+pub use crate::tls::TlsConfig;
+pub use runner::{Builder, Event, QueueHandle, Runner, ShutdownReport, HEALTHCHECK_TIMEOUT};
 pub use sa_work_queue_proc_macro::*;
 
 #[cfg(test)]
@@ -0,0 +1,140 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prometheus metrics of a [`Runner`](crate::Runner).
+
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc, Mutex,
+};
+
+use lapin::{options::QueueDeclareOptions, types::FieldTable};
+use prometheus::{
+	core::{Collector, Desc},
+	proto::MetricFamily,
+	IntCounter, IntGauge, Opts,
+};
+use threadpool::ThreadPool;
+
+use crate::runner::QueueHandle;
+
+/// Metrics of the queue and workers of a runner, read whenever they are collected.
+/// Register it with a [`prometheus::Registry`] to export them.
+pub struct QueueMetrics {
+	/// A clone of the pool of workers, sharing its state.
+	pool: Mutex<ThreadPool>,
+	handle: QueueHandle,
+	completed: Arc<AtomicU64>,
+	depth: IntGauge,
+	active: IntGauge,
+	queued: IntGauge,
+	completed_total: IntCounter,
+	panics_total: IntCounter,
+}
+
+impl QueueMetrics {
+	pub(crate) fn new(pool: ThreadPool, handle: QueueHandle, completed: Arc<AtomicU64>) -> prometheus::Result<Self> {
+		let opts = |name: &str, help: &str| Opts::new(name, help).const_label("queue", handle.name());
+		Ok(Self {
+			depth: IntGauge::with_opts(opts("sa_work_queue_depth", "Number of jobs waiting in the queue"))?,
+			active: IntGauge::with_opts(opts("sa_work_queue_active_threads", "Number of workers running a job"))?,
+			queued: IntGauge::with_opts(opts("sa_work_queue_queued_jobs", "Number of jobs waiting for a worker"))?,
+			completed_total: IntCounter::with_opts(opts(
+				"sa_work_queue_completed_jobs_total",
+				"Number of jobs which finished running, whether they succeeded or not",
+			))?,
+			panics_total: IntCounter::with_opts(opts(
+				"sa_work_queue_panics_total",
+				"Number of workers which panicked",
+			))?,
+			pool: Mutex::new(pool),
+			handle,
+			completed,
+		})
+	}
+
+	fn update(&self) {
+		let options = QueueDeclareOptions { passive: true, ..Default::default() };
+		match self.handle.channel().queue_declare(self.handle.name(), options, FieldTable::default()).wait() {
+			Ok(queue) => self.depth.set(i64::from(queue.message_count())),
+			Err(e) => log::debug!("Failed to read the depth of queue {}: {}", self.handle.name(), e),
+		}
+		let pool = self.pool.lock().expect("lock is never poisoned; qed");
+		self.active.set(pool.active_count() as i64);
+		self.queued.set(pool.queued_count() as i64);
+		// counters only go up, so they are advanced to the current count
+		let advance = |counter: &IntCounter, count: u64| counter.inc_by(count.saturating_sub(counter.get()));
+		advance(&self.completed_total, self.completed.load(Ordering::Relaxed));
+		advance(&self.panics_total, pool.panic_count() as u64);
+	}
+
+	fn collectors(&self) -> [&dyn Collector; 5] {
+		[&self.depth, &self.active, &self.queued, &self.completed_total, &self.panics_total]
+	}
+}
+
+impl Collector for QueueMetrics {
+	fn desc(&self) -> Vec<&Desc> {
+		self.collectors().into_iter().flat_map(|c| c.desc()).collect()
+	}
+
+	fn collect(&self) -> Vec<MetricFamily> {
+		self.update();
+		self.collectors().into_iter().flat_map(|c| c.collect()).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_common::TestGuard;
+
+	#[test]
+	fn metrics_are_read_from_runner() {
+		let _guard = TestGuard::lock();
+		crate::initialize();
+
+		let runner = crate::Runner::builder((), "amqp://localhost:5672")
+			.num_threads(1)
+			.queue_name(test_common::TASK_QUEUE)
+			.build()
+			.unwrap();
+		let registry = prometheus::Registry::new();
+		registry.register(Box::new(runner.metrics_handle().unwrap())).unwrap();
+
+		let job = serde_json::json!({ "job_type": "TEST_JOB", "data": {} });
+		async_std::task::block_on(runner.handle().push(
+			"TEST_JOB",
+			serde_json::to_vec(&job).unwrap(),
+			Default::default(),
+		))
+		.unwrap();
+		runner.run_pending_tasks().unwrap();
+		runner.wait_for_all_tasks().unwrap();
+
+		let value = |name: &str| {
+			let family = registry.gather().into_iter().find(|f| f.get_name() == name).expect("metric is exported");
+			let metric = &family.get_metric()[0];
+			assert_eq!(metric.get_label()[0].get_value(), test_common::TASK_QUEUE);
+			metric.get_gauge().get_value() + metric.get_counter().get_value()
+		};
+		assert_eq!(value("sa_work_queue_completed_jobs_total"), 1.0);
+		assert_eq!(value("sa_work_queue_depth"), 0.0);
+		assert_eq!(value("sa_work_queue_active_threads"), 0.0);
+		assert_eq!(value("sa_work_queue_panics_total"), 0.0);
+		assert_eq!(runner.completed_count(), 1);
+	}
+}
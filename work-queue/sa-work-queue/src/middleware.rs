@@ -0,0 +1,32 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Logic run around every job a [`Runner`](crate::Runner) performs.
+
+use crate::{error::PerformError, job::BackgroundJob};
+
+/// Cross-cutting logic around jobs, I.E timing them or entering a tracing span,
+/// registered with [`Builder::with_middleware`](crate::Builder::with_middleware).
+/// Middlewares are nested in the order they were registered: the first one registered is the outermost,
+/// so its `before` is called first and its `after` last.
+pub trait JobMiddleware: Send + Sync {
+	/// Called on the worker thread right before `job` is performed.
+	fn before(&self, _job: &BackgroundJob) {}
+
+	/// Called on the worker thread once `job` was performed, with its result.
+	/// Not called if the job panicked.
+	fn after(&self, _job: &BackgroundJob, _result: &Result<(), PerformError>) {}
+}
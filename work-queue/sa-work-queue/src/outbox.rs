@@ -0,0 +1,33 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Transactional outbox for jobs.
+//! Rather than being published directly, a job is stored in an outbox by the same transaction
+//! as the write that triggered it. A relay publishes jobs from the outbox with
+//! [`QueueHandle::push_confirmed`](crate::QueueHandle::push_confirmed) afterwards,
+//! removing them once they are confirmed. So a job is never lost between the write and the publish,
+//! but may be published more than once.
+
+use crate::error::EnqueueError;
+
+/// Storage jobs are enqueued to with [`JobExt::enqueue_to`](crate::JobExt::enqueue_to).
+#[async_trait::async_trait]
+pub trait Outbox: Send {
+	/// Store the encoded job `payload` of type `job_type`, to be published later.
+	/// If `key` is set, the job is published with it as its idempotency key,
+	/// so a job published again by the relay is dropped by the workers.
+	async fn store(&mut self, job_type: &str, payload: Vec<u8>, key: Option<&str>) -> Result<(), EnqueueError>;
+}
@@ -13,10 +13,16 @@
 // You should have received a copy of the GNU General Public License
 // along with sa-work-queue.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{error::PerformError, job::Job};
+use crate::{
+	codec::JobData,
+	error::{Error, PerformError},
+	job::Job,
+	runner::QueueHandle,
+};
 use std::any::{Any, TypeId};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
+use std::time::Duration;
 
 #[derive(Default)]
 #[allow(missing_debug_implementations)] // Can't derive debug
@@ -24,28 +30,116 @@ use std::marker::PhantomData;
 /// functions at runtime.
 pub struct Registry<Env> {
 	jobs: HashMap<&'static str, JobVTable>,
+	/// Job Type -> Routing Key Template
+	routing_keys: HashMap<&'static str, String>,
+	/// Job Type -> Timeout, overriding the timeout of the job
+	timeouts: HashMap<&'static str, Duration>,
+	/// Job types which more than one job was registered with
+	collisions: Vec<&'static str>,
+	/// Job types registered explicitly, rather than loaded from [`register_job!`]
+	registered: HashSet<&'static str>,
 	_marker: PhantomData<Env>,
 }
 
 impl<Env: 'static> Registry<Env> {
-	pub fn register_job<T: Job + 'static + Send>(&mut self) {
+	/// Register a job.
+	/// Errors if a different job is already registered with the same job type,
+	/// in which case the job registered first is kept.
+	pub fn register_job<T: Job + 'static + Send>(&mut self) -> Result<(), Error> {
 		if TypeId::of::<T::Environment>() == TypeId::of::<Env>() {
-			self.jobs.insert(T::JOB_TYPE, JobVTable::from_job::<T>());
+			self.registered.insert(T::JOB_TYPE);
+			self.insert(JobVTable::from_job::<T>())
 		} else {
 			log::warn!("could not register job {}", T::JOB_TYPE);
+			Ok(())
 		}
 	}
 
-	/// Loads the registry from all invocations of [`register_job!`] for this
-	/// environment type
-	pub fn load() -> Self {
-		let jobs = inventory::iter::<JobVTable>
-			.into_iter()
-			.filter(|vtable| vtable.env_type == TypeId::of::<Env>())
-			.map(|&vtable| (vtable.job_type, vtable))
-			.collect();
+	/// Register a job, failing if a job was already registered with its job type,
+	/// even if it is the same job. Jobs loaded from [`register_job!`] may be registered once more.
+	/// Unlike [`Registry::register_job`], a job of another environment is an error.
+	pub fn try_register_job<T: Job + 'static + Send>(&mut self) -> Result<(), Error> {
+		if TypeId::of::<T::Environment>() != TypeId::of::<Env>() {
+			return Err(Error::WrongEnvironment(T::JOB_TYPE));
+		}
+		if self.registered.contains(T::JOB_TYPE) {
+			return Err(Error::DuplicateJob(T::JOB_TYPE));
+		}
+		self.register_job::<T>()
+	}
+
+	/// Job types of all registered jobs, in no particular order.
+	pub fn job_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+		self.jobs.keys().copied()
+	}
+
+	fn insert(&mut self, vtable: JobVTable) -> Result<(), Error> {
+		match self.jobs.get(vtable.job_type) {
+			Some(registered) if registered.job != vtable.job => {
+				self.collisions.push(vtable.job_type);
+				Err(Error::JobTypeCollision(vtable.job_type))
+			}
+			Some(_) => Ok(()),
+			None => {
+				self.jobs.insert(vtable.job_type, vtable);
+				Ok(())
+			}
+		}
+	}
+
+	/// Errors if any two different jobs were registered with the same job type.
+	pub fn check(&self) -> Result<(), Error> {
+		match self.collisions.first() {
+			Some(job_type) => Err(Error::JobTypeCollision(job_type)),
+			None => Ok(()),
+		}
+	}
+
+	/// Set the routing key template used when publishing jobs of `job_type`.
+	pub fn set_routing_key(&mut self, job_type: &'static str, template: &str) {
+		self.routing_keys.insert(job_type, template.to_string());
+	}
 
-		Self { jobs, _marker: PhantomData }
+	/// Set the timeout of jobs of `job_type`, overriding [`Job::TIMEOUT`].
+	pub fn set_timeout(&mut self, job_type: &'static str, timeout: Duration) {
+		self.timeouts.insert(job_type, timeout);
+	}
+
+	/// Timeouts of all job types which have one.
+	pub fn timeouts(&self) -> HashMap<String, Duration> {
+		let mut timeouts: HashMap<String, Duration> =
+			self.jobs.values().filter_map(|vtable| Some((vtable.job_type.to_string(), vtable.timeout?))).collect();
+		timeouts.extend(self.timeouts.iter().map(|(job_type, timeout)| (job_type.to_string(), *timeout)));
+		timeouts
+	}
+
+	/// Render the routing key templates of all job types.
+	pub fn routing_keys(&self, queue: &str) -> HashMap<String, String> {
+		self.routing_keys
+			.iter()
+			.map(|(job_type, template)| {
+				(job_type.to_string(), template.replace("{job_type}", job_type).replace("{queue}", queue))
+			})
+			.collect()
+	}
+
+	/// Loads the registry from all invocations of [`register_job!`]
+	/// for this environment type. Collisions between job types are reported by [`Registry::check`].
+	pub fn load() -> Self {
+		let mut registry = Self {
+			jobs: HashMap::new(),
+			routing_keys: HashMap::new(),
+			timeouts: HashMap::new(),
+			collisions: Vec::new(),
+			registered: HashSet::new(),
+			_marker: PhantomData,
+		};
+		for vtable in inventory::iter::<JobVTable>.into_iter().filter(|v| v.env_type == TypeId::of::<Env>()) {
+			if let Err(e) = registry.insert(*vtable) {
+				log::error!("{}", e);
+			}
+		}
+		registry
 	}
 
 	/// Get the perform function for a given job type
@@ -70,26 +164,34 @@ macro_rules! register_job {
 #[derive(Clone, Copy)]
 pub struct JobVTable {
 	env_type: TypeId,
+	/// Type of the job itself, to tell apart different jobs with the same job type.
+	job: TypeId,
 	job_type: &'static str,
-	perform: fn(serde_json::Value, &dyn Any) -> Result<(), PerformError>,
+	timeout: Option<Duration>,
+	perform: fn(JobData, &dyn Any, &QueueHandle) -> Result<(), PerformError>,
 }
 
 inventory::collect!(JobVTable);
 
 impl JobVTable {
 	pub fn from_job<T: 'static + Job + Send>() -> Self {
-		Self { env_type: TypeId::of::<T::Environment>(), job_type: T::JOB_TYPE, perform: perform_job::<T> }
+		Self {
+			env_type: TypeId::of::<T::Environment>(),
+			job: TypeId::of::<T>(),
+			job_type: T::JOB_TYPE,
+			timeout: T::TIMEOUT,
+			perform: perform_job::<T>,
+		}
 	}
 }
 
-fn perform_job<T: Job>(data: serde_json::Value, env: &dyn Any) -> Result<(), PerformError> {
+fn perform_job<T: Job>(data: JobData, env: &dyn Any, handle: &QueueHandle) -> Result<(), PerformError> {
 	let environment = env.downcast_ref().ok_or_else::<PerformError, _>(|| {
 		"Incorrect environment type. This should never happen. \
          Please open an issue at https://github.com/paritytech/substrate-archive/issues/new"
 			.into()
 	})?;
-	let data = serde_json::from_value(data)?;
-	T::perform(data, environment)
+	T::perform_with(data.decode()?, environment, handle)
 }
 
 pub struct PerformJob<Env> {
@@ -98,8 +200,79 @@ pub struct PerformJob<Env> {
 }
 
 impl<Env: 'static + Send + Sync> PerformJob<Env> {
-	/// Perform a job in a synchronous way.
-	pub fn perform(&self, data: serde_json::Value, env: &Env) -> Result<(), PerformError> {
-		(self.vtable.perform)(data, env)
+	/// Perform a job in a synchronous way. The job may enqueue follow-up jobs with `handle`.
+	pub fn perform(&self, data: JobData, env: &Env, handle: &QueueHandle) -> Result<(), PerformError> {
+		(self.vtable.perform)(data, env, handle)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Serialize, Deserialize)]
+	struct Process;
+
+	#[async_trait::async_trait]
+	impl Job for Process {
+		type Environment = ();
+		const JOB_TYPE: &'static str = "process";
+		const TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
+
+		fn perform(self, _: &()) -> Result<(), PerformError> {
+			Ok(())
+		}
+	}
+
+	// `process` job of another crate
+	#[derive(Serialize, Deserialize)]
+	struct OtherProcess;
+
+	#[async_trait::async_trait]
+	impl Job for OtherProcess {
+		type Environment = ();
+		const JOB_TYPE: &'static str = "process";
+
+		fn perform(self, _: &()) -> Result<(), PerformError> {
+			Err("wrong job".into())
+		}
+	}
+
+	#[test]
+	fn detects_colliding_job_types() {
+		let mut registry = Registry::<()>::default();
+		registry.register_job::<Process>().unwrap();
+		// registering the same job again is fine
+		registry.register_job::<Process>().unwrap();
+		assert!(registry.check().is_ok());
+
+		assert!(matches!(registry.register_job::<OtherProcess>(), Err(Error::JobTypeCollision("process"))));
+		assert!(matches!(registry.check(), Err(Error::JobTypeCollision("process"))));
+		// the job registered first is kept
+		assert!(registry.get("process").unwrap().vtable.job == TypeId::of::<Process>());
+	}
+
+	#[test]
+	fn jobs_can_only_be_registered_once() {
+		let mut registry = Registry::<()>::default();
+		registry.try_register_job::<Process>().unwrap();
+		assert!(matches!(registry.try_register_job::<Process>(), Err(Error::DuplicateJob("process"))));
+		assert!(matches!(registry.try_register_job::<OtherProcess>(), Err(Error::DuplicateJob("process"))));
+		assert!(matches!(Registry::<String>::default().try_register_job::<Process>(), Err(Error::WrongEnvironment(_))));
+		assert!(registry.check().is_ok());
+		assert_eq!(registry.job_types().collect::<Vec<_>>(), ["process"]);
+	}
+
+	#[test]
+	fn job_timeouts_can_be_overridden() {
+		let mut registry = Registry::<()>::default();
+		registry.register_job::<Process>().unwrap();
+		assert_eq!(registry.timeouts().get("process"), Some(&Duration::from_secs(60)));
+
+		registry.set_timeout("process", Duration::from_secs(5));
+		registry.set_timeout("other", Duration::from_secs(1));
+		assert_eq!(registry.timeouts().get("process"), Some(&Duration::from_secs(5)));
+		assert_eq!(registry.timeouts().get("other"), Some(&Duration::from_secs(1)));
 	}
 }
@@ -0,0 +1,111 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Retries of failed jobs with exponential backoff.
+//! The number of attempts and the time of the next one are kept in the job itself,
+//! so they survive restarts of the runner.
+//! Retried jobs wait out their backoff in the broker, either in the delayed message exchange of the queue
+//! or in a retry queue per backoff, which dead-letters them back to the queue once their TTL expires.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How failed jobs are retried.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+	/// Number of times a failed job is retried before it is dropped.
+	pub max_retries: u32,
+	/// Delay before the first retry, doubled for every further retry.
+	pub initial_backoff: Duration,
+	/// Upper bound of the delay between retries.
+	pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self { max_retries: 0, initial_backoff: Duration::from_secs(1), max_backoff: Duration::from_secs(5 * 60) }
+	}
+}
+
+impl RetryPolicy {
+	/// Whether a job which failed after `retry_count` retries should be retried again.
+	pub fn should_retry(&self, retry_count: u32) -> bool {
+		retry_count < self.max_retries
+	}
+
+	/// Delay before the retry following `retry_count` previous retries.
+	pub fn backoff(&self, retry_count: u32) -> Duration {
+		let factor = 2u32.checked_pow(retry_count).unwrap_or(u32::MAX);
+		self.initial_backoff.checked_mul(factor).map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+	}
+
+	/// Every distinct backoff a job may be retried with, shortest first.
+	pub fn backoffs(&self) -> Vec<Duration> {
+		let mut backoffs = Vec::new();
+		for retry_count in 0..self.max_retries {
+			let backoff = self.backoff(retry_count);
+			if backoffs.last() == Some(&backoff) {
+				break;
+			}
+			backoffs.push(backoff);
+		}
+		backoffs
+	}
+}
+
+/// Name of the queue holding jobs of `queue` for `backoff` before they are retried.
+pub(crate) fn retry_queue(queue: &str, backoff: Duration) -> String {
+	format!("{}.retry.{}", queue, backoff.as_millis())
+}
+
+/// Milliseconds since the UNIX epoch at `delay` from now.
+pub(crate) fn millis_from_now(delay: Duration) -> u64 {
+	let at = SystemTime::now() + delay;
+	at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_grows_exponentially() {
+		let policy = RetryPolicy {
+			max_retries: 3,
+			initial_backoff: Duration::from_secs(1),
+			max_backoff: Duration::from_secs(10),
+		};
+		let backoffs = (0..6).map(|n| policy.backoff(n).as_secs()).collect::<Vec<_>>();
+		assert_eq!(backoffs, vec![1, 2, 4, 8, 10, 10]);
+		assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+
+		assert!(policy.should_retry(2));
+		assert!(!policy.should_retry(3));
+		assert!(!RetryPolicy::default().should_retry(0));
+	}
+
+	#[test]
+	fn backoffs_are_distinct() {
+		let policy = RetryPolicy {
+			max_retries: 8,
+			initial_backoff: Duration::from_millis(250),
+			max_backoff: Duration::from_secs(1),
+		};
+		let backoffs = policy.backoffs().iter().map(|d| d.as_millis()).collect::<Vec<_>>();
+		assert_eq!(backoffs, vec![250, 500, 1000]);
+		assert!(RetryPolicy::default().backoffs().is_empty());
+		assert_eq!(retry_queue("TASK_QUEUE", Duration::from_millis(500)), "TASK_QUEUE.retry.500");
+	}
+}
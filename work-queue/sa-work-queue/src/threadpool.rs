@@ -18,21 +18,40 @@
 //! Each thread in the pool gets its own RabbitMq Channel/Consumer.
 //! Each instance of a threadpool shares one RabbitMq connection amongst all of its threads.
 
-use std::{cell::RefCell, rc::Rc, sync::Arc, time::Duration};
+use std::{
+	cell::RefCell,
+	rc::Rc,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Barrier, Mutex, RwLock,
+	},
+	thread,
+	time::{Duration, Instant},
+};
 
-use async_amqp::LapinAsyncStdExt;
 use async_std::{future::timeout, task};
 use flume::{Receiver, Sender};
 use futures::StreamExt;
 use lapin::{
 	message::Delivery,
-	options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions},
-	types::FieldTable,
-	Connection, ConnectionProperties, Consumer,
+	options::{
+		BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicGetOptions, BasicNackOptions,
+		BasicPublishOptions, BasicQosOptions, QueueDeclareOptions,
+	},
+	types::{AMQPValue, FieldTable},
+	BasicProperties, Channel, Connection, Consumer,
 };
 use threadpool::ThreadPool;
 
-use crate::{error::*, job::BackgroundJob, runner::Event};
+use crate::{
+	codec, compression,
+	dedup::{self, RecentKeys},
+	error::*,
+	job::BackgroundJob,
+	retry::{self, RetryPolicy},
+	runner::Event,
+	tls::{self, ConnectOptions, Reconnect},
+};
 
 thread_local!(static CONSUMER: ConsumerHandle = Default::default());
 
@@ -41,25 +60,96 @@ struct QueueOpts {
 	queue_name: String,
 	addr: String,
 	prefetch: u16,
+	/// Ramp the prefetch of consumers up to `prefetch`.
+	rampup: Option<PrefetchRamp>,
+	/// How failed jobs are retried.
+	retry: RetryPolicy,
+	/// Delayed message exchange routing to the queue, which holds retried jobs for their backoff.
+	/// Without it, retried jobs wait in the retry queues of the queue.
+	delayed_exchange: Option<String>,
+	/// Certificates, heartbeat and timeout to connect to the broker with.
+	connect: ConnectOptions,
+	/// How often to try re-establishing a closed connection.
+	reconnect: Reconnect,
+	/// Stop consuming while every worker is running a job.
+	backpressure: bool,
 }
 
 impl Default for QueueOpts {
 	fn default() -> Self {
-		Self { queue_name: "TASK_QUEUE".to_string(), addr: "amqp://localhost:5672".to_string(), prefetch: 1 }
+		Self {
+			queue_name: "TASK_QUEUE".to_string(),
+			addr: "amqp://localhost:5672".to_string(),
+			prefetch: 1,
+			rampup: None,
+			retry: RetryPolicy::default(),
+			delayed_exchange: None,
+			connect: ConnectOptions::default(),
+			reconnect: Reconnect::default(),
+			backpressure: false,
+		}
 	}
 }
 
 impl QueueOpts {
 	fn create_connection(&self) -> Result<Connection, Error> {
-		Ok(Connection::connect(&self.addr, ConnectionProperties::default().with_async_std()).wait()?)
+		tls::connect(&self.addr, &self.connect)
+	}
+
+	/// The prefetch consumers should currently use.
+	fn effective_prefetch(&self) -> u16 {
+		match self.rampup {
+			Some(ramp) => ramped_prefetch(self.prefetch, ramp.warmup, ramp.started.elapsed()),
+			None => self.prefetch,
+		}
+	}
+
+	/// The prefetch a consumer should take its next job with.
+	/// With backpressure, a saturated pool has no worker left to run buffered jobs,
+	/// so consumers only take the job they run.
+	fn target_prefetch(&self, saturated: bool) -> u16 {
+		if self.backpressure && saturated {
+			1
+		} else {
+			self.effective_prefetch()
+		}
 	}
 }
 
+/// Increase the prefetch of consumers linearly from 1 to the configured prefetch over `warmup`,
+/// starting at `started`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+struct PrefetchRamp {
+	warmup: Duration,
+	started: Instant,
+}
+
+/// Prefetch `elapsed` into a ramp of `warmup` towards `target`.
+fn ramped_prefetch(target: u16, warmup: Duration, elapsed: Duration) -> u16 {
+	if target <= 1 || elapsed >= warmup {
+		return target;
+	}
+	let progress = elapsed.as_secs_f64() / warmup.as_secs_f64();
+	1 + (f64::from(target - 1) * progress) as u16
+}
+
+/// Release the consumers of workers once the queue has been empty for `timeout`,
+/// keeping at least `min_threads` of them.
+#[derive(Clone, Copy, Debug)]
+struct IdleScaling {
+	min_threads: usize,
+	timeout: Duration,
+}
+
 #[derive(Default)]
 pub struct Builder {
 	opts: QueueOpts,
 	threads: Option<usize>,
 	name: Option<String>,
+	stack_size: Option<usize>,
+	idle: Option<IdleScaling>,
+	rampup: Option<Duration>,
+	dedup_capacity: Option<usize>,
 }
 
 impl Builder {
@@ -78,6 +168,52 @@ impl Builder {
 		self
 	}
 
+	/// Try to re-establish a closed connection to the broker up to `attempts` times, waiting `delay` in between.
+	pub fn reconnect(mut self, attempts: u32, delay: Duration) -> Self {
+		self.opts.reconnect = Reconnect { attempts, delay };
+		self
+	}
+
+	/// Connect to the broker with `options`, I.E over TLS.
+	pub fn connect_options(mut self, options: ConnectOptions) -> Self {
+		self.opts.connect = options;
+		self
+	}
+
+	/// Remember the idempotency keys of the last `capacity` jobs consumed, dropping jobs with one of them.
+	pub fn dedup_capacity(mut self, capacity: usize) -> Self {
+		self.dedup_capacity = Some(capacity);
+		self
+	}
+
+	/// Cancel the consumers of workers while every worker is running a job, requeueing the jobs buffered on them,
+	/// so jobs are not buffered on consumers which can't run them yet. Meanwhile, workers take their next job
+	/// off the queue one at a time, and consume again once a worker is free.
+	pub fn backpressure(mut self) -> Self {
+		self.opts.backpressure = true;
+		self
+	}
+
+	/// Start consumers at a prefetch of 1, increasing it to the configured prefetch over `warmup`.
+	pub fn prefetch_rampup(mut self, warmup: Duration) -> Self {
+		self.rampup = Some(warmup);
+		self
+	}
+
+	/// Retry failed jobs up to `max_retries` times, waiting `initial_backoff` before the first retry
+	/// and doubling the wait for every further retry, up to `max_backoff`.
+	pub fn retry_policy(mut self, max_retries: u32, initial_backoff: Duration, max_backoff: Duration) -> Self {
+		self.opts.retry = RetryPolicy { max_retries, initial_backoff, max_backoff };
+		self
+	}
+
+	/// Publish retried jobs to the delayed message exchange `name`, rather than to retry queues.
+	/// The exchange must route messages with the name of the queue as routing key to the queue.
+	pub fn delayed_exchange<S: AsRef<str>>(mut self, name: S) -> Self {
+		self.opts.delayed_exchange = Some(name.as_ref().to_string());
+		self
+	}
+
 	pub fn threads(mut self, threads: usize) -> Self {
 		self.threads = Some(threads);
 		self
@@ -88,24 +224,71 @@ impl Builder {
 		self
 	}
 
-	pub fn build(self) -> Result<ThreadPoolMq, Error> {
-		let conn = Arc::new(self.opts.create_connection()?);
-		let pool = ThreadPool::with_name(
-			self.name.unwrap_or_else(|| "work-queue".into()),
-			self.threads.unwrap_or_else(num_cpus::get),
-		);
+	/// Size of the stack (in bytes) for each worker thread.
+	pub fn thread_stack_size(mut self, size: usize) -> Self {
+		self.stack_size = Some(size);
+		self
+	}
+
+	/// Scale down to `min_threads` workers once there have been no jobs for `timeout`.
+	/// The other workers release their consumer and park until jobs are queued again.
+	pub fn idle_scaling(mut self, min_threads: usize, timeout: Duration) -> Self {
+		self.idle = Some(IdleScaling { min_threads: std::cmp::max(min_threads, 1), timeout });
+		self
+	}
+
+	pub fn build(mut self) -> Result<ThreadPoolMq, Error> {
+		self.opts.rampup = self.rampup.map(|warmup| PrefetchRamp { warmup, started: Instant::now() });
+		let conn = self.opts.create_connection()?;
+		if self.opts.delayed_exchange.is_none() {
+			declare_retry_queues(&conn, &self.opts)?;
+		}
+		let conn = RwLock::new(Arc::new(conn));
+		let mut pool = threadpool::Builder::new()
+			.thread_name(self.name.unwrap_or_else(|| "work-queue".into()))
+			.num_threads(self.threads.unwrap_or_else(sa_cpus::available_cpus));
+		if let Some(size) = self.stack_size {
+			pool = pool.thread_stack_size(size);
+		}
+		let pool = pool.build();
 		let (tx, rx) = flume::bounded(pool.max_count());
 
-		Ok(ThreadPoolMq { conn, tx, rx, pool, queue_opts: Arc::new(self.opts) })
+		Ok(ThreadPoolMq {
+			conn,
+			tx,
+			rx,
+			max_threads: pool.max_count(),
+			pool,
+			queue_opts: Arc::new(self.opts),
+			idle: self.idle,
+			last_busy: Mutex::new(Instant::now()),
+			scaled_down: AtomicBool::new(false),
+			stopping: Arc::new(AtomicBool::new(false)),
+			consumers: Arc::new(AtomicUsize::new(0)),
+			recent_keys: Arc::new(Mutex::new(RecentKeys::new(self.dedup_capacity.unwrap_or(dedup::DEFAULT_CAPACITY)))),
+		})
 	}
 }
 
 pub struct ThreadPoolMq {
-	conn: Arc<Connection>,
+	/// Replaced once the connection is re-established after it was closed.
+	conn: RwLock<Arc<Connection>>,
 	queue_opts: Arc<QueueOpts>,
 	pool: ThreadPool,
 	tx: Sender<Event>,
 	rx: Receiver<Event>,
+	/// Number of threads the pool was built with.
+	max_threads: usize,
+	idle: Option<IdleScaling>,
+	/// Last time the queue had jobs.
+	last_busy: Mutex<Instant>,
+	scaled_down: AtomicBool,
+	/// Set once the pool is shutting down, after which workers take no more jobs.
+	stopping: Arc<AtomicBool>,
+	/// Number of workers holding a consumer.
+	consumers: Arc<AtomicUsize>,
+	/// Idempotency keys of jobs consumed recently.
+	recent_keys: Arc<Mutex<RecentKeys>>,
 }
 
 impl ThreadPoolMq {
@@ -120,16 +303,131 @@ impl ThreadPoolMq {
 	where
 		F: Send + 'static + FnOnce(BackgroundJob) -> Result<(), PerformError>,
 	{
-		let conn = self.conn.clone();
+		let conn = self.connection();
 		let tx = self.tx.clone();
 		let queue_opts = self.queue_opts.clone();
+		let consumers = self.consumers.clone();
+		let stopping = self.stopping.clone();
+		let recent_keys = self.recent_keys.clone();
+		let pool = self.pool.clone();
 		self.pool.execute(move || {
-			if let Err(e) = run_job(&conn, &queue_opts, tx, job) {
+			if stopping.load(Ordering::SeqCst) {
+				return;
+			}
+			// this worker is counted as active, so there is no other worker to run buffered jobs
+			let saturated = pool.active_count() >= pool.max_count();
+			if let Err(e) = run_job(&conn, &queue_opts, saturated, &consumers, &recent_keys, tx, job) {
 				log::error!("{}", e);
 			}
 		})
 	}
 
+	/// The current connection to the broker.
+	pub fn connection(&self) -> Arc<Connection> {
+		self.conn.read().expect("lock is never poisoned; qed").clone()
+	}
+
+	/// Re-establish the connection to the broker if it was closed, I.E because the broker restarted.
+	/// Workers replace their consumer with one on the new connection before running their next job.
+	/// Returns whether the connection was re-established.
+	pub fn reconnect(&self) -> Result<bool, Error> {
+		let opts = &self.queue_opts;
+		let mut conn = self.conn.write().expect("lock is never poisoned; qed");
+		if conn.status().connected() || !opts.reconnect.is_enabled() {
+			return Ok(false);
+		}
+		*conn = Arc::new(opts.reconnect.connect(&opts.addr, &opts.connect)?);
+		Ok(true)
+	}
+
+	/// Whether the pool scales down while there are no jobs.
+	pub fn is_idle_scaling(&self) -> bool {
+		self.idle.is_some()
+	}
+
+	/// Scale the pool according to the number of jobs waiting in the queue.
+	/// Once the queue has been empty for the idle timeout, the consumers of idle workers are released
+	/// and the pool shrinks to the minimum. It grows back as soon as there are jobs again.
+	/// Does nothing if idle scaling is not enabled.
+	pub fn scale(&self, depth: usize) {
+		let idle = match self.idle {
+			Some(idle) => idle,
+			None => return,
+		};
+		let mut last_busy = self.last_busy.lock().expect("lock is never poisoned; qed");
+		if depth > 0 || self.pool.active_count() > 0 || self.pool.queued_count() > 0 {
+			*last_busy = Instant::now();
+			if self.scaled_down.swap(false, Ordering::SeqCst) {
+				log::debug!("Scaling work queue up to {} threads", self.max_threads);
+				self.set_num_threads(self.max_threads);
+			}
+		} else if last_busy.elapsed() >= idle.timeout && !self.scaled_down.swap(true, Ordering::SeqCst) {
+			log::debug!("Scaling work queue down to {} threads", idle.min_threads);
+			// every worker is idle, and none can finish before all have started, so each worker gets one of these
+			let released = Arc::new(Barrier::new(self.max_threads + 1));
+			for _ in 0..self.max_threads {
+				let consumers = self.consumers.clone();
+				let released = released.clone();
+				self.pool.execute(move || {
+					ConsumerHandle::current().release(&consumers, idle.min_threads);
+					released.wait();
+				});
+			}
+			released.wait();
+			self.set_num_threads(idle.min_threads);
+		}
+	}
+
+	/// Stop taking jobs off the queue, and wait up to `timeout` for workers to finish the jobs they are running.
+	/// Once they have, every worker requeues the deliveries buffered on its consumer and closes its channel.
+	/// Returns the number of requeued deliveries, or `None` if workers were still running jobs after `timeout`.
+	pub fn shutdown(&self, timeout: Duration) -> Option<usize> {
+		self.stopping.store(true, Ordering::SeqCst);
+		let (tx, rx) = flume::bounded(1);
+		let pool = self.pool.clone();
+		thread::spawn(move || {
+			pool.join();
+			let _ = tx.send(());
+		});
+		if rx.recv_timeout(timeout).is_err() {
+			return None;
+		}
+		// every worker is idle, and none can finish before all have started, so each worker gets one of these
+		let threads = self.pool.max_count();
+		let released = Arc::new(Barrier::new(threads));
+		let (tx, rx) = flume::bounded(threads);
+		for _ in 0..threads {
+			let tx = tx.clone();
+			let released = released.clone();
+			self.pool.execute(move || {
+				let _ = tx.send(ConsumerHandle::current().requeue_buffered());
+				released.wait();
+			});
+		}
+		drop(tx);
+		let requeued = rx
+			.iter()
+			.map(|requeued| {
+				requeued.unwrap_or_else(|e| {
+					log::error!("Failed to requeue buffered jobs: {}", e);
+					0
+				})
+			})
+			.sum();
+		Some(requeued)
+	}
+
+	fn set_num_threads(&self, threads: usize) {
+		// clones of the pool share their state, so this resizes `self.pool` as well.
+		let mut pool = self.pool.clone();
+		pool.set_num_threads(threads);
+	}
+
+	/// Number of workers holding a consumer on the queue.
+	pub fn consumer_count(&self) -> usize {
+		self.consumers.load(Ordering::SeqCst)
+	}
+
 	pub fn max_count(&self) -> usize {
 		self.pool.max_count()
 	}
@@ -142,6 +440,11 @@ impl ThreadPoolMq {
 		self.pool.queued_count()
 	}
 
+	/// The prefetch of consumers, which is lower than the configured prefetch while ramping up.
+	pub fn effective_prefetch(&self) -> u16 {
+		self.queue_opts.effective_prefetch()
+	}
+
 	/// Get the receiving end of events sent from the threadpool
 	pub fn events(&self) -> &Receiver<Event> {
 		&self.rx
@@ -156,13 +459,82 @@ impl ThreadPoolMq {
 	pub fn panic_count(&self) -> usize {
 		self.pool.panic_count()
 	}
+
+	/// A clone of the pool, sharing its state, to read its counters from.
+	#[cfg(feature = "metrics")]
+	pub(crate) fn pool(&self) -> ThreadPool {
+		self.pool.clone()
+	}
 }
 
 // A handle to a consumer that by default is not initalized.
 // mostly for convenience + clarity.
 #[derive(Default, Clone)]
 struct ConsumerHandle {
-	inner: Rc<RefCell<Option<Consumer>>>,
+	inner: Rc<RefCell<Option<WorkerConsumer>>>,
+}
+
+/// A consumer along with the channel it was created on.
+/// The channel is closed when the consumer is dropped, freeing its slot on the broker.
+struct WorkerConsumer {
+	channel: Channel,
+	consumer: Consumer,
+	/// Prefetch set on the channel.
+	prefetch: u16,
+	/// Whether the consumer was cancelled while the pool is saturated.
+	paused: bool,
+	/// Count of live consumers this one is part of, if it has not been taken out of it already.
+	consumers: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for WorkerConsumer {
+	fn drop(&mut self) {
+		if let Some(consumers) = self.consumers.take() {
+			consumers.fetch_sub(1, Ordering::SeqCst);
+		}
+		if let Err(e) = self.channel.close(200, "worker released").wait() {
+			log::debug!("Failed to close the channel of a released worker: {}", e);
+		}
+	}
+}
+
+impl WorkerConsumer {
+	/// Cancel the consumer and requeue the deliveries buffered on it.
+	/// The channel stays open, so deliveries taken off the consumer before can still be acknowledged.
+	/// Returns the number of requeued deliveries.
+	fn cancel(&mut self) -> Result<usize, Error> {
+		let tag = self.consumer.tag();
+		self.channel.basic_cancel(tag.as_str(), BasicCancelOptions::default()).wait()?;
+		let mut requeued = 0;
+		while let Some((_, delivery)) =
+			task::block_on(timeout(Duration::from_millis(10), self.consumer.next())).ok().flatten().transpose()?
+		{
+			task::block_on(delivery.acker.nack(BasicNackOptions { requeue: true, ..Default::default() }))?;
+			requeued += 1;
+		}
+		Ok(requeued)
+	}
+
+	/// Stop consuming while the pool is saturated, so no jobs are buffered on the consumer.
+	fn pause(&mut self) -> Result<(), Error> {
+		if !self.paused {
+			let requeued = self.cancel()?;
+			self.paused = true;
+			log::debug!("Pausing the consumer of a saturated pool, requeued {} buffered jobs", requeued);
+		}
+		Ok(())
+	}
+
+	/// Consume `queue` again once a worker is free.
+	fn resume(&mut self, queue: &str) -> Result<(), Error> {
+		if self.paused {
+			log::debug!("Resuming the consumer of a paused worker");
+			self.consumer =
+				self.channel.basic_consume(queue, "", BasicConsumeOptions::default(), FieldTable::default()).wait()?;
+			self.paused = false;
+		}
+		Ok(())
+	}
 }
 
 impl ConsumerHandle {
@@ -170,20 +542,76 @@ impl ConsumerHandle {
 		CONSUMER.with(|c| c.clone())
 	}
 
-	/// initialize the consumer if it is not already.
-	fn init(&self, conn: &Connection, opts: &QueueOpts) -> Result<(), Error> {
+	/// initialize the consumer with `prefetch` if it is not already.
+	fn init(
+		&self,
+		conn: &Connection,
+		opts: &QueueOpts,
+		prefetch: u16,
+		consumers: &Arc<AtomicUsize>,
+	) -> Result<(), Error> {
 		let mut this = self.inner.borrow_mut();
-		if this.is_some() {
-			return Ok(());
+		match this.as_ref() {
+			Some(worker) if worker.channel.status().connected() => return Ok(()),
+			// the connection was lost, replace the consumer with one on the current connection
+			Some(_) => *this = None,
+			None => (),
 		}
 		let chan = conn.create_channel().wait()?;
-		chan.basic_qos(opts.prefetch, BasicQosOptions::default()).wait()?;
+		chan.basic_qos(prefetch, BasicQosOptions::default()).wait()?;
 		log::debug!("Creating Channel for queue {}", &opts.queue_name);
 		let consumer =
 			chan.basic_consume(&opts.queue_name, "", BasicConsumeOptions::default(), FieldTable::default()).wait()?;
-		let _ = this.insert(consumer);
+		consumers.fetch_add(1, Ordering::SeqCst);
+		let worker =
+			WorkerConsumer { channel: chan, consumer, prefetch, paused: false, consumers: Some(consumers.clone()) };
+		let _ = this.insert(worker);
 		Ok(())
 	}
+
+	/// Update the prefetch of the consumer, I.E while it is ramping up or the pool is saturated.
+	fn set_prefetch(&self, prefetch: u16) -> Result<(), Error> {
+		let mut this = self.inner.borrow_mut();
+		let consumer = match this.as_mut() {
+			Some(consumer) => consumer,
+			None => return Ok(()),
+		};
+		if prefetch != consumer.prefetch {
+			log::trace!("Changing the prefetch of a consumer from {} to {}", consumer.prefetch, prefetch);
+			consumer.channel.basic_qos(prefetch, BasicQosOptions::default()).wait()?;
+			consumer.prefetch = prefetch;
+		}
+		Ok(())
+	}
+
+	/// Release the consumer, unless only `min` workers hold one.
+	fn release(&self, consumers: &AtomicUsize, min: usize) {
+		let mut this = self.inner.borrow_mut();
+		if this.is_none() {
+			return;
+		}
+		if consumers
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n > min { Some(n - 1) } else { None })
+			.is_ok()
+		{
+			log::debug!("Releasing idle consumer");
+			if let Some(mut released) = this.take() {
+				// already taken out of the count
+				released.consumers = None;
+			}
+		}
+	}
+
+	/// Stop consuming, requeue the deliveries buffered on the consumer and close its channel.
+	/// Returns the number of requeued deliveries.
+	fn requeue_buffered(&self) -> Result<usize, Error> {
+		match self.inner.borrow_mut().take() {
+			// a paused consumer has nothing buffered
+			Some(worker) if worker.paused => Ok(0),
+			Some(mut worker) => worker.cancel(),
+			None => Ok(0),
+		}
+	}
 }
 
 // FIXME: There may be a better way to do this that avoids sending in the 'queue_name' as a string.
@@ -194,33 +622,127 @@ impl ConsumerHandle {
 // However, those options sound more extreme and unnecessary for this use-case.
 //
 //
-/// Run the job, initializing the thread-local consumer if it has not been initialized
-fn run_job<F>(conn: &Connection, opts: &QueueOpts, tx: Sender<Event>, job: F) -> Result<(), Error>
+/// Run the job, initializing the thread-local consumer if it has not been initialized.
+/// With backpressure, the consumer is paused while the pool is `saturated`, and the job is taken off the queue
+/// on its own, rather than from the consumer.
+/// Jobs with the idempotency key of a job consumed recently are acknowledged without running them.
+fn run_job<F>(
+	conn: &Connection,
+	opts: &QueueOpts,
+	saturated: bool,
+	consumers: &Arc<AtomicUsize>,
+	recent_keys: &Mutex<RecentKeys>,
+	tx: Sender<Event>,
+	job: F,
+) -> Result<(), Error>
 where
 	F: Send + 'static + FnOnce(BackgroundJob) -> Result<(), PerformError>,
 {
 	let handle = ConsumerHandle::current();
-	handle.init(conn, opts)?;
-	let mut consumer = handle.inner.borrow_mut();
-	let consumer = consumer.as_mut().expect("Initialized handle must be Some; qed");
+	if let Err(e) = handle.init(conn, opts, prefetch, consumers) {
+		let _ = tx.send(Event::ErrorLoadingJob(FetchError::Connection(Box::new(e))));
+		return Ok(());
+	}
+	handle.set_prefetch(opts.target_prefetch(saturated))?;
+	let mut worker = handle.inner.borrow_mut();
+	let worker = worker.as_mut().expect("Initialized handle must be Some; qed");
+	if opts.backpressure && saturated {
+		worker.pause()?;
+	} else {
+		worker.resume(&opts.queue_name)?;
+	}
 
-	if let Some((data, delivery)) = next_job(tx, consumer) {
+	let next =
+		if worker.paused { take_job(&worker.channel, &opts.queue_name) } else { get_next_job(&mut worker.consumer) };
+	if let Some((data, delivery)) = next_job(tx, next) {
+		// a redelivered job was never acknowledged, so it runs even if its key was seen
+		if let Some(key) = delivery.properties.message_id().as_ref().filter(|_| !delivery.redelivered) {
+			if recent_keys.lock().expect("lock is never poisoned; qed").seen(key.as_str()) {
+				log::debug!("Dropping duplicate of job `{}` with idempotency key `{}`", data.job_type, key);
+				task::block_on(delivery.acker.ack(BasicAckOptions::default()))?;
+				return Ok(());
+			}
+		}
 		match job(data) {
 			Ok(_) => {
 				task::block_on(delivery.acker.ack(BasicAckOptions::default()))?;
 			}
 			Err(e) => {
-				task::block_on(delivery.acker.nack(BasicNackOptions { requeue: false, ..Default::default() }))?;
-				let job: BackgroundJob = serde_json::from_slice(&delivery.data)?;
-				return Err(Error::Msg(format!("Job `{}` failed to run: {}", job.job_type, e)));
+				let job = decode_job(&delivery)?;
+				if opts.retry.should_retry(job.retry_count) {
+					retry_job(&worker.channel, opts, job, &e)?;
+					task::block_on(delivery.acker.ack(BasicAckOptions::default()))?;
+				} else {
+					task::block_on(delivery.acker.nack(BasicNackOptions { requeue: false, ..Default::default() }))?;
+					return Err(Error::Msg(format!(
+						"Job `{}` failed to run after {} retries: {}",
+						job.job_type, job.retry_count, e
+					)));
+				}
 			}
 		}
 	}
 	Ok(())
 }
 
-fn next_job(tx: Sender<Event>, consumer: &mut Consumer) -> Option<(BackgroundJob, Delivery)> {
-	match get_next_job(consumer) {
+/// Declare a queue for every backoff of the retry policy, which holds retried jobs until their backoff
+/// has passed and then dead-letters them back to the queue.
+fn declare_retry_queues(conn: &Connection, opts: &QueueOpts) -> Result<(), Error> {
+	let backoffs = opts.retry.backoffs();
+	if backoffs.is_empty() {
+		return Ok(());
+	}
+	let channel = conn.create_channel().wait()?;
+	for backoff in backoffs {
+		let mut args = FieldTable::default();
+		args.insert("x-message-ttl".into(), AMQPValue::LongLongInt(backoff.as_millis() as i64));
+		args.insert("x-dead-letter-exchange".into(), AMQPValue::LongString("".into()));
+		args.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(opts.queue_name.as_str().into()));
+		let options = QueueDeclareOptions { durable: true, ..Default::default() };
+		channel.queue_declare(&retry::retry_queue(&opts.queue_name, backoff), options, args).wait()?;
+	}
+	channel.close(200, "retry queues declared").wait()?;
+	Ok(())
+}
+
+/// Publish a failed job to be run again once its backoff has passed.
+/// The job waits out its backoff in the delayed message exchange of the queue if there is one,
+/// or in the retry queue for its backoff, so the worker is free to take the next job meanwhile.
+fn retry_job(channel: &Channel, opts: &QueueOpts, mut job: BackgroundJob, e: &PerformError) -> Result<(), Error> {
+	let backoff = opts.retry.backoff(job.retry_count);
+	job.retry_count += 1;
+	job.retry_at = Some(retry::millis_from_now(backoff));
+	log::warn!(
+		"Job `{}` failed to run: {}. Retrying in {:?} ({}/{})",
+		job.job_type,
+		e,
+		backoff,
+		job.retry_count,
+		opts.retry.max_retries
+	);
+	let mut properties = BasicProperties::default();
+	if job.priority > 0 {
+		properties = properties.with_priority(job.priority);
+	}
+	let payload = codec::encode_job(&job)?;
+	let (exchange, routing_key) = match opts.delayed_exchange.as_ref() {
+		Some(exchange) => {
+			let mut headers = FieldTable::default();
+			headers.insert("x-delay".into(), AMQPValue::LongLongInt(backoff.as_millis() as i64));
+			properties = properties.with_headers(headers);
+			(exchange.as_str(), opts.queue_name.clone())
+		}
+		None => ("", retry::retry_queue(&opts.queue_name, backoff)),
+	};
+	channel.basic_publish(exchange, &routing_key, BasicPublishOptions::default(), payload, properties).wait()?;
+	Ok(())
+}
+
+fn next_job(
+	tx: Sender<Event>,
+	next: Result<Option<(BackgroundJob, Delivery)>, FetchError>,
+) -> Option<(BackgroundJob, Delivery)> {
+	match next {
 		Ok(Some(d)) => {
 			let _ = tx.send(Event::Working);
 			Some(d)
@@ -239,7 +761,48 @@ fn next_job(tx: Sender<Event>, consumer: &mut Consumer) -> Option<(BackgroundJob
 fn get_next_job(consumer: &mut Consumer) -> Result<Option<(BackgroundJob, Delivery)>, FetchError> {
 	let delivery =
 		task::block_on(timeout(Duration::from_millis(10), consumer.next())).ok().flatten().transpose()?.map(|(_, d)| d);
-	let data: Option<BackgroundJob> =
-		delivery.as_ref().map(|d| serde_json::from_slice(d.data.as_slice())).transpose()?;
+	let data: Option<BackgroundJob> = delivery.as_ref().map(decode_job).transpose()?;
+	Ok(data.zip(delivery))
+}
+
+/// Take a single job off `queue`, without consuming it.
+fn take_job(channel: &Channel, queue: &str) -> Result<Option<(BackgroundJob, Delivery)>, FetchError> {
+	let delivery = channel.basic_get(queue, BasicGetOptions::default()).wait()?.map(|message| message.delivery);
+	let data: Option<BackgroundJob> = delivery.as_ref().map(decode_job).transpose()?;
 	Ok(data.zip(delivery))
 }
+
+/// Decode a job from a delivery, decompressing it according to its `content_encoding`.
+pub(crate) fn decode_job(delivery: &Delivery) -> Result<BackgroundJob, FetchError> {
+	let encoding = delivery.properties.content_encoding().as_ref().map(|e| e.as_str());
+	let data = compression::decompress(encoding, delivery.data.as_slice())?;
+	codec::decode_job(&data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn prefetch_ramps_up_over_warmup() {
+		let warmup = Duration::from_secs(10);
+		let prefetch = |secs| ramped_prefetch(100, warmup, Duration::from_secs(secs));
+		assert_eq!(prefetch(0), 1);
+		let ramp = (0..=10).map(prefetch).collect::<Vec<_>>();
+		assert!(ramp.windows(2).all(|w| w[0] <= w[1]), "prefetch never decreases: {:?}", ramp);
+		assert!(prefetch(5) > 1 && prefetch(5) < 100);
+		assert_eq!(prefetch(10), 100);
+		assert_eq!(prefetch(60), 100);
+		// nothing to ramp
+		assert_eq!(ramped_prefetch(1, warmup, Duration::from_secs(0)), 1);
+	}
+
+	#[test]
+	fn saturated_pools_take_one_job_with_backpressure() {
+		let opts = QueueOpts { prefetch: 10, ..Default::default() };
+		assert_eq!(opts.target_prefetch(true), 10);
+		let opts = QueueOpts { backpressure: true, ..opts };
+		assert_eq!(opts.target_prefetch(false), 10);
+		assert_eq!(opts.target_prefetch(true), 1);
+	}
+}
@@ -9,88 +9,143 @@ fn wrap_body(body: Vec<syn::Stmt>) -> TokenStream {
 	body
 }
 
-pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
+pub fn expand(item: syn::ItemFn, attrs: &JobAttrs) -> Result<TokenStream, Diagnostic> {
 	let job = BackgroundJob::try_from(item)?;
+	let name = &job.name;
+	let job_path = quote!(#name :: Job);
+	let job_type = quote!(concat!(module_path!(), "::", stringify!(#name)));
+	let field_vis = quote!(pub(super));
+	let scope = quote!(super);
 
-	let attrs = job.attrs;
-	let vis = job.visibility;
-	let fn_token = job.fn_token;
-	let name = job.name;
-	let env_pat = &job.args.env_arg.pat;
-	let env_type = &job.args.env_arg.ty;
-	let fn_args = job.args.iter();
-	let struct_def = job.args.struct_def();
-	let struct_assign = job.args.struct_assign();
-	let arg_names_0 = job.args.names();
-	let return_type = job.return_type;
-	let body = wrap_body(job.body);
-	let (impl_generics, ty_generics, where_clause) = job.generics.split_for_impl();
-
-	let res = if job.generics_exist {
-		quote! {
-			#(#attrs)*
-			#vis #fn_token #name #impl_generics (#(#fn_args),*) -> #name :: Job #ty_generics #where_clause {
-				#name :: Job {
-					#(#struct_assign),*
-				}
-			}
+	let constructor = job.constructor(&job_path);
+	let job_impl = job.job_impl(&job_path, &job_type, attrs);
+	let job_mod = job.job_mod(&scope, &field_vis);
 
-			#[sa_work_queue::async_trait::async_trait]
-			impl #impl_generics sa_work_queue::Job for #name :: Job #ty_generics #where_clause {
-				type Environment = #env_type;
-				const JOB_TYPE: &'static str = stringify!(#name);
+	Ok(quote! {
+		#constructor
 
-				#fn_token perform(self, #env_pat: &Self::Environment) #return_type {
-					let Self { #(#arg_names_0),* } = self;
-					#body
-				}
-			}
+		#job_impl
 
-			pub(crate) mod #name {
-				use super::*;
+		#job_mod
+	})
+}
 
-				#[derive(sa_work_queue::Serialize, sa_work_queue::Deserialize)]
-				#[serde(crate = "sa_work_queue::serde")]
-				pub struct Job #ty_generics {
-					#(#struct_def),*
-				}
-			}
+/// Expand every associated function of an `impl` block into a background job.
+/// The `Job` types are namespaced in a module named after the type, I.E `my_type_jobs::my_fn::Job`.
+pub fn expand_impl(item: syn::ItemImpl, job_attrs: &JobAttrs) -> Result<TokenStream, Diagnostic> {
+	let syn::ItemImpl { attrs, defaultness, unsafety, impl_token, generics, trait_, self_ty, items, .. } = item;
+
+	if let Some((_, path, _)) = trait_ {
+		return Err(path.span().error("#[sa_work_queue::background_job] cannot be used on trait implementations"));
+	}
+	if let Some(defaultness) = defaultness {
+		return Err(defaultness.span.error("#[sa_work_queue::background_job] cannot be used on default impls"));
+	}
+	if let Some(unsafety) = unsafety {
+		return Err(unsafety.span.error("#[sa_work_queue::background_job] cannot be used on unsafe impls"));
+	}
+	if !generics.params.is_empty() {
+		return Err(generics.span().error("#[sa_work_queue::background_job] cannot be used on generic impls"));
+	}
+	let type_name = match &*self_ty {
+		syn::Type::Path(syn::TypePath { qself: None, path }) => {
+			path.segments.last().map(|s| s.ident.clone()).expect("a path has at least one segment; qed")
 		}
-	} else {
-		quote! {
-			#(#attrs)*
-			#vis #fn_token #name (#(#fn_args),*) -> #name :: Job {
-				#name :: Job {
-					#(#struct_assign),*
-				}
+		ty => return Err(ty.span().error("#[sa_work_queue::background_job] can only be used on impls of named types")),
+	};
+	let namespace = quote::format_ident!("{}_jobs", snake_case(&type_name.to_string()));
+	let field_vis = quote!(pub(in super::super));
+	let scope = quote!(super::super);
+
+	let mut impl_items = Vec::new();
+	let mut job_impls = Vec::new();
+	let mut job_mods = Vec::new();
+	for impl_item in items {
+		let method = match impl_item {
+			syn::ImplItem::Method(method) => method,
+			item => {
+				impl_items.push(quote!(#item));
+				continue;
 			}
+		};
+		let syn::ImplItemMethod { attrs, vis, defaultness, sig, block } = method;
+		if let Some(defaultness) = defaultness {
+			return Err(defaultness.span.error("#[sa_work_queue::background_job] cannot be used on default fns"));
+		}
+		let job = BackgroundJob::try_from(syn::ItemFn { attrs, vis, sig, block: Box::new(block) })?;
+		let name = &job.name;
+		let job_path = quote!(#namespace :: #name :: Job);
+		let job_type = quote!(concat!(module_path!(), "::", stringify!(#type_name), "::", stringify!(#name)));
+
+		impl_items.push(job.constructor(&job_path));
+		job_impls.push(job.job_impl(&job_path, &job_type, job_attrs));
+		job_mods.push(job.job_mod(&scope, &field_vis));
+	}
 
-			#[sa_work_queue::async_trait::async_trait]
-			impl sa_work_queue::Job for #name :: Job {
-				type Environment = #env_type;
-				const JOB_TYPE: &'static str = stringify!(#name);
+	Ok(quote! {
+		#(#attrs)*
+		#impl_token #self_ty {
+			#(#impl_items)*
+		}
 
-				#fn_token perform(self, #env_pat: &Self::Environment) #return_type {
-					let Self { #(#arg_names_0),* } = self;
-					#body
-				}
+		#(#job_impls)*
+
+		pub(crate) mod #namespace {
+			#(#job_mods)*
+		}
+	})
+}
+
+/// `MyType` -> `my_type`
+fn snake_case(name: &str) -> String {
+	let mut snake = String::with_capacity(name.len());
+	for (i, c) in name.char_indices() {
+		if c.is_uppercase() {
+			if i > 0 {
+				snake.push('_');
 			}
+			snake.extend(c.to_lowercase());
+		} else {
+			snake.push(c);
+		}
+	}
+	snake
+}
 
-			pub(crate) mod #name {
-				use super::*;
+/// Arguments of the attribute, I.E `#[background_job(queue = "storage")]`.
+#[derive(Default)]
+pub struct JobAttrs {
+	/// Queue the jobs are pushed to, rather than the queue of the handle they are enqueued with.
+	queue: Option<syn::LitStr>,
+}
 
-				#[derive(sa_work_queue::Serialize, sa_work_queue::Deserialize)]
-				#[serde(crate = "sa_work_queue::serde")]
-				pub struct Job {
-					#(#struct_def),*
+impl JobAttrs {
+	pub fn try_from(args: syn::AttributeArgs) -> Result<Self, Diagnostic> {
+		let mut attrs = Self::default();
+		for arg in args {
+			match arg {
+				syn::NestedMeta::Meta(syn::Meta::NameValue(syn::MetaNameValue {
+					path,
+					lit: syn::Lit::Str(queue),
+					..
+				})) if path.is_ident("queue") => {
+					if attrs.queue.is_some() {
+						return Err(path.span().error("The queue of a job can only be set once"));
+					}
+					if queue.value().is_empty() {
+						return Err(queue.span().error("The queue name cannot be empty"));
+					}
+					attrs.queue = Some(queue);
+				}
+				arg => {
+					return Err(arg.span().error(
+						"Unknown argument, #[sa_work_queue::background_job] only takes `queue = \"<queue name>\"`",
+					))
 				}
-
-				sa_work_queue::register_job!(Job);
 			}
 		}
-	};
-
-	Ok(res)
+		Ok(attrs)
+	}
 }
 
 struct BackgroundJob {
@@ -106,6 +161,106 @@ struct BackgroundJob {
 }
 
 impl BackgroundJob {
+	/// The function which creates the job from its arguments.
+	fn constructor(&self, job_path: &TokenStream) -> TokenStream {
+		let attrs = &self.attrs;
+		let vis = &self.visibility;
+		let fn_token = &self.fn_token;
+		let name = &self.name;
+		let fn_args = self.args.iter();
+		let struct_assign = self.args.struct_assign();
+		let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+
+		if self.generics_exist {
+			quote! {
+				#(#attrs)*
+				#vis #fn_token #name #impl_generics (#(#fn_args),*) -> #job_path #ty_generics #where_clause {
+					#job_path {
+						#(#struct_assign),*
+					}
+				}
+			}
+		} else {
+			quote! {
+				#(#attrs)*
+				#vis #fn_token #name (#(#fn_args),*) -> #job_path {
+					#job_path {
+						#(#struct_assign),*
+					}
+				}
+			}
+		}
+	}
+
+	/// The implementation of `Job`, which runs the body of the function.
+	fn job_impl(&self, job_path: &TokenStream, job_type: &TokenStream, attrs: &JobAttrs) -> TokenStream {
+		let fn_token = &self.fn_token;
+		let env_pat = &self.args.env_arg.pat;
+		let env_type = &self.args.env_arg.ty;
+		let arg_names = self.args.names();
+		let return_type = &self.return_type;
+		let body = wrap_body(self.body.clone());
+		let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+		let queue = attrs.queue.as_ref().map(|queue| quote!(const QUEUE: Option<&'static str> = Some(#queue);));
+
+		// jobs taking a handle can only be performed by a runner, which passes its handle
+		let perform = match &self.args.handle_arg {
+			Some(handle_pat) => quote! {
+				#fn_token perform(self, _: &Self::Environment) #return_type {
+					Err(format!("Job `{}` must be performed with a queue handle", Self::JOB_TYPE).into())
+				}
+
+				#fn_token perform_with(
+					self,
+					#env_pat: &Self::Environment,
+					#handle_pat: &sa_work_queue::QueueHandle,
+				) #return_type {
+					let Self { #(#arg_names),* } = self;
+					#body
+				}
+			},
+			None => quote! {
+				#fn_token perform(self, #env_pat: &Self::Environment) #return_type {
+					let Self { #(#arg_names),* } = self;
+					#body
+				}
+			},
+		};
+
+		quote! {
+			#[sa_work_queue::async_trait::async_trait]
+			impl #impl_generics sa_work_queue::Job for #job_path #ty_generics #where_clause {
+				type Environment = #env_type;
+				const JOB_TYPE: &'static str = #job_type;
+				#queue
+
+				#perform
+			}
+		}
+	}
+
+	/// The module holding the `Job` type, nested in `scope`. Jobs without generics are registered automatically.
+	fn job_mod(&self, scope: &TokenStream, field_vis: &TokenStream) -> TokenStream {
+		let name = &self.name;
+		let struct_def = self.args.struct_def(field_vis);
+		let (_, ty_generics, _) = self.generics.split_for_impl();
+		let register = if self.generics_exist { quote!() } else { quote!(sa_work_queue::register_job!(Job);) };
+
+		quote! {
+			pub(crate) mod #name {
+				use #scope::*;
+
+				#[derive(sa_work_queue::Serialize, sa_work_queue::Deserialize)]
+				#[serde(crate = "sa_work_queue::serde")]
+				pub struct Job #ty_generics {
+					#(#struct_def),*
+				}
+
+				#register
+			}
+		}
+	}
+
 	fn try_from(item: syn::ItemFn) -> Result<Self, Diagnostic> {
 		let syn::ItemFn { attrs, vis, sig, block } = item;
 
@@ -148,6 +303,8 @@ impl BackgroundJob {
 
 struct JobArgs {
 	env_arg: EnvArg,
+	/// Pattern of the `&QueueHandle` argument, which jobs enqueue follow-up jobs with.
+	handle_arg: Option<Box<syn::Pat>>,
 	args: Punctuated<syn::PatType, syn::Token![,]>,
 }
 
@@ -158,6 +315,7 @@ impl JobArgs {
 
 	fn try_from(decl: syn::Signature) -> Result<Self, Diagnostic> {
 		let mut env_arg = None;
+		let mut handle_arg = None;
 		let mut args = Punctuated::new();
 
 		for fn_arg in decl.inputs {
@@ -180,15 +338,21 @@ impl JobArgs {
 				(Some(_), Arg::Env(_)) => {
 					return Err(span.error("Background jobs cannot take references as arguments"));
 				}
+				(_, Arg::Handle(pat)) => {
+					if handle_arg.is_some() {
+						return Err(span.error("Background jobs can only take one queue handle"));
+					}
+					handle_arg = Some(pat);
+				}
 				(_, Arg::Normal(pat_type)) => args.push(pat_type),
 			}
 		}
 
-		Ok(Self { env_arg: env_arg.unwrap_or_default(), args })
+		Ok(Self { env_arg: env_arg.unwrap_or_default(), handle_arg, args })
 	}
 
-	fn struct_def(&self) -> impl Iterator<Item = proc_macro2::TokenStream> + '_ {
-		self.args.iter().map(|arg| quote::quote!(pub(super) #arg))
+	fn struct_def<'a>(&'a self, vis: &'a TokenStream) -> impl Iterator<Item = proc_macro2::TokenStream> + 'a {
+		self.args.iter().map(move |arg| quote::quote!(#vis #arg))
 	}
 
 	fn struct_assign(&self) -> impl Iterator<Item = syn::FieldValue> + '_ {
@@ -214,6 +378,8 @@ impl<'a> IntoIterator for &'a JobArgs {
 
 enum Arg {
 	Env(EnvArg),
+	/// A `&QueueHandle`, told apart from the environment by the name of its type.
+	Handle(Box<syn::Pat>),
 	Normal(syn::PatType),
 }
 
@@ -225,13 +391,27 @@ impl Arg {
 			}
 			let pat = pat_type.pat;
 			let ty = type_ref.elem;
-			Ok(Arg::Env(EnvArg { pat, ty }))
+			if is_queue_handle(&ty) {
+				Ok(Arg::Handle(pat))
+			} else {
+				Ok(Arg::Env(EnvArg { pat, ty }))
+			}
 		} else {
 			Ok(Arg::Normal(pat_type))
 		}
 	}
 }
 
+/// `QueueHandle` or a path to it, I.E `sa_work_queue::QueueHandle`.
+fn is_queue_handle(ty: &syn::Type) -> bool {
+	match ty {
+		syn::Type::Path(syn::TypePath { qself: None, path }) => {
+			path.segments.last().map_or(false, |segment| segment.ident == "QueueHandle")
+		}
+		_ => false,
+	}
+}
+
 struct EnvArg {
 	pat: Box<syn::Pat>,
 	ty: Box<syn::Type>,
@@ -9,8 +9,7 @@ mod background_job;
 mod diagnostic_shim;
 
 use proc_macro::TokenStream;
-use proc_macro2::Span;
-use syn::{parse_macro_input, ItemFn};
+use syn::{parse_macro_input, AttributeArgs, Item};
 
 use diagnostic_shim::*;
 
@@ -34,16 +33,68 @@ use diagnostic_shim::*;
 ///     content.modify().send_to_actor_pipeline();
 /// }
 /// ````
+///
+/// Every associated function of an `impl` block becomes a job when the attribute is put on the block.
+/// The `Job` types are namespaced in a module named after the type, and the functions may not take `self`
+/// or refer to `Self`.
+///
+/// ```ignore
+/// struct Website;
+///
+/// #[background_job]
+/// impl Website {
+///     fn crawl(url: String) -> Result<(), PerformError> {
+///         crawl_url(&url)
+///     }
+///
+///     fn archive(url: String) -> Result<(), PerformError> {
+///         archive_url(&url)
+///     }
+/// }
+///
+/// // the job type is `website_jobs::crawl::Job`
+/// Website::crawl("https://parity.io".into()).enqueue(&handle).await?;
+/// ````
+///
+/// Jobs are pushed to the queue of the handle they are enqueued with, unless the attribute names another queue.
+///
+/// ```ignore
+/// #[background_job(queue = "storage")]
+/// fn index_storage(block: Block) -> Result<(), PerformError> {
+///     index(block)
+/// }
+///
+/// // pushed to the `storage` queue, to be run by a runner consuming it
+/// index_storage(block).enqueue(&handle).await?;
+/// ````
+///
+/// Jobs may take a `&QueueHandle` besides the environment, to enqueue follow-up jobs with.
+/// It is the handle of the runner performing the job, and is not part of the arguments of the job.
+///
+/// ```ignore
+/// #[background_job]
+/// fn execute_block(env: &Environment, handle: &QueueHandle, block: Block) -> Result<(), PerformError> {
+///     let storage = execute(env, block)?;
+///     futures::executor::block_on(index_storage(storage).enqueue(handle))?;
+///     Ok(())
+/// }
+/// ````
 #[proc_macro_attribute]
 pub fn background_job(attr: TokenStream, item: TokenStream) -> TokenStream {
-	if !attr.is_empty() {
-		return syn::Error::new(Span::call_site(), "sa_work_queue::background_job does not take arguments")
-			.to_compile_error()
-			.into();
-	}
+	let attrs = match background_job::JobAttrs::try_from(parse_macro_input!(attr as AttributeArgs)) {
+		Ok(attrs) => attrs,
+		Err(e) => return e.to_compile_error().into(),
+	};
 
-	let item = parse_macro_input!(item as ItemFn);
-	emit_errors(background_job::expand(item))
+	match parse_macro_input!(item as Item) {
+		Item::Fn(item) => emit_errors(background_job::expand(item, &attrs)),
+		Item::Impl(item) => emit_errors(background_job::expand_impl(item, &attrs)),
+		item => {
+			syn::Error::new_spanned(item, "sa_work_queue::background_job can only be used on functions and impl blocks")
+				.to_compile_error()
+				.into()
+		}
+	}
 }
 
 fn emit_errors(result: Result<proc_macro2::TokenStream, Diagnostic>) -> TokenStream {